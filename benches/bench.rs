@@ -25,7 +25,7 @@ use mwa_hyperdrive::{
         ShapeletCoeff, Source, SourceComponent, SourceList,
     },
     Chanblock, CrossData, Delays, MsReader, Polarisations, RawDataCorrections, RawDataReader,
-    TileBaselineFlags, Timeblock, UvfitsReader,
+    RobustWeighting, SolveMode, Solver, TileBaselineFlags, Timeblock, UvfitsReader,
 };
 
 fn model_benchmarks(c: &mut Criterion) {
@@ -469,9 +469,14 @@ fn calibrate_benchmarks(c: &mut Criterion) {
                     vis_model.view(),
                     &timeblocks,
                     &chanblocks,
+                    None,
                     50,
                     1e-8,
                     1e-4,
+                    SolveMode::default(),
+                    RobustWeighting::default(),
+                    Solver::default(),
+                    None,
                     Polarisations::default(),
                     false,
                 );
@@ -122,6 +122,58 @@ impl Spw {
         }
         Vec1::try_from_vec(freqs).expect("unlikely to fail as a SPW should have at least 1 channel")
     }
+
+    /// Get a part of this [`Spw`] with only the chanblocks in
+    /// `chanblock_range` (flagged and unflagged), e.g. so that a large spectral
+    /// window can be processed in chunks. `num_chans` is the total number of
+    /// un-averaged channels in the input data. The range of un-averaged
+    /// channels that the chunk covers is also returned.
+    ///
+    /// The chunk's chanblocks keep their `chanblock_index`es, but their
+    /// `unflagged_index`es are into only the unflagged chanblocks of the chunk.
+    /// The flagged channel and chanblock indices of the chunk are relative to
+    /// the start of the chunk.
+    pub(crate) fn get_chunk(
+        &self,
+        chanblock_range: Range<usize>,
+        num_chans: usize,
+    ) -> (Spw, Range<usize>) {
+        let chans_per_chanblock = self.chans_per_chanblock.get();
+        let chan_range = (chanblock_range.start * chans_per_chanblock).min(num_chans)
+            ..(chanblock_range.end * chans_per_chanblock).min(num_chans);
+        let chanblocks = self
+            .chanblocks
+            .iter()
+            .filter(|c| chanblock_range.contains(&usize::from(c.chanblock_index)))
+            .enumerate()
+            .map(|(i, c)| Chanblock {
+                chanblock_index: c.chanblock_index,
+                unflagged_index: i as u16,
+                freq: c.freq,
+            })
+            .collect();
+        let get_relative_indices = |indices: &HashSet<u16>, range: &Range<usize>| {
+            indices
+                .iter()
+                .map(|&i| usize::from(i))
+                .filter(|i| range.contains(i))
+                .map(|i| (i - range.start) as u16)
+                .collect()
+        };
+
+        let spw = Spw {
+            chanblocks,
+            flagged_chan_indices: get_relative_indices(&self.flagged_chan_indices, &chan_range),
+            flagged_chanblock_indices: get_relative_indices(
+                &self.flagged_chanblock_indices,
+                &chanblock_range,
+            ),
+            chans_per_chanblock: self.chans_per_chanblock,
+            freq_res: self.freq_res,
+            first_freq: (chanblock_range.start as f64).mul_add(self.freq_res, self.first_freq),
+        };
+        (spw, chan_range)
+    }
 }
 
 /// Given *all* the available timestamps in some input data, the number of
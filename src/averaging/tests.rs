@@ -281,6 +281,45 @@ fn test_no_channels_to_chanblocks() {
     assert!(spws.is_empty());
 }
 
+#[test]
+fn test_spw_get_chunk() {
+    let all_channel_freqs = [10000, 11000, 12000, 13000, 14000, 15000, 16000, 17000];
+    let freq_average_factor = NonZeroUsize::new(2).unwrap();
+    // The second chanblock is flagged, and so is a channel of the third.
+    let flagged_channels = HashSet::from([2, 3, 5]);
+    let spws = channels_to_chanblocks(
+        &all_channel_freqs,
+        1000,
+        freq_average_factor,
+        &flagged_channels,
+    );
+    assert_eq!(spws.len(), 1);
+    let spw = &spws[0];
+    assert_eq!(spw.chanblocks.len(), 3);
+
+    let (chunk, chan_range) = spw.get_chunk(1..3, all_channel_freqs.len());
+    assert_eq!(chan_range, 2..6);
+    assert_eq!(chunk.chanblocks.len(), 1);
+    assert_eq!(chunk.chanblocks[0].chanblock_index, 2);
+    assert_eq!(chunk.chanblocks[0].unflagged_index, 0);
+    assert_abs_diff_eq!(chunk.chanblocks[0].freq, 14500.0);
+    assert_eq!(chunk.flagged_chan_indices, HashSet::from([0, 1, 3]));
+    assert_eq!(chunk.flagged_chanblock_indices, HashSet::from([0]));
+    assert_abs_diff_eq!(chunk.first_freq, 12500.0);
+    assert_abs_diff_eq!(
+        chunk.get_all_freqs().as_slice(),
+        [12500.0, 14500.0].as_slice()
+    );
+
+    // The last chunk is clipped by the number of channels.
+    let (chunk, chan_range) = spw.get_chunk(3..5, all_channel_freqs.len());
+    assert_eq!(chan_range, 6..8);
+    assert_eq!(chunk.chanblocks.len(), 1);
+    assert_eq!(chunk.chanblocks[0].chanblock_index, 3);
+    assert!(chunk.flagged_chan_indices.is_empty());
+    assert!(chunk.flagged_chanblock_indices.is_empty());
+}
+
 fn test_time(
     time_resolution: Option<Duration>,
     user_input_time_factor: Option<&str>,
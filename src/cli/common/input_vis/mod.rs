@@ -8,7 +8,7 @@ mod tests;
 
 use filenames::{InputDataTypes, GPUBOX_REGEX, MWAX_REGEX};
 
-use std::{collections::HashSet, num::NonZeroUsize, path::PathBuf};
+use std::{collections::HashSet, num::NonZeroUsize, path::PathBuf, str::FromStr};
 
 use clap::Parser;
 use console::style;
@@ -40,8 +40,9 @@ use crate::{
         MsReader, RawDataCorrections, RawDataReader, UvfitsReader, VisInputType, VisRead,
     },
     math::TileBaselineFlags,
-    params::InputVisParams,
-    CalibrationSolutions,
+    params::{InputVisParams, ModelVisParams},
+    solutions::{FreqInterpolation, TimeInterpolation, FREQ_INTERPOLATIONS_COMMA_SEPARATED},
+    CalibrationSolutions, HyperdriveError,
 };
 
 lazy_static::lazy_static! {
@@ -51,6 +52,9 @@ lazy_static::lazy_static! {
     pub(super) static ref MS_DATA_COL_NAME_HELP: String =
         format!("If reading from a measurement set, this specifies the column to use in the main table containing visibilities. Default: {DEFAULT_MS_DATA_COL_NAME}");
 
+    static ref FREQ_INTERPOLATION_HELP: String =
+        format!("How calibration solutions are applied to frequencies that don't match the solutions' chanblocks (e.g. solutions made at a coarser resolution than the data). 'nearest' uses the chanblock nearest to each channel, whereas 'linear' interpolates between the two chanblocks surrounding each channel. Supported methods: {}. Default: {}", *FREQ_INTERPOLATIONS_COMMA_SEPARATED, FreqInterpolation::default());
+
     static ref SUPPORTED_INPUT_FILE_TYPES: String = format!(r#"
     metafits:         .metafits, _metafits.fits
     measurement sets: .ms
@@ -72,6 +76,9 @@ pub(crate) struct InputVisArgs {
     )]
     pub(crate) files: Option<Vec<String>>,
 
+    #[clap(long, help = FREQ_INTERPOLATION_HELP.as_str(), help_heading = "INPUT DATA")]
+    pub(crate) freq_interpolation: Option<String>,
+
     /// The timesteps to use from the input data. Any input will be ascendingly
     /// sorted. No duplicates are allowed. The default is to use all unflagged
     /// timesteps. e.g. The following skips the first two timesteps and use the
@@ -203,6 +210,7 @@ impl InputVisArgs {
     pub(crate) fn merge(self, other: Self) -> Self {
         InputVisArgs {
             files: self.files.or(other.files),
+            freq_interpolation: self.freq_interpolation.or(other.freq_interpolation),
             timesteps: self.timesteps.or(other.timesteps),
             use_all_timesteps: self.use_all_timesteps || other.use_all_timesteps,
             array_position: self.array_position.or(other.array_position),
@@ -230,9 +238,33 @@ impl InputVisArgs {
         }
     }
 
+    /// Read precomputed sky-model visibilities from `files` and match them to
+    /// the input data of `input_vis_params` (which were parsed from these
+    /// arguments). All of the model's timesteps and channels are read, and
+    /// its weights and flags are ignored.
+    pub(crate) fn parse_model_vis(
+        &self,
+        files: Vec<String>,
+        input_vis_params: &InputVisParams,
+    ) -> Result<ModelVisParams, HyperdriveError> {
+        let model_args = InputVisArgs {
+            files: Some(files),
+            use_all_timesteps: true,
+            array_position: self.array_position.clone(),
+            no_autos: true,
+            ignore_weights: true,
+            ignore_input_data_tile_flags: true,
+            ignore_input_data_fine_channel_flags: true,
+            ..Default::default()
+        };
+        let model = model_args.parse("Reading sky model from")?;
+        Ok(ModelVisParams::new(model, input_vis_params)?)
+    }
+
     pub(crate) fn parse(self, operation_verb: &str) -> Result<InputVisParams, InputVisArgsError> {
         let InputVisArgs {
             files,
+            freq_interpolation,
             timesteps,
             use_all_timesteps,
             array_position,
@@ -508,6 +540,12 @@ impl InputVisArgs {
 
         let total_num_tiles = vis_reader.get_obs_context().get_total_num_tiles();
 
+        let freq_interpolation = match freq_interpolation {
+            Some(s) => FreqInterpolation::from_str(&s.to_lowercase())
+                .map_err(|_| InputVisArgsError::UnknownFreqInterpolation(s))?,
+            None => FreqInterpolation::default(),
+        };
+
         // Read the calibration solutions, if they were supplied.
         let mut solutions_block = vec![];
         let solutions = match solutions {
@@ -540,6 +578,29 @@ impl InputVisArgs {
                     });
                 }
 
+                // The solutions need not have the same channelisation as the
+                // data, but they must cover at least some of it.
+                let data_freqs = vis_reader
+                    .get_obs_context()
+                    .fine_chan_freqs
+                    .mapped_ref(|&f| f as f64);
+                if let Some(chanblock_interps) =
+                    sols.get_chanblock_interps(&data_freqs, freq_interpolation)
+                {
+                    let num_uncovered = chanblock_interps.iter().filter(|i| i.is_none()).count();
+                    if num_uncovered == data_freqs.len() {
+                        let sol_freqs = sols.chanblock_freqs.as_ref().expect("is populated");
+                        return Err(InputVisArgsError::SolutionsFreqNoOverlap {
+                            sols_first: *sol_freqs.first() / 1e6,
+                            sols_last: *sol_freqs.last() / 1e6,
+                            data_first: *data_freqs.first() / 1e6,
+                            data_last: *data_freqs.last() / 1e6,
+                        });
+                    } else if num_uncovered > 0 {
+                        format!("{num_uncovered} of the input data's channels aren't covered by the calibration solutions; these will be flagged").warn();
+                    }
+                }
+
                 // Replace raw data corrections in the data args with what's in
                 // the solutions.
                 match sols.raw_data_corrections {
@@ -1110,6 +1171,8 @@ impl InputVisArgs {
         Ok(InputVisParams {
             vis_reader,
             solutions,
+            solutions_time_interpolation: TimeInterpolation::default(),
+            solutions_freq_interpolation: freq_interpolation,
             timeblocks,
             time_res: time_res * time_average_factor.get() as i64,
             spw,
@@ -1188,6 +1251,17 @@ pub(crate) enum InputVisArgsError {
     #[error("The input data and the solutions have different numbers of tiles (data: {data}, solutions: {solutions}); cannot continue")]
     TileCountMismatch { data: usize, solutions: usize },
 
+    #[error("Unrecognised frequency interpolation method '{0}'. Supported methods: {}", *FREQ_INTERPOLATIONS_COMMA_SEPARATED)]
+    UnknownFreqInterpolation(String),
+
+    #[error("The calibration solutions ({sols_first:.3} - {sols_last:.3} MHz) don't cover any of the input data's frequencies ({data_first:.3} - {data_last:.3} MHz); cannot continue")]
+    SolutionsFreqNoOverlap {
+        sols_first: f64,
+        sols_last: f64,
+        data_first: f64,
+        data_last: f64,
+    },
+
     #[error("Error when parsing input data time average factor: {0}")]
     ParseTimeAverageFactor(crate::unit_parsing::UnitParseError),
 
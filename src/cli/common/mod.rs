@@ -62,6 +62,15 @@ lazy_static::lazy_static! {
     pub(super) static ref ARRAY_POSITION_HELP: String =
         format!("The Earth longitude, latitude, and height of the instrumental array [degrees, degrees, meters]. Default (MWA): ({MWA_LONG_DEG}°, {MWA_LAT_DEG}°, {MWA_HEIGHT_M}m)");
 
+    pub(super) static ref MODEL_VIS_HELP: String =
+        "Paths to precomputed sky-model visibilities (a uvfits file or a measurement set, and optionally a metafits file), e.g. those written by hyperdrive with --model-filenames or by another simulator. These are used instead of generating model visibilities from a source list. The model must have the same tiles and phase centre as the input data, its channels must be the input data's channels or chanblocks, and it must have the input data's timestamps (or the centroids of averaged timeblocks).".to_string();
+
+    pub(super) static ref REF_TILE_HELP: String =
+        "Reference the phases of the written solutions to this tile, given as an index or a tile name. The XX and YY phases of the tile are made zero in every chanblock; this doesn't change the calibrated XX and YY visibilities, but does change the phase of the cross-hand visibilities, so it can't be used with an XY-phase correction. The reference tile is recorded in hyperdrive solutions files.".to_string();
+
+    pub(super) static ref REF_CROSS_HANDS_HELP: String =
+        "When referencing phases (--ref-tile), also make the phases of the reference tile's cross-hand (XY and YX) terms zero. Each tile's cross-hand terms are rotated independently, which changes the calibrated cross-hand visibilities further.".to_string();
+
     pub(super) static ref SOURCE_LIST_TYPE_HELP: String =
         format!("The type of sky-model source list. Valid types are: {}. If not specified, all types are attempted", *SOURCE_LIST_TYPES_COMMA_SEPARATED);
 
@@ -7,14 +7,18 @@
 #[cfg(test)]
 mod tests;
 
-use std::{num::NonZeroUsize, path::PathBuf, str::FromStr};
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use clap::Parser;
 use itertools::Itertools;
 use log::{debug, info, log_enabled, trace, Level::Debug};
 use marlu::{
     pos::{precession::precess_time, xyz::xyzs_to_cross_uvws},
-    LatLngHeight, XyzGeodetic,
+    LatLngHeight, XyzGeodetic, UVW,
 };
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -22,13 +26,35 @@ use vec1::{vec1, Vec1};
 
 use super::common::{
     display_warnings, BeamArgs, InfoPrinter, InputVisArgs, ModellingArgs, OutputVisArgs,
-    SkyModelWithVetoArgs, Warn, ARG_FILE_HELP,
+    SkyModelWithVetoArgs, Warn, ARG_FILE_HELP, MODEL_VIS_HELP, REF_CROSS_HANDS_HELP, REF_TILE_HELP,
 };
 use crate::{
-    averaging::{parse_time_average_factor, timesteps_to_timeblocks, AverageFactorError},
+    averaging::{
+        parse_freq_average_factor, parse_time_average_factor, timesteps_to_timeblocks,
+        AverageFactorError,
+    },
+    di_calibrate::{
+        baseline_weights::{
+            get_inverse_density_weights, get_uvw_cutoff_weight, read_baseline_weights,
+            BaselineWeighting, BaselineWeightsFileError, BASELINE_WEIGHTINGS_COMMA_SEPARATED,
+        },
+        get_initial_di_jones,
+        redundant::{RedundantGroups, DEFAULT_REDUNDANCY_TOLERANCE},
+        RobustWeighting, SolveMode, Solver, ROBUST_WEIGHTINGS_COMMA_SEPARATED,
+        SOLVERS_COMMA_SEPARATED, SOLVE_MODES_COMMA_SEPARATED,
+    },
     io::write::{can_write_to_file, VIS_OUTPUT_EXTENSIONS},
-    params::{DiCalParams, ModellingParams},
-    solutions::{self, CalSolutionType, CalibrationSolutions, CAL_SOLUTION_EXTENSIONS},
+    math::TileBaselineFlags,
+    params::{DiCalParams, InputVisParams, JointObsParams, ModellingParams},
+    solutions::{
+        self,
+        outliers::{OutlierParams, DEFAULT_OUTLIER_THRESHOLD},
+        reference::{parse_ref_tile, PhaseReferenceError},
+        smooth::SmoothParams,
+        CalSolutionType, CalibrationSolutions, PhaseReference, SolutionsWriteError,
+        CAL_SOLUTION_EXTENSIONS,
+    },
+    srclist::SourceList,
     unit_parsing::{parse_wavelength, WavelengthUnit, WAVELENGTH_FORMATS},
     HyperdriveError,
 };
@@ -36,24 +62,27 @@ use crate::{
 // The default minimum baseline cutoff.
 const DEFAULT_UVW_MIN: &str = "50λ";
 
+// The default size of the uv cells used for inverse-density baseline weighting.
+const DEFAULT_DENSITY_CELL_SIZE: &str = "5λ";
+
 /// The maximum number of times to iterate when performing calibration in
 /// direction-independent calibration.
-const DEFAULT_MAX_ITERATIONS: u32 = 50;
+pub(super) const DEFAULT_MAX_ITERATIONS: u32 = 50;
 
 /// The threshold to satisfy convergence when performing calibration in
 /// direction-independent calibration.
-const DEFAULT_STOP_THRESHOLD: f64 = 1e-8;
+pub(super) const DEFAULT_STOP_THRESHOLD: f64 = 1e-8;
 
 /// The minimum threshold to satisfy convergence when performing calibration in
 /// direction-independent calibration. Reaching this threshold counts as
 /// "converged", but it's not as good as the stop threshold.
-const DEFAULT_MIN_THRESHOLD: f64 = 1e-4;
+pub(super) const DEFAULT_MIN_THRESHOLD: f64 = 1e-4;
 
 const DEFAULT_OUTPUT_SOLUTIONS_FILENAME: &str = "hyperdrive_solutions.fits";
 
 lazy_static::lazy_static! {
     static ref DI_SOLS_OUTPUTS_HELP: String =
-        format!("Paths to the output calibration solution files. Supported formats: {}. Default: {}", *CAL_SOLUTION_EXTENSIONS, DEFAULT_OUTPUT_SOLUTIONS_FILENAME);
+        format!("Paths to the output calibration solution files. Supported formats: {}. RTS solutions are written into a directory (an existing directory or a path with an \"rts\" extension) and require a metafits file. Default: {}", *CAL_SOLUTION_EXTENSIONS, DEFAULT_OUTPUT_SOLUTIONS_FILENAME);
 
     static ref MODEL_FILENAME_HELP: String =
         format!("The paths to the files where the generated sky-model visibilities are written. If this argument isn't supplied, then no file is written. Supported formats: {}", *VIS_OUTPUT_EXTENSIONS);
@@ -64,13 +93,40 @@ lazy_static::lazy_static! {
     static ref UVW_MAX_HELP: String =
         format!("The maximum UVW length to use. This value must have a unit annotated. Allowed units: {}. No default.", *WAVELENGTH_FORMATS);
 
-    static ref MAX_ITERATIONS_HELP: String =
+    static ref UVW_MIN_TAPER_HELP: String =
+        format!("Instead of excluding baselines shorter than the minimum UVW length, down-weight them with a Gaussian taper with this standard deviation. This value must have a unit annotated. Allowed units: {}. No default.", *WAVELENGTH_FORMATS);
+
+    static ref UVW_MAX_TAPER_HELP: String =
+        format!("Instead of excluding baselines longer than the maximum UVW length, down-weight them with a Gaussian taper with this standard deviation. This value must have a unit annotated. Allowed units: {}. No default.", *WAVELENGTH_FORMATS);
+
+    static ref BASELINE_WEIGHTING_HELP: String =
+        format!("How baselines are weighted according to their positions in the uv plane. 'inverse-density' weights each baseline by the inverse of the number of baselines in its uv cell. Supported weightings: {}. Default: {}", *BASELINE_WEIGHTINGS_COMMA_SEPARATED, BaselineWeighting::default());
+
+    static ref DENSITY_CELL_SIZE_HELP: String =
+        format!("The size of the uv cells used for inverse-density baseline weighting. This value must have a unit annotated. Allowed units: {}. Default: {}", *WAVELENGTH_FORMATS, DEFAULT_DENSITY_CELL_SIZE);
+
+    pub(super) static ref MAX_ITERATIONS_HELP: String =
         format!("The maximum number of times to iterate during calibration. Default: {DEFAULT_MAX_ITERATIONS}");
 
-    static ref STOP_THRESHOLD_HELP: String =
+    pub(super) static ref STOP_THRESHOLD_HELP: String =
         format!("The threshold at which we stop iterating during calibration. Default: {DEFAULT_STOP_THRESHOLD:e}");
 
-    static ref MIN_THRESHOLD_HELP: String =
+    static ref ROBUST_WEIGHTING_HELP: String =
+        format!("How visibilities are weighted while calibrating. 'huber' and 'student-t' iteratively down-weight visibilities with outlying residuals (e.g. from unflagged RFI or unmodelled sources); 'student-t' does so more aggressively. Supported weightings: {}. Default: {}", *ROBUST_WEIGHTINGS_COMMA_SEPARATED, RobustWeighting::default());
+
+    static ref SOLVER_HELP: String =
+        format!("The iteration scheme used to find the calibration solutions. 'anderson' extrapolates from the last few iterations, which usually converges in fewer iterations, particularly for noisy data. Supported solvers: {}. Default: {}", *SOLVERS_COMMA_SEPARATED, Solver::default());
+
+    static ref SOLVE_MODE_HELP: String =
+        format!("How the calibration solutions are constrained. 'diagonal' solves only for gains (no XY/YX leakage terms), 'phase' solves only for gain phases and 'amplitude' solves only for gain amplitudes. Supported modes: {}. Default: {}", *SOLVE_MODES_COMMA_SEPARATED, SolveMode::default());
+
+    static ref REDUNDANCY_TOLERANCE_HELP: String =
+        format!("The maximum difference between the separations of two baselines' tiles for the baselines to be considered redundant [metres]. Implies --redundant. Default: {DEFAULT_REDUNDANCY_TOLERANCE}");
+
+    static ref OUTLIER_THRESHOLD_HELP: String =
+        format!("The number of robust standard deviations that a solution statistic needs to be above the median for a tile or chanblock to be flagged as an outlier. Implies --flag-outliers. Default: {DEFAULT_OUTLIER_THRESHOLD}");
+
+    pub(super) static ref MIN_THRESHOLD_HELP: String =
         format!("The minimum threshold to satisfy convergence during calibration. Even when this threshold is exceeded, iteration will continue until max iterations or the stop threshold is reached. Default: {DEFAULT_MIN_THRESHOLD:e}");
 }
 
@@ -94,6 +150,25 @@ struct DiCalCliArgs {
     #[clap(long, help = UVW_MAX_HELP.as_str(), help_heading = "CALIBRATION")]
     uvw_max: Option<String>,
 
+    #[clap(long, help = UVW_MIN_TAPER_HELP.as_str(), help_heading = "CALIBRATION")]
+    uvw_min_taper: Option<String>,
+
+    #[clap(long, help = UVW_MAX_TAPER_HELP.as_str(), help_heading = "CALIBRATION")]
+    uvw_max_taper: Option<String>,
+
+    #[clap(long, help = BASELINE_WEIGHTING_HELP.as_str(), help_heading = "CALIBRATION")]
+    baseline_weighting: Option<String>,
+
+    #[clap(long, help = DENSITY_CELL_SIZE_HELP.as_str(), help_heading = "CALIBRATION")]
+    density_cell_size: Option<String>,
+
+    /// Path to a text file of weights to multiply the baselines by during
+    /// calibration. There must be one (non-negative) weight per line for every
+    /// baseline, including those of flagged tiles, in the order (0, 1), (0, 2),
+    /// ..., (1, 2), ... Empty lines and lines starting with '#' are ignored.
+    #[clap(long, help_heading = "CALIBRATION")]
+    baseline_weights_file: Option<PathBuf>,
+
     #[clap(long, help = MAX_ITERATIONS_HELP.as_str(), help_heading = "CALIBRATION")]
     max_iterations: Option<u32>,
 
@@ -103,6 +178,112 @@ struct DiCalCliArgs {
     #[clap(long, help = MIN_THRESHOLD_HELP.as_str(), help_heading = "CALIBRATION")]
     min_threshold: Option<f64>,
 
+    #[clap(long, help = SOLVE_MODE_HELP.as_str(), help_heading = "CALIBRATION")]
+    solve_mode: Option<String>,
+
+    #[clap(long, help = ROBUST_WEIGHTING_HELP.as_str(), help_heading = "CALIBRATION")]
+    robust_weighting: Option<String>,
+
+    #[clap(long, help = SOLVER_HELP.as_str(), help_heading = "CALIBRATION")]
+    solver: Option<String>,
+
+    /// Use redundant calibration instead of calibrating against a sky model.
+    /// Baselines are grouped by their tile separations, and the gains are
+    /// solved for together with the visibilities of each group. Baselines
+    /// without redundant partners are not used. The overall amplitude and phase
+    /// of the solutions are arbitrary. No sky-model source list is needed.
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    redundant: bool,
+
+    #[clap(long, help = REDUNDANCY_TOLERANCE_HELP.as_str(), help_heading = "CALIBRATION")]
+    redundancy_tolerance: Option<f64>,
+
+    #[clap(long, multiple_values(true), help = MODEL_VIS_HELP.as_str(), help_heading = "SKY MODEL")]
+    model_vis: Option<Vec<String>>,
+
+    /// The input data files of another observation to calibrate together with
+    /// the main one (given with --data), separated by commas (e.g.
+    /// "obs.metafits,obs.uvfits"). This can be given multiple times, once per
+    /// observation. All observations must have the same tiles and channels,
+    /// and one common set of solutions is made for all of them.
+    #[clap(long, multiple_occurrences(true), help_heading = "CALIBRATION")]
+    joint_data: Option<Vec<String>>,
+
+    /// When calibrating observations jointly, also solve for per-tile phase
+    /// offsets of each observation relative to the main observation (e.g.
+    /// because of the ionosphere).
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    joint_phase_offsets: bool,
+
+    /// Path to existing calibration solutions to use as an initial guess, e.g.
+    /// those of a neighbouring observation of the same field. Tiles and
+    /// chanblocks that can't be matched to the solutions start from identity.
+    #[clap(long, help_heading = "CALIBRATION")]
+    initial_solutions: Option<PathBuf>,
+
+    /// After calibration, flag tiles and chanblocks whose solutions are
+    /// statistical outliers compared to the rest of the array (e.g. a dead
+    /// tile that isn't flagged in the metafits).
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    flag_outliers: bool,
+
+    #[clap(long, help = OUTLIER_THRESHOLD_HELP.as_str(), help_heading = "CALIBRATION")]
+    outlier_threshold: Option<f64>,
+
+    /// If any tiles are flagged as outliers, re-run calibration without them.
+    /// Implies --flag-outliers.
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    recalibrate_without_outliers: bool,
+
+    /// After calibration, fit smooth models over frequency to the solutions
+    /// (as done by solutions-smooth with default settings). Flagged and
+    /// non-converged chanblocks are filled in with the models.
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    smooth_solutions: bool,
+
+    /// After calibration, determine the phase between the X and Y
+    /// polarisations ("XY-phase") and the cross-hand delay that are common to
+    /// all tiles. This needs polarised sources in the sky model. The XY-phase
+    /// is written to hyperdrive solutions files and is applied with the
+    /// solutions.
+    #[clap(long, help_heading = "CALIBRATION")]
+    #[serde(default)]
+    xy_phase: bool,
+
+    #[clap(long, help = REF_TILE_HELP.as_str(), help_heading = "CALIBRATION")]
+    ref_tile: Option<String>,
+
+    #[clap(long, help = REF_CROSS_HANDS_HELP.as_str(), help_heading = "CALIBRATION")]
+    #[serde(default)]
+    ref_cross_hands: bool,
+
+    /// Calibrate this many chanblocks at a time, reading and modelling only
+    /// the visibilities of these chanblocks at once. Also supports a bandwidth
+    /// (e.g. 1280kHz), which must be a multiple of the chanblock resolution.
+    /// The memory needed for calibration scales with this value. The default
+    /// is to calibrate all chanblocks at once.
+    #[clap(long, help_heading = "CALIBRATION")]
+    chanblocks_per_chunk: Option<String>,
+
+    /// Write the model visibilities and any calibrated chunks of chanblocks to
+    /// this directory as they are made. If calibration is interrupted, it can
+    /// be continued with --resume instead of modelling again from scratch. The
+    /// checkpoint is removed once the solutions have been written.
+    #[clap(long, help_heading = "OUTPUT FILES")]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// Continue calibration from the checkpoint in --checkpoint-dir. The
+    /// checkpoint must have been made with the same input data, sky model and
+    /// beam. If there's no checkpoint, calibration starts from scratch.
+    #[clap(long, help_heading = "OUTPUT FILES")]
+    #[serde(default)]
+    resume: bool,
+
     #[clap(long, multiple_values(true), help = MODEL_FILENAME_HELP.as_str(), help_heading = "OUTPUT FILES")]
     model_filenames: Option<Vec<PathBuf>>,
 
@@ -223,14 +404,28 @@ impl DiCalArgs {
             srclist_args,
             model_args,
             beam_args,
-            calibration_args,
+            mut calibration_args,
         } = self;
 
-        let input_vis_params = data_args.parse("DI calibrating")?;
+        let mut input_vis_params = data_args.clone().parse("DI calibrating")?;
+        // Parse the data of any observations to be calibrated with this one.
+        // The timesteps of the main observation don't apply to them.
+        let mut joint_input_vis_params = vec![];
+        for files in calibration_args.joint_data.take().unwrap_or_default() {
+            let joint_data_args = InputVisArgs {
+                files: Some(files.split(',').map(|f| f.to_string()).collect()),
+                timesteps: None,
+                ..data_args.clone()
+            };
+            joint_input_vis_params.push(joint_data_args.parse("Jointly DI calibrating")?);
+        }
+        if !joint_input_vis_params.is_empty() {
+            unify_joint_obs(&mut input_vis_params, &mut joint_input_vis_params)?;
+        }
         let obs_context = input_vis_params.get_obs_context();
         let total_num_tiles = input_vis_params.get_total_num_tiles();
 
-        let beam = beam_args.parse(
+        let beam = beam_args.clone().parse(
             total_num_tiles,
             obs_context.dipole_delays.clone(),
             obs_context.dipole_gains.clone(),
@@ -242,9 +437,33 @@ impl DiCalArgs {
             timesteps_per_timeblock,
             uvw_min,
             uvw_max,
+            uvw_min_taper,
+            uvw_max_taper,
+            baseline_weighting,
+            density_cell_size,
+            baseline_weights_file,
             max_iterations,
             stop_threshold,
             min_threshold,
+            solve_mode,
+            robust_weighting,
+            solver,
+            redundant,
+            redundancy_tolerance,
+            model_vis,
+            joint_data: _,
+            joint_phase_offsets,
+            initial_solutions,
+            flag_outliers,
+            outlier_threshold,
+            recalibrate_without_outliers,
+            smooth_solutions,
+            xy_phase,
+            ref_tile,
+            ref_cross_hands,
+            chanblocks_per_chunk,
+            checkpoint_dir,
+            resume,
             solutions,
             model_filenames,
             output_model_time_average,
@@ -252,35 +471,69 @@ impl DiCalArgs {
             output_smallest_contiguous_band,
         } = calibration_args;
 
-        let LatLngHeight {
-            longitude_rad,
-            latitude_rad,
-            height_metres: _,
-        } = obs_context.array_position;
-        let precession_info = precess_time(
-            longitude_rad,
-            latitude_rad,
-            obs_context.phase_centre,
-            // obs_context.timestamps[*timesteps_to_use.first()],
-            input_vis_params.timeblocks.first().median,
-            input_vis_params.dut1,
-        );
-        let (lst_rad, latitude_rad) = if apply_precession {
-            (
-                precession_info.lmst_j2000,
-                precession_info.array_latitude_j2000,
-            )
+        let (lst_rad, latitude_rad) = get_lst_and_latitude(&input_vis_params, apply_precession);
+
+        let redundant = redundant || redundancy_tolerance.is_some();
+        let model_vis_params = match model_vis {
+            None => None,
+            Some(files) => {
+                for (incompatible, thing) in [
+                    (redundant, "redundant calibration"),
+                    (!joint_input_vis_params.is_empty(), "--joint-data"),
+                ] {
+                    if incompatible {
+                        return Err(DiCalArgsError::ModelVisIncompatible(thing).into());
+                    }
+                }
+                Some(data_args.parse_model_vis(files, &input_vis_params)?)
+            }
+        };
+        // Redundant calibration doesn't use a sky model, and precomputed model
+        // visibilities replace the source list.
+        let source_list = if redundant || model_vis_params.is_some() {
+            SourceList::new()
         } else {
-            (precession_info.lmst, latitude_rad)
+            srclist_args.clone().parse(
+                obs_context.phase_centre,
+                lst_rad,
+                latitude_rad,
+                &obs_context.get_veto_freqs(),
+                &*beam,
+            )?
         };
 
-        let source_list = srclist_args.parse(
-            obs_context.phase_centre,
-            lst_rad,
-            latitude_rad,
-            &obs_context.get_veto_freqs(),
-            &*beam,
-        )?;
+        // Each joint observation needs its own beam and sky model. Keep the
+        // LSTs for UVW cutoffs.
+        let mut joint_obs = vec![];
+        let mut joint_lsts = vec![];
+        for joint_input_vis_params in joint_input_vis_params {
+            let joint_obs_context = joint_input_vis_params.get_obs_context();
+            let beam = beam_args.clone().parse(
+                total_num_tiles,
+                joint_obs_context.dipole_delays.clone(),
+                joint_obs_context.dipole_gains.clone(),
+                Some(joint_obs_context.input_data_type),
+            )?;
+            let (lst_rad, latitude_rad) =
+                get_lst_and_latitude(&joint_input_vis_params, apply_precession);
+            let source_list = if redundant {
+                SourceList::new()
+            } else {
+                srclist_args.clone().parse(
+                    joint_obs_context.phase_centre,
+                    lst_rad,
+                    latitude_rad,
+                    &joint_obs_context.get_veto_freqs(),
+                    &*beam,
+                )?
+            };
+            joint_lsts.push(lst_rad);
+            joint_obs.push(JointObsParams {
+                input_vis_params: joint_input_vis_params,
+                beam,
+                source_list,
+            });
+        }
 
         // Set up the calibration timeblocks.
         let time_average_factor = parse_time_average_factor(
@@ -310,12 +563,26 @@ impl DiCalArgs {
                 .collect(),
         )
         .expect("cannot be empty");
-        let cal_timeblocks = timesteps_to_timeblocks(
+        let mut cal_timeblocks = timesteps_to_timeblocks(
             &all_selected_timestamps,
             input_vis_params.time_res,
             time_average_factor,
             None,
         );
+        // Joint observations are stacked after the main observation and
+        // calibrated together as a single timeblock.
+        if !joint_obs.is_empty() {
+            if cal_timeblocks.len() > 1 {
+                return Err(DiCalArgsError::JointMultipleTimeblocks.into());
+            }
+            let timeblock = cal_timeblocks.first_mut();
+            for joint_obs in &joint_obs {
+                for tb in joint_obs.input_vis_params.timeblocks.iter() {
+                    timeblock.timestamps.extend(tb.timestamps.iter());
+                }
+            }
+            timeblock.range = 0..timeblock.timestamps.len();
+        }
 
         let mut cal_printer = InfoPrinter::new("DI calibration set up".into());
         // I'm quite bored right now.
@@ -339,6 +606,33 @@ impl DiCalArgs {
             format!("{time_average_factor} timesteps per timeblock").into(),
             // format!("{freq_average_factor} channels per chanblock").into(), // TODO: Not yet implemented
         ]);
+        if !joint_obs.is_empty() {
+            let mut block = vec![format!(
+                "Jointly calibrating with {} other observations",
+                joint_obs.len()
+            )
+            .into()];
+            for joint_obs in &joint_obs {
+                let joint_obs_context = joint_obs.input_vis_params.get_obs_context();
+                block.push(
+                    format!(
+                        "- obsid {}, {} timesteps",
+                        joint_obs_context
+                            .obsid
+                            .map(|o| o.to_string())
+                            .unwrap_or_else(|| "<unknown>".to_string()),
+                        joint_obs.input_vis_params.timeblocks.len()
+                    )
+                    .into(),
+                );
+            }
+            if joint_phase_offsets {
+                block.push("Solving for per-observation phase offsets".into());
+            }
+            cal_printer.push_block(block);
+        } else if joint_phase_offsets {
+            "--joint-phase-offsets does nothing without --joint-data".warn();
+        }
 
         // Set baseline weights from UVW cuts. Use a lambda from the centroid
         // frequency if UVW cutoffs are specified as wavelengths.
@@ -381,31 +675,121 @@ impl DiCalArgs {
             .map(|(_, xyz)| *xyz)
             .collect();
 
+        // Gaussian tapers beyond the UVW cutoffs; no taper means a hard cutoff.
+        let uvw_min_taper = uvw_min_taper
+            .map(|s| {
+                parse_wavelength_to_metres(&s, lambda).map_err(DiCalArgsError::ParseUvwMinTaper)
+            })
+            .transpose()?;
+        let uvw_max_taper = uvw_max_taper
+            .map(|s| {
+                parse_wavelength_to_metres(&s, lambda).map_err(DiCalArgsError::ParseUvwMaxTaper)
+            })
+            .transpose()?;
+        for ((quantity, _), _) in uvw_min_taper.iter().chain(uvw_max_taper.iter()) {
+            if *quantity < 0.0 {
+                return Err(DiCalArgsError::BadUvwTaper(*quantity).into());
+            }
+        }
+        let uvw_min_taper_metres = uvw_min_taper.map(|(_, m)| m).unwrap_or(0.0);
+        let uvw_max_taper_metres = uvw_max_taper.map(|(_, m)| m).unwrap_or(0.0);
+
+        let baseline_weighting = match baseline_weighting {
+            None => BaselineWeighting::default(),
+            Some(s) => BaselineWeighting::from_str(&s.to_lowercase())
+                .map_err(|_| DiCalArgsError::UnknownBaselineWeighting(s))?,
+        };
+        let density_cell_size = match baseline_weighting {
+            BaselineWeighting::Natural => {
+                if density_cell_size.is_some() {
+                    "--density-cell-size does nothing without inverse-density baseline weighting"
+                        .warn();
+                }
+                None
+            }
+            BaselineWeighting::InverseDensity => {
+                let cell_size @ ((quantity, _), _) = parse_wavelength_to_metres(
+                    density_cell_size
+                        .as_deref()
+                        .unwrap_or(DEFAULT_DENSITY_CELL_SIZE),
+                    lambda,
+                )
+                .map_err(DiCalArgsError::ParseDensityCellSize)?;
+                if quantity <= 0.0 {
+                    return Err(DiCalArgsError::BadDensityCellSize(quantity).into());
+                }
+                Some(cell_size)
+            }
+        };
+
         let (baseline_weights, num_flagged_baselines) = {
-            let mut baseline_weights = Vec1::try_from_vec(vec![
-                1.0;
+            let uvws = xyzs_to_cross_uvws(
+                &unflagged_tile_xyzs,
+                obs_context.phase_centre.to_hadec(lst_rad),
+            );
+            let get_weight = |uvw: &UVW| {
+                let uvw_length = (uvw.u.powi(2) + uvw.v.powi(2) + uvw.w.powi(2)).sqrt();
+                get_uvw_cutoff_weight(
+                    uvw_length,
+                    uvw_min_metres,
+                    uvw_max_metres,
+                    uvw_min_taper_metres,
+                    uvw_max_taper_metres,
+                )
+            };
+            let mut baseline_weights: Vec<f64> = uvws.iter().map(&get_weight).collect();
+            assert_eq!(
+                baseline_weights.len(),
                 input_vis_params
                     .tile_baseline_flags
                     .unflagged_cross_baseline_to_tile_map
                     .len()
-            ])
-            .expect("not possible to have no unflagged tiles here");
-            let uvws = xyzs_to_cross_uvws(
-                &unflagged_tile_xyzs,
-                obs_context.phase_centre.to_hadec(lst_rad),
             );
-            assert_eq!(baseline_weights.len(), uvws.len());
-            let uvw_min = uvw_min_metres.powi(2);
-            let uvw_max = uvw_max_metres.powi(2);
-            let mut num_flagged_baselines = 0;
-            for (uvw, baseline_weight) in uvws.into_iter().zip(baseline_weights.iter_mut()) {
-                let uvw_length = uvw.u.powi(2) + uvw.v.powi(2) + uvw.w.powi(2);
-                if uvw_length < uvw_min || uvw_length > uvw_max {
-                    *baseline_weight = 0.0;
-                    num_flagged_baselines += 1;
+            // A baseline only gets the full weight if it's within the cutoffs
+            // for all joint observations.
+            for (joint_obs, &lst_rad) in joint_obs.iter().zip(joint_lsts.iter()) {
+                let uvws = xyzs_to_cross_uvws(
+                    &unflagged_tile_xyzs,
+                    joint_obs
+                        .input_vis_params
+                        .get_obs_context()
+                        .phase_centre
+                        .to_hadec(lst_rad),
+                );
+                for (uvw, baseline_weight) in uvws.iter().zip(baseline_weights.iter_mut()) {
+                    *baseline_weight = baseline_weight.min(get_weight(uvw));
+                }
+            }
+
+            if let Some((_, cell_size)) = density_cell_size {
+                let density_weights =
+                    get_inverse_density_weights(&uvws, &baseline_weights, cell_size);
+                for (baseline_weight, density_weight) in
+                    baseline_weights.iter_mut().zip(density_weights)
+                {
+                    *baseline_weight *= density_weight;
                 }
             }
-            (baseline_weights, num_flagged_baselines)
+
+            if let Some(file) = baseline_weights_file.as_ref() {
+                let file_weights = read_baseline_weights(
+                    file,
+                    total_num_tiles,
+                    &input_vis_params.tile_baseline_flags.flagged_tiles,
+                )
+                .map_err(DiCalArgsError::from)?;
+                for (baseline_weight, file_weight) in baseline_weights.iter_mut().zip(file_weights)
+                {
+                    *baseline_weight *= file_weight;
+                }
+            }
+
+            let num_flagged_baselines = baseline_weights.iter().filter(|&&w| w <= 0.0).count();
+            (
+                Vec1::try_from_vec(baseline_weights)
+                    .expect("not possible to have no unflagged tiles here"),
+                num_flagged_baselines,
+            )
         };
         if num_flagged_baselines == baseline_weights.len() {
             return Err(DiCalArgsError::AllBaselinesFlaggedFromUvwCutoffs.into());
@@ -447,9 +831,43 @@ impl DiCalArgs {
                 .into(),
             ),
         }
+        for (taper, label) in [(uvw_min_taper, "Minimum"), (uvw_max_taper, "Maximum")] {
+            match taper {
+                None => (),
+                Some(((quantity, WavelengthUnit::M), _)) => {
+                    block.push(format!("{label} UVW Gaussian taper: {quantity}m").into())
+                }
+                Some(((quantity, WavelengthUnit::L), metres)) => block
+                    .push(format!("{label} UVW Gaussian taper: {quantity}λ ({metres:.3}m)").into()),
+            }
+        }
+        match density_cell_size {
+            None => (),
+            Some(((quantity, WavelengthUnit::M), _)) => block.push(
+                format!("Inverse-density baseline weighting (cell size: {quantity}m)").into(),
+            ),
+            Some(((quantity, WavelengthUnit::L), metres)) => block.push(
+                format!(
+                    "Inverse-density baseline weighting (cell size: {quantity}λ ({metres:.3}m))"
+                )
+                .into(),
+            ),
+        }
+        if let Some(file) = baseline_weights_file.as_ref() {
+            block.push(format!("Using baseline weights from {}", file.display()).into());
+        }
         // Report extra info if we need to use our own lambda (the user
         // specified wavelengths).
-        if matches!(uvw_min.1, WavelengthUnit::L) || matches!(uvw_max.1, WavelengthUnit::L) {
+        if [
+            Some(uvw_min.1),
+            Some(uvw_max.1),
+            uvw_min_taper.map(|((_, u), _)| u),
+            uvw_max_taper.map(|((_, u), _)| u),
+            density_cell_size.map(|((_, u), _)| u),
+        ]
+        .into_iter()
+        .any(|u| matches!(u, Some(WavelengthUnit::L)))
+        {
             block.push(
                 format!(
                     "(Used obs. centroid frequency {} MHz to convert lambdas to metres)",
@@ -495,6 +913,217 @@ impl DiCalArgs {
             stop_threshold = min_threshold;
         }
         let max_iterations = max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS);
+        let solve_mode = match solve_mode {
+            None => SolveMode::default(),
+            Some(s) => SolveMode::from_str(&s.to_lowercase())
+                .map_err(|_| DiCalArgsError::UnknownSolveMode(s))?,
+        };
+        cal_printer.push_line(format!("Solve mode: {solve_mode}").into());
+        let robust_weighting = match robust_weighting {
+            None => RobustWeighting::default(),
+            Some(s) => RobustWeighting::from_str(&s.to_lowercase())
+                .map_err(|_| DiCalArgsError::UnknownRobustWeighting(s))?,
+        };
+        cal_printer.push_line(format!("Robust weighting: {robust_weighting}").into());
+        let solver = match solver {
+            None => Solver::default(),
+            Some(s) => {
+                Solver::from_str(&s.to_lowercase()).map_err(|_| DiCalArgsError::UnknownSolver(s))?
+            }
+        };
+        cal_printer.push_line(format!("Solver: {solver}").into());
+
+        let redundant_groups = if redundant {
+            let tolerance = redundancy_tolerance.unwrap_or(DEFAULT_REDUNDANCY_TOLERANCE);
+            if tolerance <= 0.0 {
+                return Err(DiCalArgsError::BadRedundancyTolerance(tolerance).into());
+            }
+            let groups = RedundantGroups::new(&unflagged_tile_xyzs, tolerance);
+            let num_redundant_baselines = groups.num_redundant_baselines();
+            if num_redundant_baselines == 0 {
+                return Err(DiCalArgsError::NoRedundantBaselines(tolerance).into());
+            }
+            cal_printer.push_block(vec![
+                format!("Redundant calibration (tolerance: {tolerance}m)").into(),
+                format!(
+                    "{num_redundant_baselines} of {} baselines are in {} redundant groups",
+                    baseline_weights.len(),
+                    groups.num_groups
+                )
+                .into(),
+            ]);
+            Some(groups)
+        } else {
+            None
+        };
+
+        if joint_phase_offsets && !joint_obs.is_empty() && redundant_groups.is_some() {
+            return Err(DiCalArgsError::JointPhaseOffsetsRedundant.into());
+        }
+
+        let outlier_params =
+            if flag_outliers || outlier_threshold.is_some() || recalibrate_without_outliers {
+                let threshold = outlier_threshold.unwrap_or(DEFAULT_OUTLIER_THRESHOLD);
+                if threshold <= 0.0 {
+                    return Err(DiCalArgsError::BadOutlierThreshold(threshold).into());
+                }
+                cal_printer.push_line(
+                    format!("Flagging outlier tiles and chanblocks (threshold: {threshold})")
+                        .into(),
+                );
+                if recalibrate_without_outliers {
+                    cal_printer.push_line("Re-calibrating without outlier tiles".into());
+                }
+                Some(OutlierParams {
+                    threshold,
+                    recalibrate: recalibrate_without_outliers,
+                })
+            } else {
+                None
+            };
+
+        let smooth_params = if smooth_solutions {
+            cal_printer.push_line("Smoothing solutions after calibration".into());
+            Some(SmoothParams::default())
+        } else {
+            None
+        };
+
+        if xy_phase {
+            if redundant_groups.is_some() {
+                return Err(DiCalArgsError::XyPhaseRedundant.into());
+            }
+            cal_printer.push_line("Determining the XY-phase after calibration".into());
+        }
+
+        let phase_reference = match ref_tile {
+            Some(_) if xy_phase => return Err(PhaseReferenceError::XyPhase.into()),
+            Some(tile) => {
+                let obs_context = input_vis_params.get_obs_context();
+                let tile = parse_ref_tile(
+                    &tile,
+                    Some(obs_context.tile_names.as_slice()),
+                    obs_context.get_total_num_tiles(),
+                )?;
+                // Flagged tiles don't get solutions.
+                if input_vis_params
+                    .tile_baseline_flags
+                    .flagged_tiles
+                    .contains(&tile)
+                {
+                    return Err(PhaseReferenceError::NoSolutions(tile).into());
+                }
+                cal_printer.push_line(
+                    format!(
+                        "Referencing solution phases to tile {tile} ({})",
+                        obs_context.tile_names[tile]
+                    )
+                    .into(),
+                );
+                Some(PhaseReference {
+                    tile,
+                    cross_hands: ref_cross_hands,
+                })
+            }
+            None => {
+                if ref_cross_hands {
+                    "--ref-cross-hands does nothing without --ref-tile".warn();
+                }
+                None
+            }
+        };
+
+        let total_num_chanblocks = input_vis_params.spw.chanblocks.len()
+            + input_vis_params.spw.flagged_chanblock_indices.len();
+        let chanblocks_per_chunk = match chanblocks_per_chunk {
+            None => None,
+            Some(s) => {
+                let n = parse_freq_average_factor(
+                    Some(input_vis_params.spw.freq_res),
+                    Some(&s),
+                    NonZeroUsize::new(total_num_chanblocks).expect("is not 0"),
+                )
+                .map_err(|e| match e {
+                    AverageFactorError::Zero => DiCalArgsError::ChunkSizeZero,
+                    AverageFactorError::NotInteger => DiCalArgsError::ChunkSizeNotInteger,
+                    AverageFactorError::NotIntegerMultiple { out, inp } => {
+                        DiCalArgsError::ChunkSizeNotMultiple { out, inp }
+                    }
+                    AverageFactorError::Parse(e) => DiCalArgsError::ParseChunkSize(e),
+                })?;
+                // A chunk with all of the chanblocks is the same as not using
+                // chunks.
+                if n.get() >= total_num_chanblocks {
+                    None
+                } else {
+                    for (incompatible, thing) in [
+                        (model_filenames.is_some(), "--model-filenames"),
+                        (xy_phase, "--xy-phase"),
+                        (
+                            input_vis_params
+                                .solutions
+                                .as_ref()
+                                .map(|s| s.chanblock_freqs.is_none())
+                                .unwrap_or(false),
+                            "input solutions without chanblock frequencies",
+                        ),
+                        (
+                            joint_phase_offsets && !joint_obs.is_empty(),
+                            "--joint-phase-offsets",
+                        ),
+                    ] {
+                        if incompatible {
+                            return Err(DiCalArgsError::ChunkedIncompatible(thing).into());
+                        }
+                    }
+                    cal_printer.push_line(format!("Calibrating {n} chanblocks at a time").into());
+                    Some(n)
+                }
+            }
+        };
+
+        match (&checkpoint_dir, resume) {
+            (None, true) => return Err(DiCalArgsError::ResumeWithoutCheckpoint.into()),
+            (None, false) => (),
+            (Some(dir), _) => cal_printer.push_line(
+                format!(
+                    "{} checkpoint in {}",
+                    if resume { "Resuming from" } else { "Writing" },
+                    dir.display()
+                )
+                .into(),
+            ),
+        }
+
+        let initial_di_jones = match initial_solutions {
+            None => None,
+            Some(initial_solutions) => {
+                // The metafits file is only needed for reading RTS solutions.
+                let sols = CalibrationSolutions::read_solutions_from_ext_inner(
+                    &initial_solutions,
+                    input_vis_params
+                        .vis_reader
+                        .get_metafits_context()
+                        .map(|c| Path::new(&c.metafits_filename)),
+                )?;
+                cal_printer.push_line(
+                    format!(
+                        "Using initial solutions from {}",
+                        initial_solutions.display()
+                    )
+                    .into(),
+                );
+                Some(get_initial_di_jones(
+                    &sols,
+                    &cal_timeblocks,
+                    &input_vis_params.spw.chanblocks,
+                    input_vis_params.spw.chanblocks.len()
+                        + input_vis_params.spw.flagged_chanblock_indices.len(),
+                    &obs_context.tile_names,
+                    &input_vis_params.tile_baseline_flags.flagged_tiles,
+                ))
+            }
+        };
 
         cal_printer.push_block(vec![
             "Chanblocks will stop iterating".into(),
@@ -528,8 +1157,12 @@ impl DiCalArgs {
                     let mut cal_sols = vec![];
                     for file in outputs {
                         // Is the output file type supported?
-                        let ext = file.extension().and_then(|os_str| os_str.to_str());
-                        match ext.and_then(|s| CalSolutionType::from_str(s).ok()) {
+                        match CalSolutionType::from_path(&file) {
+                            Some(CalSolutionType::Rts)
+                                if input_vis_params.vis_reader.get_metafits_context().is_none() =>
+                            {
+                                return Err(SolutionsWriteError::RtsMetafitsRequired.into())
+                            }
                             Some(sol_type) => {
                                 trace!("{} is a solution output", file.display());
                                 can_write_to_file(&file)
@@ -538,7 +1171,11 @@ impl DiCalArgs {
                             }
                             None => {
                                 return Err(DiCalArgsError::CalibrationOutputFile {
-                                    ext: ext.unwrap_or("<no extension>").to_string(),
+                                    ext: file
+                                        .extension()
+                                        .and_then(|os_str| os_str.to_str())
+                                        .unwrap_or("<no extension>")
+                                        .to_string(),
                                 }
                                 .into())
                             }
@@ -594,6 +1231,7 @@ impl DiCalArgs {
             input_vis_params,
             beam,
             source_list,
+            model_vis_params,
             cal_timeblocks,
             uvw_min: uvw_min_metres,
             uvw_max: uvw_max_metres,
@@ -602,6 +1240,20 @@ impl DiCalArgs {
             max_iterations,
             stop_threshold,
             min_threshold,
+            solve_mode,
+            robust_weighting,
+            solver,
+            redundant_groups,
+            initial_di_jones,
+            outlier_params,
+            smooth_params,
+            xy_phase,
+            phase_reference,
+            joint_obs,
+            joint_phase_offsets,
+            chanblocks_per_chunk,
+            checkpoint_dir,
+            resume,
             output_solution_files,
             output_model_vis_params,
             modelling_params,
@@ -617,6 +1269,7 @@ impl DiCalArgs {
         let params = self.parse()?;
 
         if dry_run {
+            params.get_resource_estimate().display();
             info!("Dry run -- exiting now.");
             return Ok(None);
         }
@@ -625,10 +1278,23 @@ impl DiCalArgs {
 
         // Write out the solutions.
         let num_solution_files = params.output_solution_files.len();
-        for (i, (file, sol_type)) in params.output_solution_files.into_iter().enumerate() {
+        for (i, (file, sol_type)) in params.output_solution_files.iter().enumerate() {
             match sol_type {
-                CalSolutionType::Fits => solutions::hyperdrive::write(&sols, &file)?,
-                CalSolutionType::Bin => solutions::ao::write(&sols, &file)?,
+                CalSolutionType::Fits => solutions::hyperdrive::write(&sols, file)?,
+                CalSolutionType::Bin => solutions::ao::write(&sols, file)?,
+                CalSolutionType::Casa => {
+                    solutions::casa::write(&sols, file, Some(params.input_vis_params.spw.freq_res))?
+                }
+                CalSolutionType::Rts => solutions::rts::write(
+                    &sols,
+                    file,
+                    params
+                        .input_vis_params
+                        .vis_reader
+                        .get_metafits_context()
+                        .expect("checked when parsing arguments"),
+                )
+                .map_err(SolutionsWriteError::from)?,
             }
             if num_solution_files == 1 {
                 info!("Calibration solutions written to {}", file.display());
@@ -639,11 +1305,87 @@ impl DiCalArgs {
                 info!("  {}", file.display());
             }
         }
+        // The checkpoint isn't needed now that the solutions are safe.
+        params.remove_checkpoint()?;
 
         Ok(Some(sols))
     }
 }
 
+/// Get the LST and array latitude \[radians\] of the first timeblock of an
+/// observation, precessed to J2000 if requested.
+fn get_lst_and_latitude(input_vis_params: &InputVisParams, apply_precession: bool) -> (f64, f64) {
+    let obs_context = input_vis_params.get_obs_context();
+    let LatLngHeight {
+        longitude_rad,
+        latitude_rad,
+        height_metres: _,
+    } = obs_context.array_position;
+    let precession_info = precess_time(
+        longitude_rad,
+        latitude_rad,
+        obs_context.phase_centre,
+        input_vis_params.timeblocks.first().median,
+        input_vis_params.dut1,
+    );
+    if apply_precession {
+        (
+            precession_info.lmst_j2000,
+            precession_info.array_latitude_j2000,
+        )
+    } else {
+        (precession_info.lmst, latitude_rad)
+    }
+}
+
+/// Check that observations to be calibrated jointly have the same tiles and
+/// chanblocks as the main observation, and give them all the same tile flags
+/// (a tile flagged in any observation is flagged in all of them), so that
+/// their visibilities can be stacked.
+fn unify_joint_obs(
+    input_vis_params: &mut InputVisParams,
+    joint_input_vis_params: &mut [InputVisParams],
+) -> Result<(), DiCalArgsError> {
+    let total_num_tiles = input_vis_params.get_total_num_tiles();
+    let get_freqs =
+        |p: &InputVisParams| p.spw.chanblocks.iter().map(|c| c.freq).collect::<Vec<_>>();
+    let freqs = get_freqs(input_vis_params);
+    let mut flagged_tiles = input_vis_params.tile_baseline_flags.flagged_tiles.clone();
+    for (i_obs, joint) in joint_input_vis_params.iter().enumerate() {
+        if joint.get_obs_context().tile_names != input_vis_params.get_obs_context().tile_names {
+            return Err(DiCalArgsError::JointTilesMismatch(i_obs + 1));
+        }
+        if get_freqs(joint) != freqs
+            || joint.spw.flagged_chanblock_indices != input_vis_params.spw.flagged_chanblock_indices
+        {
+            return Err(DiCalArgsError::JointChanblocksMismatch(i_obs + 1));
+        }
+        flagged_tiles.extend(joint.tile_baseline_flags.flagged_tiles.iter().copied());
+    }
+
+    for p in std::iter::once(input_vis_params).chain(joint_input_vis_params.iter_mut()) {
+        if p.tile_baseline_flags.flagged_tiles != flagged_tiles {
+            p.tile_baseline_flags = TileBaselineFlags::new(total_num_tiles, flagged_tiles.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Parse a string with a wavelength unit (e.g. "10λ"), returning the quantity
+/// and unit as well as the quantity in metres. `lambda` is used to convert
+/// wavelengths to metres.
+fn parse_wavelength_to_metres(
+    s: &str,
+    lambda: f64,
+) -> Result<((f64, WavelengthUnit), f64), crate::unit_parsing::UnitParseError> {
+    let (quantity, unit) = parse_wavelength(s)?;
+    let metres = match unit {
+        WavelengthUnit::M => quantity,
+        WavelengthUnit::L => quantity * lambda,
+    };
+    Ok(((quantity, unit), metres))
+}
+
 /// Errors associated with DI calibration arguments.
 #[derive(thiserror::Error, Debug)]
 pub(super) enum DiCalArgsError {
@@ -681,12 +1423,91 @@ pub(super) enum DiCalArgsError {
 
     // #[error("Calibration freq. average factor cannot be 0")]
     // CalFreqFactorZero,
+    #[error("Unrecognised calibration solve mode '{0}'. Supported modes: {}", *SOLVE_MODES_COMMA_SEPARATED)]
+    UnknownSolveMode(String),
+
+    #[error("Unrecognised calibration robust weighting '{0}'. Supported weightings: {}", *ROBUST_WEIGHTINGS_COMMA_SEPARATED)]
+    UnknownRobustWeighting(String),
+
+    #[error("Unrecognised calibration solver '{0}'. Supported solvers: {}", *SOLVERS_COMMA_SEPARATED)]
+    UnknownSolver(String),
+
+    #[error("The redundancy tolerance must be positive; got {0}")]
+    BadRedundancyTolerance(f64),
+
+    #[error("No redundant baselines were found with a redundancy tolerance of {0}m; cannot do redundant calibration")]
+    NoRedundantBaselines(f64),
+
+    #[error("Joint observation {0} doesn't have the same tiles as the main observation")]
+    JointTilesMismatch(usize),
+
+    #[error(
+        "Joint observation {0} doesn't have the same (unflagged) channels as the main observation"
+    )]
+    JointChanblocksMismatch(usize),
+
+    #[error("Joint calibration makes a single timeblock, but the calibration time average factor gives multiple timeblocks")]
+    JointMultipleTimeblocks,
+
+    #[error("Joint phase offsets can't be solved for with redundant calibration")]
+    JointPhaseOffsetsRedundant,
+
+    #[error("The XY-phase can't be determined with redundant calibration")]
+    XyPhaseRedundant,
+
+    #[error("The outlier threshold must be positive; got {0}")]
+    BadOutlierThreshold(f64),
+
+    #[error("Error when parsing the number of chanblocks per chunk: {0}")]
+    ParseChunkSize(crate::unit_parsing::UnitParseError),
+
+    #[error("The number of chanblocks per chunk isn't an integer")]
+    ChunkSizeNotInteger,
+
+    #[error(
+        "The chunk bandwidth isn't a multiple of the chanblock resolution: {out} Hz vs {inp} Hz"
+    )]
+    ChunkSizeNotMultiple { out: f64, inp: f64 },
+
+    #[error("The number of chanblocks per chunk cannot be 0")]
+    ChunkSizeZero,
+
+    #[error("Chanblocks can't be calibrated in chunks with {0}")]
+    ChunkedIncompatible(&'static str),
+
+    #[error("Can't resume calibration without a checkpoint directory (--checkpoint-dir)")]
+    ResumeWithoutCheckpoint,
+
+    #[error("Precomputed model visibilities (--model-vis) can't be used with {0}")]
+    ModelVisIncompatible(&'static str),
+
     #[error("Error when parsing minimum UVW cutoff: {0}")]
     ParseUvwMin(crate::unit_parsing::UnitParseError),
 
     #[error("Error when parsing maximum UVW cutoff: {0}")]
     ParseUvwMax(crate::unit_parsing::UnitParseError),
 
+    #[error("Error when parsing minimum UVW taper: {0}")]
+    ParseUvwMinTaper(crate::unit_parsing::UnitParseError),
+
+    #[error("Error when parsing maximum UVW taper: {0}")]
+    ParseUvwMaxTaper(crate::unit_parsing::UnitParseError),
+
+    #[error("Error when parsing density cell size: {0}")]
+    ParseDensityCellSize(crate::unit_parsing::UnitParseError),
+
+    #[error("UVW tapers must not be negative; got {0}")]
+    BadUvwTaper(f64),
+
+    #[error("The density cell size must be positive; got {0}")]
+    BadDensityCellSize(f64),
+
+    #[error("Unrecognised baseline weighting '{0}'. Supported weightings: {}", *BASELINE_WEIGHTINGS_COMMA_SEPARATED)]
+    UnknownBaselineWeighting(String),
+
+    #[error(transparent)]
+    BaselineWeightsFile(#[from] BaselineWeightsFileError),
+
     #[error(transparent)]
     IO(#[from] std::io::Error),
 }
@@ -699,9 +1520,34 @@ impl DiCalCliArgs {
                 .or(other.timesteps_per_timeblock),
             uvw_min: self.uvw_min.or(other.uvw_min),
             uvw_max: self.uvw_max.or(other.uvw_max),
+            uvw_min_taper: self.uvw_min_taper.or(other.uvw_min_taper),
+            uvw_max_taper: self.uvw_max_taper.or(other.uvw_max_taper),
+            baseline_weighting: self.baseline_weighting.or(other.baseline_weighting),
+            density_cell_size: self.density_cell_size.or(other.density_cell_size),
+            baseline_weights_file: self.baseline_weights_file.or(other.baseline_weights_file),
             max_iterations: self.max_iterations.or(other.max_iterations),
             stop_threshold: self.stop_threshold.or(other.stop_threshold),
             min_threshold: self.min_threshold.or(other.min_threshold),
+            solve_mode: self.solve_mode.or(other.solve_mode),
+            robust_weighting: self.robust_weighting.or(other.robust_weighting),
+            solver: self.solver.or(other.solver),
+            redundant: self.redundant || other.redundant,
+            redundancy_tolerance: self.redundancy_tolerance.or(other.redundancy_tolerance),
+            model_vis: self.model_vis.or(other.model_vis),
+            joint_data: self.joint_data.or(other.joint_data),
+            joint_phase_offsets: self.joint_phase_offsets || other.joint_phase_offsets,
+            initial_solutions: self.initial_solutions.or(other.initial_solutions),
+            flag_outliers: self.flag_outliers || other.flag_outliers,
+            outlier_threshold: self.outlier_threshold.or(other.outlier_threshold),
+            recalibrate_without_outliers: self.recalibrate_without_outliers
+                || other.recalibrate_without_outliers,
+            smooth_solutions: self.smooth_solutions || other.smooth_solutions,
+            xy_phase: self.xy_phase || other.xy_phase,
+            ref_tile: self.ref_tile.or(other.ref_tile),
+            ref_cross_hands: self.ref_cross_hands || other.ref_cross_hands,
+            chanblocks_per_chunk: self.chanblocks_per_chunk.or(other.chanblocks_per_chunk),
+            checkpoint_dir: self.checkpoint_dir.or(other.checkpoint_dir),
+            resume: self.resume || other.resume,
             solutions: self.solutions.or(other.solutions),
             model_filenames: self.model_filenames.or(other.model_filenames),
             output_model_time_average: self
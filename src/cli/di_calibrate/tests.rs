@@ -835,6 +835,196 @@ fn test_flagging_all_uvw_lengths_causes_error() {
     }
 }
 
+#[test]
+fn test_uvw_tapers_down_weight_baselines() {
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.uvw_min = Some("100L".to_string());
+    let params = args.parse().unwrap();
+    let hard_weights = params.baseline_weights;
+    assert!(hard_weights.iter().all(|&w| w == 0.0 || w == 1.0));
+    assert!(hard_weights.iter().any(|&w| w == 0.0));
+
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.uvw_min = Some("100L".to_string());
+    args.calibration_args.uvw_min_taper = Some("20L".to_string());
+    let params = args.parse().unwrap();
+    let tapered_weights = params.baseline_weights;
+    for (&hard, &tapered) in hard_weights.iter().zip(tapered_weights.iter()) {
+        if hard == 1.0 {
+            assert_abs_diff_eq!(tapered, 1.0);
+        } else {
+            assert!(tapered > 0.0 && tapered < 1.0);
+        }
+    }
+
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.uvw_min_taper = Some("-20L".to_string());
+    let error = args.parse().err().unwrap();
+    assert!(matches!(error, HyperdriveError::DiCalibrate(_)));
+}
+
+#[test]
+fn test_baseline_weights_file() {
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.uvw_min = Some("0L".to_string());
+    let params = args.parse().unwrap();
+    let total_num_tiles = params.input_vis_params.get_total_num_tiles();
+    let total_num_baselines = (total_num_tiles * (total_num_tiles - 1)) / 2;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    for i_baseline in 0..total_num_baselines {
+        writeln!(file, "{}", (i_baseline % 4) as f64 / 4.0).unwrap();
+    }
+    file.flush().unwrap();
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.uvw_min = Some("0L".to_string());
+    args.calibration_args.baseline_weights_file = Some(file.path().to_path_buf());
+    let params = args.parse().unwrap();
+    // No tiles are flagged, so all baselines are used.
+    assert_eq!(params.baseline_weights.len(), total_num_baselines);
+    for (i_baseline, &w) in params.baseline_weights.iter().enumerate() {
+        assert_abs_diff_eq!(w, (i_baseline % 4) as f64 / 4.0);
+    }
+
+    // Too few weights.
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(file, "1.0").unwrap();
+    file.flush().unwrap();
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.baseline_weights_file = Some(file.path().to_path_buf());
+    let error = args.parse().err().unwrap();
+    assert!(matches!(error, HyperdriveError::DiCalibrate(_)));
+}
+
+#[test]
+fn test_joint_data_stacks_timesteps() {
+    let DataAsStrings {
+        metafits, mut vis, ..
+    } = get_reduced_1090008640_raw();
+    let mut files = vec![metafits];
+    files.append(&mut vis);
+
+    let args = get_reduced_1090008640(false, false);
+    let params = args.parse().unwrap();
+    let num_timestamps = params.cal_timeblocks.first().timestamps.len();
+
+    // Use the same observation as the "other" observation.
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.joint_data = Some(vec![files.join(",")]);
+    let params = args.parse().unwrap();
+    assert_eq!(params.joint_obs.len(), 1);
+    assert_eq!(params.cal_timeblocks.len(), 1);
+    let timeblock = params.cal_timeblocks.first();
+    assert_eq!(timeblock.timestamps.len(), 2 * num_timestamps);
+    assert_eq!(timeblock.range, 0..2 * num_timestamps);
+}
+
+#[test]
+fn test_new_params_chanblocks_per_chunk() {
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.chanblocks_per_chunk = Some("5".to_string());
+    let params = args.parse().unwrap();
+    assert_eq!(params.chanblocks_per_chunk.map(|n| n.get()), Some(5));
+
+    // 40 kHz chanblocks.
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.chanblocks_per_chunk = Some("200kHz".to_string());
+    let params = args.parse().unwrap();
+    assert_eq!(params.chanblocks_per_chunk.map(|n| n.get()), Some(5));
+
+    // A chunk with all of the chanblocks isn't a chunk.
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.chanblocks_per_chunk = Some("32".to_string());
+    let params = args.parse().unwrap();
+    assert!(params.chanblocks_per_chunk.is_none());
+
+    for bad in ["0", "100kHz", "1.5", "foo"] {
+        let mut args = get_reduced_1090008640(false, false);
+        args.calibration_args.chanblocks_per_chunk = Some(bad.to_string());
+        let error = args.parse().err().unwrap();
+        assert!(matches!(error, HyperdriveError::DiCalibrate(_)), "{bad}");
+    }
+
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.chanblocks_per_chunk = Some("5".to_string());
+    args.calibration_args.xy_phase = true;
+    let error = args.parse().err().unwrap();
+    assert!(matches!(error, HyperdriveError::DiCalibrate(_)));
+}
+
+#[test]
+fn test_resource_estimate() {
+    let args = get_reduced_1090008640(false, false);
+    let params = args.parse().unwrap();
+    let estimate = params.get_resource_estimate();
+    let num_tiles = params.input_vis_params.get_num_unflagged_tiles();
+    let num_baselines = (num_tiles * (num_tiles - 1)) / 2;
+    let num_timesteps = params.cal_timeblocks.first().timestamps.len();
+    let num_chanblocks = params.input_vis_params.spw.chanblocks.len();
+    let counts = params.source_list.get_counts();
+    let num_components = counts.num_points + counts.num_gaussians + counts.num_shapelets;
+    assert_eq!(
+        estimate.num_model_ops,
+        (num_components * num_baselines * num_chanblocks * num_timesteps) as u64
+    );
+    // At least the data and model visibilities and their weights are needed.
+    assert!(estimate.peak_memory >= (num_timesteps * num_chanblocks * num_baselines * 68) as u64);
+
+    // Calibrating in chunks needs less memory, but the same modelling.
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.chanblocks_per_chunk = Some("5".to_string());
+    let params = args.parse().unwrap();
+    let chunked_estimate = params.get_resource_estimate();
+    assert!(chunked_estimate.peak_memory < estimate.peak_memory);
+    assert_eq!(chunked_estimate.num_model_ops, estimate.num_model_ops);
+}
+
+#[test]
+fn test_chunked_calibration_matches_unchunked() {
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.recalibrate_without_outliers = true;
+    let params = args.parse().unwrap();
+    let sols = params.run().unwrap();
+
+    // The chunks don't divide the chanblocks evenly, and some chunks have
+    // flagged chanblocks.
+    let mut args = get_reduced_1090008640(false, false);
+    args.calibration_args.recalibrate_without_outliers = true;
+    args.calibration_args.chanblocks_per_chunk = Some("5".to_string());
+    let params = args.parse().unwrap();
+    let chunked_sols = params.run().unwrap();
+
+    assert_eq!(sols.di_jones.dim(), chunked_sols.di_jones.dim());
+    for (&j, &chunked_j) in sols.di_jones.iter().zip(chunked_sols.di_jones.iter()) {
+        if j.any_nan() {
+            assert!(chunked_j.any_nan());
+        } else {
+            assert_abs_diff_eq!(j, chunked_j, epsilon = 1e-10);
+        }
+    }
+    assert_eq!(sols.flagged_tiles, chunked_sols.flagged_tiles);
+    assert_eq!(sols.flagged_chanblocks, chunked_sols.flagged_chanblocks);
+
+    let stats = sols.residual_stats.unwrap();
+    let chunked_stats = chunked_sols.residual_stats.unwrap();
+    for (stat, chunked_stat) in stats.tiles.iter().chain(stats.baselines.iter()).zip(
+        chunked_stats
+            .tiles
+            .iter()
+            .chain(chunked_stats.baselines.iter()),
+    ) {
+        assert_eq!(stat.num_vis, chunked_stat.num_vis);
+        if stat.num_vis > 0 {
+            assert_abs_diff_eq!(stat.rms, chunked_stat.rms, epsilon = 1e-6);
+            assert_abs_diff_eq!(
+                stat.chi_squared,
+                chunked_stat.chi_squared,
+                epsilon = 1e-6 * stat.chi_squared
+            );
+        }
+    }
+}
+
 /// Given calibration parameters and visibilities, this function tests that
 /// everything matches an expected quality. The values may change over time but
 /// they should be consistent with whatever tests use this test code.
@@ -848,9 +1038,14 @@ fn test_1090008640_quality(
         vis_model,
         &params.input_vis_params.timeblocks,
         &params.input_vis_params.spw.chanblocks,
+        None,
         50,
         1e-8,
         1e-4,
+        crate::di_calibrate::SolveMode::default(),
+        crate::di_calibrate::RobustWeighting::default(),
+        crate::di_calibrate::Solver::default(),
+        None,
         crate::context::Polarisations::default(),
         false,
     );
@@ -10,7 +10,11 @@ use thiserror::Error;
 use super::{
     common::InputVisArgsError,
     di_calibrate::DiCalArgsError,
-    solutions::{SolutionsApplyArgsError, SolutionsPlotError},
+    peel::PeelArgsError,
+    solutions::{
+        SolutionsApplyArgsError, SolutionsCombineArgsError, SolutionsFitIonosphereArgsError,
+        SolutionsPlotError,
+    },
     srclist::SrclistByBeamError,
     vis_convert::VisConvertArgsError,
     vis_simulate::VisSimulateArgsError,
@@ -24,8 +28,14 @@ use crate::{
         GlobError,
     },
     model::ModelError,
-    params::{DiCalibrateError, VisConvertError, VisSimulateError, VisSubtractError},
-    solutions::{SolutionsReadError, SolutionsWriteError},
+    params::{
+        DiCalibrateError, ModelVisError, PeelError, VisConvertError, VisSimulateError,
+        VisSubtractError,
+    },
+    solutions::{
+        combine::SolutionsCombineError, diff::SolutionsDiffError, ionosphere::IonosphereFitError,
+        reference::PhaseReferenceError, SolutionsReadError, SolutionsWriteError,
+    },
     srclist::{ReadSourceListError, SrclistError, WriteSourceListError},
 };
 
@@ -55,6 +65,10 @@ pub enum HyperdriveError {
     #[error("{0}\n\nSee for more info: {URL}/user/vis_subtract/intro.html")]
     VisSubtract(String),
 
+    /// An error related to peel.
+    #[error("{0}\n\nSee for more info: {URL}/user/peel/intro.html")]
+    Peel(String),
+
     /// Generic error surrounding source lists.
     #[error("{0}\n\nSee for more info: {URL}/defs/source_lists.html")]
     Srclist(String),
@@ -75,6 +89,10 @@ pub enum HyperdriveError {
     #[error("{0}\n\nSee for more info: {URL}/defs/cal_sols_rts.html")]
     SolutionsRts(String),
 
+    /// Error specific to CASA calibration tables.
+    #[error("{0}\n\nSee for more info: {URL}/defs/cal_sols_casa.html")]
+    SolutionsCasa(String),
+
     /// An error related to reading visibilities.
     #[error("{0}\n\nSee for more info: {URL}/defs/vis_formats_read.html")]
     VisRead(String),
@@ -136,8 +154,33 @@ impl From<DiCalArgsError> for HyperdriveError {
         match e {
             DiCalArgsError::NoOutput
             | DiCalArgsError::AllBaselinesFlaggedFromUvwCutoffs
+            | DiCalArgsError::UnknownSolveMode(_)
+            | DiCalArgsError::UnknownRobustWeighting(_)
+            | DiCalArgsError::UnknownSolver(_)
+            | DiCalArgsError::BadOutlierThreshold(_)
+            | DiCalArgsError::BadRedundancyTolerance(_)
+            | DiCalArgsError::NoRedundantBaselines(_)
+            | DiCalArgsError::JointTilesMismatch(_)
+            | DiCalArgsError::JointChanblocksMismatch(_)
+            | DiCalArgsError::JointMultipleTimeblocks
+            | DiCalArgsError::JointPhaseOffsetsRedundant
+            | DiCalArgsError::XyPhaseRedundant
+            | DiCalArgsError::ParseChunkSize(_)
+            | DiCalArgsError::ChunkSizeNotInteger
+            | DiCalArgsError::ChunkSizeNotMultiple { .. }
+            | DiCalArgsError::ChunkSizeZero
+            | DiCalArgsError::ChunkedIncompatible(_)
+            | DiCalArgsError::ResumeWithoutCheckpoint
+            | DiCalArgsError::ModelVisIncompatible(_)
             | DiCalArgsError::ParseUvwMin(_)
-            | DiCalArgsError::ParseUvwMax(_) => Self::DiCalibrate(e.to_string()),
+            | DiCalArgsError::ParseUvwMax(_)
+            | DiCalArgsError::ParseUvwMinTaper(_)
+            | DiCalArgsError::ParseUvwMaxTaper(_)
+            | DiCalArgsError::ParseDensityCellSize(_)
+            | DiCalArgsError::BadUvwTaper(_)
+            | DiCalArgsError::BadDensityCellSize(_)
+            | DiCalArgsError::UnknownBaselineWeighting(_)
+            | DiCalArgsError::BaselineWeightsFile(_) => Self::DiCalibrate(e.to_string()),
             DiCalArgsError::CalibrationOutputFile { .. } => Self::Solutions(e.to_string()),
             DiCalArgsError::ParseCalTimeAverageFactor(_)
             | DiCalArgsError::CalTimeFactorNotInteger
@@ -159,6 +202,8 @@ impl From<DiCalibrateError> for HyperdriveError {
             DiCalibrateError::Fitsio(_) => Self::Cfitsio(s),
             DiCalibrateError::VisRead(e) => Self::from(e),
             DiCalibrateError::VisWrite(_) => Self::VisWrite(s),
+            DiCalibrateError::Checkpoint(_) => Self::DiCalibrate(s),
+            DiCalibrateError::PhaseReference(_) => Self::Solutions(s),
             DiCalibrateError::Model(_) | DiCalibrateError::IO(_) => Self::Generic(s),
         }
     }
@@ -168,7 +213,8 @@ impl From<SolutionsApplyArgsError> for HyperdriveError {
     fn from(e: SolutionsApplyArgsError) -> Self {
         let s = e.to_string();
         match e {
-            SolutionsApplyArgsError::NoSolutions => Self::SolutionsApply(s),
+            SolutionsApplyArgsError::NoSolutions
+            | SolutionsApplyArgsError::UnknownTimeInterpolation(_) => Self::SolutionsApply(s),
         }
     }
 }
@@ -244,7 +290,8 @@ impl From<VisSubtractArgsError> for HyperdriveError {
         match e {
             VisSubtractArgsError::MissingSource { .. }
             | VisSubtractArgsError::NoSources
-            | VisSubtractArgsError::AllSourcesFiltered => Self::VisSubtract(s),
+            | VisSubtractArgsError::AllSourcesFiltered
+            | VisSubtractArgsError::ModelVisWithSources => Self::VisSubtract(s),
         }
     }
 }
@@ -262,6 +309,42 @@ impl From<VisSubtractError> for HyperdriveError {
     }
 }
 
+impl From<ModelVisError> for HyperdriveError {
+    fn from(e: ModelVisError) -> Self {
+        let s = e.to_string();
+        match e {
+            ModelVisError::TilesMismatch { .. }
+            | ModelVisError::TileNamesMismatch
+            | ModelVisError::UnmodelledTiles(_)
+            | ModelVisError::PhaseCentreMismatch
+            | ModelVisError::ChannelsMismatch { .. }
+            | ModelVisError::MissingTimestamp(_) => Self::VisRead(s),
+        }
+    }
+}
+
+impl From<PeelArgsError> for HyperdriveError {
+    fn from(e: PeelArgsError) -> Self {
+        let s = e.to_string();
+        match e {
+            PeelArgsError::ZeroPasses => Self::Peel(s),
+        }
+    }
+}
+
+impl From<PeelError> for HyperdriveError {
+    fn from(e: PeelError) -> Self {
+        match e {
+            PeelError::VisRead(e) => Self::from(e),
+            PeelError::VisWrite(e) => Self::from(e),
+            PeelError::Model(e) => Self::from(e),
+            PeelError::IO(e) => Self::from(e),
+            #[cfg(any(feature = "cuda", feature = "hip"))]
+            PeelError::Gpu(e) => Self::from(e),
+        }
+    }
+}
+
 impl From<SrclistByBeamError> for HyperdriveError {
     fn from(e: SrclistByBeamError) -> Self {
         match e {
@@ -311,9 +394,10 @@ impl From<InputVisArgsError> for HyperdriveError {
             | InputVisArgsError::Raw(_)
             | InputVisArgsError::Ms(_)
             | InputVisArgsError::Uvfits(_) => Self::VisRead(s),
-            InputVisArgsError::TileCountMismatch { .. } | InputVisArgsError::Solutions(_) => {
-                Self::Solutions(s)
-            }
+            InputVisArgsError::TileCountMismatch { .. }
+            | InputVisArgsError::UnknownFreqInterpolation(_)
+            | InputVisArgsError::SolutionsFreqNoOverlap { .. }
+            | InputVisArgsError::Solutions(_) => Self::Solutions(s),
             InputVisArgsError::ParseTimeAverageFactor(_)
             | InputVisArgsError::TimeFactorNotInteger
             | InputVisArgsError::TimeResNotMultiple { .. }
@@ -392,9 +476,10 @@ impl From<SolutionsReadError> for HyperdriveError {
         let s = e.to_string();
         match e {
             SolutionsReadError::UnsupportedExt { .. } => Self::Solutions(s),
-            SolutionsReadError::BadShape { .. } | SolutionsReadError::ParsePfbFlavour(_) => {
-                Self::SolutionsHyp(s)
-            }
+            SolutionsReadError::BadShape { .. }
+            | SolutionsReadError::UnknownSolveMode(_)
+            | SolutionsReadError::UnknownSolver(_)
+            | SolutionsReadError::ParsePfbFlavour(_) => Self::SolutionsHyp(s),
             SolutionsReadError::AndreBinaryStr { .. }
             | SolutionsReadError::AndreBinaryVal { .. } => Self::SolutionsAO(s),
             SolutionsReadError::RtsMetafitsRequired | SolutionsReadError::Rts(_) => {
@@ -411,12 +496,94 @@ impl From<SolutionsWriteError> for HyperdriveError {
         let s = e.to_string();
         match e {
             SolutionsWriteError::UnsupportedExt { .. } => Self::Solutions(s),
+            SolutionsWriteError::RtsMetafitsRequired | SolutionsWriteError::Rts(_) => {
+                Self::SolutionsRts(s)
+            }
+            SolutionsWriteError::CasaNoChanblockFreqs
+            | SolutionsWriteError::CasaNoFreqRes
+            | SolutionsWriteError::CasaTable(_)
+            | SolutionsWriteError::Casacore(_) => Self::SolutionsCasa(s),
             SolutionsWriteError::Fits(_) | SolutionsWriteError::Fitsio(_) => Self::Cfitsio(s),
             SolutionsWriteError::IO(e) => Self::from(e),
         }
     }
 }
 
+impl From<IonosphereFitError> for HyperdriveError {
+    fn from(e: IonosphereFitError) -> Self {
+        let s = e.to_string();
+        match e {
+            IonosphereFitError::NoFreqs
+            | IonosphereFitError::BadRefTile(_)
+            | IonosphereFitError::NoGoodTiles
+            | IonosphereFitError::TilePositionsMismatch { .. }
+            | IonosphereFitError::TooManyDtecSteps { .. } => Self::Solutions(s),
+        }
+    }
+}
+
+impl From<SolutionsCombineArgsError> for HyperdriveError {
+    fn from(e: SolutionsCombineArgsError) -> Self {
+        let s = e.to_string();
+        match e {
+            SolutionsCombineArgsError::UnknownMode(_) => Self::Solutions(s),
+        }
+    }
+}
+
+impl From<SolutionsFitIonosphereArgsError> for HyperdriveError {
+    fn from(e: SolutionsFitIonosphereArgsError) -> Self {
+        let s = e.to_string();
+        match e {
+            SolutionsFitIonosphereArgsError::BadMaxDtec(_) => Self::Solutions(s),
+        }
+    }
+}
+
+impl From<SolutionsCombineError> for HyperdriveError {
+    fn from(e: SolutionsCombineError) -> Self {
+        let s = e.to_string();
+        match e {
+            SolutionsCombineError::NoInputs
+            | SolutionsCombineError::TilesMismatch(_)
+            | SolutionsCombineError::TimeblockCountMismatch { .. }
+            | SolutionsCombineError::NoFreqs(_)
+            | SolutionsCombineError::FreqResMismatch(_)
+            | SolutionsCombineError::OverlappingFreqs(_)
+            | SolutionsCombineError::FreqsNotOnGrid(_)
+            | SolutionsCombineError::ChanblocksMismatch(_)
+            | SolutionsCombineError::PhaseReference(_) => Self::Solutions(s),
+        }
+    }
+}
+
+impl From<SolutionsDiffError> for HyperdriveError {
+    fn from(e: SolutionsDiffError) -> Self {
+        let s = e.to_string();
+        match e {
+            SolutionsDiffError::TileCountMismatch { .. }
+            | SolutionsDiffError::ChanblockCountMismatch { .. }
+            | SolutionsDiffError::TimeblockCountMismatch { .. }
+            | SolutionsDiffError::NoCommonTiles
+            | SolutionsDiffError::NoCommonChanblocks
+            | SolutionsDiffError::RefTileNotMatched(_)
+            | SolutionsDiffError::PhaseReference(_) => Self::Solutions(s),
+        }
+    }
+}
+
+impl From<PhaseReferenceError> for HyperdriveError {
+    fn from(e: PhaseReferenceError) -> Self {
+        let s = e.to_string();
+        match e {
+            PhaseReferenceError::BadTileIndex { .. }
+            | PhaseReferenceError::BadTileName(_)
+            | PhaseReferenceError::NoSolutions(_)
+            | PhaseReferenceError::XyPhase => Self::Solutions(s),
+        }
+    }
+}
+
 impl From<BeamError> for HyperdriveError {
     fn from(e: BeamError) -> Self {
         let s = e.to_string();
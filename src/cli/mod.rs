@@ -18,6 +18,7 @@ mod beam;
 mod di_calibrate;
 mod dipole_gains;
 mod error;
+mod peel;
 mod solutions;
 mod srclist;
 mod vis_convert;
@@ -72,7 +73,8 @@ struct GlobalArgs {
     verbosity: u8,
 
     /// Only verify that arguments were correctly ingested and print out
-    /// high-level information.
+    /// high-level information. Some subcommands also print estimates of the
+    /// peak memory use and sky-modelling work.
     #[clap(long)]
     #[clap(global = true)]
     dry_run: bool,
@@ -109,6 +111,13 @@ https://mwatelescope.github.io/mwa_hyperdrive/user/vis_simulate/intro.html"#)]
 https://mwatelescope.github.io/mwa_hyperdrive/user/vis_subtract/intro.html")]
     VisSubtract(vis_subtract::VisSubtractArgs),
 
+    #[clap(alias = "dd-calibrate")]
+    #[clap(
+        about = "Peel the brightest sky-model sources from supplied visibilities, solving for direction-dependent gains towards each of them.
+https://mwatelescope.github.io/mwa_hyperdrive/user/peel/intro.html"
+    )]
+    Peel(peel::PeelArgs),
+
     #[clap(alias = "apply-solutions")]
     #[clap(about = r#"Apply calibration solutions to input data.
 https://mwatelescope.github.io/mwa_hyperdrive/user/solutions_apply/intro.html"#)]
@@ -125,6 +134,34 @@ https://mwatelescope.github.io/mwa_hyperdrive/user/plotting.html"#
     #[clap(about = "Convert between calibration solution file formats.")]
     SolutionsConvert(solutions::SolutionsConvertArgs),
 
+    #[clap(alias = "smooth-solutions")]
+    #[clap(
+        about = "Fit smooth models over frequency to calibration solutions, filling in flagged and non-converged chanblocks.
+https://mwatelescope.github.io/mwa_hyperdrive/user/solutions_smooth.html"
+    )]
+    SolutionsSmooth(solutions::SolutionsSmoothArgs),
+
+    #[clap(alias = "fit-ionosphere")]
+    #[clap(
+        about = "Fit differential TECs and a TEC gradient across the array to the phases of calibration solutions.
+https://mwatelescope.github.io/mwa_hyperdrive/user/solutions_fit_ionosphere.html"
+    )]
+    SolutionsFitIonosphere(solutions::SolutionsFitIonosphereArgs),
+
+    #[clap(alias = "diff-solutions")]
+    #[clap(
+        about = "Compare two sets of calibration solutions, reporting amplitude ratios and phase differences.
+https://mwatelescope.github.io/mwa_hyperdrive/user/solutions_diff.html"
+    )]
+    SolutionsDiff(solutions::SolutionsDiffArgs),
+
+    #[clap(alias = "combine-solutions")]
+    #[clap(
+        about = "Combine multiple sets of calibration solutions, either by concatenating them in frequency or averaging them in time.
+https://mwatelescope.github.io/mwa_hyperdrive/user/solutions_combine.html"
+    )]
+    SolutionsCombine(solutions::SolutionsCombineArgs),
+
     SrclistByBeam(srclist::SrclistByBeamArgs),
 
     SrclistConvert(srclist::SrclistConvertArgs),
@@ -161,9 +198,14 @@ impl Hyperdrive {
             Command::VisConvert(_) => "vis-convert",
             Command::VisSimulate(_) => "vis-simulate",
             Command::VisSubtract(_) => "vis-subtract",
+            Command::Peel(_) => "peel",
             Command::SolutionsApply(_) => "solutions-apply",
             Command::SolutionsConvert(_) => "solutions-convert",
             Command::SolutionsPlot(_) => "solutions-plot",
+            Command::SolutionsSmooth(_) => "solutions-smooth",
+            Command::SolutionsFitIonosphere(_) => "solutions-fit-ionosphere",
+            Command::SolutionsDiff(_) => "solutions-diff",
+            Command::SolutionsCombine(_) => "solutions-combine",
             Command::SrclistByBeam(_) => "srclist-by-beam",
             Command::SrclistConvert(_) => "srclist-convert",
             Command::SrclistShift(_) => "srclist-shift",
@@ -208,6 +250,10 @@ impl Hyperdrive {
                 merge_save_run!(args)
             }
 
+            Command::Peel(args) => {
+                merge_save_run!(args)
+            }
+
             Command::SolutionsApply(args) => {
                 merge_save_run!(args)
             }
@@ -220,6 +266,22 @@ impl Hyperdrive {
                 args.run()?;
             }
 
+            Command::SolutionsSmooth(args) => {
+                args.run()?;
+            }
+
+            Command::SolutionsFitIonosphere(args) => {
+                args.run()?;
+            }
+
+            Command::SolutionsDiff(args) => {
+                args.run()?;
+            }
+
+            Command::SolutionsCombine(args) => {
+                args.run()?;
+            }
+
             // Source list utilities.
             Command::SrclistByBeam(args) => args.run()?,
             Command::SrclistConvert(args) => args.run()?,
@@ -0,0 +1,374 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parse peeling arguments into parameters.
+
+use std::{num::NonZeroUsize, path::PathBuf};
+
+use clap::Parser;
+use log::{debug, info, trace};
+use marlu::{precession::precess_time, LatLngHeight};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    common::{
+        display_warnings, BeamArgs, InfoPrinter, InputVisArgs, ModellingArgs, OutputVisArgs,
+        SkyModelWithVetoArgs, Warn, ARG_FILE_HELP,
+    },
+    di_calibrate::{
+        DEFAULT_MAX_ITERATIONS, DEFAULT_MIN_THRESHOLD, DEFAULT_STOP_THRESHOLD, MAX_ITERATIONS_HELP,
+        MIN_THRESHOLD_HELP, STOP_THRESHOLD_HELP,
+    },
+};
+use crate::{
+    io::write::VIS_OUTPUT_EXTENSIONS,
+    params::{ModellingParams, PeelParams},
+    HyperdriveError,
+};
+
+const DEFAULT_OUTPUT_VIS_FILENAME: &str = "hyp_peeled.uvfits";
+
+/// The number of sources to peel if the user doesn't specify it.
+const DEFAULT_NUM_SOURCES_TO_PEEL: usize = 5;
+
+/// The number of passes over all peel sources if the user doesn't specify it.
+const DEFAULT_NUM_PASSES: usize = 1;
+
+lazy_static::lazy_static! {
+    static ref OUTPUTS_HELP: String =
+        format!("Paths to the peeled (residual) visibility files. Supported formats: {}. Default: {}", *VIS_OUTPUT_EXTENSIONS, DEFAULT_OUTPUT_VIS_FILENAME);
+
+    static ref NUM_SOURCES_TO_PEEL_HELP: String =
+        format!("The number of sources to peel. The brightest sources (after beam attenuation) are peeled; all other sources in the sky model are subtracted without any direction-dependent corrections. Default: {DEFAULT_NUM_SOURCES_TO_PEEL}");
+
+    static ref NUM_PASSES_HELP: String =
+        format!("The number of times to iterate over all peel sources. On each pass, the corrupted model of each source is added back to the residuals and solved for again. Default: {DEFAULT_NUM_PASSES}");
+}
+
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+struct PeelCliArgs {
+    #[clap(long, help = NUM_SOURCES_TO_PEEL_HELP.as_str(), help_heading = "PEELING")]
+    num_sources_to_peel: Option<usize>,
+
+    #[clap(long, help = NUM_PASSES_HELP.as_str(), help_heading = "PEELING")]
+    num_passes: Option<usize>,
+
+    #[clap(long, help = MAX_ITERATIONS_HELP.as_str(), help_heading = "PEELING")]
+    max_iterations: Option<u32>,
+
+    #[clap(long, help = STOP_THRESHOLD_HELP.as_str(), help_heading = "PEELING")]
+    stop_threshold: Option<f64>,
+
+    #[clap(long, help = MIN_THRESHOLD_HELP.as_str(), help_heading = "PEELING")]
+    min_threshold: Option<f64>,
+
+    #[clap(
+        short = 'o',
+        long,
+        multiple_values(true),
+        help = OUTPUTS_HELP.as_str(),
+        help_heading = "OUTPUT FILES"
+    )]
+    outputs: Option<Vec<PathBuf>>,
+
+    /// When writing out visibilities, average this many timesteps together.
+    /// Also supports a target time resolution (e.g. 8s). The value must be a
+    /// multiple of the input data's time resolution. The default is no
+    /// averaging, i.e. a value of 1. Examples: If the input data is in 0.5s
+    /// resolution and this variable is 4, then we average 2s worth of data
+    /// together before writing the data out. If the variable is instead 4s,
+    /// then 8 timesteps are averaged together before writing the data out.
+    #[clap(long, help_heading = "OUTPUT FILES")]
+    output_vis_time_average: Option<String>,
+
+    /// When writing out visibilities, average this many fine freq. channels
+    /// together. Also supports a target freq. resolution (e.g. 80kHz). The
+    /// value must be a multiple of the input data's freq. resolution. The
+    /// default is no averaging, i.e. a value of 1. Examples: If the input data
+    /// is in 40kHz resolution and this variable is 4, then we average 160kHz
+    /// worth of data together before writing the data out. If the variable is
+    /// instead 80kHz, then 2 fine freq. channels are averaged together before
+    /// writing the data out.
+    #[clap(long, help_heading = "OUTPUT FILES")]
+    output_vis_freq_average: Option<String>,
+
+    /// Rather than writing out the entire input bandwidth, write out only the
+    /// smallest contiguous band. e.g. Typical 40 kHz MWA data has 768 channels,
+    /// but the first 2 and last 2 channels are usually flagged. Turning this
+    /// option on means that 764 channels would be written out instead of 768.
+    /// Note that other flagged channels in the band are unaffected, because the
+    /// data written out must be contiguous.
+    #[clap(long, help_heading = "OUTPUT FILES")]
+    #[serde(default)]
+    output_smallest_contiguous_band: bool,
+}
+
+#[derive(Parser, Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct PeelArgs {
+    #[clap(name = "ARGUMENTS_FILE", help = ARG_FILE_HELP.as_str(), parse(from_os_str))]
+    args_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    #[serde(rename = "data")]
+    #[serde(default)]
+    data_args: InputVisArgs,
+
+    #[clap(flatten)]
+    #[serde(rename = "sky-model")]
+    #[serde(default)]
+    srclist_args: SkyModelWithVetoArgs,
+
+    #[clap(flatten)]
+    #[serde(rename = "model")]
+    #[serde(default)]
+    modelling_args: ModellingArgs,
+
+    #[clap(flatten)]
+    #[serde(rename = "beam")]
+    #[serde(default)]
+    beam_args: BeamArgs,
+
+    #[clap(flatten)]
+    #[serde(rename = "peel")]
+    #[serde(default)]
+    peel_args: PeelCliArgs,
+}
+
+impl PeelArgs {
+    /// Both command-line and file arguments overlap in terms of what is
+    /// available; this function consolidates everything that was specified into
+    /// a single struct. Where applicable, it will prefer CLI parameters over
+    /// those in the file.
+    ///
+    /// The argument to this function is the path to the arguments file.
+    ///
+    /// This function should only ever merge arguments, and not try to make
+    /// sense of them.
+    pub(super) fn merge(self) -> Result<PeelArgs, HyperdriveError> {
+        debug!("Merging command-line arguments with the argument file");
+
+        let cli_args = self;
+
+        if let Some(arg_file) = cli_args.args_file {
+            // Read in the file arguments. Ensure all of the file args are
+            // accounted for by pattern matching.
+            let PeelArgs {
+                args_file: _,
+                data_args,
+                srclist_args,
+                modelling_args,
+                beam_args,
+                peel_args,
+            } = unpack_arg_file!(arg_file);
+
+            // Merge all the arguments, preferring the CLI args when available.
+            Ok(PeelArgs {
+                args_file: None,
+                data_args: cli_args.data_args.merge(data_args),
+                srclist_args: cli_args.srclist_args.merge(srclist_args),
+                modelling_args: cli_args.modelling_args.merge(modelling_args),
+                beam_args: cli_args.beam_args.merge(beam_args),
+                peel_args: cli_args.peel_args.merge(peel_args),
+            })
+        } else {
+            Ok(cli_args)
+        }
+    }
+
+    fn parse(self) -> Result<PeelParams, HyperdriveError> {
+        debug!("{:#?}", self);
+
+        let Self {
+            args_file: _,
+            data_args,
+            srclist_args,
+            modelling_args,
+            beam_args,
+            peel_args:
+                PeelCliArgs {
+                    num_sources_to_peel,
+                    num_passes,
+                    max_iterations,
+                    stop_threshold,
+                    min_threshold,
+                    outputs,
+                    output_vis_time_average,
+                    output_vis_freq_average,
+                    output_smallest_contiguous_band,
+                },
+        } = self;
+
+        let input_vis_params = data_args.parse("Peeling")?;
+        let obs_context = input_vis_params.get_obs_context();
+        let total_num_tiles = input_vis_params.get_total_num_tiles();
+
+        let beam = beam_args.parse(
+            total_num_tiles,
+            obs_context.dipole_delays.clone(),
+            obs_context.dipole_gains.clone(),
+            Some(obs_context.input_data_type),
+        )?;
+        let modelling_params @ ModellingParams { apply_precession } = modelling_args.parse();
+
+        let LatLngHeight {
+            longitude_rad,
+            latitude_rad,
+            height_metres: _,
+        } = obs_context.array_position;
+        let precession_info = precess_time(
+            longitude_rad,
+            latitude_rad,
+            obs_context.phase_centre,
+            input_vis_params.timeblocks.first().median,
+            input_vis_params.dut1,
+        );
+        let (lst_rad, latitude_rad) = if apply_precession {
+            (
+                precession_info.lmst_j2000,
+                precession_info.array_latitude_j2000,
+            )
+        } else {
+            (precession_info.lmst, latitude_rad)
+        };
+
+        // The source list is sorted by reverse brightness after vetoing, so
+        // the first sources are the ones to peel.
+        let source_list = srclist_args.parse(
+            obs_context.phase_centre,
+            lst_rad,
+            latitude_rad,
+            &obs_context.get_veto_freqs(),
+            &*beam,
+        )?;
+
+        let mut num_sources_to_peel = num_sources_to_peel.unwrap_or(DEFAULT_NUM_SOURCES_TO_PEEL);
+        if num_sources_to_peel > source_list.len() {
+            format!(
+                "Asked to peel {num_sources_to_peel} sources, but only {} are in the sky model; peeling all of them",
+                source_list.len()
+            )
+            .warn();
+            num_sources_to_peel = source_list.len();
+        }
+        let num_passes = NonZeroUsize::new(num_passes.unwrap_or(DEFAULT_NUM_PASSES))
+            .ok_or(PeelArgsError::ZeroPasses)?;
+
+        // Make sure the calibration thresholds are sensible.
+        let mut stop_threshold = stop_threshold.unwrap_or(DEFAULT_STOP_THRESHOLD);
+        let min_threshold = min_threshold.unwrap_or(DEFAULT_MIN_THRESHOLD);
+        if stop_threshold > min_threshold {
+            format!("Specified stop threshold ({:e}) is bigger than the min. threshold ({:e}); capping stop threshold.", stop_threshold, min_threshold).warn();
+            stop_threshold = min_threshold;
+        }
+        let max_iterations = max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS);
+
+        let mut peel_printer = InfoPrinter::new("Peeling set up".into());
+        let source_plural = if num_sources_to_peel == 1 {
+            "source"
+        } else {
+            "sources"
+        };
+        let pass_plural = if num_passes.get() == 1 {
+            "pass"
+        } else {
+            "passes"
+        };
+        peel_printer.push_block(vec![
+            format!(
+                "Peeling the brightest {num_sources_to_peel} {source_plural} ({num_passes} {pass_plural})"
+            )
+            .into(),
+            format!(
+                "Subtracting the remaining {} sources without DD corrections",
+                source_list.len() - num_sources_to_peel
+            )
+            .into(),
+        ]);
+        peel_printer.push_block(vec![
+            "Peel sources will stop iterating".into(),
+            format!(
+                "- when the iteration difference is less than {:e} (stop threshold)",
+                stop_threshold
+            )
+            .into(),
+            format!("- or after {} iterations.", max_iterations).into(),
+            format!(
+                "Peel sources with an iteration diff. less than {:e} are considered converged (min. threshold)",
+                min_threshold
+            )
+            .into(),
+        ]);
+        peel_printer.display();
+
+        let output_vis_params = OutputVisArgs {
+            outputs,
+            output_vis_time_average,
+            output_vis_freq_average,
+        }
+        .parse(
+            input_vis_params.time_res,
+            input_vis_params.spw.freq_res,
+            &input_vis_params.timeblocks.mapped_ref(|tb| tb.median),
+            output_smallest_contiguous_band,
+            DEFAULT_OUTPUT_VIS_FILENAME,
+            Some("peeled"),
+        )?;
+
+        display_warnings();
+
+        Ok(PeelParams {
+            input_vis_params,
+            output_vis_params,
+            beam,
+            source_list,
+            num_sources_to_peel,
+            num_passes,
+            max_iterations,
+            stop_threshold,
+            min_threshold,
+            modelling_params,
+        })
+    }
+
+    pub(super) fn run(self, dry_run: bool) -> Result<(), HyperdriveError> {
+        debug!("Converting arguments into parameters");
+        trace!("{:#?}", self);
+        let params = self.parse()?;
+
+        if dry_run {
+            info!("Dry run -- exiting now.");
+            return Ok(());
+        }
+
+        params.run()?;
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum PeelArgsError {
+    #[error("The number of peeling passes cannot be 0")]
+    ZeroPasses,
+}
+
+impl PeelCliArgs {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            num_sources_to_peel: self.num_sources_to_peel.or(other.num_sources_to_peel),
+            num_passes: self.num_passes.or(other.num_passes),
+            max_iterations: self.max_iterations.or(other.max_iterations),
+            stop_threshold: self.stop_threshold.or(other.stop_threshold),
+            min_threshold: self.min_threshold.or(other.min_threshold),
+            outputs: self.outputs.or(other.outputs),
+            output_vis_time_average: self
+                .output_vis_time_average
+                .or(other.output_vis_time_average),
+            output_vis_freq_average: self
+                .output_vis_freq_average
+                .or(other.output_vis_freq_average),
+            output_smallest_contiguous_band: self.output_smallest_contiguous_band
+                || other.output_smallest_contiguous_band,
+        }
+    }
+}
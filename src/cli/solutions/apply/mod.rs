@@ -8,7 +8,7 @@
 #[cfg(test)]
 mod tests;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use clap::Parser;
 use log::{debug, info, trace};
@@ -18,7 +18,7 @@ use crate::{
     cli::common::{display_warnings, InputVisArgs, OutputVisArgs, ARG_FILE_HELP},
     io::write::VIS_OUTPUT_EXTENSIONS,
     params::SolutionsApplyParams,
-    solutions::CAL_SOLUTION_EXTENSIONS,
+    solutions::{TimeInterpolation, CAL_SOLUTION_EXTENSIONS, TIME_INTERPOLATIONS_COMMA_SEPARATED},
     HyperdriveError,
 };
 
@@ -28,6 +28,9 @@ lazy_static::lazy_static! {
     static ref SOLS_INPUT_HELP: String =
         format!("Path to the calibration solutions file to be applied. Supported formats: {}", *CAL_SOLUTION_EXTENSIONS);
 
+    static ref TIME_INTERPOLATION_HELP: String =
+        format!("How solutions with multiple timeblocks are applied. 'nearest' uses the timeblock best corresponding to each timestep, whereas 'linear' interpolates between the two timeblocks surrounding each timestep (using their average timestamps). Supported methods: {}. Default: {}", *TIME_INTERPOLATIONS_COMMA_SEPARATED, TimeInterpolation::default());
+
     static ref OUTPUTS_HELP: String =
         format!("Paths to the output calibrated visibility files. Supported formats: {}. Default: {}", *VIS_OUTPUT_EXTENSIONS, DEFAULT_OUTPUT_VIS_FILENAME);
 }
@@ -46,6 +49,9 @@ pub(crate) struct SolutionsApplyArgs {
     #[clap(short, long, help = SOLS_INPUT_HELP.as_str(), help_heading = "INPUT DATA")]
     solutions: Option<String>,
 
+    #[clap(long, help = TIME_INTERPOLATION_HELP.as_str(), help_heading = "INPUT DATA")]
+    time_interpolation: Option<String>,
+
     #[clap(
         short = 'o',
         long,
@@ -109,6 +115,7 @@ impl SolutionsApplyArgs {
                 args_file: _,
                 data_args,
                 solutions,
+                time_interpolation,
                 outputs,
                 output_vis_time_average,
                 output_vis_freq_average,
@@ -120,6 +127,7 @@ impl SolutionsApplyArgs {
                 args_file: None,
                 data_args: cli_args.data_args.merge(data_args),
                 solutions: cli_args.solutions.or(solutions),
+                time_interpolation: cli_args.time_interpolation.or(time_interpolation),
                 outputs: cli_args.outputs.or(outputs),
                 output_vis_time_average: cli_args
                     .output_vis_time_average
@@ -142,6 +150,7 @@ impl SolutionsApplyArgs {
             args_file: _,
             mut data_args,
             solutions,
+            time_interpolation,
             outputs,
             output_vis_time_average,
             output_vis_freq_average,
@@ -161,10 +170,15 @@ impl SolutionsApplyArgs {
             (Some(_), None) => (),
         }
 
-        let input_vis_params = data_args.parse("Applying solutions")?;
+        let mut input_vis_params = data_args.parse("Applying solutions")?;
         if input_vis_params.solutions.is_none() {
             return Err(SolutionsApplyArgsError::NoSolutions.into());
         }
+        if let Some(s) = time_interpolation {
+            input_vis_params.solutions_time_interpolation =
+                TimeInterpolation::from_str(&s.to_lowercase())
+                    .map_err(|_| SolutionsApplyArgsError::UnknownTimeInterpolation(s))?;
+        }
 
         let output_vis_params = OutputVisArgs {
             outputs,
@@ -194,6 +208,7 @@ impl SolutionsApplyArgs {
         let params = self.parse()?;
 
         if dry_run {
+            params.get_resource_estimate().display();
             info!("Dry run -- exiting now.");
             return Ok(());
         }
@@ -207,4 +222,7 @@ impl SolutionsApplyArgs {
 pub(crate) enum SolutionsApplyArgsError {
     #[error("No calibration solutions were supplied")]
     NoSolutions,
+
+    #[error("Unrecognised time interpolation method '{0}'. Supported methods: {}", *TIME_INTERPOLATIONS_COMMA_SEPARATED)]
+    UnknownTimeInterpolation(String),
 }
@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to combine multiple sets of calibration solutions.
+
+use std::{path::PathBuf, str::FromStr};
+
+use clap::Parser;
+use log::info;
+use ndarray::Axis;
+use thiserror::Error;
+
+use crate::{
+    cli::common::display_warnings,
+    solutions::{
+        combine::{combine_solutions, CombineMode, COMBINE_MODES_COMMA_SEPARATED},
+        CalibrationSolutions,
+    },
+    HyperdriveError,
+};
+
+lazy_static::lazy_static! {
+    static ref MODE_HELP: String =
+        format!("How the solutions are combined. 'freq' concatenates solutions with different chanblocks (e.g. those of the coarse-channel groups of a picket-fence observation), filling any gaps with flagged chanblocks. 'time' averages all timeblocks of solutions with the same chanblocks, weighting by calibration precision where available. Supported modes: {}. Default: {}", *COMBINE_MODES_COMMA_SEPARATED, CombineMode::default());
+
+    static ref REF_TILE_HELP: String =
+        "Reference the phases of each of the solutions to this tile before combining them, given as an index or a tile name. This is strongly recommended when averaging in time, as solutions can otherwise have arbitrarily different phases.".to_string();
+}
+
+#[derive(Parser, Debug, Default)]
+pub(crate) struct SolutionsCombineArgs {
+    /// The paths to the input solutions files. If any of these is a directory
+    /// instead, then we attempt to read RTS calibration files in the
+    /// directory.
+    #[clap(name = "INPUT_SOLUTIONS_FILES", parse(from_os_str), required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// The path to the output file. If this is an existing directory (or has
+    /// an "rts" extension) instead, then RTS calibration files are written
+    /// into the directory; this requires a metafits file.
+    #[clap(short, long, parse(from_os_str))]
+    output: PathBuf,
+
+    #[clap(long, help = MODE_HELP.as_str())]
+    mode: Option<String>,
+
+    #[clap(long, help = REF_TILE_HELP.as_str())]
+    ref_tile: Option<String>,
+
+    /// The metafits file associated with the solutions. This may be required.
+    #[clap(short, long, parse(from_str))]
+    metafits: Option<PathBuf>,
+}
+
+impl SolutionsCombineArgs {
+    pub fn run(self) -> Result<(), HyperdriveError> {
+        let mode = match self.mode {
+            None => CombineMode::default(),
+            Some(s) => CombineMode::from_str(&s.to_lowercase())
+                .map_err(|_| SolutionsCombineArgsError::UnknownMode(s))?,
+        };
+
+        let mut sols = Vec::with_capacity(self.inputs.len());
+        for (i, input) in self.inputs.iter().enumerate() {
+            let sol = CalibrationSolutions::read_solutions_from_ext(input, self.metafits.as_ref())?;
+            let (num_timeblocks, num_tiles, num_chanblocks) = sol.di_jones.dim();
+            info!(
+                "Solutions {i}: {} ({num_timeblocks} timeblocks, {num_tiles} tiles, {num_chanblocks} chanblocks)",
+                input.display()
+            );
+            sols.push(sol);
+        }
+
+        let combined = combine_solutions(sols, mode, self.ref_tile.as_deref())?;
+        let num_flagged_chanblocks = combined.flagged_chanblocks.len();
+        info!(
+            "Combined solutions have {} timeblocks and {} chanblocks ({num_flagged_chanblocks} flagged)",
+            combined.di_jones.len_of(Axis(0)),
+            combined.di_jones.len_of(Axis(2)),
+        );
+        combined.write_solutions_from_ext_with_metafits(&self.output, self.metafits.as_ref())?;
+
+        display_warnings();
+
+        info!(
+            "Combined {} solutions files ({mode}) into {}",
+            self.inputs.len(),
+            self.output.display()
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SolutionsCombineArgsError {
+    #[error("Unrecognised combine mode '{0}'. Supported modes: {}", *COMBINE_MODES_COMMA_SEPARATED)]
+    UnknownMode(String),
+}
@@ -8,8 +8,16 @@ use std::path::PathBuf;
 
 use clap::Parser;
 use log::info;
+use ndarray::Axis;
 
-use crate::{cli::common::display_warnings, solutions::CalibrationSolutions, HyperdriveError};
+use crate::{
+    cli::common::{display_warnings, Warn, REF_CROSS_HANDS_HELP, REF_TILE_HELP},
+    solutions::{
+        reference::{parse_ref_tile, reference_phases},
+        CalibrationSolutions,
+    },
+    HyperdriveError,
+};
 
 #[derive(Parser, Debug, Default)]
 pub(crate) struct SolutionsConvertArgs {
@@ -18,21 +26,39 @@ pub(crate) struct SolutionsConvertArgs {
     #[clap(name = "INPUT_SOLUTIONS_FILE", parse(from_os_str))]
     input: PathBuf,
 
-    /// The path to the output file. If this is a directory instead, then we
-    /// attempt to write RTS calibration files to the directory.
+    /// The path to the output file. If this is an existing directory (or has
+    /// an "rts" extension) instead, then RTS calibration files are written
+    /// into the directory; this requires a metafits file.
     #[clap(name = "OUTPUT_SOLUTIONS_FILE", parse(from_os_str))]
     output: PathBuf,
 
     /// The metafits file associated with the solutions. This may be required.
     #[clap(short, long, parse(from_str))]
     metafits: Option<PathBuf>,
+
+    #[clap(long, help = REF_TILE_HELP.as_str())]
+    ref_tile: Option<String>,
+
+    #[clap(long, help = REF_CROSS_HANDS_HELP.as_str())]
+    ref_cross_hands: bool,
 }
 
 impl SolutionsConvertArgs {
     pub fn run(self) -> Result<(), HyperdriveError> {
-        let sols =
+        let mut sols =
             CalibrationSolutions::read_solutions_from_ext(&self.input, self.metafits.as_ref())?;
-        sols.write_solutions_from_ext(&self.output)?;
+        if let Some(ref_tile) = self.ref_tile.as_deref() {
+            let ref_tile = parse_ref_tile(
+                ref_tile,
+                sols.tile_names.as_ref().map(|n| n.as_slice()),
+                sols.di_jones.len_of(Axis(1)),
+            )?;
+            info!("Referencing solution phases to tile {ref_tile}");
+            reference_phases(&mut sols, ref_tile, self.ref_cross_hands)?;
+        } else if self.ref_cross_hands {
+            "--ref-cross-hands does nothing without --ref-tile".warn();
+        }
+        sols.write_solutions_from_ext_with_metafits(&self.output, self.metafits.as_ref())?;
 
         display_warnings();
 
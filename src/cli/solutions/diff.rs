@@ -0,0 +1,184 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to compare two sets of calibration solutions.
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::Parser;
+use log::{debug, info};
+use ndarray::Axis;
+use vec1::Vec1;
+
+use crate::{
+    cli::common::{display_warnings, Warn},
+    solutions::{diff::diff_solutions, CalibrationSolutions},
+    HyperdriveError,
+};
+
+#[derive(Parser, Debug, Default)]
+pub(crate) struct SolutionsDiffArgs {
+    /// The path to the first solutions file. Ratios and differences are
+    /// relative to these solutions. If this is a directory instead, then we
+    /// attempt to read RTS calibration files in the directory.
+    #[clap(name = "FIRST_SOLUTIONS_FILE", parse(from_os_str))]
+    first: PathBuf,
+
+    /// The path to the second solutions file. If this is a directory instead,
+    /// then we attempt to read RTS calibration files in the directory.
+    #[clap(name = "SECOND_SOLUTIONS_FILE", parse(from_os_str))]
+    second: PathBuf,
+
+    /// The metafits file associated with the solutions. This is required to
+    /// read RTS solutions, and provides tile names for solutions that don't
+    /// have them (e.g. those in the "André Offringa" format).
+    #[clap(short, long, parse(from_str))]
+    metafits: Option<PathBuf>,
+
+    /// Reference the phases of both solutions to this tile before comparing
+    /// them, given as an index or a tile name of the first solutions. Without
+    /// this, phase differences include any difference in the arbitrary
+    /// phase of each polarisation.
+    #[clap(short, long)]
+    ref_tile: Option<String>,
+
+    /// Write a JSON summary of the comparison to this file.
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Write plots of the amplitude ratios and phase differences into this
+    /// directory. Only available if compiled with the "plotting" feature.
+    #[clap(short, long, parse(from_os_str))]
+    plot_directory: Option<PathBuf>,
+}
+
+impl SolutionsDiffArgs {
+    pub fn run(self) -> Result<(), HyperdriveError> {
+        #[cfg(not(feature = "plotting"))]
+        if self.plot_directory.is_some() {
+            return Err(HyperdriveError::from(
+                super::SolutionsPlotError::NoPlottingFeature,
+            ));
+        }
+
+        let metafits_tile_names = match self.metafits.as_deref() {
+            Some(m) => {
+                let context = mwalib::MetafitsContext::new(m, None)?;
+                Vec1::try_from_vec(context.antennas.into_iter().map(|a| a.tile_name).collect()).ok()
+            }
+            None => None,
+        };
+        let read = |file: &PathBuf| -> Result<CalibrationSolutions, HyperdriveError> {
+            let mut sols =
+                CalibrationSolutions::read_solutions_from_ext(file, self.metafits.as_ref())?;
+            if sols.tile_names.is_none() {
+                match metafits_tile_names.as_ref() {
+                    Some(names) if names.len() == sols.di_jones.len_of(Axis(1)) => {
+                        debug!("Using tile names from the metafits for {}", file.display());
+                        sols.tile_names = Some(names.clone());
+                    }
+                    _ => format!(
+                        "{} doesn't have tile names; tiles will be matched by index",
+                        file.display()
+                    )
+                    .warn(),
+                }
+            }
+            Ok(sols)
+        };
+        let first = read(&self.first)?;
+        let second = read(&self.second)?;
+
+        let diff = diff_solutions(&first, &second, self.ref_tile.as_deref())?;
+        info!(
+            "Compared {} tiles and {} chanblocks",
+            diff.tile_names.len(),
+            diff.chanblock_indices.len()
+        );
+        if !diff.unmatched_tiles.is_empty() {
+            format!(
+                "Tiles not in the second solutions: {}",
+                diff.unmatched_tiles.join(", ")
+            )
+            .warn();
+        }
+        if let Some(ref_tile) = diff.ref_tile.as_deref() {
+            info!("Phases referenced to tile {ref_tile}");
+        }
+
+        for (i_timeblock, timeblock) in diff.timeblocks.iter().enumerate() {
+            info!(
+                "Timeblock {i_timeblock}: median amp. ratio XX {:.4} YY {:.4}, RMS phase diff. XX {:.3}° YY {:.3}°",
+                timeblock.overall.median_amp_ratio[0],
+                timeblock.overall.median_amp_ratio[1],
+                timeblock.overall.rms_phase_diff[0].to_degrees(),
+                timeblock.overall.rms_phase_diff[1].to_degrees(),
+            );
+            info!(
+                "  {:>12} {:>8} {:>8} {:>10} {:>10} {:>6}",
+                "Tile", "Ratio XX", "Ratio YY", "Phase XX", "Phase YY", "Num"
+            );
+            for (name, stats) in diff.tile_names.iter().zip(timeblock.tiles.iter()) {
+                info!(
+                    "  {name:>12} {:>8.4} {:>8.4} {:>9.3}° {:>9.3}° {:>6}",
+                    stats.median_amp_ratio[0],
+                    stats.median_amp_ratio[1],
+                    stats.rms_phase_diff[0].to_degrees(),
+                    stats.rms_phase_diff[1].to_degrees(),
+                    stats.num_compared
+                );
+            }
+            // There are usually a lot of chanblocks, so only show them when
+            // asked.
+            debug!(
+                "  {:>12} {:>8} {:>8} {:>10} {:>10} {:>6}",
+                "Freq. [MHz]", "Ratio XX", "Ratio YY", "Phase XX", "Phase YY", "Num"
+            );
+            for (i_chanblock, stats) in timeblock.chanblocks.iter().enumerate() {
+                let chanblock = match diff.chanblock_freqs.as_ref() {
+                    Some(freqs) => format!("{:.3}", freqs[i_chanblock] / 1e6),
+                    None => diff.chanblock_indices[i_chanblock][0].to_string(),
+                };
+                debug!(
+                    "  {chanblock:>12} {:>8.4} {:>8.4} {:>9.3}° {:>9.3}° {:>6}",
+                    stats.median_amp_ratio[0],
+                    stats.median_amp_ratio[1],
+                    stats.rms_phase_diff[0].to_degrees(),
+                    stats.rms_phase_diff[1].to_degrees(),
+                    stats.num_compared
+                );
+            }
+        }
+
+        if let Some(output) = self.output.as_ref() {
+            let f = BufWriter::new(File::create(output)?);
+            serde_json::to_writer_pretty(f, &diff).map_err(std::io::Error::from)?;
+            info!("Wrote {}", output.display());
+        }
+
+        #[cfg(feature = "plotting")]
+        if let Some(plot_directory) = self.plot_directory.as_ref() {
+            if !plot_directory.exists() {
+                std::fs::create_dir_all(plot_directory)?;
+            }
+            let stem = |file: &PathBuf| {
+                file.file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            };
+            let filename_base = plot_directory
+                .join(format!("{}_vs_{}", stem(&self.second), stem(&self.first)))
+                .display()
+                .to_string();
+            let title = format!("{} vs. {}", self.second.display(), self.first.display());
+            let plot_files = super::plot::plot_diff(&diff, &filename_base, &title)
+                .map_err(super::SolutionsPlotError::from)?;
+            info!("Wrote {:?}", plot_files);
+        }
+
+        display_warnings();
+
+        Ok(())
+    }
+}
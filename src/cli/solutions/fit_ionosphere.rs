@@ -0,0 +1,124 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to fit ionospheric models to calibration solutions.
+
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::Parser;
+use log::info;
+use marlu::{LatLngHeight, XyzGeodetic};
+use thiserror::Error;
+
+use crate::{
+    cli::common::{display_warnings, Warn},
+    solutions::{
+        ionosphere::{fit_ionosphere, DEFAULT_MAX_DTEC},
+        CalibrationSolutions,
+    },
+    HyperdriveError,
+};
+
+lazy_static::lazy_static! {
+    static ref MAX_DTEC_HELP: String =
+        format!("The largest differential TEC (in either direction) to search for [TECU]. Default: {DEFAULT_MAX_DTEC}");
+}
+
+#[derive(Parser, Debug, Default)]
+pub(crate) struct SolutionsFitIonosphereArgs {
+    /// The path to the input file. If this is a directory instead, then we
+    /// attempt to read RTS calibration files in the directory.
+    #[clap(name = "INPUT_SOLUTIONS_FILE", parse(from_os_str))]
+    input: PathBuf,
+
+    /// The path to the output JSON file.
+    #[clap(name = "OUTPUT_FILE", parse(from_os_str))]
+    output: PathBuf,
+
+    /// The metafits file associated with the solutions. This provides the tile
+    /// positions needed to fit a plane to the differential TECs; without it,
+    /// only the differential TECs of each tile are fitted.
+    #[clap(short, long, parse(from_str))]
+    metafits: Option<PathBuf>,
+
+    /// The reference tile to use. If this isn't specified, the last tile with
+    /// solutions is used.
+    #[clap(short, long)]
+    ref_tile: Option<usize>,
+
+    #[clap(long, help = MAX_DTEC_HELP.as_str())]
+    max_dtec: Option<f64>,
+}
+
+impl SolutionsFitIonosphereArgs {
+    pub fn run(self) -> Result<(), HyperdriveError> {
+        let max_dtec = match self.max_dtec {
+            None => DEFAULT_MAX_DTEC,
+            Some(m) if m.is_finite() && m > 0.0 => m,
+            Some(m) => return Err(SolutionsFitIonosphereArgsError::BadMaxDtec(m).into()),
+        };
+
+        let sols =
+            CalibrationSolutions::read_solutions_from_ext(&self.input, self.metafits.as_ref())?;
+        let tile_positions = match self.metafits.as_ref() {
+            Some(m) => {
+                let context = mwalib::MetafitsContext::new(m, None)?;
+                let latitude_rad = LatLngHeight::mwa().latitude_rad;
+                Some(
+                    XyzGeodetic::get_tiles(&context, latitude_rad)
+                        .into_iter()
+                        .map(|xyz| xyz.to_enh(latitude_rad))
+                        .collect::<Vec<_>>(),
+                )
+            }
+            None => {
+                "No metafits supplied; a plane won't be fitted to the differential TECs".warn();
+                None
+            }
+        };
+
+        let fit = fit_ionosphere(&sols, tile_positions.as_deref(), self.ref_tile, max_dtec)?;
+        info!("Using reference tile {}", fit.ref_tile);
+        for (i_timeblock, timeblock) in fit.timeblocks.iter().enumerate() {
+            let good_dtecs: Vec<f64> = timeblock
+                .dtecs
+                .iter()
+                .copied()
+                .filter(|d| d.is_finite())
+                .collect();
+            let rms = (good_dtecs.iter().map(|d| d * d).sum::<f64>()
+                / good_dtecs.len().max(1) as f64)
+                .sqrt();
+            info!(
+                "Timeblock {i_timeblock}: fitted {} tiles, RMS differential TEC {rms:.4} TECU",
+                good_dtecs.len()
+            );
+            if let Some(plane) = timeblock.plane.as_ref() {
+                info!(
+                    "Timeblock {i_timeblock}: TEC gradient {:.4} TECU/km east, {:.4} TECU/km north",
+                    plane.east_gradient, plane.north_gradient
+                );
+            }
+        }
+
+        let f = BufWriter::new(File::create(&self.output)?);
+        serde_json::to_writer_pretty(f, &fit).map_err(std::io::Error::from)?;
+
+        display_warnings();
+
+        info!(
+            "Fitted the ionosphere to {} and wrote to {}",
+            self.input.display(),
+            self.output.display()
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SolutionsFitIonosphereArgsError {
+    #[error("The maximum dTEC must be a positive number, but got {0}")]
+    BadMaxDtec(f64),
+}
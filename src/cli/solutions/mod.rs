@@ -3,9 +3,17 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 mod apply;
+mod combine;
 mod convert;
+mod diff;
+mod fit_ionosphere;
 mod plot;
+mod smooth;
 
 pub(super) use apply::{SolutionsApplyArgs, SolutionsApplyArgsError};
+pub(super) use combine::{SolutionsCombineArgs, SolutionsCombineArgsError};
 pub(super) use convert::SolutionsConvertArgs;
+pub(super) use diff::SolutionsDiffArgs;
+pub(super) use fit_ionosphere::{SolutionsFitIonosphereArgs, SolutionsFitIonosphereArgsError};
 pub(super) use plot::{SolutionsPlotArgs, SolutionsPlotError};
+pub(super) use smooth::SolutionsSmoothArgs;
@@ -7,6 +7,8 @@
 mod error;
 
 pub(crate) use error::SolutionsPlotError;
+#[cfg(feature = "plotting")]
+pub(super) use plotting::plot_diff;
 
 use std::path::PathBuf;
 
@@ -61,6 +63,12 @@ pub(crate) struct SolutionsPlotArgs {
     /// additional information on the plots, like the tile names.
     #[clap(short, long, parse(from_str))]
     metafits: Option<PathBuf>,
+
+    /// Plot each tile's g_x and g_y solutions as time-frequency waterfalls,
+    /// rather than making plots of each timeblock. This is useful for
+    /// solutions with many timeblocks.
+    #[clap(long)]
+    waterfall: bool,
 }
 
 impl SolutionsPlotArgs {
@@ -96,7 +104,9 @@ mod plotting {
     use vec1::Vec1;
 
     use super::*;
-    use crate::solutions::{ao, hyperdrive, CalSolutionType, CalibrationSolutions};
+    use crate::solutions::{
+        ao, diff::SolutionsDiff, hyperdrive, CalSolutionType, CalibrationSolutions,
+    };
 
     /// The number of X pixels on the plots.
     const X_PIXELS: u32 = 3200;
@@ -126,6 +136,7 @@ mod plotting {
             num_cols,
             output_directory,
             metafits,
+            waterfall,
         } = args;
 
         if files.is_empty() {
@@ -197,6 +208,13 @@ mod plotting {
             let sols = match solutions_type {
                 CalSolutionType::Fits => hyperdrive::read(&solutions_file)?,
                 CalSolutionType::Bin => ao::read(&solutions_file)?,
+                CalSolutionType::Rts => CalibrationSolutions::read_solutions_from_ext_inner(
+                    &solutions_file,
+                    metafits.as_deref(),
+                )?,
+                CalSolutionType::Casa => {
+                    return Err(SolutionsPlotError::InvalidSolsFormat(solutions_file))
+                }
             };
             let plot_title = format!(
                 "obsid {}",
@@ -225,20 +243,36 @@ mod plotting {
                 let num_cols = (total_num_tiles as f64 / num_rows as f64).ceil() as usize;
                 (num_rows, num_cols, tile_name_font_size)
             };
-            let plot_files = plotting::plot_sols(
-                &sols,
-                &base,
-                &plot_title,
-                ref_tile,
-                no_ref_tile,
-                tile_names,
-                ignore_cross_pols,
-                min_amp,
-                max_amp,
-                num_rows.unwrap_or(auto_num_rows),
-                num_cols.unwrap_or(auto_num_cols),
-                tile_name_font_size,
-            )?;
+            let plot_files = if waterfall {
+                plotting::plot_sols_waterfall(
+                    &sols,
+                    &base,
+                    &plot_title,
+                    ref_tile,
+                    no_ref_tile,
+                    tile_names,
+                    min_amp,
+                    max_amp,
+                    num_rows.unwrap_or(auto_num_rows),
+                    num_cols.unwrap_or(auto_num_cols),
+                    tile_name_font_size,
+                )?
+            } else {
+                plotting::plot_sols(
+                    &sols,
+                    &base,
+                    &plot_title,
+                    ref_tile,
+                    no_ref_tile,
+                    tile_names,
+                    ignore_cross_pols,
+                    min_amp,
+                    max_amp,
+                    num_rows.unwrap_or(auto_num_rows),
+                    num_cols.unwrap_or(auto_num_cols),
+                    tile_name_font_size,
+                )?
+            };
             info!("Wrote {:?}", plot_files);
         }
 
@@ -260,7 +294,7 @@ mod plotting {
         num_cols: usize,
         tile_name_font_size: i32,
     ) -> Result<Vec<String>, DrawError> {
-        let (num_timeblocks, total_num_tiles, _) = sols.di_jones.dim();
+        let num_timeblocks = sols.di_jones.len_of(Axis(0));
 
         let mut amps = Array2::from_elem(
             (sols.di_jones.dim().1, sols.di_jones.dim().2),
@@ -271,40 +305,7 @@ mod plotting {
             [0.0, 0.0, 0.0, 0.0],
         );
 
-        let ref_tile = match (no_ref_tile, ref_tile) {
-            (true, _) => {
-                debug!("Not using a reference tile");
-                None
-            }
-            (_, Some(r)) => {
-                debug!("Using user-specified reference tile: {r}");
-                Some(r)
-            }
-            // If the reference tile wasn't defined, use the first valid one from
-            // the end.
-            (_, None) => {
-                let possibly_good = sols
-                    .di_jones
-                    .slice(s![0_usize, .., ..])
-                    // Search only in the first timeblock
-                    .outer_iter()
-                    // Search by tile from the end
-                    .rev()
-                    .enumerate()
-                    // Include solutions for tiles that (1) aren't all NaN and
-                    // (2) aren't singular (this can happen when dealing with
-                    // single-pol data).
-                    .filter(|(_, j)| !j.iter().all(|f| f.any_nan() || f.inv().any_nan()))
-                    .map(|(i, _)| i)
-                    .next();
-                // If the search for a valid tile didn't find anything, all
-                // solutions must be NaN. In this case, it doesn't matter what the
-                // reference is.
-                let r = possibly_good.map(|g| total_num_tiles - 1 - g);
-                debug!("Automatically determined reference tile: {r:?}");
-                r
-            }
-        };
+        let ref_tile = get_ref_tile(sols, ref_tile, no_ref_tile);
 
         let title_style = ("sans-serif", 60).into_font();
 
@@ -561,6 +562,419 @@ mod plotting {
         Ok(output_filenames)
     }
 
+    /// Plot the amplitude ratios and phase differences of two compared sets of
+    /// calibration solutions. Each timeblock has its own pair of plots.
+    pub(crate) fn plot_diff(
+        diff: &SolutionsDiff,
+        filename_base: &str,
+        title: &str,
+    ) -> Result<Vec<String>, DrawError> {
+        let num_timeblocks = diff.timeblocks.len();
+        let num_tiles = diff.tile_names.len();
+        let (num_rows, tile_name_font_size) = match num_tiles {
+            0..=128 => (8, 30),
+            129..=256 => (10, 24),
+            _ => (16, 18),
+        };
+        let num_cols = (num_tiles as f64 / num_rows as f64).ceil() as usize;
+        let tile_names = diff
+            .tile_names
+            .iter()
+            .enumerate()
+            .map(|(i_tile, name)| format!("{i_tile}: {name}"))
+            .collect::<Vec<_>>();
+
+        let mut output_filenames = vec![];
+        for (i_timeblock, timeblock) in diff.timeblocks.iter().enumerate() {
+            let suffix = if num_timeblocks > 1 {
+                format!("_{i_timeblock:03}")
+            } else {
+                String::new()
+            };
+
+            // Make the amplitude-ratio axis symmetric (in log space) around 1.
+            let max_amp_ratio = timeblock
+                .amp_ratios
+                .iter()
+                .flatten()
+                .flatten()
+                .filter(|r| r.is_finite() && **r > 0.0)
+                .fold(1.01_f64, |acc, &r| acc.max(r).max(1.0 / r));
+            let phase_diffs = timeblock
+                .phase_diffs
+                .iter()
+                .map(|p| {
+                    p.iter()
+                        .map(|[xx, yy]| [xx.to_degrees(), yy.to_degrees()])
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            for (kind, description, values, y_range) in [
+                (
+                    "amp_ratios",
+                    "Amp. ratios",
+                    &timeblock.amp_ratios,
+                    1.0 / max_amp_ratio..max_amp_ratio,
+                ),
+                ("phase_diffs", "Phase diffs.", &phase_diffs, -180.0..180.0),
+            ] {
+                let filename = format!("{filename_base}_{kind}{suffix}.png");
+                let root_area =
+                    BitMapBackend::new(&filename, (X_PIXELS, Y_PIXELS)).into_drawing_area();
+                root_area
+                    .fill(&WHITE)
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                // Draw the coloured text for each polarisation.
+                for (i, (first_char, second_char, colour)) in
+                    [&POLS[0], &POLS[3]].into_iter().enumerate()
+                {
+                    root_area
+                        .draw_text(
+                            first_char,
+                            &("sans-serif", 50).into_font().color(colour),
+                            (X_PIXELS as i32 - 340 + 160 * i as i32, 10),
+                        )
+                        .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                    root_area
+                        .draw_text(
+                            second_char,
+                            &("sans-serif", 35).into_font().color(colour),
+                            (X_PIXELS as i32 - 310 + 160 * i as i32, 30),
+                        )
+                        .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                }
+                let meta_str = match diff.ref_tile.as_deref() {
+                    Some(ref_tile) => format!("Ref. tile {ref_tile}, timeblock {i_timeblock}"),
+                    None => format!("Timeblock {i_timeblock}"),
+                };
+                root_area
+                    .draw_text(
+                        &meta_str,
+                        &("sans-serif", 38).into_font().color(&BLACK),
+                        (10, 10),
+                    )
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+
+                let root_area = root_area
+                    .shrink((15, 0), (X_PIXELS - 15, Y_PIXELS))
+                    .titled(
+                        &format!("{description} for {title}"),
+                        ("sans-serif", 60).into_font(),
+                    )
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                let tile_plots = root_area.split_evenly((num_rows, num_cols));
+                for (i_tile, (values, tile_plot)) in values.iter().zip(tile_plots).enumerate() {
+                    plot_diff_tile(
+                        &tile_plot,
+                        values,
+                        y_range.clone(),
+                        &tile_names[i_tile],
+                        tile_name_font_size,
+                        i_tile % num_cols == 0,
+                    )?;
+                }
+
+                root_area
+                    .present()
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                output_filenames.push(filename.clone());
+            }
+        }
+
+        Ok(output_filenames)
+    }
+
+    /// For a single drawing area, plot the XX and YY values of a comparison
+    /// of solutions.
+    fn plot_diff_tile<DB: DrawingBackend>(
+        drawing_area: &DrawingArea<DB, Shift>,
+        values: &[[f64; 2]],
+        y_range: std::ops::Range<f64>,
+        tile_name: &str,
+        tile_name_font_size: i32,
+        y_labels: bool,
+    ) -> Result<(), DrawError> {
+        let mut cc = ChartBuilder::on(drawing_area)
+            .caption(tile_name, ("sans-serif", tile_name_font_size))
+            .top_x_label_area_size(15)
+            .y_label_area_size(if y_labels { 45 } else { 0 })
+            .build_cartesian_2d(0..values.len(), y_range)
+            .map_err(|e| DrawError::Diff(e.to_string()))?;
+
+        cc.configure_mesh()
+            .light_line_style(WHITE)
+            .draw()
+            .map_err(|e| DrawError::Diff(e.to_string()))?;
+
+        if values.iter().all(|v| v[0].is_nan() && v[1].is_nan()) {
+            cc.plotting_area()
+                .fill(&RGBColor(220, 220, 220))
+                .map_err(|e| DrawError::Diff(e.to_string()))?;
+            return Ok(());
+        }
+
+        for (i_pol, (_, _, colour)) in [&POLS[0], &POLS[3]].into_iter().enumerate() {
+            cc.draw_series(PointSeries::of_element(
+                values
+                    .iter()
+                    .map(|v| v[i_pol])
+                    .enumerate()
+                    .filter(|(_, y)| y.is_finite()),
+                1,
+                ShapeStyle::from(colour).filled(),
+                &|coord, size, style| EmptyElement::at(coord) + Circle::new((0, 0), size, style),
+            ))
+            .map_err(|e| DrawError::Diff(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the reference tile to use when plotting solutions.
+    fn get_ref_tile(
+        sols: &CalibrationSolutions,
+        ref_tile: Option<usize>,
+        no_ref_tile: bool,
+    ) -> Option<usize> {
+        let total_num_tiles = sols.di_jones.len_of(Axis(1));
+        match (no_ref_tile, ref_tile) {
+            (true, _) => {
+                debug!("Not using a reference tile");
+                None
+            }
+            (_, Some(r)) => {
+                debug!("Using user-specified reference tile: {r}");
+                Some(r)
+            }
+            // If the reference tile wasn't defined, use the first valid one from
+            // the end.
+            (_, None) => {
+                let possibly_good = sols
+                    .di_jones
+                    .slice(s![0_usize, .., ..])
+                    // Search only in the first timeblock
+                    .outer_iter()
+                    // Search by tile from the end
+                    .rev()
+                    .enumerate()
+                    // Include solutions for tiles that (1) aren't all NaN and
+                    // (2) aren't singular (this can happen when dealing with
+                    // single-pol data).
+                    .filter(|(_, j)| !j.iter().all(|f| f.any_nan() || f.inv().any_nan()))
+                    .map(|(i, _)| i)
+                    .next();
+                // If the search for a valid tile didn't find anything, all
+                // solutions must be NaN. In this case, it doesn't matter what the
+                // reference is.
+                let r = possibly_good.map(|g| total_num_tiles - 1 - g);
+                debug!("Automatically determined reference tile: {r:?}");
+                r
+            }
+        }
+    }
+
+    /// Plot each tile's g_x and g_y solutions as time-frequency waterfalls. The
+    /// amplitudes and phases of each polarisation are plotted in separate
+    /// files.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn plot_sols_waterfall(
+        sols: &CalibrationSolutions,
+        filename_base: &str,
+        obs_name: &str,
+        ref_tile: Option<usize>,
+        no_ref_tile: bool,
+        tile_names: Option<&Vec1<String>>,
+        min_amp: Option<f64>,
+        max_amp: Option<f64>,
+        num_rows: usize,
+        num_cols: usize,
+        tile_name_font_size: i32,
+    ) -> Result<Vec<String>, DrawError> {
+        let (num_timeblocks, total_num_tiles, num_chanblocks) = sols.di_jones.dim();
+        let ref_tile = get_ref_tile(sols, ref_tile, no_ref_tile);
+
+        // The g_x and g_y amps and phases. The dimensions are (tile,
+        // timeblock, chanblock).
+        let mut amps = Array3::from_elem(
+            (total_num_tiles, num_timeblocks, num_chanblocks),
+            [f64::NAN; 2],
+        );
+        let mut phases = amps.clone();
+        for (i_timeblock, di_jones) in sols.di_jones.outer_iter().enumerate() {
+            for ((i_tile, i_chanblock), j) in di_jones.indexed_iter() {
+                let div = match ref_tile {
+                    Some(r) => *j / &di_jones[(r, i_chanblock)],
+                    None => *j,
+                };
+                amps[(i_tile, i_timeblock, i_chanblock)] = [div[0].norm(), div[3].norm()];
+                phases[(i_tile, i_timeblock, i_chanblock)] = [div[0].arg(), div[3].arg()];
+            }
+        }
+
+        let (data_min, data_max) = amps
+            .iter()
+            .flatten()
+            .filter(|a| a.is_finite())
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &a| {
+                (min.min(a), max.max(a))
+            });
+        let min_amp = min_amp.unwrap_or(if data_min.is_finite() { data_min } else { 0.0 });
+        let max_amp = max_amp.unwrap_or(if data_max.is_finite() { data_max } else { 1.0 });
+        // Low amps are blue, high amps are red.
+        let amp_colour = |a: f64| {
+            let frac = if max_amp > min_amp {
+                ((a - min_amp) / (max_amp - min_amp)).clamp(0.0, 1.0)
+            } else {
+                0.5
+            };
+            HSLColor(2.0 / 3.0 * (1.0 - frac), 1.0, 0.5)
+        };
+        // Phases wrap around the colour wheel.
+        let phase_colour = |p: f64| HSLColor((p / std::f64::consts::TAU).rem_euclid(1.0), 1.0, 0.5);
+
+        // Label the timeblocks by their average times relative to the first,
+        // if possible.
+        let (time_labels, time_str) = match sols.average_timestamps.as_ref() {
+            Some(t) if t.len() == num_timeblocks => {
+                let first = t.first().to_gpst_seconds();
+                (
+                    t.iter()
+                        .map(|t| format!("{:.0}", t.to_gpst_seconds() - first))
+                        .collect::<Vec<_>>(),
+                    format!("Timeblock seconds after GPS {first}"),
+                )
+            }
+            _ => (
+                (0..num_timeblocks).map(|i| i.to_string()).collect(),
+                "Timeblock indices".to_string(),
+            ),
+        };
+
+        let title_style = ("sans-serif", 60).into_font();
+        let mut output_filenames = vec![];
+        for (i_pol, pol) in ["x", "y"].into_iter().enumerate() {
+            for (kind, description, values, colour, range_str) in [
+                (
+                    "amps",
+                    "Amps",
+                    &amps,
+                    &amp_colour as &dyn Fn(f64) -> HSLColor,
+                    format!("amps {min_amp:.3} (blue) to {max_amp:.3} (red)"),
+                ),
+                (
+                    "phases",
+                    "Phases",
+                    &phases,
+                    &phase_colour as &dyn Fn(f64) -> HSLColor,
+                    "phases -180° to 180° (cyclic)".to_string(),
+                ),
+            ] {
+                let filename = format!("{filename_base}_{kind}_g{pol}_waterfall.png");
+                let root_area =
+                    BitMapBackend::new(&filename, (X_PIXELS, Y_PIXELS)).into_drawing_area();
+                root_area
+                    .fill(&WHITE)
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                let mut meta_str = match ref_tile {
+                    Some(ref_tile) => format!("Ref. tile {ref_tile}, "),
+                    None => String::new(),
+                };
+                meta_str.push_str(&format!("{time_str}, {range_str}"));
+                root_area
+                    .draw_text(
+                        &meta_str,
+                        &("sans-serif", 38).into_font().color(&BLACK),
+                        (10, 10),
+                    )
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+
+                let root_area = root_area
+                    .shrink((15, 0), (X_PIXELS - 15, Y_PIXELS))
+                    .titled(
+                        &format!("{description} of g_{pol} for {obs_name}"),
+                        title_style.clone(),
+                    )
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                let tile_plots = root_area.split_evenly((num_rows, num_cols));
+                for (i_tile, (values, tile_plot)) in values.outer_iter().zip(tile_plots).enumerate()
+                {
+                    let tile_name = match tile_names {
+                        Some(names) => format!("{}: {}", i_tile, names[i_tile]),
+                        None => format!("{i_tile}"),
+                    };
+                    plot_waterfall(
+                        &tile_plot,
+                        values,
+                        i_pol,
+                        colour,
+                        &tile_name,
+                        tile_name_font_size,
+                        i_tile % num_cols == 0,
+                        &time_labels,
+                    )?;
+                }
+
+                root_area
+                    .present()
+                    .map_err(|e| DrawError::Plotters(Box::new(e)))?;
+                output_filenames.push(filename.clone());
+            }
+        }
+
+        Ok(output_filenames)
+    }
+
+    /// For a single drawing area, plot a polarisation of a tile's amps or
+    /// phases as a time-frequency waterfall. The first dimension of `values`
+    /// is timeblock, the second is chanblock.
+    #[allow(clippy::too_many_arguments)]
+    fn plot_waterfall<DB: DrawingBackend>(
+        drawing_area: &DrawingArea<DB, Shift>,
+        values: ArrayView2<[f64; 2]>,
+        i_pol: usize,
+        colour: &dyn Fn(f64) -> HSLColor,
+        tile_name: &str,
+        tile_name_font_size: i32,
+        y_labels: bool,
+        time_labels: &[String],
+    ) -> Result<(), DrawError> {
+        let (num_timeblocks, num_chanblocks) = values.dim();
+        let mut cc = ChartBuilder::on(drawing_area)
+            .caption(tile_name, ("sans-serif", tile_name_font_size))
+            .top_x_label_area_size(15)
+            .y_label_area_size(if y_labels { 60 } else { 0 })
+            .build_cartesian_2d(0..num_chanblocks, 0..num_timeblocks)
+            .map_err(|e| DrawError::Waterfall(e.to_string()))?;
+
+        cc.configure_mesh()
+            .disable_mesh()
+            .y_label_formatter(&|i| time_labels.get(*i).cloned().unwrap_or_default())
+            .draw()
+            .map_err(|e| DrawError::Waterfall(e.to_string()))?;
+
+        cc.plotting_area()
+            .fill(&RGBColor(220, 220, 220))
+            .map_err(|e| DrawError::Waterfall(e.to_string()))?;
+        cc.draw_series(
+            values
+                .indexed_iter()
+                .filter(|(_, v)| v[i_pol].is_finite())
+                .map(|((i_timeblock, i_chanblock), v)| {
+                    Rectangle::new(
+                        [
+                            (i_chanblock, i_timeblock),
+                            (i_chanblock + 1, i_timeblock + 1),
+                        ],
+                        colour(v[i_pol]).filled(),
+                    )
+                }),
+        )
+        .map_err(|e| DrawError::Waterfall(e.to_string()))?;
+
+        Ok(())
+    }
+
     /// For a single drawing area, plot gains.
     #[allow(clippy::too_many_arguments)]
     fn plot_amps<DB: DrawingBackend>(
@@ -692,6 +1106,12 @@ mod plotting {
         #[error("While plotting phases: {0}")]
         Phases(String),
 
+        #[error("While plotting solution differences: {0}")]
+        Diff(String),
+
+        #[error("While plotting waterfalls: {0}")]
+        Waterfall(String),
+
         #[error("Error from the plotters library: {0}")]
         Plotters(Box<dyn std::error::Error>),
     }
@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to smooth calibration solutions over frequency.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use log::info;
+
+use crate::{
+    cli::common::display_warnings,
+    solutions::{
+        smooth::{smooth, SmoothParams, DEFAULT_AMP_ORDER},
+        CalibrationSolutions,
+    },
+    HyperdriveError,
+};
+
+lazy_static::lazy_static! {
+    static ref AMP_ORDER_HELP: String =
+        format!("The order of the polynomial used to model the amplitudes of the solutions over frequency. Default: {DEFAULT_AMP_ORDER}");
+}
+
+#[derive(Parser, Debug, Default)]
+pub(crate) struct SolutionsSmoothArgs {
+    /// The path to the input file. If this is a directory instead, then we
+    /// attempt to read RTS calibration files in the directory.
+    #[clap(name = "INPUT_SOLUTIONS_FILE", parse(from_os_str))]
+    input: PathBuf,
+
+    /// The path to the output file. If this is an existing directory (or has
+    /// an "rts" extension) instead, then RTS calibration files are written
+    /// into the directory; this requires a metafits file.
+    #[clap(name = "OUTPUT_SOLUTIONS_FILE", parse(from_os_str))]
+    output: PathBuf,
+
+    /// The metafits file associated with the solutions. This may be required.
+    #[clap(short, long, parse(from_str))]
+    metafits: Option<PathBuf>,
+
+    #[clap(long, help = AMP_ORDER_HELP.as_str())]
+    amp_order: Option<usize>,
+
+    /// Also model a cable reflection (a sinusoid over frequency) in each of
+    /// the solutions.
+    #[clap(long)]
+    reflection: bool,
+
+    /// Only replace flagged and non-converged chanblocks with the fitted
+    /// models. By default, all chanblocks are replaced.
+    #[clap(long)]
+    fill_only: bool,
+}
+
+impl SolutionsSmoothArgs {
+    pub fn run(self) -> Result<(), HyperdriveError> {
+        let mut sols =
+            CalibrationSolutions::read_solutions_from_ext(&self.input, self.metafits.as_ref())?;
+        let params = SmoothParams {
+            amp_order: self.amp_order.unwrap_or(DEFAULT_AMP_ORDER),
+            reflection: self.reflection,
+            fill_only: self.fill_only,
+        };
+        smooth(&mut sols, &params);
+        sols.write_solutions_from_ext_with_metafits(&self.output, self.metafits.as_ref())?;
+
+        display_warnings();
+
+        info!(
+            "Smoothed {} and wrote to {}",
+            self.input.display(),
+            self.output.display()
+        );
+
+        Ok(())
+    }
+}
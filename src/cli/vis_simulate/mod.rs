@@ -590,6 +590,7 @@ impl VisSimulateArgs {
         let params = self.parse()?;
 
         if dry_run {
+            params.get_resource_estimate().display();
             info!("Dry run -- exiting now.");
             return Ok(());
         }
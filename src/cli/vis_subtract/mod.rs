@@ -11,7 +11,7 @@ use serde::{Deserialize, Serialize};
 
 use super::common::{
     display_warnings, BeamArgs, InputVisArgs, ModellingArgs, OutputVisArgs, SkyModelWithVetoArgs,
-    ARG_FILE_HELP,
+    ARG_FILE_HELP, MODEL_VIS_HELP,
 };
 use crate::{
     cli::common::InfoPrinter,
@@ -45,6 +45,9 @@ struct VisSubtractCliArgs {
     #[clap(long, multiple_values(true), help_heading = "SKY-MODEL SOURCES")]
     sources_to_subtract: Option<Vec<String>>,
 
+    #[clap(long, multiple_values(true), help = MODEL_VIS_HELP.as_str(), help_heading = "SKY-MODEL SOURCES")]
+    model_vis: Option<Vec<String>>,
+
     #[clap(
         short = 'o',
         long,
@@ -171,6 +174,7 @@ impl VisSubtractArgs {
                 VisSubtractCliArgs {
                     invert,
                     sources_to_subtract,
+                    model_vis,
                     outputs,
                     output_vis_time_average,
                     output_vis_freq_average,
@@ -178,7 +182,7 @@ impl VisSubtractArgs {
                 },
         } = self;
 
-        let input_vis_params = data_args.parse("Vis subtracting")?;
+        let input_vis_params = data_args.clone().parse("Vis subtracting")?;
         let obs_context = input_vis_params.get_obs_context();
         let total_num_tiles = obs_context.get_total_num_tiles();
 
@@ -213,126 +217,144 @@ impl VisSubtractArgs {
             (precession_info.lmst, latitude_rad)
         };
 
-        // If we're not inverted but `sources_to_subtract` is empty, then there's
-        // nothing to do.
-        let sources_to_subtract = sources_to_subtract.unwrap_or_default();
-        if !invert && sources_to_subtract.is_empty() {
-            return Err(VisSubtractArgsError::NoSources.into());
-        }
-
-        // Read in the source list and remove all but the specified sources. We
-        // have to parse the arguments manually as we're doing custom stuff here
-        // in vis-subtract.
-        let SkyModelWithVetoArgs {
-            source_list,
-            source_list_type,
-            num_sources,
-            source_dist_cutoff,
-            veto_threshold,
-        } = srclist_args;
-
-        let source_list: SourceList = {
-            let source_list = source_list.ok_or(ReadSourceListError::NoSourceList)?;
-            // If the specified source list file can't be found, treat it as a glob
-            // and expand it to find a match.
-            let pb = PathBuf::from(&source_list);
-            let pb = if pb.exists() {
-                pb
-            } else {
-                get_single_match_from_glob(&source_list)
-                    .map_err(|e| HyperdriveError::Generic(e.to_string()))?
-            };
-
-            // Read the source list file. If the type was manually specified,
-            // use that, otherwise the reading code will try all available
-            // kinds.
-            let sl_type_not_specified = source_list_type.is_none();
-            let sl_type = source_list_type
-                .as_ref()
-                .and_then(|t| SourceListType::from_str(t.as_ref()).ok());
-            let (sl, sl_type) = read_source_list_file(pb, sl_type)?;
-
-            // If the user didn't specify the source list type, then print out
-            // what we found.
-            if sl_type_not_specified {
-                trace!("Successfully parsed {}-style source list", sl_type);
-            }
-            if num_sources == Some(0) || sl.is_empty() {
-                return Err(ReadSourceListError::NoSources.into());
+        // Precomputed model visibilities are subtracted in their entirety, so
+        // there's no source list.
+        let model_vis_params = match model_vis {
+            None => None,
+            Some(files) => {
+                if invert || sources_to_subtract.is_some() {
+                    return Err(VisSubtractArgsError::ModelVisWithSources.into());
+                }
+                Some(data_args.parse_model_vis(files, &input_vis_params)?)
             }
-            sl
         };
-        debug!("Found {} sources in the source list", source_list.len());
-        let ComponentCounts {
-            num_points,
-            num_gaussians,
-            num_shapelets,
-            ..
-        } = source_list.get_counts();
-        let mut sl_printer = InfoPrinter::new("Sky model info".into());
-        sl_printer.push_block(vec![
-            format!("Source list contains {} sources", source_list.len()).into(),
-            format!("({} components, {num_points} points, {num_gaussians} Gaussians, {num_shapelets} shapelets)", num_points + num_gaussians + num_shapelets).into()
-        ]);
-
-        // Ensure that all specified sources are actually in the source list.
-        for name in &sources_to_subtract {
-            if !source_list.contains_key(name) {
-                return Err(HyperdriveError::from(VisSubtractArgsError::MissingSource {
-                    name: name.to_string().into(),
-                }));
-            }
-        }
-        // Handle the invert option.
-        let source_list: SourceList = if invert {
-            let mut sl: SourceList = source_list
-                .into_iter()
-                .filter(|(name, _)| !sources_to_subtract.contains(name))
-                .collect();
-            if sl.is_empty() {
-                // Nothing to do.
-                return Err(VisSubtractArgsError::AllSourcesFiltered.into());
+        let source_list = if model_vis_params.is_some() {
+            SourceList::new()
+        } else {
+            // If we're not inverted but `sources_to_subtract` is empty, then there's
+            // nothing to do.
+            let sources_to_subtract = sources_to_subtract.unwrap_or_default();
+            if !invert && sources_to_subtract.is_empty() {
+                return Err(VisSubtractArgsError::NoSources.into());
             }
-            veto_sources(
-                &mut sl,
-                obs_context.phase_centre,
-                lmst,
-                latitude,
-                &obs_context.get_veto_freqs(),
-                &*beam,
+
+            // Read in the source list and remove all but the specified sources. We
+            // have to parse the arguments manually as we're doing custom stuff here
+            // in vis-subtract.
+            let SkyModelWithVetoArgs {
+                source_list,
+                source_list_type,
                 num_sources,
-                source_dist_cutoff.unwrap_or(DEFAULT_CUTOFF_DISTANCE),
-                veto_threshold.unwrap_or(DEFAULT_VETO_THRESHOLD),
-            )?;
-            if sl.is_empty() {
-                return Err(ReadSourceListError::NoSourcesAfterVeto.into());
+                source_dist_cutoff,
+                veto_threshold,
+            } = srclist_args;
+
+            let source_list: SourceList = {
+                let source_list = source_list.ok_or(ReadSourceListError::NoSourceList)?;
+                // If the specified source list file can't be found, treat it as a glob
+                // and expand it to find a match.
+                let pb = PathBuf::from(&source_list);
+                let pb = if pb.exists() {
+                    pb
+                } else {
+                    get_single_match_from_glob(&source_list)
+                        .map_err(|e| HyperdriveError::Generic(e.to_string()))?
+                };
+
+                // Read the source list file. If the type was manually specified,
+                // use that, otherwise the reading code will try all available
+                // kinds.
+                let sl_type_not_specified = source_list_type.is_none();
+                let sl_type = source_list_type
+                    .as_ref()
+                    .and_then(|t| SourceListType::from_str(t.as_ref()).ok());
+                let (sl, sl_type) = read_source_list_file(pb, sl_type)?;
+
+                // If the user didn't specify the source list type, then print out
+                // what we found.
+                if sl_type_not_specified {
+                    trace!("Successfully parsed {}-style source list", sl_type);
+                }
+                if num_sources == Some(0) || sl.is_empty() {
+                    return Err(ReadSourceListError::NoSources.into());
+                }
+                sl
+            };
+            debug!("Found {} sources in the source list", source_list.len());
+            let ComponentCounts {
+                num_points,
+                num_gaussians,
+                num_shapelets,
+                ..
+            } = source_list.get_counts();
+            let mut sl_printer = InfoPrinter::new("Sky model info".into());
+            sl_printer.push_block(vec![
+                format!("Source list contains {} sources", source_list.len()).into(),
+                format!("({} components, {num_points} points, {num_gaussians} Gaussians, {num_shapelets} shapelets)", num_points + num_gaussians + num_shapelets).into()
+            ]);
+
+            // Ensure that all specified sources are actually in the source list.
+            for name in &sources_to_subtract {
+                if !source_list.contains_key(name) {
+                    return Err(HyperdriveError::from(VisSubtractArgsError::MissingSource {
+                        name: name.to_string().into(),
+                    }));
+                }
             }
-            sl
-        } else {
+            // Handle the invert option.
+            let source_list: SourceList = if invert {
+                let mut sl: SourceList = source_list
+                    .into_iter()
+                    .filter(|(name, _)| !sources_to_subtract.contains(name))
+                    .collect();
+                if sl.is_empty() {
+                    // Nothing to do.
+                    return Err(VisSubtractArgsError::AllSourcesFiltered.into());
+                }
+                veto_sources(
+                    &mut sl,
+                    obs_context.phase_centre,
+                    lmst,
+                    latitude,
+                    &obs_context.get_veto_freqs(),
+                    &*beam,
+                    num_sources,
+                    source_dist_cutoff.unwrap_or(DEFAULT_CUTOFF_DISTANCE),
+                    veto_threshold.unwrap_or(DEFAULT_VETO_THRESHOLD),
+                )?;
+                if sl.is_empty() {
+                    return Err(ReadSourceListError::NoSourcesAfterVeto.into());
+                }
+                sl
+            } else {
+                source_list
+                    .into_iter()
+                    .filter(|(name, _)| sources_to_subtract.contains(name))
+                    .collect()
+            };
+            let ComponentCounts {
+                num_points,
+                num_gaussians,
+                num_shapelets,
+                num_power_laws: _,
+                num_curved_power_laws: _,
+                num_lists: _,
+            } = source_list.get_counts();
+            sl_printer.push_block(vec![
+                format!(
+                    "Subtracting {} sources with a total of {} components",
+                    source_list.len(),
+                    num_points + num_gaussians + num_shapelets
+                )
+                .into(),
+                format!(
+                    "{num_points} points, {num_gaussians} Gaussians, {num_shapelets} shapelets"
+                )
+                .into(),
+            ]);
+            sl_printer.display();
             source_list
-                .into_iter()
-                .filter(|(name, _)| sources_to_subtract.contains(name))
-                .collect()
         };
-        let ComponentCounts {
-            num_points,
-            num_gaussians,
-            num_shapelets,
-            num_power_laws: _,
-            num_curved_power_laws: _,
-            num_lists: _,
-        } = source_list.get_counts();
-        sl_printer.push_block(vec![
-            format!(
-                "Subtracting {} sources with a total of {} components",
-                source_list.len(),
-                num_points + num_gaussians + num_shapelets
-            )
-            .into(),
-            format!("{num_points} points, {num_gaussians} Gaussians, {num_shapelets} shapelets")
-                .into(),
-        ]);
-        sl_printer.display();
 
         let output_vis_params = OutputVisArgs {
             outputs,
@@ -355,6 +377,7 @@ impl VisSubtractArgs {
             output_vis_params,
             beam,
             source_list,
+            model_vis_params,
             modelling_params,
         })
     }
@@ -365,6 +388,7 @@ impl VisSubtractArgs {
         let params = self.parse()?;
 
         if dry_run {
+            params.get_resource_estimate().display();
             info!("Dry run -- exiting now.");
             return Ok(());
         }
@@ -384,6 +408,9 @@ pub(super) enum VisSubtractArgsError {
 
     #[error("No sources were left after removing specified sources from the source list.")]
     AllSourcesFiltered,
+
+    #[error("Precomputed model visibilities (--model-vis) are subtracted entirely; sources to subtract can't be specified")]
+    ModelVisWithSources,
 }
 
 impl VisSubtractCliArgs {
@@ -391,6 +418,7 @@ impl VisSubtractCliArgs {
         Self {
             invert: self.invert || other.invert,
             sources_to_subtract: self.sources_to_subtract.or(other.sources_to_subtract),
+            model_vis: self.model_vis.or(other.model_vis),
             outputs: self.outputs.or(other.outputs),
             output_vis_time_average: self
                 .output_vis_time_average
@@ -225,4 +225,54 @@ fn test_1090008640_vis_subtract() {
         expected.chunks_exact_mut(3).for_each(|c| c[2] = 64.0);
         assert_abs_diff_eq!(&vis[..], &expected[..]);
     }
+
+    {
+        // Subtracting precomputed model visibilities of both sources should
+        // also give zeros.
+        #[rustfmt::skip]
+        let sub_args = VisSubtractArgs::parse_from([
+            "vis-subtract",
+            "--data", metafits, &format!("{}", model_2.display()),
+            "--outputs", &format!("{}", subtracted.display()),
+            "--model-vis", &format!("{}", model_2.display()),
+        ]);
+        let result = sub_args.run(false);
+        assert!(result.is_ok(), "result={:?} not ok", result.err().unwrap());
+
+        let mut uvfits = fits_open(&subtracted).unwrap();
+        fits_open_hdu(&mut uvfits, 0).unwrap();
+        let mut vis: Vec<f32> = vec![0.0; num_chans * 4 * 3];
+        let mut status = 0;
+        unsafe {
+            // ffgpve = fits_read_img_flt
+            fitsio_sys::ffgpve(
+                uvfits.as_raw(),  /* I - FITS file pointer                       */
+                1,                /* I - group to read (1 = 1st group)           */
+                1,                /* I - first vector element to read (1 = 1st)  */
+                vis.len() as i64, /* I - number of values to read                */
+                0.0,              /* I - value for undefined pixels              */
+                vis.as_mut_ptr(), /* O - array of values that are returned       */
+                &mut 0,           /* O - set to 1 if any values are null; else 0 */
+                &mut status,      /* IO - error status                           */
+            );
+            assert_eq!(status, 0, "Status wasn't 0");
+        };
+
+        let mut expected = vec![0.0; num_chans * 4 * 3];
+        expected.chunks_exact_mut(3).for_each(|c| c[2] = 64.0);
+        assert_abs_diff_eq!(&vis[..], &expected[..]);
+
+        // Sources can't be specified alongside model visibilities.
+        #[rustfmt::skip]
+        let sub_args = VisSubtractArgs::parse_from([
+            "vis-subtract",
+            "--data", metafits, &format!("{}", model_2.display()),
+            "--outputs", &format!("{}", subtracted.display()),
+            "--model-vis", &format!("{}", model_2.display()),
+            "--source-list", &format!("{}", source_list_2.display()),
+            "--sources-to-subtract", "src1",
+        ]);
+        let result = sub_args.run(false);
+        assert!(matches!(result, Err(HyperdriveError::VisSubtract(_))));
+    }
 }
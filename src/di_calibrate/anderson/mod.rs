@@ -0,0 +1,164 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Anderson acceleration of calibration iterations.
+//!
+//! Every two calibration iterations, StefCal updates the solutions of all tiles
+//! with a fixed-point map `x -> H(x)` (two MitchCal updates, averaged).
+//! Anderson acceleration remembers the last few solutions and updates, and uses
+//! the combination of them that best cancels the updates' residuals `H(x) - x`
+//! to extrapolate towards the fixed point. This is "Type II" Anderson
+//! acceleration as described by Walker & Ni (2011)
+//! <https://doi.org/10.1137/10078356X>.
+//!
+//! The updates depend on the complex conjugates of the solutions, so they
+//! aren't complex-linear; the combinations of previous iterations are
+//! therefore real.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+
+use marlu::c64;
+
+/// The number of previous iterations used by Anderson acceleration.
+pub(super) const ANDERSON_DEPTH: usize = 5;
+
+/// The Tikhonov regularisation of the least-squares problem, relative to the
+/// size of its normal matrix. This stops near-degenerate histories from
+/// producing enormous extrapolations.
+const ANDERSON_REGULARISATION: f64 = 1e-10;
+
+/// Keeps the history of fixed-point iterations needed for Anderson
+/// acceleration.
+pub(super) struct AndersonMixer {
+    /// The maximum number of previous iterations to use.
+    depth: usize,
+
+    /// The last solutions and their residual.
+    last: Option<(Vec<c64>, Vec<c64>)>,
+
+    /// The differences between successive solutions.
+    delta_x: VecDeque<Vec<c64>>,
+
+    /// The differences between successive residuals.
+    delta_f: VecDeque<Vec<c64>>,
+}
+
+impl AndersonMixer {
+    pub(super) fn new(depth: usize) -> AndersonMixer {
+        AndersonMixer {
+            depth,
+            last: None,
+            delta_x: VecDeque::with_capacity(depth + 1),
+            delta_f: VecDeque::with_capacity(depth + 1),
+        }
+    }
+
+    /// Forget the history of iterations, e.g. because some solutions have
+    /// failed and are no longer being updated.
+    pub(super) fn reset(&mut self) {
+        self.last = None;
+        self.delta_x.clear();
+        self.delta_f.clear();
+    }
+
+    /// Given the solutions `x` and their update `h` (i.e. `H(x)`), get the next
+    /// solutions. Without any history, these are simply `h`.
+    pub(super) fn next(&mut self, x: &[c64], h: &[c64]) -> Vec<c64> {
+        assert_eq!(x.len(), h.len());
+        let f = h.iter().zip(x).map(|(h, x)| h - x).collect::<Vec<_>>();
+
+        if let Some((last_x, last_f)) = self.last.take() {
+            self.delta_x
+                .push_back(x.iter().zip(last_x).map(|(x, l)| x - l).collect());
+            self.delta_f
+                .push_back(f.iter().zip(last_f).map(|(f, l)| f - l).collect());
+            while self.delta_x.len() > self.depth {
+                self.delta_x.pop_front();
+                self.delta_f.pop_front();
+            }
+        }
+
+        let mut next = h.to_vec();
+        if let Some(gamma) = solve_least_squares(&self.delta_f, &f) {
+            for ((delta_x, delta_f), gamma) in self.delta_x.iter().zip(&self.delta_f).zip(gamma) {
+                for ((next, dx), df) in next.iter_mut().zip(delta_x).zip(delta_f) {
+                    *next -= (dx + df) * gamma;
+                }
+            }
+        }
+        self.last = Some((x.to_vec(), f));
+
+        // Extrapolating can go wrong; fall back to the plain update and start
+        // again.
+        if next.iter().any(|c| !c.is_finite()) {
+            self.reset();
+            next = h.to_vec();
+        }
+        next
+    }
+}
+
+/// Find the real coefficients `gamma` that minimise `|f - sum(gamma_i
+/// delta_f_i)|^2`, by solving the (regularised) normal equations. `None` is
+/// returned if there is no history or the equations can't be solved.
+fn solve_least_squares(delta_f: &VecDeque<Vec<c64>>, f: &[c64]) -> Option<Vec<f64>> {
+    let m = delta_f.len();
+    if m == 0 {
+        return None;
+    }
+
+    // The real part of the complex inner product is the inner product of the
+    // vectors' real and imaginary parts.
+    let dot =
+        |a: &[c64], b: &[c64]| -> f64 { a.iter().zip(b).map(|(a, b)| (a.conj() * b).re).sum() };
+    // The augmented matrix of the normal equations; the last column is the
+    // right-hand side.
+    let mut a = vec![vec![0.0; m + 1]; m];
+    for (row, df_i) in a.iter_mut().zip(delta_f) {
+        for (a_ij, df_j) in row.iter_mut().zip(delta_f) {
+            *a_ij = dot(df_i, df_j);
+        }
+        row[m] = dot(df_i, f);
+    }
+    let max_diag = (0..m).map(|i| a[i][i]).fold(0.0, f64::max);
+    if max_diag <= 0.0 {
+        return None;
+    }
+    for (i, row) in a.iter_mut().enumerate() {
+        row[i] += ANDERSON_REGULARISATION * max_diag;
+    }
+
+    // Gaussian elimination with partial pivoting.
+    for col in 0..m {
+        let pivot = (col..m)
+            .max_by(|&r1, &r2| a[r1][col].abs().total_cmp(&a[r2][col].abs()))
+            .expect("range isn't empty");
+        if a[pivot][col] == 0.0 {
+            return None;
+        }
+        a.swap(col, pivot);
+        let (upper, lower) = a.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in lower {
+            let factor = row[col] / pivot_row[col];
+            for (r, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *r -= factor * p;
+            }
+        }
+    }
+    let mut gamma = vec![0.0; m];
+    for (i, row) in a.iter().enumerate().rev() {
+        let sum: f64 = (i + 1..m).map(|k| row[k] * gamma[k]).sum();
+        gamma[i] = (row[m] - sum) / row[i];
+    }
+
+    if gamma.iter().all(|g| g.is_finite()) {
+        Some(gamma)
+    } else {
+        None
+    }
+}
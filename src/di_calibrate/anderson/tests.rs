@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+
+use super::*;
+
+/// A contractive, linear fixed-point map with the solution (1, 2i, -3).
+fn g(x: &[c64]) -> Vec<c64> {
+    let a = [
+        [c64::new(0.6, 0.1), c64::new(0.2, 0.0), c64::new(0.0, 0.1)],
+        [c64::new(0.1, 0.0), c64::new(0.7, -0.1), c64::new(0.1, 0.0)],
+        [c64::new(0.0, -0.1), c64::new(0.1, 0.1), c64::new(0.5, 0.0)],
+    ];
+    let solution = [c64::new(1.0, 0.0), c64::new(0.0, 2.0), c64::new(-3.0, 0.0)];
+    (0..3)
+        .map(|i| solution[i] + (0..3).map(|j| a[i][j] * (x[j] - solution[j])).sum::<c64>())
+        .collect()
+}
+
+/// Iterate until the update is smaller than `threshold`, returning the
+/// solution and the number of iterations needed.
+fn iterate(mut mixer: AndersonMixer, threshold: f64) -> (Vec<c64>, usize) {
+    let mut x = vec![c64::default(); 3];
+    for i in 1..=1000 {
+        let g = g(&x);
+        let diff: f64 = g.iter().zip(&x).map(|(g, x)| (g - x).norm_sqr()).sum();
+        if diff < threshold {
+            return (g, i);
+        }
+        x = mixer.next(&x, &g);
+    }
+    panic!("didn't converge");
+}
+
+#[test]
+fn test_anderson_converges_faster() {
+    let (simple, simple_iterations) = iterate(AndersonMixer::new(0), 1e-20);
+    let (anderson, anderson_iterations) = iterate(AndersonMixer::new(ANDERSON_DEPTH), 1e-20);
+    let expected = [c64::new(1.0, 0.0), c64::new(0.0, 2.0), c64::new(-3.0, 0.0)];
+    for (s, (a, e)) in simple.iter().zip(anderson.iter().zip(expected)) {
+        assert_abs_diff_eq!(s.re, e.re, epsilon = 1e-9);
+        assert_abs_diff_eq!(s.im, e.im, epsilon = 1e-9);
+        assert_abs_diff_eq!(a.re, e.re, epsilon = 1e-9);
+        assert_abs_diff_eq!(a.im, e.im, epsilon = 1e-9);
+    }
+    // Anderson acceleration should need far fewer iterations than simple
+    // iteration.
+    assert!(
+        anderson_iterations < simple_iterations / 2,
+        "{anderson_iterations} vs. {simple_iterations}"
+    );
+}
+
+#[test]
+fn test_anderson_without_history() {
+    let mut mixer = AndersonMixer::new(ANDERSON_DEPTH);
+    let x = [c64::new(1.0, 1.0), c64::new(2.0, 0.0)];
+    let h = [c64::new(3.0, -1.0), c64::new(2.0, 4.0)];
+    let next = mixer.next(&x, &h);
+    assert_eq!(next, h);
+
+    // After resetting the history, the same thing happens.
+    let next = mixer.next(&h, &x);
+    assert_ne!(next, x);
+    mixer.reset();
+    let next = mixer.next(&h, &x);
+    assert_eq!(next, x);
+}
+
+#[test]
+fn test_solve_least_squares() {
+    // f is exactly 2 * df0 - 0.5 * df1.
+    let df0 = vec![c64::new(1.0, 0.0), c64::new(0.0, 1.0), c64::new(1.0, 1.0)];
+    let df1 = vec![c64::new(0.0, 0.0), c64::new(2.0, 0.0), c64::new(1.0, -1.0)];
+    let f = df0
+        .iter()
+        .zip(&df1)
+        .map(|(a, b)| a * 2.0 - b * 0.5)
+        .collect::<Vec<_>>();
+    let gamma = solve_least_squares(&VecDeque::from([df0, df1]), &f).unwrap();
+    assert_abs_diff_eq!(gamma[0], 2.0, epsilon = 1e-8);
+    assert_abs_diff_eq!(gamma[1], -0.5, epsilon = 1e-8);
+
+    assert!(solve_least_squares(&VecDeque::new(), &f).is_none());
+    assert!(solve_least_squares(&VecDeque::from([vec![c64::default(); 3]]), &f).is_none());
+}
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to weight baselines during calibration.
+//!
+//! Baselines outside of the UVW cutoffs are normally excluded from calibration
+//! entirely. Instead, they can be smoothly down-weighted with Gaussian tapers,
+//! which avoids the ringing caused by sharp edges in the uv plane. Baselines
+//! may also be weighted by the inverse of the density of baselines in the uv
+//! plane, and/or with arbitrary weights read from a file. All of these weights
+//! are multiplied together.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use marlu::UVW;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
+
+lazy_static::lazy_static! {
+    pub(crate) static ref BASELINE_WEIGHTINGS_COMMA_SEPARATED: String = BaselineWeighting::iter().join(", ");
+}
+
+/// How baselines are weighted according to their positions in the uv plane.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub(crate) enum BaselineWeighting {
+    /// All baselines are weighted equally.
+    #[default]
+    #[strum(serialize = "natural")]
+    Natural,
+
+    /// Baselines are weighted by the inverse of the number of baselines in
+    /// the same uv cell, such that densely-sampled parts of the uv plane (e.g.
+    /// short baselines) don't dominate calibration.
+    #[strum(serialize = "inverse-density")]
+    InverseDensity,
+}
+
+/// Get the weight of a baseline from its UVW length and the UVW cutoffs (all
+/// in the same units). Baselines within the cutoffs have a weight of 1. If a
+/// taper is positive, baselines beyond the corresponding cutoff are
+/// down-weighted by a Gaussian with the taper as its standard deviation;
+/// otherwise, these baselines have a weight of 0.
+pub(crate) fn get_uvw_cutoff_weight(
+    uvw_length: f64,
+    uvw_min: f64,
+    uvw_max: f64,
+    uvw_min_taper: f64,
+    uvw_max_taper: f64,
+) -> f64 {
+    let taper = |distance: f64, width: f64| {
+        if width > 0.0 {
+            (-0.5 * (distance / width).powi(2)).exp()
+        } else {
+            0.0
+        }
+    };
+
+    if uvw_length < uvw_min {
+        taper(uvw_min - uvw_length, uvw_min_taper)
+    } else if uvw_length > uvw_max {
+        taper(uvw_length - uvw_max, uvw_max_taper)
+    } else {
+        1.0
+    }
+}
+
+/// Get weights for baselines that are inversely proportional to the number of
+/// baselines in the same (square) cell of the uv plane. `cell_size` has the
+/// same units as the `uvws`. Baselines that have non-positive `weights` (i.e.
+/// they aren't used) are not counted, and their density weights are 0. The
+/// weights are normalised such that the largest is 1.
+pub(crate) fn get_inverse_density_weights(
+    uvws: &[UVW],
+    weights: &[f64],
+    cell_size: f64,
+) -> Vec<f64> {
+    assert_eq!(uvws.len(), weights.len());
+
+    // A baseline and its conjugate sample the same part of the uv plane.
+    let get_cell = |uvw: &UVW| {
+        let (u, v) = if uvw.u < 0.0 {
+            (-uvw.u, -uvw.v)
+        } else {
+            (uvw.u, uvw.v)
+        };
+        (
+            (u / cell_size).floor() as i64,
+            (v / cell_size).floor() as i64,
+        )
+    };
+
+    let mut counts: HashMap<(i64, i64), usize> = HashMap::new();
+    for (uvw, &weight) in uvws.iter().zip(weights) {
+        if weight > 0.0 {
+            *counts.entry(get_cell(uvw)).or_default() += 1;
+        }
+    }
+    let min_count = counts.values().copied().min().unwrap_or(1);
+
+    uvws.iter()
+        .zip(weights)
+        .map(|(uvw, &weight)| {
+            if weight > 0.0 {
+                min_count as f64 / counts[&get_cell(uvw)] as f64
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+/// Read baseline weights from a text file. The file must contain one weight per
+/// line for every baseline (including those of flagged tiles) in the order
+/// (0, 1), (0, 2), ..., (1, 2), ...; empty lines and those starting with '#'
+/// are ignored. The weights of the baselines of unflagged tiles are returned.
+pub(crate) fn read_baseline_weights(
+    file: &Path,
+    total_num_tiles: usize,
+    flagged_tiles: &HashSet<usize>,
+) -> Result<Vec<f64>, BaselineWeightsFileError> {
+    let f = BufReader::new(File::open(file)?);
+    let mut all_weights = vec![];
+    for (i_line, line) in f.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let weight: f64 = line.parse().map_err(|_| BaselineWeightsFileError::Parse {
+            file: file.to_path_buf(),
+            line_num: i_line + 1,
+            line: line.to_string(),
+        })?;
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(BaselineWeightsFileError::BadWeight {
+                file: file.to_path_buf(),
+                line_num: i_line + 1,
+                weight,
+            });
+        }
+        all_weights.push(weight);
+    }
+
+    let total_num_baselines = (total_num_tiles * (total_num_tiles - 1)) / 2;
+    if all_weights.len() != total_num_baselines {
+        return Err(BaselineWeightsFileError::Count {
+            file: file.to_path_buf(),
+            expected: total_num_baselines,
+            got: all_weights.len(),
+        });
+    }
+
+    let mut weights = Vec::with_capacity(total_num_baselines);
+    let mut i_baseline = 0;
+    for i_tile1 in 0..total_num_tiles {
+        for i_tile2 in i_tile1 + 1..total_num_tiles {
+            if !flagged_tiles.contains(&i_tile1) && !flagged_tiles.contains(&i_tile2) {
+                weights.push(all_weights[i_baseline]);
+            }
+            i_baseline += 1;
+        }
+    }
+    Ok(weights)
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum BaselineWeightsFileError {
+    #[error("Couldn't parse line {line_num} of baseline weights file '{}' as a number: '{line}'", file.display())]
+    Parse {
+        file: PathBuf,
+        line_num: usize,
+        line: String,
+    },
+
+    #[error("Line {line_num} of baseline weights file '{}' has a bad weight ({weight}); weights must be finite and non-negative", file.display())]
+    BadWeight {
+        file: PathBuf,
+        line_num: usize,
+        weight: f64,
+    },
+
+    #[error("Baseline weights file '{}' has {got} weights, but {expected} were expected (one for every baseline)", file.display())]
+    Count {
+        file: PathBuf,
+        expected: usize,
+        got: usize,
+    },
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
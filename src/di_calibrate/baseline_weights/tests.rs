@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Write;
+
+use approx::assert_abs_diff_eq;
+
+use super::*;
+
+#[test]
+fn test_uvw_cutoff_weight_hard() {
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(10.0, 20.0, 100.0, 0.0, 0.0), 0.0);
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(20.0, 20.0, 100.0, 0.0, 0.0), 1.0);
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(50.0, 20.0, 100.0, 0.0, 0.0), 1.0);
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(100.0, 20.0, 100.0, 0.0, 0.0), 1.0);
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(101.0, 20.0, 100.0, 0.0, 0.0), 0.0);
+}
+
+#[test]
+fn test_uvw_cutoff_weight_taper() {
+    // One standard deviation below the minimum.
+    assert_abs_diff_eq!(
+        get_uvw_cutoff_weight(15.0, 20.0, 100.0, 5.0, 0.0),
+        (-0.5_f64).exp()
+    );
+    // Two standard deviations above the maximum.
+    assert_abs_diff_eq!(
+        get_uvw_cutoff_weight(120.0, 20.0, 100.0, 0.0, 10.0),
+        (-2.0_f64).exp()
+    );
+    // Without a taper at this end, the cutoff is hard.
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(120.0, 20.0, 100.0, 5.0, 0.0), 0.0);
+    // Baselines within the cutoffs are unaffected.
+    assert_abs_diff_eq!(get_uvw_cutoff_weight(50.0, 20.0, 100.0, 5.0, 10.0), 1.0);
+    // The taper is continuous at the cutoff.
+    assert_abs_diff_eq!(
+        get_uvw_cutoff_weight(20.0 - 1e-9, 20.0, 100.0, 5.0, 10.0),
+        1.0,
+        epsilon = 1e-9
+    );
+}
+
+#[test]
+fn test_inverse_density_weights() {
+    let uvw = |u, v| UVW { u, v, w: 0.0 };
+    let uvws = [
+        // Three baselines in the same cell (one is conjugated).
+        uvw(1.0, 1.0),
+        uvw(2.0, 3.0),
+        uvw(-4.0, -2.0),
+        // One baseline by itself.
+        uvw(50.0, 10.0),
+        // Two baselines in the same cell, but one isn't used.
+        uvw(20.0, -26.0),
+        uvw(21.0, -27.0),
+    ];
+    let weights = [1.0, 1.0, 1.0, 1.0, 0.5, 0.0];
+    let density_weights = get_inverse_density_weights(&uvws, &weights, 5.0);
+    assert_abs_diff_eq!(
+        density_weights.as_slice(),
+        [1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 1.0, 1.0, 0.0].as_slice()
+    );
+}
+
+#[test]
+fn test_read_baseline_weights() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    // 4 tiles -> 6 baselines.
+    writeln!(file, "# Baseline weights").unwrap();
+    for w in [0.1, 0.2, 0.3, 0.4, 0.5] {
+        writeln!(file, "{w}").unwrap();
+    }
+    writeln!(file).unwrap();
+    writeln!(file, "0.6").unwrap();
+    file.flush().unwrap();
+
+    let weights = read_baseline_weights(file.path(), 4, &HashSet::new()).unwrap();
+    assert_abs_diff_eq!(
+        weights.as_slice(),
+        [0.1, 0.2, 0.3, 0.4, 0.5, 0.6].as_slice()
+    );
+
+    // With tile 1 flagged, baselines (0, 1), (1, 2) and (1, 3) aren't used.
+    let weights = read_baseline_weights(file.path(), 4, &HashSet::from([1])).unwrap();
+    assert_abs_diff_eq!(weights.as_slice(), [0.2, 0.3, 0.6].as_slice());
+
+    let result = read_baseline_weights(file.path(), 5, &HashSet::new());
+    assert!(matches!(
+        result,
+        Err(BaselineWeightsFileError::Count {
+            expected: 10,
+            got: 6,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_read_baseline_weights_bad_lines() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(file, "1.0\nfoo\n1.0").unwrap();
+    file.flush().unwrap();
+    let result = read_baseline_weights(file.path(), 3, &HashSet::new());
+    assert!(matches!(
+        result,
+        Err(BaselineWeightsFileError::Parse { line_num: 2, .. })
+    ));
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    writeln!(file, "1.0\n-1.0\n1.0").unwrap();
+    file.flush().unwrap();
+    let result = read_baseline_weights(file.path(), 3, &HashSet::new());
+    assert!(matches!(
+        result,
+        Err(BaselineWeightsFileError::BadWeight { line_num: 2, .. })
+    ));
+}
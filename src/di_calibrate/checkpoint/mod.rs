@@ -0,0 +1,475 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Checkpointing of calibration, so that interrupted jobs can be resumed.
+//!
+//! Generating sky-model visibilities for a big source list can take hours, so
+//! a calibration job that is killed (e.g. pre-empted on a cluster) shouldn't
+//! have to start again from scratch. The model visibilities of each timestep
+//! are written to a checkpoint directory as soon as they are generated and,
+//! when calibrating in chunks of chanblocks, so are the solutions of each
+//! chunk. A resumed job reads these instead of making them again.
+//!
+//! The checkpoint records fingerprints of everything that the model
+//! visibilities and solutions depend on. A checkpoint is only resumed from if
+//! the fingerprints match those of the resumed job.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    fs::{File, OpenOptions},
+    hash::Hasher,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, info};
+use marlu::Jones;
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::IncompleteSolutions;
+use crate::{
+    averaging::Chanblock,
+    cli::Warn,
+    params::DiCalParams,
+    solutions::{ResidualStat, ResidualStats},
+};
+
+/// The name of the file describing a checkpoint.
+const CHECKPOINT_INFO_FILENAME: &str = "checkpoint.json";
+
+/// The prefix of model visibility checkpoint files.
+const MODEL_PREFIX: &str = "model_";
+
+/// The prefix of solutions checkpoint files.
+const SOLUTIONS_PREFIX: &str = "solutions_";
+
+/// The solutions of a chunk of chanblocks, the precisions that they converged
+/// with and their residual statistics.
+pub(crate) type ChunkSolutions<'a> = (IncompleteSolutions<'a>, Array2<f64>, ResidualStats);
+
+/// What's written to [`CHECKPOINT_INFO_FILENAME`].
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CheckpointInfo {
+    /// The version of hyperdrive that wrote the checkpoint.
+    version: String,
+
+    /// The fingerprint of everything that the model visibilities depend on.
+    model_fingerprint: String,
+
+    /// The fingerprint of everything that the calibration solutions depend on
+    /// (including the model visibilities).
+    calibration_fingerprint: String,
+}
+
+/// A directory containing checkpointed model visibilities and solutions.
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    /// Prepare a checkpoint in `dir`. If `resume` is true and `dir` already
+    /// contains a checkpoint with the same model fingerprint, its model
+    /// visibilities are kept; its solutions are also kept if the calibration
+    /// fingerprint is the same. Otherwise, any existing checkpoint is
+    /// discarded.
+    pub(crate) fn new(
+        dir: &Path,
+        model_fingerprint: u64,
+        calibration_fingerprint: u64,
+        resume: bool,
+    ) -> Result<Checkpoint, CheckpointError> {
+        std::fs::create_dir_all(dir)?;
+        let info = CheckpointInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            model_fingerprint: format!("{model_fingerprint:016x}"),
+            calibration_fingerprint: format!("{calibration_fingerprint:016x}"),
+        };
+        let checkpoint = Checkpoint {
+            dir: dir.to_path_buf(),
+        };
+
+        let info_file = dir.join(CHECKPOINT_INFO_FILENAME);
+        let existing_info = if info_file.exists() {
+            let existing_info: CheckpointInfo =
+                serde_json::from_reader(BufReader::new(File::open(&info_file)?)).map_err(|e| {
+                    CheckpointError::Info {
+                        file: info_file.clone(),
+                        err: e.to_string(),
+                    }
+                })?;
+            Some(existing_info)
+        } else {
+            None
+        };
+
+        match (resume, existing_info) {
+            (false, _) => remove_files(dir, |_| true)?,
+
+            (true, None) => info!(
+                "No checkpoint found in {}; starting from scratch",
+                dir.display()
+            ),
+
+            (true, Some(existing_info)) => {
+                if existing_info.version != info.version {
+                    return Err(CheckpointError::Mismatch {
+                        dir: dir.to_path_buf(),
+                        reason: format!(
+                            "it was made by hyperdrive {}, not {}",
+                            existing_info.version, info.version
+                        ),
+                    });
+                }
+                if existing_info.model_fingerprint != info.model_fingerprint {
+                    return Err(CheckpointError::Mismatch {
+                        dir: dir.to_path_buf(),
+                        reason:
+                            "the input data, sky model, beam, array position or DUT1 are different"
+                                .to_string(),
+                    });
+                }
+                if existing_info.calibration_fingerprint != info.calibration_fingerprint {
+                    "The calibration settings are different to those of the checkpoint; discarding its solutions".warn();
+                    remove_files(dir, |name| name.starts_with(SOLUTIONS_PREFIX))?;
+                }
+                info!("Resuming from the checkpoint in {}", dir.display());
+            }
+        }
+
+        let mut f = BufWriter::new(File::create(&info_file)?);
+        serde_json::to_writer_pretty(&mut f, &info).expect("CheckpointInfo can be serialised");
+        f.flush()?;
+
+        Ok(checkpoint)
+    }
+
+    /// Remove the checkpoint in `dir`, e.g. after calibration has finished.
+    /// The directory is also removed if nothing else is in it.
+    pub(crate) fn remove(dir: &Path) -> Result<(), CheckpointError> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        remove_files(dir, |_| true)?;
+        // Don't care if the directory can't be removed.
+        let _ = std::fs::remove_dir(dir);
+        Ok(())
+    }
+
+    /// Get the name of a checkpoint file for the chanblocks in
+    /// `chanblock_range` (all chanblocks if this is `None`).
+    fn get_filename(&self, prefix: &str, chanblock_range: Option<&Range<usize>>) -> PathBuf {
+        self.dir.join(match chanblock_range {
+            Some(r) => format!("{prefix}chanblocks{}-{}.bin", r.start, r.end),
+            None => format!("{prefix}all.bin"),
+        })
+    }
+
+    /// Open the model visibilities checkpoint of an observation (`i_obs` is 0
+    /// for the main observation, and 1 onwards for joint observations). Each
+    /// timestep has `num_chanblocks` * `num_baselines` visibilities.
+    pub(crate) fn open_model(
+        &self,
+        i_obs: usize,
+        chanblock_range: Option<&Range<usize>>,
+        num_chanblocks: usize,
+        num_baselines: usize,
+    ) -> Result<ModelCheckpoint, CheckpointError> {
+        let file = self.get_filename(&format!("{MODEL_PREFIX}obs{i_obs}_"), chanblock_range);
+        ModelCheckpoint::open(&file, (num_chanblocks, num_baselines))
+    }
+
+    /// Write the solutions of a chunk of chanblocks, along with the precisions
+    /// that they converged with and their residual statistics.
+    pub(crate) fn write_solutions(
+        &self,
+        chanblock_range: &Range<usize>,
+        sols: &IncompleteSolutions,
+        precisions: ArrayView2<f64>,
+        residual_stats: &ResidualStats,
+    ) -> Result<(), CheckpointError> {
+        let file = self.get_filename(SOLUTIONS_PREFIX, Some(chanblock_range));
+        debug!("Writing checkpoint {}", file.display());
+        // Write to a temporary file first, so that an interrupted write doesn't
+        // leave behind a corrupted checkpoint.
+        let tmp_file = file.with_extension("bin.tmp");
+        let mut f = BufWriter::new(File::create(&tmp_file)?);
+
+        write_shape(&mut f, sols.di_jones.shape())?;
+        for j in sols.di_jones.iter() {
+            for float in j.to_float_array() {
+                f.write_f64::<LittleEndian>(float)?;
+            }
+        }
+
+        write_shape(&mut f, precisions.shape())?;
+        for &p in precisions.iter() {
+            f.write_f64::<LittleEndian>(p)?;
+        }
+
+        for stats in [
+            residual_stats.tiles.view().into_dyn(),
+            residual_stats.baselines.view().into_dyn(),
+        ] {
+            write_shape(&mut f, stats.shape())?;
+            for stat in stats.iter() {
+                f.write_f64::<LittleEndian>(stat.rms)?;
+                f.write_f64::<LittleEndian>(stat.chi_squared)?;
+                f.write_u64::<LittleEndian>(stat.num_vis as u64)?;
+            }
+        }
+
+        f.flush()?;
+        drop(f);
+        std::fs::rename(&tmp_file, &file)?;
+        Ok(())
+    }
+
+    /// Read the checkpointed solutions of a chunk of chanblocks, if there are
+    /// any. The returned items are the same as those from calibrating the
+    /// chunk.
+    pub(crate) fn read_solutions<'a>(
+        &self,
+        chanblock_range: &Range<usize>,
+        params: &'a DiCalParams,
+        chanblocks: &'a [Chanblock],
+    ) -> Result<Option<ChunkSolutions<'a>>, CheckpointError> {
+        let file = self.get_filename(SOLUTIONS_PREFIX, Some(chanblock_range));
+        if !file.exists() {
+            return Ok(None);
+        }
+        debug!("Reading checkpoint {}", file.display());
+        let bad_file = || CheckpointError::BadFile(file.clone());
+        let mut f = BufReader::new(File::open(&file)?);
+
+        let shape = read_shape::<3>(&mut f)?;
+        if shape[0] != params.cal_timeblocks.len()
+            || shape[1] != params.input_vis_params.get_num_unflagged_tiles()
+            || shape[2] != chanblocks.len()
+        {
+            return Err(bad_file());
+        }
+        let mut floats = vec![0.0; shape.iter().product::<usize>() * 8];
+        f.read_f64_into::<LittleEndian>(&mut floats)?;
+        let di_jones = floats
+            .chunks_exact(8)
+            .map(|c| Jones::from([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+            .collect::<Vec<_>>();
+        let di_jones = Array3::from_shape_vec(shape, di_jones).map_err(|_| bad_file())?;
+
+        let shape = read_shape::<2>(&mut f)?;
+        let mut precisions = Array2::zeros(shape);
+        f.read_f64_into::<LittleEndian>(precisions.as_slice_mut().expect("is contiguous"))?;
+
+        let shape = read_shape::<3>(&mut f)?;
+        let tiles = Array3::from_shape_vec(shape, read_stats(&mut f, shape.iter().product())?)
+            .map_err(|_| bad_file())?;
+        let shape = read_shape::<2>(&mut f)?;
+        let baselines = Array2::from_shape_vec(shape, read_stats(&mut f, shape.iter().product())?)
+            .map_err(|_| bad_file())?;
+
+        let sols = IncompleteSolutions {
+            di_jones,
+            timeblocks: &params.cal_timeblocks,
+            chanblocks,
+            max_iterations: params.max_iterations,
+            stop_threshold: params.stop_threshold,
+            min_threshold: params.min_threshold,
+            solve_mode: params.solve_mode,
+            solver: params.solver,
+        };
+        Ok(Some((sols, precisions, ResidualStats { tiles, baselines })))
+    }
+}
+
+/// The checkpointed model visibilities of an observation. Timesteps are
+/// appended to the file as they are modelled; the timesteps already in the
+/// file are read back in order.
+pub(crate) struct ModelCheckpoint {
+    file: File,
+
+    /// The shape of each timestep's visibilities (num_chanblocks,
+    /// num_baselines).
+    shape: (usize, usize),
+
+    /// The number of timesteps in the checkpoint that haven't been read yet.
+    num_unread: usize,
+}
+
+impl ModelCheckpoint {
+    fn open(file: &Path, shape: (usize, usize)) -> Result<ModelCheckpoint, CheckpointError> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file)?;
+        // Only complete timesteps can be used; anything else was being written
+        // when the job was interrupted.
+        let bytes_per_timestep = (shape.0 * shape.1 * std::mem::size_of::<Jones<f32>>()) as u64;
+        let num_timesteps = if bytes_per_timestep == 0 {
+            0
+        } else {
+            f.metadata()?.len() / bytes_per_timestep
+        };
+        f.set_len(num_timesteps * bytes_per_timestep)?;
+        f.seek(SeekFrom::Start(0))?;
+        if num_timesteps > 0 {
+            info!(
+                "Using {num_timesteps} timesteps of checkpointed model visibilities from {}",
+                file.display()
+            );
+        }
+
+        Ok(ModelCheckpoint {
+            file: f,
+            shape,
+            num_unread: num_timesteps as usize,
+        })
+    }
+
+    /// Read the next checkpointed timestep into `vis_fb`. If all of the
+    /// checkpointed timesteps have been read, `false` is returned, and the
+    /// timestep must be modelled and written with
+    /// [`ModelCheckpoint::write_timestep`].
+    pub(crate) fn read_timestep(
+        &mut self,
+        mut vis_fb: ArrayViewMut2<Jones<f32>>,
+    ) -> Result<bool, CheckpointError> {
+        assert_eq!(vis_fb.dim(), self.shape);
+        if self.num_unread == 0 {
+            return Ok(false);
+        }
+        let mut bytes = vec![0; vis_fb.len() * std::mem::size_of::<Jones<f32>>()];
+        self.file.read_exact(&mut bytes)?;
+        let mut floats = vec![0.0; vis_fb.len() * 8];
+        LittleEndian::read_f32_into(&bytes, &mut floats);
+        vis_fb
+            .iter_mut()
+            .zip(floats.chunks_exact(8))
+            .for_each(|(j, c)| *j = Jones::from([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]));
+        self.num_unread -= 1;
+        Ok(true)
+    }
+
+    /// Append a timestep of model visibilities to the checkpoint.
+    pub(crate) fn write_timestep(
+        &mut self,
+        vis_fb: ArrayView2<Jones<f32>>,
+    ) -> Result<(), CheckpointError> {
+        assert_eq!(vis_fb.dim(), self.shape);
+        assert_eq!(
+            self.num_unread, 0,
+            "all checkpointed timesteps are read before writing"
+        );
+        let floats = vis_fb
+            .iter()
+            .flat_map(|j| j.to_float_array())
+            .collect::<Vec<_>>();
+        let mut bytes = vec![0; floats.len() * std::mem::size_of::<f32>()];
+        LittleEndian::write_f32_into(&floats, &mut bytes);
+        self.file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+/// Remove the checkpoint files in `dir` whose names satisfy `predicate`. Files
+/// that don't belong to checkpoints are left alone.
+fn remove_files(dir: &Path, predicate: impl Fn(&str) -> bool) -> Result<(), CheckpointError> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => continue,
+        };
+        let is_checkpoint_file = name == CHECKPOINT_INFO_FILENAME
+            || ((name.starts_with(MODEL_PREFIX) || name.starts_with(SOLUTIONS_PREFIX))
+                && (name.ends_with(".bin") || name.ends_with(".bin.tmp")));
+        if is_checkpoint_file && predicate(name) {
+            debug!("Removing checkpoint file {}", entry.path().display());
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_shape(f: &mut impl Write, shape: &[usize]) -> Result<(), std::io::Error> {
+    for &dim in shape {
+        f.write_u64::<LittleEndian>(dim as u64)?;
+    }
+    Ok(())
+}
+
+fn read_shape<const N: usize>(f: &mut impl Read) -> Result<[usize; N], std::io::Error> {
+    let mut shape = [0; N];
+    for dim in shape.iter_mut() {
+        *dim = f.read_u64::<LittleEndian>()? as usize;
+    }
+    Ok(shape)
+}
+
+fn read_stats(f: &mut impl Read, n: usize) -> Result<Vec<ResidualStat>, std::io::Error> {
+    (0..n)
+        .map(|_| {
+            Ok(ResidualStat {
+                rms: f.read_f64::<LittleEndian>()?,
+                chi_squared: f.read_f64::<LittleEndian>()?,
+                num_vis: f.read_u64::<LittleEndian>()? as usize,
+            })
+        })
+        .collect()
+}
+
+/// A hasher whose output is stable, unlike that of
+/// [`std::collections::hash_map::DefaultHasher`], such that fingerprints can be
+/// compared between runs. This is the 64-bit FNV-1a hash.
+pub(crate) struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub(crate) fn new() -> Fingerprint {
+        Fingerprint(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Add floats to the fingerprint (floats don't implement
+    /// [`std::hash::Hash`]).
+    pub(crate) fn write_f64s(&mut self, floats: impl IntoIterator<Item = f64>) {
+        for float in floats {
+            self.write_u64(float.to_bits());
+        }
+    }
+}
+
+impl Hasher for Fingerprint {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum CheckpointError {
+    #[error("Couldn't read checkpoint file '{}': {err}", file.display())]
+    Info { file: PathBuf, err: String },
+
+    #[error("Can't resume from the checkpoint in '{}', because {reason}. Remove the checkpoint or don't use --resume", dir.display())]
+    Mismatch { dir: PathBuf, reason: String },
+
+    #[error("Checkpoint file '{}' doesn't match this calibration; remove it to continue", .0.display())]
+    BadFile(PathBuf),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
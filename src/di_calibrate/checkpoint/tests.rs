@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::hash::Hash;
+
+use approx::assert_abs_diff_eq;
+
+use super::*;
+use crate::di_calibrate::tests::get_default_params;
+
+#[test]
+fn test_fingerprint_is_fnv1a() {
+    let mut f = Fingerprint::new();
+    f.write(b"a");
+    assert_eq!(f.finish(), 0xaf63dc4c8601ec8c);
+
+    // Hashing the same things gives the same fingerprint.
+    let get_fingerprint = |floats: &[f64]| {
+        let mut f = Fingerprint::new();
+        "srclist".hash(&mut f);
+        f.write_f64s(floats.iter().copied());
+        f.finish()
+    };
+    assert_eq!(get_fingerprint(&[1.0, 2.0]), get_fingerprint(&[1.0, 2.0]));
+    assert_ne!(get_fingerprint(&[1.0, 2.0]), get_fingerprint(&[1.0, 2.5]));
+}
+
+#[test]
+fn test_model_checkpoint_resumes_complete_timesteps() {
+    let dir = tempfile::tempdir().unwrap();
+    let shape = (2, 3);
+    let timesteps = (0..3)
+        .map(|i| {
+            Array2::from_shape_fn(shape, |(c, b)| {
+                Jones::identity() * (i * 10 + c * 3 + b) as f32
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let checkpoint = Checkpoint::new(dir.path(), 1, 2, false).unwrap();
+    let mut model = checkpoint.open_model(0, None, shape.0, shape.1).unwrap();
+    for timestep in &timesteps[..2] {
+        let mut vis = Array2::zeros(shape);
+        assert!(!model.read_timestep(vis.view_mut()).unwrap());
+        model.write_timestep(timestep.view()).unwrap();
+    }
+    drop(model);
+    // Simulate a job being killed while writing the third timestep.
+    let file = dir.path().join("model_obs0_all.bin");
+    let mut f = OpenOptions::new().append(true).open(&file).unwrap();
+    f.write_all(&[1, 2, 3]).unwrap();
+    drop(f);
+
+    let checkpoint = Checkpoint::new(dir.path(), 1, 2, true).unwrap();
+    let mut model = checkpoint.open_model(0, None, shape.0, shape.1).unwrap();
+    for timestep in &timesteps[..2] {
+        let mut vis = Array2::zeros(shape);
+        assert!(model.read_timestep(vis.view_mut()).unwrap());
+        assert_abs_diff_eq!(vis, *timestep);
+    }
+    let mut vis = Array2::zeros(shape);
+    assert!(!model.read_timestep(vis.view_mut()).unwrap());
+    model.write_timestep(timesteps[2].view()).unwrap();
+    drop(model);
+    assert_eq!(
+        std::fs::metadata(&file).unwrap().len() as usize,
+        3 * shape.0 * shape.1 * std::mem::size_of::<Jones<f32>>()
+    );
+
+    // Without resuming, the checkpoint is discarded.
+    let checkpoint = Checkpoint::new(dir.path(), 1, 2, false).unwrap();
+    assert!(!file.exists());
+    let mut model = checkpoint.open_model(0, None, shape.0, shape.1).unwrap();
+    assert!(!model.read_timestep(vis.view_mut()).unwrap());
+}
+
+#[test]
+fn test_checkpoint_fingerprints() {
+    let dir = tempfile::tempdir().unwrap();
+    let model_file = dir.path().join("model_obs0_all.bin");
+    let sols_file = dir.path().join("solutions_chanblocks0-4.bin");
+    let other_file = dir.path().join("notes.txt");
+    Checkpoint::new(dir.path(), 1, 2, false).unwrap();
+    for file in [&model_file, &sols_file, &other_file] {
+        File::create(file).unwrap();
+    }
+
+    // A different model can't be resumed from.
+    let result = Checkpoint::new(dir.path(), 3, 2, true);
+    assert!(matches!(result, Err(CheckpointError::Mismatch { .. })));
+    assert!(model_file.exists());
+
+    // Different calibration settings discard only the solutions.
+    Checkpoint::new(dir.path(), 1, 4, true).unwrap();
+    assert!(model_file.exists());
+    assert!(!sols_file.exists());
+
+    // Removing the checkpoint leaves other files alone.
+    Checkpoint::remove(dir.path()).unwrap();
+    assert!(!model_file.exists());
+    assert!(!dir.path().join(CHECKPOINT_INFO_FILENAME).exists());
+    assert!(other_file.exists());
+}
+
+#[test]
+fn test_solutions_checkpoint_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let params = get_default_params();
+    let chanblocks = &params.input_vis_params.spw.chanblocks;
+    let num_tiles = params.input_vis_params.get_num_unflagged_tiles();
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let sols = IncompleteSolutions {
+        di_jones: Array3::from_shape_fn((1, num_tiles, chanblocks.len()), |(_, i, _)| {
+            Jones::identity() * (i + 1) as f64
+        }),
+        timeblocks: &params.cal_timeblocks,
+        chanblocks,
+        max_iterations: params.max_iterations,
+        stop_threshold: params.stop_threshold,
+        min_threshold: params.min_threshold,
+        solve_mode: params.solve_mode,
+        solver: params.solver,
+    };
+    let precisions = array![[1e-7]];
+    let residual_stats = ResidualStats {
+        tiles: Array3::from_elem(
+            (1, num_tiles, 1),
+            ResidualStat {
+                rms: 0.5,
+                chi_squared: 2.0,
+                num_vis: 127,
+            },
+        ),
+        baselines: Array2::from_elem((1, num_baselines), ResidualStat::default()),
+    };
+
+    let checkpoint = Checkpoint::new(dir.path(), 1, 2, false).unwrap();
+    let range = 0..1;
+    assert!(checkpoint
+        .read_solutions(&range, &params, chanblocks)
+        .unwrap()
+        .is_none());
+    checkpoint
+        .write_solutions(&range, &sols, precisions.view(), &residual_stats)
+        .unwrap();
+    let (sols2, precisions2, residual_stats2) = checkpoint
+        .read_solutions(&range, &params, chanblocks)
+        .unwrap()
+        .unwrap();
+    assert_abs_diff_eq!(sols2.di_jones, sols.di_jones);
+    assert_abs_diff_eq!(precisions2, precisions);
+    assert_eq!(residual_stats2.tiles, residual_stats.tiles);
+    // NaNs aren't equal to each other, so compare the bits.
+    assert!(residual_stats2
+        .baselines
+        .iter()
+        .zip(residual_stats.baselines.iter())
+        .all(|(a, b)| a.rms.to_bits() == b.rms.to_bits() && a.num_vis == b.num_vis));
+
+    // Solutions that don't match the calibration are rejected.
+    let other_chanblocks = vec![chanblocks[0].clone(), chanblocks[0].clone()];
+    let result = checkpoint.read_solutions(&range, &params, &other_chanblocks);
+    assert!(matches!(result, Err(CheckpointError::BadFile(_))));
+
+    // So are solutions with a different number of timeblocks.
+    let range = 1..2;
+    let sols = IncompleteSolutions {
+        di_jones: Array3::from_elem(
+            (params.cal_timeblocks.len() + 1, num_tiles, chanblocks.len()),
+            Jones::identity(),
+        ),
+        ..sols
+    };
+    checkpoint
+        .write_solutions(&range, &sols, precisions.view(), &residual_stats)
+        .unwrap();
+    let result = checkpoint.read_solutions(&range, &params, chanblocks);
+    assert!(matches!(result, Err(CheckpointError::BadFile(_))));
+}
@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to handle joint calibration of multiple observations.
+//!
+//! The visibilities of all observations are stacked along the time axis and
+//! calibrated together as a single timeblock, giving one common set of
+//! solutions. Each observation may also have its own per-tile phase offsets
+//! (e.g. because the ionosphere is different); these are constant over
+//! frequency and are solved for by comparing an observation's data with its
+//! model corrupted by the common solutions. The data are then corrected for
+//! the offsets before the common solutions are solved for again.
+
+#[cfg(test)]
+mod tests;
+
+use marlu::Jones;
+use ndarray::prelude::*;
+use rayon::prelude::*;
+
+use super::{calibrate, CalibrationResult, RobustWeighting, SolveMode, Solver};
+use crate::context::Polarisations;
+
+/// The number of times to alternate between solving for the common solutions
+/// and the phase offsets of each observation.
+pub(crate) const JOINT_PHASE_OFFSET_ROUNDS: usize = 3;
+
+/// Solve for the per-tile phase offsets of a single observation, relative to
+/// common calibration solutions. The visibilities are expected to have had
+/// their weights applied, as is done for calibration. `di_jones` are the
+/// (incomplete) common solutions of all unflagged tiles and chanblocks; the
+/// first dimension is tile, the second is chanblock.
+///
+/// The returned offsets are diagonal Jones matrices with unit amplitudes (one
+/// per unflagged tile); tiles whose offsets couldn't be determined are NaN.
+pub(crate) fn get_phase_offsets(
+    vis_data_tfb: ArrayView3<Jones<f32>>,
+    vis_model_tfb: ArrayView3<Jones<f32>>,
+    di_jones: ArrayView2<Jones<f64>>,
+    max_iterations: u32,
+    stop_threshold: f64,
+    min_threshold: f64,
+    pols: Polarisations,
+) -> (Array1<Jones<f64>>, CalibrationResult) {
+    assert_eq!(vis_data_tfb.dim(), vis_model_tfb.dim());
+    let num_unflagged_tiles = di_jones.len_of(Axis(0));
+    assert_eq!(di_jones.len_of(Axis(1)), vis_data_tfb.len_of(Axis(1)));
+
+    // Corrupt the model with the common solutions; the offsets are whatever is
+    // left between this and the data.
+    let mut corrupted_model_tfb = vis_model_tfb.to_owned();
+    corrupted_model_tfb
+        .axis_iter_mut(Axis(1))
+        .into_par_iter()
+        .zip(di_jones.axis_iter(Axis(1)))
+        .for_each(|(mut model_tb, di_jones)| {
+            for mut model_b in model_tb.outer_iter_mut() {
+                let mut i_tile1 = 0;
+                let mut i_tile2 = 0;
+                for model in model_b.iter_mut() {
+                    i_tile2 += 1;
+                    if i_tile2 == num_unflagged_tiles {
+                        i_tile1 += 1;
+                        i_tile2 = i_tile1 + 1;
+                    }
+
+                    let (j1, j2) = (di_jones[i_tile1], di_jones[i_tile2]);
+                    *model = if j1.any_nan() || j2.any_nan() {
+                        Jones::default()
+                    } else {
+                        Jones::from(j1 * Jones::<f64>::from(*model) * j2.h())
+                    };
+                }
+            }
+        });
+
+    let mut offsets = Array1::from_elem(num_unflagged_tiles, Jones::identity());
+    let result = calibrate(
+        vis_data_tfb,
+        corrupted_model_tfb.view(),
+        offsets.view_mut(),
+        max_iterations,
+        stop_threshold,
+        min_threshold,
+        SolveMode::PhaseOnly,
+        RobustWeighting::None,
+        Solver::default(),
+        pols,
+    );
+    (offsets, result)
+}
+
+/// Remove phase offsets (as returned by [`get_phase_offsets`]) from an
+/// observation's visibilities. Baselines with a tile without an offset are
+/// left alone.
+pub(crate) fn apply_phase_offsets(
+    mut vis_data_tfb: ArrayViewMut3<Jones<f32>>,
+    offsets: ArrayView1<Jones<f64>>,
+) {
+    let num_unflagged_tiles = offsets.len();
+    vis_data_tfb
+        .outer_iter_mut()
+        .into_par_iter()
+        .for_each(|mut vis_data_fb| {
+            for mut vis_data_b in vis_data_fb.outer_iter_mut() {
+                let mut i_tile1 = 0;
+                let mut i_tile2 = 0;
+                for vis_data in vis_data_b.iter_mut() {
+                    i_tile2 += 1;
+                    if i_tile2 == num_unflagged_tiles {
+                        i_tile1 += 1;
+                        i_tile2 = i_tile1 + 1;
+                    }
+
+                    let (p1, p2) = (offsets[i_tile1], offsets[i_tile2]);
+                    if p1.any_nan() || p2.any_nan() {
+                        continue;
+                    }
+                    // The offsets are unitary, so their inverses are their
+                    // conjugate transposes.
+                    *vis_data = Jones::from(p1.h() * Jones::<f64>::from(*vis_data) * p2);
+                }
+            }
+        });
+}
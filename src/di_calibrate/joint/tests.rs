@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+
+use super::*;
+
+fn diag(xx: c64, yy: c64) -> Jones<f64> {
+    Jones::from([xx, c64::default(), c64::default(), yy])
+}
+
+#[test]
+fn test_phase_offsets_are_removed() {
+    let num_tiles = 8;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let num_chanblocks = 4;
+
+    // Common solutions that vary over frequency, and phase offsets that don't.
+    let di_jones = Array2::from_shape_fn((num_tiles, num_chanblocks), |(i_tile, i_chan)| {
+        let x = i_tile as f64 + 0.3 * i_chan as f64;
+        diag(
+            c64::from_polar(1.0 + 0.1 * x.sin(), 0.2 * x.cos()),
+            c64::from_polar(1.0 - 0.1 * x.cos(), 0.3 * x.sin()),
+        )
+    });
+    let offsets: Vec<Jones<f64>> = (0..num_tiles)
+        .map(|i_tile| {
+            let x = i_tile as f64;
+            diag(
+                c64::from_polar(1.0, 0.5 * (1.3 * x).sin()),
+                c64::from_polar(1.0, -0.4 * (0.7 * x).cos()),
+            )
+        })
+        .collect();
+
+    let vis_model =
+        Array3::from_shape_fn((2, num_chanblocks, num_baselines), |(i_time, _, i_bl)| {
+            Jones::from(Jones::<f64>::identity() * (1.0 + 0.1 * (i_time + i_bl) as f64))
+        });
+    let mut vis_corrupted = Array3::from_elem(vis_model.dim(), Jones::default());
+    let mut vis_data = Array3::from_elem(vis_model.dim(), Jones::default());
+    for ((i_time, i_chan, i_bl), model) in vis_model.indexed_iter() {
+        let mut i_baseline = 0;
+        for i_tile1 in 0..num_tiles {
+            for i_tile2 in i_tile1 + 1..num_tiles {
+                if i_baseline == i_bl {
+                    let corrupted = di_jones[(i_tile1, i_chan)]
+                        * Jones::<f64>::from(*model)
+                        * di_jones[(i_tile2, i_chan)].h();
+                    vis_corrupted[(i_time, i_chan, i_bl)] = Jones::from(corrupted);
+                    vis_data[(i_time, i_chan, i_bl)] =
+                        Jones::from(offsets[i_tile1] * corrupted * offsets[i_tile2].h());
+                }
+                i_baseline += 1;
+            }
+        }
+    }
+
+    let (fitted_offsets, result) = get_phase_offsets(
+        vis_data.view(),
+        vis_model.view(),
+        di_jones.view(),
+        50,
+        1e-10,
+        1e-5,
+        Polarisations::default(),
+    );
+    assert!(result.converged);
+    assert_eq!(fitted_offsets.len(), num_tiles);
+    // The offsets have unit amplitudes.
+    for o in fitted_offsets.iter() {
+        assert_abs_diff_eq!(o[0].norm(), 1.0, epsilon = 1e-10);
+        assert_abs_diff_eq!(o[3].norm(), 1.0, epsilon = 1e-10);
+    }
+
+    // The overall phase of the offsets is arbitrary, but cancels when the
+    // offsets are removed from the data.
+    apply_phase_offsets(vis_data.view_mut(), fitted_offsets.view());
+    for (data, corrupted) in vis_data.iter().zip(vis_corrupted.iter()) {
+        assert_abs_diff_eq!(
+            Jones::<f64>::from(*data),
+            Jones::<f64>::from(*corrupted),
+            epsilon = 1e-4
+        );
+    }
+}
+
+#[test]
+fn test_apply_phase_offsets_ignores_nans() {
+    let num_tiles = 3;
+    let vis = Jones::from(Jones::<f64>::identity() * 2.0);
+    let mut vis_data = Array3::from_elem((1, 1, 3), vis);
+    let offsets = array![
+        diag(c64::new(0.0, 1.0), c64::new(0.0, 1.0)),
+        Jones::nan(),
+        Jones::identity(),
+    ];
+    assert_eq!(offsets.len(), num_tiles);
+    apply_phase_offsets(vis_data.view_mut(), offsets.view());
+    // Baseline 0-1 has a NaN offset and is untouched; baseline 0-2 has its
+    // phase removed; baseline 1-2 is untouched.
+    assert_eq!(vis_data[(0, 0, 0)], vis);
+    let expected = Jones::from(diag(c64::new(0.0, -2.0), c64::new(0.0, -2.0)));
+    assert_abs_diff_eq!(vis_data[(0, 0, 1)], expected);
+    assert_eq!(vis_data[(0, 0, 2)], vis);
+}
@@ -7,26 +7,172 @@
 //! This code borrows heavily from Torrance Hodgson's excellent Julia code at
 //! <https://github.com/torrance/MWAjl>
 
+mod anderson;
+pub(crate) mod baseline_weights;
+pub(crate) mod checkpoint;
+pub(crate) mod joint;
+pub(crate) mod redundant;
 #[cfg(test)]
 pub(crate) mod tests;
+pub(crate) mod xy_phase;
+
+use std::collections::HashSet;
 
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::Itertools;
-use log::info;
+use log::{debug, info};
 use marlu::{c64, math::num_tiles_from_num_cross_correlation_baselines, Jones};
 use ndarray::prelude::*;
 use rayon::prelude::*;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
 use vec1::Vec1;
 
+use self::{
+    anderson::{AndersonMixer, ANDERSON_DEPTH},
+    redundant::{calibrate_redundant, RedundantCalVis},
+};
 use crate::{
     averaging::{Chanblock, Timeblock},
     context::Polarisations,
-    math::average_epoch,
+    math::{average_epoch, robust_sigma},
     params::DiCalParams,
-    solutions::CalibrationSolutions,
+    solutions::{CalibrationSolutions, ResidualStat, ResidualStats},
     MODEL_DEVICE, PROGRESS_BARS,
 };
 
+lazy_static::lazy_static! {
+    pub(crate) static ref SOLVE_MODES_COMMA_SEPARATED: String = SolveMode::iter().join(", ");
+
+    pub(crate) static ref ROBUST_WEIGHTINGS_COMMA_SEPARATED: String = RobustWeighting::iter().join(", ");
+
+    pub(crate) static ref SOLVERS_COMMA_SEPARATED: String = Solver::iter().join(", ");
+}
+
+/// The tuning constant of [`RobustWeighting::Huber`], in units of the robust
+/// standard deviation of the residuals.
+const HUBER_K: f64 = 1.345;
+
+/// The number of degrees of freedom of [`RobustWeighting::StudentT`].
+const STUDENT_T_DOF: f64 = 2.0;
+
+/// How calibration solutions are constrained while they are solved for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub enum SolveMode {
+    /// Full Jones matrices, i.e. gains and leakage terms.
+    #[default]
+    #[strum(serialize = "full")]
+    Full,
+
+    /// Only the diagonal elements of the Jones matrices (gains); the leakage
+    /// terms are always zero.
+    #[strum(serialize = "diagonal")]
+    Diagonal,
+
+    /// Diagonal Jones matrices with unit amplitudes; only phases are solved
+    /// for.
+    #[strum(serialize = "phase")]
+    PhaseOnly,
+
+    /// Diagonal Jones matrices with zero phases; only amplitudes are solved
+    /// for.
+    #[strum(serialize = "amplitude")]
+    AmplitudeOnly,
+}
+
+impl SolveMode {
+    /// Apply the constraints of this mode to a Jones matrix.
+    fn constrain(self, j: Jones<f64>) -> Jones<f64> {
+        // Normalising a zero (e.g. the unused element of single-pol data)
+        // would produce NaNs; leave these alone.
+        let unit_phase = |c: c64| -> c64 {
+            let norm = c.norm();
+            if norm > 0.0 {
+                c / norm
+            } else {
+                c
+            }
+        };
+        match self {
+            SolveMode::Full => j,
+            SolveMode::Diagonal => Jones::from([j[0], c64::default(), c64::default(), j[3]]),
+            SolveMode::PhaseOnly => Jones::from([
+                unit_phase(j[0]),
+                c64::default(),
+                c64::default(),
+                unit_phase(j[3]),
+            ]),
+            SolveMode::AmplitudeOnly => Jones::from([
+                c64::new(j[0].norm(), 0.0),
+                c64::default(),
+                c64::default(),
+                c64::new(j[3].norm(), 0.0),
+            ]),
+        }
+    }
+}
+
+/// The iteration scheme used to find calibration solutions. Each iteration
+/// updates the solutions of every tile from the solutions of all other tiles;
+/// the schemes differ in how these updates are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub enum Solver {
+    /// Every second iteration, the solutions are set to the average of the
+    /// last two updates, as per StefCal.
+    #[default]
+    #[strum(serialize = "stefcal")]
+    Stefcal,
+
+    /// Anderson acceleration; the last few updates are used to extrapolate
+    /// towards the solutions. This usually needs fewer iterations than
+    /// [`Solver::Stefcal`], particularly for noisy data.
+    #[strum(serialize = "anderson")]
+    Anderson,
+}
+
+/// How visibilities are weighted during calibration. Robust weightings are
+/// recomputed every iteration from the residuals between the data and the
+/// calibrated model, such that baselines with outlying residuals (e.g. from
+/// unflagged RFI or unmodelled sources) are down-weighted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub enum RobustWeighting {
+    /// All visibilities are weighted equally (ordinary least squares).
+    #[default]
+    #[strum(serialize = "none")]
+    None,
+
+    /// Residuals bigger than a few robust standard deviations have weights
+    /// inversely proportional to their size.
+    #[strum(serialize = "huber")]
+    Huber,
+
+    /// Weights follow from assuming that the residuals have a Student's t
+    /// distribution, which down-weights outliers more aggressively than
+    /// [`RobustWeighting::Huber`].
+    #[strum(serialize = "student-t")]
+    StudentT,
+}
+
+impl RobustWeighting {
+    /// Get the weight of a residual, given the robust standard deviation of all
+    /// residuals.
+    fn weight(self, residual: f64, sigma: f64) -> f64 {
+        match self {
+            RobustWeighting::None => 1.0,
+            RobustWeighting::Huber => {
+                if residual <= HUBER_K * sigma {
+                    1.0
+                } else {
+                    HUBER_K * sigma / residual
+                }
+            }
+            RobustWeighting::StudentT => {
+                (STUDENT_T_DOF + 1.0) / (STUDENT_T_DOF + (residual / sigma).powi(2))
+            }
+        }
+    }
+}
+
 /// (Possibly) incomplete calibration solutions.
 ///
 /// hyperdrive only reads in the data it needs for DI calibration; it ignores
@@ -59,9 +205,204 @@ pub struct IncompleteSolutions<'a> {
 
     /// The minimum threshold used during calibration.
     min_threshold: f64,
+
+    /// How the solutions were constrained during calibration.
+    solve_mode: SolveMode,
+
+    /// The iteration scheme used during calibration.
+    solver: Solver,
 }
 
 impl<'a> IncompleteSolutions<'a> {
+    /// Get statistics on the residuals between the calibration data and the
+    /// model corrupted by these solutions. The visibilities are expected to
+    /// have had their weights applied, as is done for calibration (flagged
+    /// visibilities have non-positive weights). The statistics are "padded"
+    /// to include flagged tiles, chanblocks and baselines, like
+    /// [`CalibrationSolutions`].
+    pub(crate) fn get_residual_stats(
+        &self,
+        vis_data_tfb: ArrayView3<Jones<f32>>,
+        vis_model_tfb: ArrayView3<Jones<f32>>,
+        vis_weights_tfb: ArrayView3<f32>,
+        params: &DiCalParams,
+    ) -> ResidualStats {
+        let input_vis_params = &params.input_vis_params;
+        let total_num_tiles = input_vis_params.get_total_num_tiles();
+        // These solutions may be for only some of the chanblocks (e.g. when
+        // calibrating in chunks), but the statistics are for all of them.
+        let total_num_chanblocks = input_vis_params.spw.chanblocks.len()
+            + input_vis_params.spw.flagged_chanblock_indices.len();
+        let flagged_tiles = &input_vis_params.tile_baseline_flags.flagged_tiles;
+        let unflagged_tiles = (0..total_num_tiles)
+            .filter(|i_tile| !flagged_tiles.contains(i_tile))
+            .collect::<Vec<_>>();
+        let (num_timeblocks, num_unflagged_tiles, _) = self.di_jones.dim();
+        let num_unflagged_baselines = vis_data_tfb.len_of(Axis(2));
+        assert_eq!(num_unflagged_tiles, unflagged_tiles.len());
+        assert_eq!(vis_data_tfb.dim(), vis_model_tfb.dim());
+        assert_eq!(vis_data_tfb.dim(), vis_weights_tfb.dim());
+
+        // Accumulate the sum of squared residuals, the weighted sum of squared
+        // residuals and the number of visibilities. Visibilities and their
+        // weights are along the time, chanblock and baseline axes; tile stats
+        // can be done for each chanblock in parallel, but baselines need to be
+        // summed over chanblocks.
+        let (tile_sums, baseline_sums) = self
+            .chanblocks
+            .par_iter()
+            .map(|chanblock| {
+                let i_chanblock = usize::from(chanblock.unflagged_index);
+                let mut tile_sums =
+                    Array2::<[f64; 3]>::from_elem((num_timeblocks, num_unflagged_tiles), [0.0; 3]);
+                let mut baseline_sums = Array2::<[f64; 3]>::from_elem(
+                    (num_timeblocks, num_unflagged_baselines),
+                    [0.0; 3],
+                );
+                for timeblock in self.timeblocks.iter() {
+                    let i_timeblock = timeblock.index;
+                    let di_jones = self.di_jones.slice(s![i_timeblock, .., i_chanblock]);
+                    for i_time in timeblock.range.clone() {
+                        let mut i_tile1 = 0;
+                        let mut i_tile2 = 0;
+                        for (i_baseline, ((data, model), &weight)) in vis_data_tfb
+                            .slice(s![i_time, i_chanblock, ..])
+                            .iter()
+                            .zip(vis_model_tfb.slice(s![i_time, i_chanblock, ..]))
+                            .zip(vis_weights_tfb.slice(s![i_time, i_chanblock, ..]))
+                            .enumerate()
+                        {
+                            i_tile2 += 1;
+                            if i_tile2 == num_unflagged_tiles {
+                                i_tile1 += 1;
+                                i_tile2 = i_tile1 + 1;
+                            }
+
+                            let weight = f64::from(weight) * params.baseline_weights[i_baseline];
+                            let (j1, j2) = (di_jones[i_tile1], di_jones[i_tile2]);
+                            if weight <= 0.0 || j1.any_nan() || j2.any_nan() {
+                                continue;
+                            }
+                            // The data and model have been multiplied by the
+                            // weight; undo this.
+                            let data = Jones::<f64>::from(data) / weight;
+                            let model = Jones::<f64>::from(model) / weight;
+                            let residual = data - j1 * model * j2.h();
+                            let sq = residual.norm_sqr().iter().sum::<f64>();
+                            let add = |sums: &mut [f64; 3]| {
+                                sums[0] += sq;
+                                sums[1] += weight * sq;
+                                sums[2] += 1.0;
+                            };
+                            add(&mut tile_sums[(i_timeblock, i_tile1)]);
+                            add(&mut tile_sums[(i_timeblock, i_tile2)]);
+                            add(&mut baseline_sums[(i_timeblock, i_baseline)]);
+                        }
+                    }
+                }
+                (vec![(chanblock.chanblock_index, tile_sums)], baseline_sums)
+            })
+            .reduce(
+                || {
+                    (
+                        vec![],
+                        Array2::from_elem((num_timeblocks, num_unflagged_baselines), [0.0; 3]),
+                    )
+                },
+                |(mut tile_sums, mut baseline_sums), (mut t, b)| {
+                    tile_sums.append(&mut t);
+                    baseline_sums.zip_mut_with(&b, |a, b| {
+                        a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    });
+                    (tile_sums, baseline_sums)
+                },
+            );
+
+        let to_stat = |[sum_sq, chi_squared, num_vis]: [f64; 3]| {
+            if num_vis > 0.0 {
+                ResidualStat {
+                    rms: (sum_sq / num_vis).sqrt(),
+                    chi_squared,
+                    num_vis: num_vis as usize,
+                }
+            } else {
+                ResidualStat::default()
+            }
+        };
+
+        let mut tiles = Array3::from_elem(
+            (num_timeblocks, total_num_tiles, total_num_chanblocks),
+            ResidualStat::default(),
+        );
+        for (i_chanblock, tile_sums) in tile_sums {
+            for ((i_timeblock, i_unflagged_tile), &sums) in tile_sums.indexed_iter() {
+                tiles[(
+                    i_timeblock,
+                    unflagged_tiles[i_unflagged_tile],
+                    usize::from(i_chanblock),
+                )] = to_stat(sums);
+            }
+        }
+
+        let total_num_baselines = (total_num_tiles * (total_num_tiles - 1)) / 2;
+        let mut baselines = Array2::from_elem(
+            (num_timeblocks, total_num_baselines),
+            ResidualStat::default(),
+        );
+        let mut i_unflagged_baseline = 0;
+        let mut i_baseline = 0;
+        for i_tile_1 in 0..total_num_tiles {
+            for i_tile_2 in i_tile_1 + 1..total_num_tiles {
+                if !flagged_tiles.contains(&i_tile_1) && !flagged_tiles.contains(&i_tile_2) {
+                    for i_timeblock in 0..num_timeblocks {
+                        baselines[(i_timeblock, i_baseline)] =
+                            to_stat(baseline_sums[(i_timeblock, i_unflagged_baseline)]);
+                    }
+                    i_unflagged_baseline += 1;
+                }
+                i_baseline += 1;
+            }
+        }
+
+        ResidualStats { tiles, baselines }
+    }
+
+    /// Concatenate the solutions of consecutive chunks of chanblocks (e.g.
+    /// from calibrating chanblocks in chunks) into solutions for all of
+    /// `chanblocks`. The chunks must have been calibrated with the same
+    /// `timeblocks` and settings.
+    pub(crate) fn concatenate_chunks(
+        chunks: Vec<IncompleteSolutions>,
+        timeblocks: &'a Vec1<Timeblock>,
+        chanblocks: &'a [Chanblock],
+    ) -> IncompleteSolutions<'a> {
+        let first = chunks.first().expect("there is at least one chunk");
+        let (max_iterations, stop_threshold, min_threshold, solve_mode, solver) = (
+            first.max_iterations,
+            first.stop_threshold,
+            first.min_threshold,
+            first.solve_mode,
+            first.solver,
+        );
+        let di_jones = ndarray::concatenate(
+            Axis(2),
+            &chunks.iter().map(|c| c.di_jones.view()).collect::<Vec<_>>(),
+        )
+        .expect("all chunks have the same tiles and timeblocks");
+        assert_eq!(di_jones.len_of(Axis(2)), chanblocks.len());
+
+        IncompleteSolutions {
+            di_jones,
+            timeblocks,
+            chanblocks,
+            max_iterations,
+            stop_threshold,
+            min_threshold,
+            solve_mode,
+            solver,
+        }
+    }
+
     /// Convert these [`IncompleteSolutions`] into "padded"
     /// [`CalibrationSolutions`].
     ///
@@ -98,6 +439,8 @@ impl<'a> IncompleteSolutions<'a> {
             max_iterations,
             stop_threshold,
             min_threshold,
+            solve_mode,
+            solver,
         } = self;
 
         let input_vis_params = &params.input_vis_params;
@@ -245,6 +588,8 @@ impl<'a> IncompleteSolutions<'a> {
             max_iterations: Some(max_iterations),
             stop_threshold: Some(stop_threshold),
             min_threshold: Some(min_threshold),
+            solve_mode: Some(solve_mode),
+            solver: Some(solver),
             raw_data_corrections: input_vis_params.vis_reader.get_raw_data_corrections(),
             tile_names: Some(obs_context.tile_names.clone()),
             dipole_gains: params.beam.get_dipole_gains(),
@@ -252,6 +597,9 @@ impl<'a> IncompleteSolutions<'a> {
             beam_file: params.beam.get_beam_file().map(|p| p.to_path_buf()),
             calibration_results,
             baseline_weights,
+            residual_stats: None,
+            xy_phase: None,
+            phase_reference: None,
             uvw_min: Some(params.uvw_min),
             uvw_max: Some(params.uvw_max),
             freq_centroid: Some(params.freq_centroid),
@@ -262,6 +610,111 @@ impl<'a> IncompleteSolutions<'a> {
     }
 }
 
+/// Use existing calibration solutions to make initial guesses for DI
+/// calibration. The returned array has dimensions of (num_timeblocks,
+/// num_unflagged_tiles, num_unflagged_chanblocks), suitable for
+/// `calibrate_timeblocks`.
+///
+/// Tiles are matched by name if the solutions have tile names, otherwise by
+/// index (if the total number of tiles is the same). Chanblocks are matched by
+/// frequency if the solutions have chanblock frequencies, otherwise by index
+/// (if the total number of chanblocks is the same). Anything that can't be
+/// matched, or has a non-finite or zero solution, is initialised to identity.
+pub(crate) fn get_initial_di_jones(
+    sols: &CalibrationSolutions,
+    timeblocks: &[Timeblock],
+    chanblocks: &[Chanblock],
+    total_num_chanblocks: usize,
+    tile_names: &[String],
+    flagged_tiles: &HashSet<usize>,
+) -> Array3<Jones<f64>> {
+    let (_, num_sol_tiles, num_sol_chanblocks) = sols.di_jones.dim();
+    let num_unflagged_tiles = tile_names.len() - flagged_tiles.len();
+    let mut initial_di_jones = Array3::from_elem(
+        (timeblocks.len(), num_unflagged_tiles, chanblocks.len()),
+        Jones::identity(),
+    );
+
+    // For each unflagged tile, get the corresponding index into the solutions.
+    let tile_map: Vec<Option<usize>> = tile_names
+        .iter()
+        .enumerate()
+        .filter(|(i_tile, _)| !flagged_tiles.contains(i_tile))
+        .map(|(i_tile, name)| match sols.tile_names.as_ref() {
+            Some(sol_names) => sol_names.iter().position(|n| n == name),
+            None => (num_sol_tiles == tile_names.len()).then_some(i_tile),
+        })
+        .collect();
+
+    // For each unflagged chanblock, get the corresponding index into the
+    // solutions. When matching by frequency, the solution chanblock must be
+    // within half of its frequency resolution.
+    let chanblock_map: Vec<Option<usize>> = match sols.chanblock_freqs.as_ref() {
+        Some(sol_freqs) => {
+            let half_res = if sol_freqs.len() > 1 {
+                (sol_freqs[1] - sol_freqs[0]).abs() / 2.0
+            } else {
+                f64::INFINITY
+            };
+            chanblocks
+                .iter()
+                .map(|c| {
+                    sol_freqs
+                        .iter()
+                        .map(|f| (f - c.freq).abs())
+                        .enumerate()
+                        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .filter(|(_, diff)| *diff <= half_res)
+                        .map(|(i, _)| i)
+                })
+                .collect()
+        }
+        None => chanblocks
+            .iter()
+            .map(|c| {
+                (num_sol_chanblocks == total_num_chanblocks).then_some(c.chanblock_index as usize)
+            })
+            .collect(),
+    };
+
+    let num_timeblocks = timeblocks.len();
+    for (i_timeblock, (timeblock, mut initial_di_jones)) in timeblocks
+        .iter()
+        .zip(initial_di_jones.outer_iter_mut())
+        .enumerate()
+    {
+        let sol_di_jones = sols.get_timeblock(
+            timeblock.median,
+            (i_timeblock as f64 + 0.5) / num_timeblocks as f64,
+        );
+        for (i_sol_tile, mut initial_di_jones) in
+            tile_map.iter().zip(initial_di_jones.outer_iter_mut())
+        {
+            let i_sol_tile = match i_sol_tile {
+                Some(i) => *i,
+                None => continue,
+            };
+            for (i_sol_chanblock, initial_jones) in
+                chanblock_map.iter().zip(initial_di_jones.iter_mut())
+            {
+                if let Some(i_sol_chanblock) = i_sol_chanblock {
+                    let j = sol_di_jones[(i_sol_tile, *i_sol_chanblock)];
+                    // Zeros would prevent calibration from doing anything.
+                    if j.to_float_array().iter().all(|f| f.is_finite()) && j != Jones::default() {
+                        *initial_jones = j;
+                    }
+                }
+            }
+        }
+    }
+
+    let num_matched_tiles = tile_map.iter().filter(|t| t.is_some()).count();
+    let num_matched_chanblocks = chanblock_map.iter().filter(|c| c.is_some()).count();
+    debug!("Initial solutions matched {num_matched_tiles}/{num_unflagged_tiles} tiles and {num_matched_chanblocks}/{} chanblocks", chanblocks.len());
+
+    initial_di_jones
+}
+
 /// Perform DI calibration on the data and model. Incomplete DI solutions are
 /// returned; these need to be "padded" with NaNs by `into_cal_sols` before they
 /// can be saved to disk or applied to an observation's visibilities.
@@ -272,15 +725,30 @@ impl<'a> IncompleteSolutions<'a> {
 /// The way this code is currently structured mandates that all timesteps are
 /// calibrated together (as if they all belonged to a single timeblock) before
 /// any timeblocks are individually calibrated. This decision can be revisited.
+///
+/// If `initial_di_jones` is supplied, it is used as the initial guess of the
+/// solutions instead of identity matrices. It must have the dimensions
+/// (num_timeblocks, num_unflagged_tiles, num_unflagged_chanblocks). Each
+/// timeblock starts from its own initial guess, so the initial calibration of
+/// all timesteps together is skipped.
+///
+/// If `redundant` is supplied, redundant calibration is done instead (see
+/// [`redundant`]) and the model visibilities aren't used; the data
+/// visibilities must have been prepared with [`redundant::prepare_cal_vis`].
 #[allow(clippy::too_many_arguments)]
 pub fn calibrate_timeblocks<'a>(
     vis_data_tfb: ArrayView3<Jones<f32>>,
     vis_model_tfb: ArrayView3<Jones<f32>>,
     timeblocks: &'a Vec1<Timeblock>,
     chanblocks: &'a [Chanblock],
+    initial_di_jones: Option<Array3<Jones<f64>>>,
     max_iterations: u32,
     stop_threshold: f64,
     min_threshold: f64,
+    solve_mode: SolveMode,
+    robust_weighting: RobustWeighting,
+    solver: Solver,
+    redundant: Option<RedundantCalVis>,
     pols: Polarisations,
     print_convergence_messages: bool,
 ) -> (IncompleteSolutions<'a>, Array2<CalibrationResult>) {
@@ -288,7 +756,14 @@ pub fn calibrate_timeblocks<'a>(
     let num_timeblocks = timeblocks.len();
     let num_chanblocks = chanblocks.len();
     let shape = (num_timeblocks, num_unflagged_tiles, num_chanblocks);
-    let mut di_jones = Array3::from_elem(shape, Jones::identity());
+    let have_initial_di_jones = initial_di_jones.is_some();
+    let mut di_jones = match initial_di_jones {
+        Some(initial_di_jones) => {
+            assert_eq!(initial_di_jones.dim(), shape);
+            initial_di_jones
+        }
+        None => Array3::from_elem(shape, Jones::identity()),
+    };
 
     let cal_results = if num_timeblocks == 1 {
         // Calibrate all timesteps together.
@@ -302,6 +777,10 @@ pub fn calibrate_timeblocks<'a>(
             max_iterations,
             stop_threshold,
             min_threshold,
+            solve_mode,
+            robust_weighting,
+            solver,
+            redundant,
             pols,
             pb,
             print_convergence_messages,
@@ -315,46 +794,56 @@ pub fn calibrate_timeblocks<'a>(
         );
         Array2::from_shape_vec((num_timeblocks, num_chanblocks), cal_results).unwrap()
     } else {
-        // Calibrate all timesteps together to get a good initial guess at what
-        // the solutions for each timeblock should be.
-        let pb = make_calibration_progress_bar(
-            num_chanblocks,
-            "Calibrating all timeblocks together".to_string(),
-        );
-        // This timeblock represents all timeblocks.
-        let timeblock = {
-            let mut timeblock = timeblocks.first().clone();
-            for tb in timeblocks.iter().skip(1) {
-                timeblock.range = timeblock.range.start..tb.range.end;
-                timeblock.timestamps.extend(tb.timestamps.iter());
-            }
-            timeblock
-        };
-        let cal_results = calibrate_timeblock(
-            vis_data_tfb.view(),
-            vis_model_tfb.view(),
-            di_jones.view_mut(),
-            &timeblock,
-            chanblocks,
-            max_iterations,
-            stop_threshold,
-            min_threshold,
-            pols,
-            pb,
-            print_convergence_messages,
-        );
-        let total_converged_count = cal_results.into_iter().filter(|r| r.converged).count();
-        info!(
-            "All timesteps for initial guesses: {}/{} ({}%) chanblocks converged",
-            total_converged_count,
-            num_chanblocks,
-            ((total_converged_count as f64 / num_chanblocks as f64) * 100.0).round()
-        );
+        // When there are initial solutions, each timeblock already has a good
+        // initial guess.
+        if have_initial_di_jones {
+            debug!("Using the initial solutions for each timeblock");
+        } else {
+            // Calibrate all timesteps together to get a good initial guess at
+            // what the solutions for each timeblock should be.
+            let pb = make_calibration_progress_bar(
+                num_chanblocks,
+                "Calibrating all timeblocks together".to_string(),
+            );
+            // This timeblock represents all timeblocks.
+            let timeblock = {
+                let mut timeblock = timeblocks.first().clone();
+                for tb in timeblocks.iter().skip(1) {
+                    timeblock.range = timeblock.range.start..tb.range.end;
+                    timeblock.timestamps.extend(tb.timestamps.iter());
+                }
+                timeblock
+            };
+            let cal_results = calibrate_timeblock(
+                vis_data_tfb.view(),
+                vis_model_tfb.view(),
+                di_jones.view_mut(),
+                &timeblock,
+                chanblocks,
+                max_iterations,
+                stop_threshold,
+                min_threshold,
+                solve_mode,
+                robust_weighting,
+                solver,
+                redundant,
+                pols,
+                pb,
+                print_convergence_messages,
+            );
+            let total_converged_count = cal_results.into_iter().filter(|r| r.converged).count();
+            info!(
+                "All timesteps for initial guesses: {}/{} ({}%) chanblocks converged",
+                total_converged_count,
+                num_chanblocks,
+                ((total_converged_count as f64 / num_chanblocks as f64) * 100.0).round()
+            );
 
-        // Calibrate each timeblock individually. Set all solutions to be that
-        // of the averaged solutions so that the individual timeblocks have less
-        // work to do.
-        di_jones.accumulate_axis_inplace(Axis(0), |&prev, curr| *curr = prev);
+            // Calibrate each timeblock individually. Set all solutions to be
+            // that of the averaged solutions so that the individual timeblocks
+            // have less work to do.
+            di_jones.accumulate_axis_inplace(Axis(0), |&prev, curr| *curr = prev);
+        }
         let mut all_cal_results = Vec::with_capacity(timeblocks.len());
         for (i_timeblock, timeblock) in timeblocks.iter().enumerate() {
             let pb = make_calibration_progress_bar(
@@ -374,6 +863,10 @@ pub fn calibrate_timeblocks<'a>(
                 max_iterations,
                 stop_threshold,
                 min_threshold,
+                solve_mode,
+                robust_weighting,
+                solver,
+                redundant,
                 pols,
                 pb,
                 print_convergence_messages,
@@ -399,6 +892,8 @@ pub fn calibrate_timeblocks<'a>(
             max_iterations,
             stop_threshold,
             min_threshold,
+            solve_mode,
+            solver,
         },
         cal_results,
     )
@@ -437,12 +932,49 @@ fn calibrate_timeblock(
     max_iterations: u32,
     stop_threshold: f64,
     min_threshold: f64,
+    solve_mode: SolveMode,
+    robust_weighting: RobustWeighting,
+    solver: Solver,
+    redundant: Option<RedundantCalVis>,
     pols: Polarisations,
     progress_bar: ProgressBar,
     print_convergence_messages: bool,
 ) -> Vec<CalibrationResult> {
     let (_, num_unflagged_tiles, num_chanblocks) = di_jones.dim();
 
+    let calibrate_chanblock = |data_tfb: ArrayView3<Jones<f32>>,
+                               model_tfb: ArrayView3<Jones<f32>>,
+                               redundant: Option<RedundantCalVis>,
+                               di_jones: ArrayViewMut1<Jones<f64>>| {
+        match redundant {
+            Some(redundant) => calibrate_redundant(
+                data_tfb,
+                redundant.weights_tfb,
+                redundant.groups,
+                di_jones,
+                max_iterations,
+                stop_threshold,
+                min_threshold,
+                solve_mode,
+                robust_weighting,
+                solver,
+                pols,
+            ),
+            None => calibrate(
+                data_tfb,
+                model_tfb,
+                di_jones,
+                max_iterations,
+                stop_threshold,
+                min_threshold,
+                solve_mode,
+                robust_weighting,
+                solver,
+                pols,
+            ),
+        }
+    };
+
     let mut di_jones_rev = di_jones
         .slice_mut(s![timeblock.index, .., ..])
         .reversed_axes();
@@ -460,14 +992,14 @@ fn calibrate_timeblock(
                 i_chanblock..i_chanblock + 1,
                 ..
             ];
-            let mut cal_result = calibrate(
+            let mut cal_result = calibrate_chanblock(
                 vis_data_tfb.slice(range),
                 vis_model_tfb.slice(range),
+                redundant.map(|r| RedundantCalVis {
+                    weights_tfb: r.weights_tfb.slice_move(range),
+                    ..r
+                }),
                 di_jones,
-                max_iterations,
-                stop_threshold,
-                min_threshold,
-                pols,
             );
             cal_result.chanblock = Some(chanblock.chanblock_index as usize);
             cal_result.i_chanblock = Some(chanblock.unflagged_index as usize);
@@ -591,14 +1123,10 @@ fn calibrate_timeblock(
                         let chanblock = old_cal_result.chanblock.unwrap();
                         let i_chanblock = old_cal_result.i_chanblock.unwrap();
                         let range = s![timeblock.range.clone(), i_chanblock..i_chanblock + 1, ..];
-                        let mut new_cal_result = calibrate(
+                        let mut new_cal_result = calibrate_chanblock(
                             vis_data_tfb.slice(range),
                             vis_model_tfb.slice(range),
                             di_jones,
-                            max_iterations,
-                            stop_threshold,
-                            min_threshold,
-                            pols,
                         );
                         new_cal_result.chanblock = Some(chanblock);
                         new_cal_result.i_chanblock = Some(i_chanblock);
@@ -681,6 +1209,7 @@ pub struct CalibrationResult {
 ///
 /// This function is intended to be run in parallel; for that reason, no
 /// parallel code is inside this function.
+#[allow(clippy::too_many_arguments)]
 pub(super) fn calibrate(
     data_tfb: ArrayView3<Jones<f32>>,
     model_tfb: ArrayView3<Jones<f32>>,
@@ -688,6 +1217,9 @@ pub(super) fn calibrate(
     max_iterations: u32,
     stop_threshold: f64,
     min_threshold: f64,
+    solve_mode: SolveMode,
+    robust_weighting: RobustWeighting,
+    solver: Solver,
     pols: Polarisations,
 ) -> CalibrationResult {
     assert_eq!(data_tfb.dim(), model_tfb.dim());
@@ -701,27 +1233,52 @@ pub(super) fn calibrate(
     // largest value in the entire array.
     let mut precisions: Array2<f64> = Array::zeros((num_tiles, 4));
     let mut failed: Array1<bool> = Array1::from_elem(num_tiles, false);
+    let mut anderson = match solver {
+        Solver::Stefcal => None,
+        Solver::Anderson => Some(AndersonMixer::new(ANDERSON_DEPTH)),
+    };
+    let mut anderson_start: Vec<c64> = vec![];
+    let mut anderson_num_failed = 0;
+    let flatten = |j: &Jones<f64>| [j[0], j[1], j[2], j[3]];
+    // Only robust weighting needs per-visibility weights.
+    let mut robust_weights_tfb: Option<Array3<f64>> = match robust_weighting {
+        RobustWeighting::None => None,
+        RobustWeighting::Huber | RobustWeighting::StudentT => Some(Array3::ones(data_tfb.dim())),
+    };
 
     // If we only have a single polarisation in the data, then we need to not do
     // proper Jones matrix division, because all Jones matrices are singular.
+    // Similarly, if we're only solving for the diagonal elements, then the
+    // diagonals are divided independently.
     let div_func = |top: &Jones<f64>, bot: &Jones<f64>| -> Jones<f64> {
-        match pols {
-            Polarisations::XX => Jones::from([
+        let div = match (pols, solve_mode) {
+            (Polarisations::XX, _) => Jones::from([
                 top[0] / bot[0],
                 c64::default(),
                 c64::default(),
                 c64::default(),
             ]),
-            Polarisations::YY => Jones::from([
+            (Polarisations::YY, _) => Jones::from([
                 c64::default(),
                 c64::default(),
                 c64::default(),
                 top[3] / bot[3],
             ]),
-            Polarisations::XX_XY_YX_YY | Polarisations::XX_YY | Polarisations::XX_YY_XY => {
-                *top / bot
-            }
-        }
+            (
+                Polarisations::XX_XY_YX_YY | Polarisations::XX_YY | Polarisations::XX_YY_XY,
+                SolveMode::Full,
+            ) => *top / bot,
+            (
+                Polarisations::XX_XY_YX_YY | Polarisations::XX_YY | Polarisations::XX_YY_XY,
+                SolveMode::Diagonal | SolveMode::PhaseOnly | SolveMode::AmplitudeOnly,
+            ) => Jones::from([
+                top[0] / bot[0],
+                c64::default(),
+                c64::default(),
+                top[3] / bot[3],
+            ]),
+        };
+        solve_mode.constrain(div)
     };
 
     let mut iteration = 0;
@@ -731,9 +1288,24 @@ pub(super) fn calibrate(
         top.fill(Jones::default());
         bot.fill(Jones::default());
 
+        // Re-weight the visibilities with the latest solutions. On the first
+        // iteration, the solutions are only a guess, so don't bother.
+        if let Some(robust_weights_tfb) = robust_weights_tfb.as_mut() {
+            if iteration > 1 {
+                update_robust_weights(
+                    data_tfb,
+                    model_tfb,
+                    di_jones.view(),
+                    robust_weighting,
+                    robust_weights_tfb.view_mut(),
+                );
+            }
+        }
+
         calibration_loop(
             data_tfb,
             model_tfb,
+            robust_weights_tfb.as_ref().map(|w| w.view()),
             di_jones.view(),
             top.view_mut(),
             bot.view_mut(),
@@ -872,9 +1444,11 @@ pub(super) fn calibrate(
                         });
 
                     // di_jones = 0.5 * (di_jones + old_jones)
+                    // The average may not satisfy the solve mode's
+                    // constraints (e.g. unit amplitudes), so re-apply them.
                     di_jones.iter_mut().zip(old_jones.iter().copied()).for_each(
                         |(di_jones, old_jones)| {
-                            *di_jones = (*di_jones + old_jones) * 0.5;
+                            *di_jones = solve_mode.constrain((*di_jones + old_jones) * 0.5);
                         },
                     )
                 });
@@ -883,6 +1457,32 @@ pub(super) fn calibrate(
             if precisions.iter().all(|&v| v < stop_threshold) {
                 break;
             }
+
+            // With Anderson acceleration, the new gain solution is instead
+            // extrapolated from the last few pairs of iterations.
+            if let Some(anderson) = anderson.as_mut() {
+                // Failed tiles are no longer updated, so the history of
+                // iterations is no longer relevant.
+                if num_failed != anderson_num_failed {
+                    anderson.reset();
+                    anderson_num_failed = num_failed;
+                }
+                let averaged = di_jones.iter().flat_map(flatten).collect::<Vec<_>>();
+                let next = anderson.next(&anderson_start, &averaged);
+                di_jones
+                    .iter_mut()
+                    .zip(next.chunks_exact(4))
+                    .zip(failed.iter())
+                    .filter(|(_, &failed)| !failed)
+                    .for_each(|((di_jones, next), _)| {
+                        *di_jones =
+                            solve_mode.constrain(Jones::from([next[0], next[1], next[2], next[3]]));
+                    });
+            }
+        } else if anderson.is_some() {
+            // Remember the solutions that the pair of iterations started from.
+            anderson_start.clear();
+            anderson_start.extend(old_jones.iter().flat_map(flatten));
         }
         old_jones.assign(&di_jones);
     }
@@ -940,10 +1540,12 @@ pub(super) fn calibrate(
 /// <https://ui.adsabs.harvard.edu/abs/2008ISTSP...2..707M/abstract>
 ///
 /// The next iteration of gains is determined by summing the numerator ("top")
-/// and denominator ("bot") of each antenna separately.
+/// and denominator ("bot") of each antenna separately. If `weights_tfb` is
+/// supplied, each visibility's contribution to these sums is weighted.
 fn calibration_loop(
     data_tfb: ArrayView3<Jones<f32>>,
     model_tfb: ArrayView3<Jones<f32>>,
+    weights_tfb: Option<ArrayView3<f64>>,
     di_jones: ArrayView1<Jones<f64>>,
     mut top: ArrayViewMut1<Jones<f64>>,
     mut bot: ArrayViewMut1<Jones<f64>>,
@@ -954,59 +1556,136 @@ fn calibration_loop(
     data_tfb
         .outer_iter()
         .zip(model_tfb.outer_iter())
-        .for_each(|(data_fb, model_fb)| {
+        .enumerate()
+        .for_each(|(i_time, (data_fb, model_fb))| {
             // Unflagged frequency chan axis.
             data_fb
                 .outer_iter()
                 .zip(model_fb.outer_iter())
-                .for_each(|(data_b, model_b)| {
+                .enumerate()
+                .for_each(|(i_freq, (data_b, model_b))| {
                     let mut i_tile1 = 0;
                     let mut i_tile2 = 0;
+                    let weights_b = weights_tfb
+                        .as_ref()
+                        .map(|w| w.slice(s![i_time, i_freq, ..]));
 
                     // Unflagged baseline axis.
                     #[allow(non_snake_case)]
-                    data_b.iter().zip(model_b.iter()).for_each(|(data, model)| {
-                        i_tile2 += 1;
-                        if i_tile2 == num_tiles {
-                            i_tile1 += 1;
-                            i_tile2 = i_tile1 + 1;
-                        }
+                    data_b.iter().zip(model_b.iter()).enumerate().for_each(
+                        |(i_bl, (data, model))| {
+                            i_tile2 += 1;
+                            if i_tile2 == num_tiles {
+                                i_tile1 += 1;
+                                i_tile2 = i_tile1 + 1;
+                            }
 
-                        let D = Jones::<f64>::from(data);
-                        let M = Jones::<f64>::from(model);
-
-                        // Suppress boundary checks for maximum performance!
-                        unsafe {
-                            // Tile 1
-                            {
-                                let J2 = *di_jones.uget(i_tile2);
-                                let top_tile1 = top.uget_mut(i_tile1);
-                                let bot_tile1 = bot.uget_mut(i_tile1);
-
-                                // For tile 1, ( D G M^H ) / ( (M G^H) (M G^H)^H )
-                                // let Z = G M^H
-                                let Z = J2 * M.h();
-                                // D (G M^H) = D Z
-                                *top_tile1 += D * Z;
-                                // (M G^H) (M G^H)^H = (G M^H)^H (G M^H) = Z^H Z
-                                *bot_tile1 += Z.h() * Z;
+                            let mut D = Jones::<f64>::from(data);
+                            let mut M = Jones::<f64>::from(model);
+                            // Weighting the data and model by the square root
+                            // of the weight means each sum below is weighted
+                            // by the weight.
+                            if let Some(weights_b) = weights_b.as_ref() {
+                                // Suppress boundary checks for maximum performance!
+                                let w = unsafe { weights_b.uget(i_bl).sqrt() };
+                                D *= w;
+                                M *= w;
                             }
-                            // Tile 2
-                            {
-                                let J1 = *di_jones.uget(i_tile1);
-                                let top_tile2 = top.uget_mut(i_tile2);
-                                let bot_tile2 = bot.uget_mut(i_tile2);
-
-                                // For tile 2, ( D^H G M ) / ( (G M)^H (G M) )
-                                // let Z = G M
-                                let Z = J1 * M;
-                                // D^H G M = D^H Z
-                                *top_tile2 += D.h() * Z;
-                                // (G M)^H (G M) = Z^H Z
-                                *bot_tile2 += Z.h() * Z;
+
+                            // Suppress boundary checks for maximum performance!
+                            unsafe {
+                                // Tile 1
+                                {
+                                    let J2 = *di_jones.uget(i_tile2);
+                                    let top_tile1 = top.uget_mut(i_tile1);
+                                    let bot_tile1 = bot.uget_mut(i_tile1);
+
+                                    // For tile 1, ( D G M^H ) / ( (M G^H) (M G^H)^H )
+                                    // let Z = G M^H
+                                    let Z = J2 * M.h();
+                                    // D (G M^H) = D Z
+                                    *top_tile1 += D * Z;
+                                    // (M G^H) (M G^H)^H = (G M^H)^H (G M^H) = Z^H Z
+                                    *bot_tile1 += Z.h() * Z;
+                                }
+                                // Tile 2
+                                {
+                                    let J1 = *di_jones.uget(i_tile1);
+                                    let top_tile2 = top.uget_mut(i_tile2);
+                                    let bot_tile2 = bot.uget_mut(i_tile2);
+
+                                    // For tile 2, ( D^H G M ) / ( (G M)^H (G M) )
+                                    // let Z = G M
+                                    let Z = J1 * M;
+                                    // D^H G M = D^H Z
+                                    *top_tile2 += D.h() * Z;
+                                    // (G M)^H (G M) = Z^H Z
+                                    *bot_tile2 += Z.h() * Z;
+                                }
                             }
-                        }
-                    });
+                        },
+                    );
                 })
         });
 }
+
+/// Update the robust weights of visibilities from the residuals between the
+/// data and the model corrupted by the current solutions. The weights depend on
+/// the size of each residual relative to the robust standard deviation of all
+/// residuals (estimated from their median).
+fn update_robust_weights(
+    data_tfb: ArrayView3<Jones<f32>>,
+    model_tfb: ArrayView3<Jones<f32>>,
+    di_jones: ArrayView1<Jones<f64>>,
+    robust_weighting: RobustWeighting,
+    mut weights_tfb: ArrayViewMut3<f64>,
+) {
+    let num_tiles = di_jones.len_of(Axis(0));
+
+    // Store the residuals in the weights array before converting them.
+    for ((data_b, model_b), mut residuals_b) in data_tfb
+        .rows()
+        .into_iter()
+        .zip(model_tfb.rows())
+        .zip(weights_tfb.rows_mut())
+    {
+        let mut i_tile1 = 0;
+        let mut i_tile2 = 0;
+        for ((data, model), residual) in data_b.iter().zip(model_b).zip(residuals_b.iter_mut()) {
+            i_tile2 += 1;
+            if i_tile2 == num_tiles {
+                i_tile1 += 1;
+                i_tile2 = i_tile1 + 1;
+            }
+
+            let j1 = di_jones[i_tile1];
+            let j2 = di_jones[i_tile2];
+            let diff = Jones::<f64>::from(data) - j1 * Jones::<f64>::from(model) * j2.h();
+            *residual = diff.norm_sqr().iter().sum::<f64>().sqrt();
+        }
+    }
+
+    // Visibilities without data or model (e.g. flagged) and those involving
+    // failed tiles shouldn't affect the estimate of the spread of residuals.
+    let mut residuals = weights_tfb
+        .iter()
+        .copied()
+        .filter(|r| *r > 0.0 && r.is_finite())
+        .collect::<Vec<_>>();
+    // The residuals are amplitudes, so they're centred on zero.
+    let sigma = robust_sigma(&mut residuals, 0.0).unwrap_or(0.0);
+
+    if sigma > 0.0 && sigma.is_finite() {
+        weights_tfb.mapv_inplace(|r| {
+            if r.is_finite() {
+                robust_weighting.weight(r, sigma)
+            } else {
+                1.0
+            }
+        });
+    } else {
+        // All residuals are zero (or something is very wrong); use equal
+        // weights.
+        weights_tfb.fill(1.0);
+    }
+}
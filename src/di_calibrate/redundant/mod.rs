@@ -0,0 +1,440 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to handle redundant calibration.
+//!
+//! Baselines with the same separation between their tiles ("redundant"
+//! baselines) see the same sky, so the only differences between their
+//! visibilities are due to the tile gains. Redundant calibration solves for the
+//! tile gains and the "true" visibilities of each group of redundant baselines
+//! together, without needing a sky model.
+//!
+//! This is done by alternating between two steps. Given the current gains, the
+//! visibilities of each redundant group are estimated by averaging the
+//! gain-corrected visibilities of its baselines. The sky rotates over a
+//! timeblock, so this is done separately for each timestep. These group
+//! visibilities are then used as the model for regular DI calibration
+//! ([`super::calibrate`]), which updates the gains. This is repeated until the
+//! gains stop changing.
+//!
+//! Redundant calibration cannot determine everything about the gains; the
+//! overall amplitude and phase of each polarisation are arbitrary, as is a
+//! phase gradient across the array. After calibration, the gains of each
+//! chanblock are normalised to have an average amplitude of 1 and an average
+//! phase of 0, but the phase gradient is left alone.
+
+#[cfg(test)]
+mod tests;
+
+use marlu::{c64, Jones, XyzGeodetic};
+use ndarray::prelude::*;
+use rayon::prelude::*;
+
+use super::{
+    calibrate, CalibrationResult, IncompleteSolutions, RobustWeighting, SolveMode, Solver,
+};
+use crate::context::Polarisations;
+
+/// The default tolerance used to determine if two baselines are redundant
+/// \[metres\].
+pub(crate) const DEFAULT_REDUNDANCY_TOLERANCE: f64 = 0.1;
+
+/// The maximum number of times to alternate between estimating the group
+/// visibilities and the gains.
+const MAX_REDUNDANT_ROUNDS: u32 = 20;
+
+/// Groups of redundant baselines.
+#[derive(Debug, Clone)]
+pub struct RedundantGroups {
+    /// The redundant group of each unflagged cross-correlation baseline, and
+    /// whether the baseline is "reversed" relative to the group (i.e. its
+    /// visibilities are the conjugate transpose of the group's). Baselines
+    /// without any redundant partners are `None` and aren't used in
+    /// calibration.
+    pub(crate) baseline_groups: Vec<Option<(usize, bool)>>,
+
+    /// The number of redundant groups.
+    pub(crate) num_groups: usize,
+}
+
+impl RedundantGroups {
+    /// Group the baselines formed by unflagged tiles by their separations.
+    /// Baselines are redundant if their separations are within `tolerance`
+    /// \[metres\] of each other.
+    pub fn new(unflagged_tile_xyzs: &[XyzGeodetic], tolerance: f64) -> RedundantGroups {
+        // The separation vector of the first baseline of each group, and the
+        // number of baselines in the group.
+        let mut groups: Vec<([f64; 3], usize)> = vec![];
+        let mut baseline_groups = vec![];
+        for (i_tile1, xyz1) in unflagged_tile_xyzs.iter().enumerate() {
+            for xyz2 in unflagged_tile_xyzs.iter().skip(i_tile1 + 1) {
+                let sep = [xyz1.x - xyz2.x, xyz1.y - xyz2.y, xyz1.z - xyz2.z];
+                let distance = |a: [f64; 3], b: [f64; 3]| -> f64 {
+                    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+                };
+                let neg_sep = [-sep[0], -sep[1], -sep[2]];
+
+                let group = groups.iter_mut().enumerate().find_map(|(i_group, group)| {
+                    if distance(group.0, sep) < tolerance {
+                        group.1 += 1;
+                        Some((i_group, false))
+                    } else if distance(group.0, neg_sep) < tolerance {
+                        group.1 += 1;
+                        Some((i_group, true))
+                    } else {
+                        None
+                    }
+                });
+                match group {
+                    Some(g) => baseline_groups.push(Some(g)),
+                    None => {
+                        baseline_groups.push(Some((groups.len(), false)));
+                        groups.push((sep, 1));
+                    }
+                }
+            }
+        }
+
+        // Remove groups with only one baseline, and re-number the rest.
+        let mut group_map = vec![None; groups.len()];
+        let mut num_groups = 0;
+        for ((_, count), new_index) in groups.iter().zip(group_map.iter_mut()) {
+            if *count > 1 {
+                *new_index = Some(num_groups);
+                num_groups += 1;
+            }
+        }
+        for baseline_group in baseline_groups.iter_mut() {
+            *baseline_group = baseline_group
+                .and_then(|(i_group, reversed)| group_map[i_group].map(|i| (i, reversed)));
+        }
+
+        RedundantGroups {
+            baseline_groups,
+            num_groups,
+        }
+    }
+
+    /// Get the number of baselines that belong to a redundant group.
+    pub(crate) fn num_redundant_baselines(&self) -> usize {
+        self.baseline_groups.iter().flatten().count()
+    }
+}
+
+/// The visibility weights and redundant groups needed for redundant
+/// calibration.
+#[derive(Debug, Clone, Copy)]
+pub struct RedundantCalVis<'a> {
+    /// The groups of redundant baselines.
+    pub(crate) groups: &'a RedundantGroups,
+
+    /// The calibration weight of each visibility, i.e. the visibility weights
+    /// multiplied by the baseline weights (see [`get_cal_weights`]). The
+    /// dimensions are (time, frequency, baseline).
+    pub(crate) weights_tfb: ArrayView3<'a, f32>,
+}
+
+/// Prepare visibilities (that have had their weights applied, as is done for
+/// calibration) for redundant calibration. Baselines without redundant partners
+/// are flagged; the model visibilities are not used.
+pub(crate) fn prepare_cal_vis(
+    groups: &RedundantGroups,
+    mut vis_data_tfb: ArrayViewMut3<Jones<f32>>,
+    mut vis_weights_tfb: ArrayViewMut3<f32>,
+) {
+    assert_eq!(vis_data_tfb.len_of(Axis(2)), groups.baseline_groups.len());
+    vis_data_tfb
+        .axis_iter_mut(Axis(2))
+        .into_par_iter()
+        .zip(vis_weights_tfb.axis_iter_mut(Axis(2)))
+        .zip(groups.baseline_groups.par_iter())
+        .filter(|(_, group)| group.is_none())
+        .for_each(|((mut vis_data, mut vis_weights), _)| {
+            vis_data.fill(Jones::default());
+            vis_weights.mapv_inplace(|w| -w.abs());
+        });
+}
+
+/// Get the calibration weight of each visibility, i.e. the visibility weights
+/// multiplied by the baseline weights. Flagged visibilities have weights of 0.
+pub(crate) fn get_cal_weights(
+    vis_weights_tfb: ArrayView3<f32>,
+    baseline_weights: &[f64],
+) -> Array3<f32> {
+    assert_eq!(vis_weights_tfb.len_of(Axis(2)), baseline_weights.len());
+    let mut cal_weights_tfb = vis_weights_tfb.to_owned();
+    for mut cal_weights_b in cal_weights_tfb.rows_mut() {
+        cal_weights_b
+            .iter_mut()
+            .zip(baseline_weights.iter())
+            .for_each(|(w, baseline_weight)| {
+                *w = (f64::from(*w) * baseline_weight).max(0.0) as f32;
+            });
+    }
+    cal_weights_tfb
+}
+
+/// Set the model visibilities to the visibilities of the redundant groups
+/// (multiplied by the weights), as estimated with the calibration solutions
+/// for each time and frequency. Afterwards, the model can be treated like a
+/// sky model, e.g. to get residuals.
+pub(crate) fn set_model_vis(
+    redundant: RedundantCalVis,
+    sols: &IncompleteSolutions,
+    pols: Polarisations,
+    vis_data_tfb: ArrayView3<Jones<f32>>,
+    mut vis_model_tfb: ArrayViewMut3<Jones<f32>>,
+) {
+    for timeblock in sols.timeblocks.iter() {
+        let range = s![timeblock.range.clone(), .., ..];
+        vis_data_tfb
+            .slice(range)
+            .axis_iter(Axis(1))
+            .into_par_iter()
+            .zip(vis_model_tfb.slice_mut(range).axis_iter_mut(Axis(1)))
+            .zip(redundant.weights_tfb.slice(range).axis_iter(Axis(1)))
+            .zip(
+                sols.di_jones
+                    .slice(s![timeblock.index, .., ..])
+                    .axis_iter(Axis(1)),
+            )
+            .for_each(
+                |(((vis_data_tb, mut vis_model_tb), weights_tb), di_jones)| {
+                    let inv_di_jones = get_inv_di_jones(di_jones, pols);
+                    for ((data_b, mut model_b), weights_b) in vis_data_tb
+                        .outer_iter()
+                        .zip(vis_model_tb.outer_iter_mut())
+                        .zip(weights_tb.outer_iter())
+                    {
+                        let group_vis =
+                            get_group_vis(data_b, weights_b, redundant.groups, &inv_di_jones);
+                        set_group_model(
+                            model_b.view_mut(),
+                            weights_b,
+                            redundant.groups,
+                            &group_vis,
+                        );
+                    }
+                },
+            );
+    }
+}
+
+/// Calibrate the antennas of the array with redundant calibration (see the
+/// module documentation). The data visibilities must have had their weights
+/// applied, and `weights_tfb` are the calibration weights (see
+/// [`get_cal_weights`]).
+///
+/// This function is intended to be run in parallel; for that reason, no
+/// parallel code is inside this function.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn calibrate_redundant(
+    data_tfb: ArrayView3<Jones<f32>>,
+    weights_tfb: ArrayView3<f32>,
+    groups: &RedundantGroups,
+    mut di_jones: ArrayViewMut1<Jones<f64>>,
+    max_iterations: u32,
+    stop_threshold: f64,
+    min_threshold: f64,
+    solve_mode: SolveMode,
+    robust_weighting: RobustWeighting,
+    solver: Solver,
+    pols: Polarisations,
+) -> CalibrationResult {
+    assert_eq!(data_tfb.dim(), weights_tfb.dim());
+    let mut model_tfb = Array3::from_elem(data_tfb.dim(), Jones::default());
+    let mut old_jones = di_jones.to_owned();
+    let mut num_iterations = 0;
+    let mut precision = f64::INFINITY;
+
+    let mut result = None;
+    for _ in 0..MAX_REDUNDANT_ROUNDS {
+        // Failed tiles come back as NaN; start them from identity again.
+        di_jones.mapv_inplace(|j| if j.any_nan() { Jones::identity() } else { j });
+
+        // The sky changes with time (and frequency), so the group
+        // visibilities are estimated separately for each time and frequency.
+        let inv_di_jones = get_inv_di_jones(di_jones.view(), pols);
+        for ((data_b, mut model_b), weights_b) in data_tfb
+            .rows()
+            .into_iter()
+            .zip(model_tfb.rows_mut())
+            .zip(weights_tfb.rows())
+        {
+            let group_vis = get_group_vis(data_b, weights_b, groups, &inv_di_jones);
+            set_group_model(model_b.view_mut(), weights_b, groups, &group_vis);
+        }
+
+        let round_result = calibrate(
+            data_tfb,
+            model_tfb.view(),
+            di_jones.view_mut(),
+            max_iterations,
+            stop_threshold,
+            min_threshold,
+            solve_mode,
+            robust_weighting,
+            solver,
+            pols,
+        );
+        num_iterations += round_result.num_iterations;
+        // Calibration failed outright; don't bother trying again.
+        if di_jones.iter().all(|j| j.any_nan()) {
+            result = Some(round_result);
+            break;
+        }
+        normalise_gains(di_jones.view_mut());
+
+        // How much have the gains changed since the last round?
+        precision = di_jones
+            .iter()
+            .zip(old_jones.iter())
+            .filter(|(new, old)| !new.any_nan() && !old.any_nan())
+            .map(|(new, old)| (*new - *old).norm_sqr().into_iter().fold(0.0, f64::max))
+            .fold(0.0, f64::max);
+        old_jones.assign(&di_jones);
+        result = Some(round_result);
+        if precision < stop_threshold {
+            break;
+        }
+    }
+
+    let mut result = result.expect("at least one round of calibration is done");
+    result.num_iterations = num_iterations;
+    if result.converged && precision > min_threshold {
+        di_jones.fill(Jones::nan());
+        result.converged = false;
+    }
+    result.max_precision = result.max_precision.max(precision);
+    result
+}
+
+/// Invert the gains for correcting visibilities.
+fn get_inv_di_jones(di_jones: ArrayView1<Jones<f64>>, pols: Polarisations) -> Vec<Jones<f64>> {
+    // If we only have a single polarisation in the data, then we need to not
+    // do proper Jones matrix inversion, because all Jones matrices are
+    // singular.
+    di_jones
+        .iter()
+        .map(|j| match pols {
+            Polarisations::XX => {
+                Jones::from([j[0].inv(), c64::default(), c64::default(), c64::default()])
+            }
+            Polarisations::YY => {
+                Jones::from([c64::default(), c64::default(), c64::default(), j[3].inv()])
+            }
+            Polarisations::XX_XY_YX_YY | Polarisations::XX_YY | Polarisations::XX_YY_XY => j.inv(),
+        })
+        .collect()
+}
+
+/// Estimate the visibilities of each redundant group at a single time and
+/// frequency by averaging the gain-corrected visibilities of its baselines
+/// (weighted by the calibration weights). The data visibilities must have had
+/// their weights applied. Groups without any data are `None`.
+fn get_group_vis(
+    data_b: ArrayView1<Jones<f32>>,
+    weights_b: ArrayView1<f32>,
+    groups: &RedundantGroups,
+    inv_di_jones: &[Jones<f64>],
+) -> Vec<Option<Jones<f64>>> {
+    let num_tiles = inv_di_jones.len();
+
+    let mut sums = vec![(Jones::<f64>::default(), 0.0); groups.num_groups];
+    let mut i_tile1 = 0;
+    let mut i_tile2 = 0;
+    for ((data, weight), group) in data_b
+        .iter()
+        .zip(weights_b.iter())
+        .zip(groups.baseline_groups.iter())
+    {
+        i_tile2 += 1;
+        if i_tile2 == num_tiles {
+            i_tile1 += 1;
+            i_tile2 = i_tile1 + 1;
+        }
+
+        let (i_group, reversed) = match group {
+            Some(g) => *g,
+            None => continue,
+        };
+        let weight = f64::from(*weight);
+        if weight <= 0.0 {
+            continue;
+        }
+        let (inv1, inv2) = (inv_di_jones[i_tile1], inv_di_jones[i_tile2]);
+        if inv1.any_nan() || inv2.any_nan() {
+            continue;
+        }
+        // The data have been multiplied by the weight, so this sum is
+        // weighted by the weight squared, like in calibration.
+        let corrected = inv1 * Jones::<f64>::from(data) * inv2.h() * weight;
+        let sum = &mut sums[i_group];
+        sum.0 += if reversed { corrected.h() } else { corrected };
+        sum.1 += weight * weight;
+    }
+
+    sums.into_iter()
+        .map(|(sum, weight)| {
+            if weight > 0.0 {
+                Some(sum / weight)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Set model visibilities of a single time and frequency to their group
+/// visibilities multiplied by their calibration weights. Baselines without a
+/// group (or without a group visibility) get zeros.
+fn set_group_model(
+    mut model_b: ArrayViewMut1<Jones<f32>>,
+    weights_b: ArrayView1<f32>,
+    groups: &RedundantGroups,
+    group_vis: &[Option<Jones<f64>>],
+) {
+    for ((model, weight), group) in model_b
+        .iter_mut()
+        .zip(weights_b.iter())
+        .zip(groups.baseline_groups.iter())
+    {
+        *model = match group.and_then(|(i_group, reversed)| {
+            group_vis[i_group].map(|v| if reversed { v.h() } else { v })
+        }) {
+            Some(v) if *weight > 0.0 => Jones::from(v * f64::from(*weight)),
+            _ => Jones::default(),
+        };
+    }
+}
+
+/// Remove the overall amplitude and phase of each polarisation of the gains;
+/// these aren't determined by redundant calibration. The gains of each
+/// polarisation are scaled to have an average amplitude of 1 and an average
+/// phase of 0.
+fn normalise_gains(mut di_jones: ArrayViewMut1<Jones<f64>>) {
+    // The columns of the Jones matrices (elements 0 and 2 for X, 1 and 3 for
+    // Y) are affected by the same degeneracy; use the diagonal elements to
+    // find it. Multiplying by a diagonal matrix on the right scales the
+    // columns.
+    let factors = [0, 3].map(|i_diag| {
+        let (sum, amp_sum, count) = di_jones
+            .iter()
+            .filter(|j| !j.any_nan() && j[i_diag].norm_sqr() > 0.0)
+            .fold((c64::default(), 0.0, 0), |(sum, amp_sum, count), j| {
+                (
+                    sum + j[i_diag] / j[i_diag].norm(),
+                    amp_sum + j[i_diag].norm(),
+                    count + 1,
+                )
+            });
+        if count == 0 || sum.norm() == 0.0 {
+            c64::new(1.0, 0.0)
+        } else {
+            (sum / sum.norm()).conj() * (count as f64 / amp_sum)
+        }
+    });
+    let factor = Jones::from([factors[0], c64::default(), c64::default(), factors[1]]);
+    di_jones.mapv_inplace(|j| j * factor);
+}
@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use hifitime::Epoch;
+use marlu::{c64, Jones, XyzGeodetic};
+use ndarray::prelude::*;
+use vec1::vec1;
+
+use super::*;
+use crate::averaging::Timeblock;
+
+fn xyz(x: f64, y: f64) -> XyzGeodetic {
+    XyzGeodetic { x, y, z: 0.0 }
+}
+
+#[test]
+fn test_redundant_groups() {
+    // The last tile is slightly out of place, but within the tolerance.
+    let xyzs = [
+        xyz(14.0, 0.0),
+        xyz(0.0, 0.0),
+        xyz(28.0, 0.0),
+        xyz(42.0, 0.05),
+    ];
+    let groups = RedundantGroups::new(&xyzs, DEFAULT_REDUNDANCY_TOLERANCE);
+    assert_eq!(groups.num_groups, 2);
+    assert_eq!(
+        groups.baseline_groups,
+        [
+            // 0-1: +14 m
+            Some((0, false)),
+            // 0-2: -14 m
+            Some((0, true)),
+            // 0-3: -28 m
+            Some((1, false)),
+            // 1-2: -28 m
+            Some((1, false)),
+            // 1-3: -42 m; there are no other baselines this long.
+            None,
+            // 2-3: -14 m
+            Some((0, true)),
+        ]
+    );
+    assert_eq!(groups.num_redundant_baselines(), 5);
+
+    // With a smaller tolerance, the last tile has no redundant baselines.
+    let groups = RedundantGroups::new(&xyzs, 0.01);
+    assert_eq!(groups.num_groups, 1);
+    assert_eq!(groups.num_redundant_baselines(), 2);
+}
+
+/// The gain of a tile for a polarisation.
+fn gain(i_tile: usize, i_pol: usize) -> c64 {
+    let x = i_tile as f64 + 0.5 * i_pol as f64;
+    c64::from_polar(1.0 + 0.1 * x.sin(), 0.3 * (1.7 * x).cos())
+}
+
+#[test]
+fn test_calibrate_redundant_line() {
+    // Tiles in a line. Redundant calibration can't determine the overall
+    // amplitude and phase of the gains nor a phase gradient along the line, so
+    // only quantities independent of these are checked.
+    let num_tiles = 7;
+    let xyzs: Vec<XyzGeodetic> = (0..num_tiles).map(|i| xyz(14.0 * i as f64, 0.0)).collect();
+    let groups = RedundantGroups::new(&xyzs, DEFAULT_REDUNDANCY_TOLERANCE);
+    // The longest baseline isn't redundant.
+    assert_eq!(groups.num_groups, num_tiles - 2);
+
+    let gains: Vec<Jones<f64>> = (0..num_tiles)
+        .map(|i| Jones::from([gain(i, 0), c64::default(), c64::default(), gain(i, 1)]))
+        .collect();
+    let num_timesteps = 2;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let mut vis_data = Array3::from_elem((num_timesteps, 1, num_baselines), Jones::default());
+    for i_timestep in 0..num_timesteps {
+        let mut i_baseline = 0;
+        for i_tile1 in 0..num_tiles {
+            for i_tile2 in i_tile1 + 1..num_tiles {
+                // Each separation sees a different (but made-up) sky, which
+                // changes with time.
+                let sep = (i_tile2 - i_tile1) as f64;
+                let phase = sep * (1.0 + i_timestep as f64);
+                let true_vis = Jones::from([
+                    c64::from_polar(10.0 / sep, phase),
+                    c64::default(),
+                    c64::default(),
+                    c64::from_polar(8.0 / sep, -phase),
+                ]);
+                vis_data[(i_timestep, 0, i_baseline)] =
+                    Jones::from(gains[i_tile1] * true_vis * gains[i_tile2].h());
+                i_baseline += 1;
+            }
+        }
+    }
+    let mut vis_weights = Array3::from_elem(vis_data.dim(), 1.0);
+    prepare_cal_vis(&groups, vis_data.view_mut(), vis_weights.view_mut());
+    // The non-redundant baseline is flagged.
+    assert_eq!(vis_data[(0, 0, 5)], Jones::default());
+    assert!(vis_weights[(0, 0, 5)] < 0.0);
+    let cal_weights = get_cal_weights(vis_weights.view(), &vec![1.0; num_baselines]);
+    assert_eq!(cal_weights[(0, 0, 5)], 0.0);
+    assert_eq!(cal_weights[(0, 0, 0)], 1.0);
+
+    let mut di_jones = Array1::from_elem(num_tiles, Jones::identity());
+    let result = calibrate_redundant(
+        vis_data.view(),
+        cal_weights.view(),
+        &groups,
+        di_jones.view_mut(),
+        50,
+        1e-12,
+        1e-6,
+        SolveMode::Diagonal,
+        RobustWeighting::default(),
+        Solver::default(),
+        Polarisations::default(),
+    );
+    assert!(result.converged);
+    assert_eq!(result.num_failed, 0);
+
+    for (i_pol, i_diag) in [(0, 0), (1, 3)] {
+        // The average amplitude is normalised to 1.
+        let mean_amp =
+            (0..num_tiles).map(|i| gain(i, i_pol).norm()).sum::<f64>() / num_tiles as f64;
+        for i_tile in 0..num_tiles {
+            assert_abs_diff_eq!(
+                di_jones[i_tile][i_diag].norm(),
+                gain(i_tile, i_pol).norm() / mean_amp,
+                epsilon = 1e-5
+            );
+        }
+
+        // Second differences of the phases are independent of the overall
+        // phase and the phase gradient.
+        for i_tile in 1..num_tiles - 1 {
+            let second_diff = |g: &dyn Fn(usize) -> c64| {
+                (g(i_tile + 1) * g(i_tile - 1) / (g(i_tile) * g(i_tile))).arg()
+            };
+            assert_abs_diff_eq!(
+                second_diff(&|i| di_jones[i][i_diag]),
+                second_diff(&|i| gain(i, i_pol)),
+                epsilon = 1e-5
+            );
+        }
+    }
+
+    // With the solutions, the model is the data.
+    let timestamps = vec1![
+        Epoch::from_gpst_seconds(1090008640.0),
+        Epoch::from_gpst_seconds(1090008642.0)
+    ];
+    let timeblocks = vec1![Timeblock {
+        index: 0,
+        range: 0..num_timesteps,
+        timestamps: timestamps.clone(),
+        timesteps: vec1![0, 1],
+        median: timestamps[0],
+    }];
+    let sols = IncompleteSolutions {
+        di_jones: di_jones.into_shape((1, num_tiles, 1)).unwrap(),
+        timeblocks: &timeblocks,
+        chanblocks: &[],
+        max_iterations: 50,
+        stop_threshold: 1e-12,
+        min_threshold: 1e-6,
+        solve_mode: SolveMode::Diagonal,
+        solver: Solver::default(),
+    };
+    let mut vis_model = Array3::from_elem(vis_data.dim(), Jones::default());
+    set_model_vis(
+        RedundantCalVis {
+            groups: &groups,
+            weights_tfb: cal_weights.view(),
+        },
+        &sols,
+        Polarisations::default(),
+        vis_data.view(),
+        vis_model.view_mut(),
+    );
+    for i_timestep in 0..num_timesteps {
+        let mut i_baseline = 0;
+        for i_tile1 in 0..num_tiles {
+            for i_tile2 in i_tile1 + 1..num_tiles {
+                let model = Jones::<f64>::from(vis_model[(i_timestep, 0, i_baseline)]);
+                let corrupted =
+                    sols.di_jones[(0, i_tile1, 0)] * model * sols.di_jones[(0, i_tile2, 0)].h();
+                assert_abs_diff_eq!(
+                    corrupted,
+                    Jones::<f64>::from(vis_data[(i_timestep, 0, i_baseline)]),
+                    epsilon = 1e-4
+                );
+                i_baseline += 1;
+            }
+        }
+    }
+}
@@ -13,11 +13,14 @@ use std::{
 use approx::{assert_abs_diff_eq, assert_abs_diff_ne};
 use hifitime::{Duration, Epoch};
 use indicatif::{ProgressBar, ProgressDrawTarget};
-use marlu::Jones;
+use marlu::{c64, Jones};
 use ndarray::prelude::*;
 use vec1::{vec1, Vec1};
 
-use super::{calibrate, calibrate_timeblocks, DiCalParams, IncompleteSolutions};
+use super::{
+    calibrate, calibrate_timeblocks, get_initial_di_jones, DiCalParams, IncompleteSolutions,
+    RobustWeighting, SolveMode, Solver,
+};
 use crate::{
     averaging::{channels_to_chanblocks, timesteps_to_timeblocks, Chanblock, Spw, Timeblock},
     beam::NoBeam,
@@ -26,7 +29,7 @@ use crate::{
     io::read::{RawDataCorrections, RawDataReader},
     math::{is_prime, TileBaselineFlags},
     params::{InputVisParams, ModellingParams},
-    solutions::CalSolutionType,
+    solutions::{CalSolutionType, CalibrationSolutions, FreqInterpolation, TimeInterpolation},
     srclist::SourceList,
 };
 
@@ -74,6 +77,9 @@ fn test_calibrate_trivial() {
                 20,
                 1e-8,
                 1e-5,
+                SolveMode::default(),
+                RobustWeighting::default(),
+                Solver::default(),
                 Polarisations::default(),
             );
 
@@ -92,6 +98,156 @@ fn test_calibrate_trivial() {
     assert_abs_diff_eq!(di_jones, expected, epsilon = 1e-14);
 }
 
+/// As above, but with the solutions constrained. The data are "four times as
+/// bright as the model", so only the amplitudes can be fixed; a phase-only
+/// solve should leave the solutions as identity.
+#[test]
+fn test_calibrate_trivial_solve_modes() {
+    let num_tiles = 5;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let vis_shape = (1, 1, num_baselines);
+    let vis_data: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity() * 4.0);
+    let vis_model: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity());
+
+    for (solve_mode, expected) in [
+        (SolveMode::Diagonal, Jones::identity() * 2.0),
+        (SolveMode::AmplitudeOnly, Jones::identity() * 2.0),
+        (SolveMode::PhaseOnly, Jones::identity()),
+    ] {
+        let mut di_jones = Array1::from_elem(num_tiles, Jones::<f64>::identity());
+        let result = calibrate(
+            vis_data.view(),
+            vis_model.view(),
+            di_jones.view_mut(),
+            20,
+            1e-8,
+            1e-5,
+            solve_mode,
+            RobustWeighting::default(),
+            Solver::default(),
+            Polarisations::default(),
+        );
+
+        assert!(result.converged, "{solve_mode} did not converge");
+        assert_eq!(result.num_failed, 0);
+        let expected = Array1::from_elem(num_tiles, expected);
+        assert_abs_diff_eq!(di_jones, expected, epsilon = 1e-14);
+    }
+}
+
+/// As above, but with one baseline corrupted (e.g. by RFI). Ordinary least
+/// squares is biased by the outlier, but robust weighting isn't.
+#[test]
+fn test_calibrate_trivial_robust_weighting() {
+    let num_tiles = 30;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let vis_shape = (1, 1, num_baselines);
+    let mut vis_data: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity() * 4.0);
+    vis_data[(0, 0, 3)] = Jones::identity() * 400.0;
+    let vis_model: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity());
+
+    for (robust_weighting, max_error) in [
+        (RobustWeighting::None, None),
+        (RobustWeighting::Huber, Some(1e-4)),
+        (RobustWeighting::StudentT, Some(1e-6)),
+    ] {
+        let mut di_jones = Array1::from_elem(num_tiles, Jones::<f64>::identity());
+        let result = calibrate(
+            vis_data.view(),
+            vis_model.view(),
+            di_jones.view_mut(),
+            50,
+            1e-8,
+            1e-5,
+            SolveMode::default(),
+            robust_weighting,
+            Solver::default(),
+            Polarisations::default(),
+        );
+
+        assert!(result.converged, "{robust_weighting} did not converge");
+        assert_eq!(result.num_failed, 0);
+        let error = di_jones
+            .iter()
+            .map(|j| {
+                (*j - Jones::identity() * 2.0)
+                    .norm_sqr()
+                    .iter()
+                    .sum::<f64>()
+                    .sqrt()
+            })
+            .fold(0.0, f64::max);
+        match max_error {
+            None => assert!(error > 1.0, "{robust_weighting}: {error}"),
+            Some(max_error) => assert!(error < max_error, "{robust_weighting}: {error}"),
+        }
+    }
+}
+
+/// Noisy data with only a few tiles take many StefCal iterations to converge;
+/// Anderson acceleration should need far fewer, and arrive at the same
+/// solutions.
+#[test]
+fn test_calibrate_noisy_anderson() {
+    let num_tiles = 8;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let vis_shape = (1, 1, num_baselines);
+    let gain = |i_tile: usize, i_pol: usize| {
+        let (i, p) = (i_tile as f64, i_pol as f64);
+        c64::from_polar(1.0 + 0.3 * (i + p).sin(), 0.7 * i + 0.3 * p)
+    };
+    let mut vis_data: Array3<Jones<f32>> = Array3::zeros(vis_shape);
+    let mut i_bl = 0;
+    for i_tile1 in 0..num_tiles {
+        for i_tile2 in i_tile1 + 1..num_tiles {
+            let [xx, yy] = [0, 1].map(|i_pol| {
+                let k = i_bl as f64;
+                let p = i_pol as f64;
+                let noise = c64::new((7.0 * k + p).sin(), (13.0 * k + 2.0 * p).cos()) * 2.0;
+                gain(i_tile1, i_pol) * gain(i_tile2, i_pol).conj() + noise
+            });
+            vis_data[(0, 0, i_bl)] =
+                Jones::<f32>::from(Jones::from([xx, c64::default(), c64::default(), yy]));
+            i_bl += 1;
+        }
+    }
+    let vis_model: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity());
+
+    let mut results = vec![];
+    for solver in [Solver::Stefcal, Solver::Anderson] {
+        let mut di_jones = Array1::from_elem(num_tiles, Jones::<f64>::identity());
+        let result = calibrate(
+            vis_data.view(),
+            vis_model.view(),
+            di_jones.view_mut(),
+            300,
+            1e-10,
+            1e-5,
+            SolveMode::default(),
+            RobustWeighting::default(),
+            solver,
+            Polarisations::default(),
+        );
+        assert!(result.converged, "{solver} did not converge");
+        assert_eq!(result.num_failed, 0);
+        results.push((result.num_iterations, di_jones));
+    }
+
+    let (stefcal_iterations, stefcal_jones) = &results[0];
+    let (anderson_iterations, anderson_jones) = &results[1];
+    assert!(
+        *anderson_iterations < stefcal_iterations / 2,
+        "{anderson_iterations} vs. {stefcal_iterations}"
+    );
+    // The solutions are only determined up to a common phase, so compare them
+    // relative to the first tile.
+    for (s, a) in stefcal_jones.iter().zip(anderson_jones.iter()) {
+        let s = *s * stefcal_jones[0].h();
+        let a = *a * anderson_jones[0].h();
+        assert_abs_diff_eq!(s, a, epsilon = 1e-2);
+    }
+}
+
 /// As above, but make one Jones matrix much "bigger" than the rest. This should
 /// make the calibration solutions not match what we expected, but when it's
 /// flagged via the weights, things go back to normal.
@@ -147,6 +303,9 @@ fn test_calibrate_trivial_with_flags() {
                 20,
                 1e-8,
                 1e-5,
+                SolveMode::default(),
+                RobustWeighting::default(),
+                Solver::default(),
                 Polarisations::default(),
             );
 
@@ -200,6 +359,9 @@ fn test_calibrate_trivial_with_flags() {
                 20,
                 1e-8,
                 1e-5,
+                SolveMode::default(),
+                RobustWeighting::default(),
+                Solver::default(),
                 Polarisations::default(),
             );
 
@@ -235,7 +397,7 @@ fn test_calibrate_trivial_with_flags() {
 }
 
 /// The majority of parameters don't matter for these tests.
-fn get_default_params() -> DiCalParams {
+pub(crate) fn get_default_params() -> DiCalParams {
     let e = Epoch::from_gpst_seconds(1090008640.0);
     DiCalParams {
         input_vis_params: InputVisParams {
@@ -252,6 +414,8 @@ fn get_default_params() -> DiCalParams {
                 .unwrap(),
             ),
             solutions: None,
+            solutions_time_interpolation: TimeInterpolation::default(),
+            solutions_freq_interpolation: FreqInterpolation::default(),
             timeblocks: vec1![Timeblock {
                 index: 0,
                 range: 0..1,
@@ -285,6 +449,7 @@ fn get_default_params() -> DiCalParams {
         },
         beam: Box::new(NoBeam { num_tiles: 1 }),
         source_list: SourceList::new(),
+        model_vis_params: None,
         cal_timeblocks: vec1![Timeblock {
             index: 0,
             range: 0..1,
@@ -299,6 +464,20 @@ fn get_default_params() -> DiCalParams {
         max_iterations: 50,
         stop_threshold: 1e-6,
         min_threshold: 1e-3,
+        solve_mode: SolveMode::default(),
+        robust_weighting: RobustWeighting::default(),
+        solver: Solver::default(),
+        redundant_groups: None,
+        initial_di_jones: None,
+        outlier_params: None,
+        smooth_params: None,
+        xy_phase: false,
+        phase_reference: None,
+        joint_obs: vec![],
+        joint_phase_offsets: false,
+        chanblocks_per_chunk: None,
+        checkpoint_dir: None,
+        resume: false,
         output_solution_files: vec1![(PathBuf::from("asdf.fits"), CalSolutionType::Fits)],
         output_model_vis_params: None,
         modelling_params: ModellingParams {
@@ -360,6 +539,7 @@ fn incomplete_to_complete_trivial() {
         max_iterations: 50,
         stop_threshold: 1e-8,
         min_threshold: 1e-4,
+        solve_mode: SolveMode::default(),
     };
 
     let complete = incomplete.into_cal_sols(&params, None);
@@ -429,6 +609,7 @@ fn incomplete_to_complete_flags_simple() {
         max_iterations: 50,
         stop_threshold: 1e-8,
         min_threshold: 1e-4,
+        solve_mode: SolveMode::default(),
     };
 
     let complete = incomplete.into_cal_sols(&params, None);
@@ -512,6 +693,7 @@ fn incomplete_to_complete_flags_simple2() {
         max_iterations: 50,
         stop_threshold: 1e-8,
         min_threshold: 1e-4,
+        solve_mode: SolveMode::default(),
     };
 
     let complete = incomplete.into_cal_sols(&params, None);
@@ -603,6 +785,7 @@ fn incomplete_to_complete_flags_complex() {
         max_iterations: 50,
         stop_threshold: 1e-8,
         min_threshold: 1e-4,
+        solve_mode: SolveMode::default(),
     };
 
     let complete = incomplete.into_cal_sols(&params, None);
@@ -688,9 +871,14 @@ fn test_multiple_timeblocks_behave() {
         vis_model.view(),
         &timeblocks,
         &spws.first().unwrap().chanblocks,
+        None,
         10,
         1e-8,
         1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
         Polarisations::default(),
         false,
     );
@@ -703,6 +891,135 @@ fn test_multiple_timeblocks_behave() {
     assert_abs_diff_eq!(incomplete_sols.di_jones, expected, epsilon = 1e-14);
 }
 
+#[test]
+fn test_multiple_timeblocks_use_their_own_initial_solutions() {
+    let timestamps = vec1![
+        Epoch::from_gpst_seconds(1090008640.0),
+        Epoch::from_gpst_seconds(1090008642.0),
+        Epoch::from_gpst_seconds(1090008644.0),
+    ];
+    let num_timesteps = timestamps.len();
+    let num_tiles = 5;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let num_chanblocks = 1;
+
+    // Each timestep has different gains.
+    let vis_shape = (num_timesteps, num_chanblocks, num_baselines);
+    let vis_data: Array3<Jones<f32>> = Array3::from_shape_fn(vis_shape, |(i_timestep, _, _)| {
+        Jones::identity() * ((i_timestep + 1) * (i_timestep + 1)) as f32
+    });
+    let vis_model: Array3<Jones<f32>> = Array3::from_elem(vis_shape, Jones::identity());
+
+    let timeblocks = timesteps_to_timeblocks(
+        &timestamps,
+        Duration::from_seconds(2.0),
+        NonZeroUsize::new(1).unwrap(),
+        None,
+    );
+    let spws = channels_to_chanblocks(
+        &[150000000],
+        40e3 as u64,
+        NonZeroUsize::new(1).unwrap(),
+        &HashSet::new(),
+    );
+
+    // The initial solutions are already correct for each timeblock, so a
+    // single iteration must not move them.
+    let expected = Array3::from_shape_fn(
+        (num_timesteps, num_tiles, num_chanblocks),
+        |(i_timeblock, _, _)| Jones::identity() * (i_timeblock + 1) as f64,
+    );
+    let (incomplete_sols, _) = calibrate_timeblocks(
+        vis_data.view(),
+        vis_model.view(),
+        &timeblocks,
+        &spws.first().unwrap().chanblocks,
+        Some(expected.clone()),
+        1,
+        1e-8,
+        1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
+        Polarisations::default(),
+        false,
+    );
+    assert_abs_diff_eq!(incomplete_sols.di_jones, expected, epsilon = 1e-6);
+}
+
+#[test]
+fn test_initial_di_jones_mapping() {
+    let timestamps = vec1![Epoch::from_gpst_seconds(1090008640.0)];
+    let timeblocks = timesteps_to_timeblocks(
+        &timestamps,
+        Duration::from_seconds(2.0),
+        NonZeroUsize::new(1).unwrap(),
+        None,
+    );
+    // The middle chanblock is flagged.
+    let chanblocks = [
+        Chanblock {
+            chanblock_index: 0,
+            unflagged_index: 0,
+            freq: 150e6,
+        },
+        Chanblock {
+            chanblock_index: 2,
+            unflagged_index: 1,
+            freq: 150.08e6,
+        },
+    ];
+    let tile_names = ["a", "b", "c"].map(|s| s.to_string());
+    let flagged_tiles = HashSet::from([1]);
+
+    // Tiles and chanblocks are matched by name and frequency. "b" is flagged
+    // and the last chanblock has no corresponding frequency in the solutions.
+    let mut sols = CalibrationSolutions {
+        di_jones: Array3::from_shape_fn((1, 2, 2), |(_, i_tile, i_chan)| {
+            Jones::identity() * (10 * i_tile + i_chan + 1) as f64
+        }),
+        tile_names: Some(vec1!["c".to_string(), "a".to_string()]),
+        chanblock_freqs: Some(vec1![150e6, 150.04e6]),
+        ..Default::default()
+    };
+    // NaN solutions are ignored.
+    sols.di_jones[(0, 0, 0)] = Jones::identity() * f64::NAN;
+    let initial_di_jones = get_initial_di_jones(
+        &sols,
+        &timeblocks,
+        &chanblocks,
+        3,
+        &tile_names,
+        &flagged_tiles,
+    );
+    assert_eq!(initial_di_jones.dim(), (1, 2, 2));
+    assert_abs_diff_eq!(initial_di_jones[(0, 0, 0)], Jones::identity() * 11.0);
+    assert_abs_diff_eq!(initial_di_jones[(0, 0, 1)], Jones::identity());
+    assert_abs_diff_eq!(initial_di_jones[(0, 1, 0)], Jones::identity());
+    assert_abs_diff_eq!(initial_di_jones[(0, 1, 1)], Jones::identity());
+
+    // Without names and frequencies, indices are used.
+    let sols = CalibrationSolutions {
+        di_jones: Array3::from_shape_fn((1, 3, 3), |(_, i_tile, i_chan)| {
+            Jones::identity() * (10 * i_tile + i_chan + 1) as f64
+        }),
+        ..Default::default()
+    };
+    let initial_di_jones = get_initial_di_jones(
+        &sols,
+        &timeblocks,
+        &chanblocks,
+        3,
+        &tile_names,
+        &flagged_tiles,
+    );
+    assert_abs_diff_eq!(initial_di_jones[(0, 0, 0)], Jones::identity());
+    assert_abs_diff_eq!(initial_di_jones[(0, 0, 1)], Jones::identity() * 3.0);
+    assert_abs_diff_eq!(initial_di_jones[(0, 1, 0)], Jones::identity() * 21.0);
+    assert_abs_diff_eq!(initial_di_jones[(0, 1, 1)], Jones::identity() * 23.0);
+}
+
 #[test]
 fn test_chanblocks_without_data_have_nan_solutions() {
     let timestamps = vec1![
@@ -738,9 +1055,14 @@ fn test_chanblocks_without_data_have_nan_solutions() {
         vis_model.view(),
         &timeblocks,
         &fences[0].chanblocks,
+        None,
         10,
         1e-8,
         1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
         Polarisations::default(),
         false,
     );
@@ -759,9 +1081,14 @@ fn test_chanblocks_without_data_have_nan_solutions() {
         vis_model.view(),
         &timeblocks,
         &fences[0].chanblocks,
+        None,
         10,
         1e-8,
         1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
         Polarisations::default(),
         false,
     );
@@ -811,6 +1138,10 @@ fn test_recalibrating_failed_chanblocks() {
         10,
         1e-8,
         1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
         Polarisations::default(),
         pb.clone(),
         false,
@@ -834,6 +1165,10 @@ fn test_recalibrating_failed_chanblocks() {
         10,
         1e-8,
         1e-4,
+        SolveMode::default(),
+        RobustWeighting::default(),
+        Solver::default(),
+        None,
         Polarisations::default(),
         pb,
         false,
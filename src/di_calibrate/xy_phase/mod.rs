@@ -0,0 +1,196 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to determine the phase between the X and Y polarisations ("XY-phase")
+//! after DI calibration.
+//!
+//! DI calibration can't constrain a phase between X and Y that is common to
+//! all tiles; any such phase cancels in the parallel-hand visibilities, and an
+//! unpolarised sky model has no cross-hand signal to fix it. If the sky model
+//! has polarised sources, the cross-hand visibilities of the calibrated data
+//! can be compared with the model to measure this phase in each chanblock. A
+//! phase offset and a cross-hand delay are then fitted to the measured phases
+//! to give the correction.
+
+#[cfg(test)]
+mod tests;
+
+use std::f64::consts::TAU;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use rayon::prelude::*;
+
+use crate::{
+    averaging::{Chanblock, Timeblock},
+    solutions::{CalibrationSolutions, XyPhase},
+};
+
+/// The number of times to refine the cross-hand delay after the coarse search.
+const NUM_DELAY_REFINEMENTS: usize = 3;
+
+/// Measure and fit the XY-phase of calibrated data. The visibilities are
+/// expected to have had their weights applied, as is done for calibration.
+/// `sols` are the complete solutions made from the visibilities, and
+/// `unflagged_tiles` are the indices of the tiles in the visibilities into all
+/// tiles of the solutions.
+///
+/// `None` is returned if there is no cross-hand signal in the model (i.e. the
+/// sky model is unpolarised).
+pub(crate) fn get_xy_phase(
+    vis_data_tfb: ArrayView3<Jones<f32>>,
+    vis_model_tfb: ArrayView3<Jones<f32>>,
+    sols: &CalibrationSolutions,
+    timeblocks: &[Timeblock],
+    chanblocks: &[Chanblock],
+    unflagged_tiles: &[usize],
+) -> Option<XyPhase> {
+    let num_unflagged_tiles = unflagged_tiles.len();
+    let total_num_chanblocks = sols.di_jones.len_of(Axis(2));
+    assert_eq!(vis_data_tfb.dim(), vis_model_tfb.dim());
+
+    // For each chanblock, sum the products of the calibrated cross-hand
+    // visibilities with the model. The phase of the sum is the XY-phase.
+    let sums: Vec<(usize, f64, c64)> = chanblocks
+        .par_iter()
+        .map(|chanblock| {
+            let i_chanblock = usize::from(chanblock.unflagged_index);
+            let mut sum = c64::default();
+            for timeblock in timeblocks {
+                let di_jones = sols.di_jones.slice(s![
+                    timeblock.index,
+                    ..,
+                    usize::from(chanblock.chanblock_index)
+                ]);
+                for i_time in timeblock.range.clone() {
+                    let mut i_tile1 = 0;
+                    let mut i_tile2 = 0;
+                    for (data, model) in vis_data_tfb
+                        .slice(s![i_time, i_chanblock, ..])
+                        .iter()
+                        .zip(vis_model_tfb.slice(s![i_time, i_chanblock, ..]))
+                    {
+                        i_tile2 += 1;
+                        if i_tile2 == num_unflagged_tiles {
+                            i_tile1 += 1;
+                            i_tile2 = i_tile1 + 1;
+                        }
+
+                        let (j1, j2) = (
+                            di_jones[unflagged_tiles[i_tile1]],
+                            di_jones[unflagged_tiles[i_tile2]],
+                        );
+                        if j1.any_nan() || j2.any_nan() {
+                            continue;
+                        }
+                        // The solutions are stored as data-to-model, so they
+                        // can be applied directly. Flagged visibilities are 0.
+                        let calibrated = j1 * Jones::<f64>::from(data) * j2.h();
+                        let model = Jones::<f64>::from(model);
+                        sum += calibrated[1].conj() * model[1] + calibrated[2] * model[2].conj();
+                    }
+                }
+            }
+            (usize::from(chanblock.chanblock_index), chanblock.freq, sum)
+        })
+        .collect();
+
+    let mut measured_phases = Array1::from_elem(total_num_chanblocks, f64::NAN);
+    let mut freqs = Vec::with_capacity(sums.len());
+    let mut weighted_phasors = Vec::with_capacity(sums.len());
+    for (i_chanblock, freq, sum) in sums {
+        if sum.norm() > 0.0 && sum.is_finite() {
+            measured_phases[i_chanblock] = sum.arg();
+            freqs.push(freq);
+            weighted_phasors.push(sum);
+        }
+    }
+    let (phase, delay, freq_centre) = fit_xy_phase(&freqs, &weighted_phasors)?;
+
+    // Evaluate the fit for all chanblocks with known frequencies.
+    let mut phases = Array1::from_elem(total_num_chanblocks, f64::NAN);
+    match sols.chanblock_freqs.as_ref() {
+        Some(all_freqs) => {
+            for (p, &freq) in phases.iter_mut().zip(all_freqs.iter()) {
+                *p = phase + TAU * (freq - freq_centre) * delay;
+            }
+        }
+        None => {
+            for chanblock in chanblocks {
+                phases[usize::from(chanblock.chanblock_index)] =
+                    phase + TAU * (chanblock.freq - freq_centre) * delay;
+            }
+        }
+    }
+
+    Some(XyPhase {
+        phases,
+        measured_phases,
+        delay,
+    })
+}
+
+/// Fit a phase and a delay to complex numbers (whose phases are the XY-phases
+/// and whose amplitudes are their weights) at the specified frequencies. The
+/// returned tuple is the phase at the reference frequency, the delay and the
+/// reference frequency. `None` is returned if there is nothing to fit.
+fn fit_xy_phase(freqs: &[f64], weighted_phasors: &[c64]) -> Option<(f64, f64, f64)> {
+    if freqs.is_empty() {
+        return None;
+    }
+    let (min_freq, max_freq) = freqs
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &f| {
+            (min.min(f), max.max(f))
+        });
+    let freq_centre = (min_freq + max_freq) / 2.0;
+    let fit_delay = |delay: f64| -> c64 {
+        freqs
+            .iter()
+            .zip(weighted_phasors)
+            .map(|(f, z)| z * c64::cis(-TAU * (f - freq_centre) * delay))
+            .sum()
+    };
+
+    let bandwidth = max_freq - min_freq;
+    let mut sorted_freqs = freqs.to_vec();
+    sorted_freqs.sort_unstable_by(|a, b| a.total_cmp(b));
+    let min_df = sorted_freqs
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|df| *df > 0.0)
+        .fold(f64::INFINITY, f64::min);
+    // With only one frequency, no delay can be fitted.
+    if bandwidth <= 0.0 || !min_df.is_finite() {
+        return Some((fit_delay(0.0).arg(), 0.0, freq_centre));
+    }
+
+    // Coarse search over all delays that aren't aliased.
+    let max_delay = 1.0 / (2.0 * min_df);
+    let mut delay_step = 1.0 / (4.0 * bandwidth);
+    let num_steps = (max_delay / delay_step).floor() as i64;
+    let (mut best_sum, mut best_delay) = (fit_delay(0.0), 0.0);
+    for i in -num_steps..=num_steps {
+        let delay = i as f64 * delay_step;
+        let sum = fit_delay(delay);
+        if sum.norm() > best_sum.norm() {
+            (best_sum, best_delay) = (sum, delay);
+        }
+    }
+
+    // Fine searches around the best delay.
+    for _ in 0..NUM_DELAY_REFINEMENTS {
+        let centre = best_delay;
+        for i in -10..=10 {
+            let delay = centre + i as f64 * delay_step / 10.0;
+            let sum = fit_delay(delay);
+            if sum.norm() > best_sum.norm() {
+                (best_sum, best_delay) = (sum, delay);
+            }
+        }
+        delay_step /= 10.0;
+    }
+
+    Some((best_sum.arg(), best_delay, freq_centre))
+}
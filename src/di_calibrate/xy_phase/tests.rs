@@ -0,0 +1,169 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::f64::consts::TAU;
+
+use approx::assert_abs_diff_eq;
+use hifitime::Epoch;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use vec1::{vec1, Vec1};
+
+use super::*;
+
+const NUM_TILES: usize = 6;
+const NUM_CHANBLOCKS: usize = 24;
+const XY_PHASE: f64 = 0.7;
+const XY_DELAY: f64 = 4e-9;
+
+fn get_freqs() -> Vec<f64> {
+    (0..NUM_CHANBLOCKS)
+        .map(|i| 167e6 + i as f64 * 1.28e6)
+        .collect()
+}
+
+fn get_true_xy_phase(freq: f64) -> f64 {
+    XY_PHASE + TAU * (freq - 181.72e6) * XY_DELAY
+}
+
+fn get_timeblocks() -> Vec1<Timeblock> {
+    let e = Epoch::from_gpst_seconds(1090008640.0);
+    vec1![Timeblock {
+        index: 0,
+        range: 0..2,
+        timestamps: vec1![e],
+        timesteps: vec1![0],
+        median: e,
+    }]
+}
+
+fn get_chanblocks() -> Vec<Chanblock> {
+    get_freqs()
+        .into_iter()
+        .enumerate()
+        .map(|(i, freq)| Chanblock {
+            chanblock_index: i as u16,
+            unflagged_index: i as u16,
+            freq,
+        })
+        .collect()
+}
+
+/// Make model visibilities of a polarised source and data visibilities that
+/// have the XY-phase (but are otherwise perfectly calibrated).
+fn get_vis(polarised: bool) -> (Array3<Jones<f32>>, Array3<Jones<f32>>) {
+    let num_baselines = NUM_TILES * (NUM_TILES - 1) / 2;
+    let freqs = get_freqs();
+    let vis_model =
+        Array3::from_shape_fn((2, NUM_CHANBLOCKS, num_baselines), |(i_time, _, i_bl)| {
+            let x = (i_time + i_bl) as f64;
+            let (i, q, u) = (1.0 + 0.1 * x, 0.1 * x.cos(), 0.2 + 0.05 * x.sin());
+            let (q, u) = if polarised { (q, u) } else { (0.0, 0.0) };
+            Jones::from([
+                c64::new(i + q, 0.0),
+                c64::new(u, 0.0),
+                c64::new(u, 0.0),
+                c64::new(i - q, 0.0),
+            ])
+        });
+    let mut vis_data = vis_model.clone();
+    for ((_, i_chan, _), data) in vis_data.indexed_iter_mut() {
+        let xy = Jones::from([
+            c64::new(1.0, 0.0),
+            c64::default(),
+            c64::default(),
+            c64::cis(get_true_xy_phase(freqs[i_chan])),
+        ]);
+        *data = Jones::from(xy * Jones::<f64>::from(*data) * xy.h());
+    }
+    (vis_data, vis_model)
+}
+
+fn get_sols() -> CalibrationSolutions {
+    CalibrationSolutions {
+        di_jones: Array3::from_elem((1, NUM_TILES, NUM_CHANBLOCKS), Jones::identity()),
+        chanblock_freqs: Some(Vec1::try_from_vec(get_freqs()).unwrap()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_xy_phase_is_recovered() {
+    let (vis_data, vis_model) = get_vis(true);
+    let mut sols = get_sols();
+    let unflagged_tiles = (0..NUM_TILES).collect::<Vec<_>>();
+    let xy_phase = get_xy_phase(
+        vis_data.view(),
+        vis_model.view(),
+        &sols,
+        &get_timeblocks(),
+        &get_chanblocks(),
+        &unflagged_tiles,
+    )
+    .unwrap();
+
+    assert_abs_diff_eq!(xy_phase.delay, XY_DELAY, epsilon = 1e-11);
+    for ((&phase, &measured), freq) in xy_phase
+        .phases
+        .iter()
+        .zip(xy_phase.measured_phases.iter())
+        .zip(get_freqs())
+    {
+        let expected = c64::cis(get_true_xy_phase(freq));
+        // Compare phasors to avoid phase wrapping.
+        assert_abs_diff_eq!((c64::cis(phase) - expected).norm(), 0.0, epsilon = 2e-3);
+        assert_abs_diff_eq!((c64::cis(measured) - expected).norm(), 0.0, epsilon = 1e-6);
+    }
+
+    // Applying the XY-phase to the solutions removes it from the data.
+    xy_phase.apply(sols.di_jones.slice_mut(s![0, .., ..]));
+    for ((i_time, i_chan, i_bl), data) in vis_data.indexed_iter() {
+        let j = sols.di_jones[(0, 0, i_chan)];
+        let corrected = j * Jones::<f64>::from(*data) * j.h();
+        let diff = corrected - Jones::<f64>::from(vis_model[(i_time, i_chan, i_bl)]);
+        assert_abs_diff_eq!(diff.norm_sqr().iter().sum::<f64>(), 0.0, epsilon = 1e-5);
+    }
+}
+
+#[test]
+fn test_xy_phase_flagged_tiles_and_chanblocks() {
+    let (vis_data, vis_model) = get_vis(true);
+    let mut sols = get_sols();
+    // The last tile and the third chanblock are flagged in the solutions.
+    sols.di_jones
+        .slice_mut(s![.., NUM_TILES - 1, ..])
+        .fill(Jones::nan());
+    sols.di_jones.slice_mut(s![.., .., 2]).fill(Jones::nan());
+    let unflagged_tiles = (0..NUM_TILES).collect::<Vec<_>>();
+    let xy_phase = get_xy_phase(
+        vis_data.view(),
+        vis_model.view(),
+        &sols,
+        &get_timeblocks(),
+        &get_chanblocks(),
+        &unflagged_tiles,
+    )
+    .unwrap();
+
+    assert!(xy_phase.measured_phases[2].is_nan());
+    // The fitted phases are still available for the flagged chanblock.
+    assert!(xy_phase.phases[2].is_finite());
+    assert_abs_diff_eq!(xy_phase.delay, XY_DELAY, epsilon = 1e-11);
+}
+
+#[test]
+fn test_xy_phase_needs_polarised_model() {
+    let (vis_data, vis_model) = get_vis(false);
+    let sols = get_sols();
+    let unflagged_tiles = (0..NUM_TILES).collect::<Vec<_>>();
+    let xy_phase = get_xy_phase(
+        vis_data.view(),
+        vis_model.view(),
+        &sols,
+        &get_timeblocks(),
+        &get_chanblocks(),
+        &unflagged_tiles,
+    );
+    assert!(xy_phase.is_none());
+}
@@ -617,9 +617,26 @@ impl RawDataReader {
                 .expect("at least one coarse channel provided")
                 + 1;
 
-        // Read in the data via mwalib.
+        // Only read the coarse channels that have unflagged fine channels. When
+        // calibrating in chunks, most of the band is flagged for each chunk,
+        // so this avoids reading and correcting data that would be discarded.
         let metafits_context = &self.mwalib_context.metafits_context;
         let fine_chans_per_coarse = metafits_context.num_corr_fine_chans_per_coarse;
+        let mut needed_coarse_chans = (0..gpubox_channels.len()).filter(|i_cc| {
+            (i_cc * fine_chans_per_coarse..(i_cc + 1) * fine_chans_per_coarse)
+                .any(|i_chan| !flagged_fine_chans.contains(&(i_chan as u16)))
+        });
+        let (first_cc, last_cc) = match needed_coarse_chans.next() {
+            Some(first) => (first, needed_coarse_chans.last().unwrap_or(first)),
+            // Every channel is flagged; there's nothing to write out.
+            None => return Ok(()),
+        };
+        let gpubox_channels = gpubox_channels.start + first_cc..gpubox_channels.start + last_cc + 1;
+        let coarse_chan_range =
+            coarse_chan_range.start + first_cc..coarse_chan_range.start + last_cc + 1;
+        let first_fine_chan = first_cc * fine_chans_per_coarse;
+
+        // Read in the data via mwalib.
         let size = fine_chans_per_coarse * metafits_context.num_baselines;
         let full_size = size * gpubox_channels.len();
         let mut jones_array_tfb = vec![Jones::default(); full_size];
@@ -734,7 +751,7 @@ impl RawDataReader {
         let data_weights_fb = data_weights_fb.into_raw_vec();
 
         let chan_flags = (0..num_chans)
-            .map(|i_chan| flagged_fine_chans.contains(&(i_chan as u16)))
+            .map(|i_chan| flagged_fine_chans.contains(&((first_fine_chan + i_chan) as u16)))
             .collect::<Vec<_>>();
 
         // If applicable, write the cross-correlation visibilities to our
@@ -56,8 +56,10 @@ pub use beam::{create_beam_object, Delays};
 pub use cli::Hyperdrive;
 pub use cli::HyperdriveError;
 pub use context::Polarisations;
-pub use di_calibrate::calibrate_timeblocks;
+pub use di_calibrate::{
+    calibrate_timeblocks, redundant::RedundantGroups, RobustWeighting, SolveMode, Solver,
+};
 pub use io::read::{CrossData, MsReader, RawDataCorrections, RawDataReader, UvfitsReader};
 pub use math::TileBaselineFlags;
 pub use model::ModelDevice;
-pub use solutions::CalibrationSolutions;
+pub use solutions::{CalibrationSolutions, ResidualStat, ResidualStats, XyPhase};
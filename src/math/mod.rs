@@ -10,6 +10,7 @@ mod tests;
 use std::collections::{HashMap, HashSet};
 
 use hifitime::{Epoch, TimeUnits};
+use ndarray::prelude::*;
 
 /// Is the supplied number prime? This isn't necessarily efficient code; it's
 /// used just for testing. Stolen from
@@ -42,6 +43,76 @@ pub(crate) fn average_epoch<I: IntoIterator<Item = Epoch>>(es: I) -> Epoch {
     Epoch::from_gpst_seconds(average).round(10.milliseconds())
 }
 
+/// Solve the weighted linear least-squares problem `a x = b` via the normal
+/// equations. `a` has a row for each of the `b` values, which are weighted by
+/// `weights`. `None` is returned if the system is singular.
+pub(crate) fn least_squares(
+    a: ArrayView2<f64>,
+    b: ArrayView1<f64>,
+    weights: ArrayView1<f64>,
+) -> Option<Array1<f64>> {
+    let n = a.len_of(Axis(1));
+    let mut ata = Array2::<f64>::zeros((n, n));
+    let mut atb = Array1::<f64>::zeros(n);
+    for ((row, &b), &w) in a.outer_iter().zip(b.iter()).zip(weights.iter()) {
+        for i in 0..n {
+            atb[i] += w * row[i] * b;
+            for j in 0..n {
+                ata[(i, j)] += w * row[i] * row[j];
+            }
+        }
+    }
+
+    // Gaussian elimination with partial pivoting.
+    let scale = ata.iter().fold(0.0_f64, |acc, v: &f64| acc.max(v.abs()));
+    for i_col in 0..n {
+        let i_pivot = (i_col..n)
+            .max_by(|&i, &j| ata[(i, i_col)].abs().total_cmp(&ata[(j, i_col)].abs()))
+            .expect("range isn't empty");
+        if ata[(i_pivot, i_col)].abs() <= scale * 1e-14 {
+            return None;
+        }
+        if i_pivot != i_col {
+            for j in 0..n {
+                ata.swap((i_pivot, j), (i_col, j));
+            }
+            atb.swap(i_pivot, i_col);
+        }
+        for i_row in i_col + 1..n {
+            let factor = ata[(i_row, i_col)] / ata[(i_col, i_col)];
+            for j in i_col..n {
+                ata[(i_row, j)] -= factor * ata[(i_col, j)];
+            }
+            atb[i_row] -= factor * atb[i_col];
+        }
+    }
+    let mut x = Array1::<f64>::zeros(n);
+    for i in (0..n).rev() {
+        let sum: f64 = (i + 1..n).map(|j| ata[(i, j)] * x[j]).sum();
+        x[i] = (atb[i] - sum) / ata[(i, i)];
+    }
+    Some(x)
+}
+
+/// Get the median of some (non-NaN) numbers. The numbers are re-ordered.
+pub(crate) fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mid = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
+    Some(*median)
+}
+
+/// Get a robust estimate of the standard deviation of some (non-NaN) numbers
+/// about `centre`, i.e. their median absolute deviation from `centre`, scaled
+/// so that it matches the standard deviation of normally-distributed numbers.
+/// The numbers are replaced by their absolute deviations.
+pub(crate) fn robust_sigma(values: &mut [f64], centre: f64) -> Option<f64> {
+    values.iter_mut().for_each(|v| *v = (*v - centre).abs());
+    median(values).map(|m| 1.4826 * m)
+}
+
 /// Information on flagged tiles, baselines and maps to and from array indices.
 pub struct TileBaselineFlags {
     /// Map between a pair of tile numbers and its unflagged *cross-correlation*
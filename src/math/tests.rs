@@ -4,6 +4,7 @@
 
 use approx::assert_abs_diff_eq;
 use hifitime::{Epoch, TimeUnits};
+use ndarray::prelude::*;
 
 use super::*;
 
@@ -145,3 +146,43 @@ fn test_hifitime_works_as_expected() {
     let e = Epoch::from_gpst_seconds(1090008640.26);
     assert_abs_diff_eq!(e.round(10.milliseconds()).to_gpst_seconds(), 1090008640.26);
 }
+
+#[test]
+fn test_least_squares() {
+    // Fit y = 1 + 2x - 3x^2 exactly.
+    let xs: [f64; 6] = [-2.0, -1.0, 0.0, 0.5, 1.0, 3.0];
+    let a = Array2::from_shape_fn((xs.len(), 3), |(i, j)| xs[i].powi(j as i32));
+    let b = Array1::from_iter(xs.iter().map(|x| 1.0 + 2.0 * x - 3.0 * x * x));
+    let weights = Array1::ones(xs.len());
+    let result = least_squares(a.view(), b.view(), weights.view()).unwrap();
+    assert_abs_diff_eq!(result, array![1.0, 2.0, -3.0], epsilon = 1e-10);
+
+    // A zero weight removes the influence of a bad point.
+    let mut b = b;
+    b[2] = 1000.0;
+    let mut weights = weights;
+    weights[2] = 0.0;
+    let result = least_squares(a.view(), b.view(), weights.view()).unwrap();
+    assert_abs_diff_eq!(result, array![1.0, 2.0, -3.0], epsilon = 1e-10);
+
+    // Not enough information.
+    let a = Array2::from_elem((3, 2), 1.0);
+    assert!(least_squares(a.view(), b.slice(s![..3]), weights.slice(s![..3])).is_none());
+}
+
+#[test]
+fn test_median() {
+    assert!(median(&mut []).is_none());
+    assert_abs_diff_eq!(median(&mut [3.0]).unwrap(), 3.0);
+    assert_abs_diff_eq!(median(&mut [5.0, -1.0, 3.0, 100.0, 2.0]).unwrap(), 3.0);
+}
+
+#[test]
+fn test_robust_sigma() {
+    assert!(robust_sigma(&mut [], 0.0).is_none());
+
+    // The absolute deviations from 3 are [2, 4, 0, 97, 1]; the outlier doesn't
+    // affect the result.
+    let mut values = [5.0, -1.0, 3.0, 100.0, 2.0];
+    assert_abs_diff_eq!(robust_sigma(&mut values, 3.0).unwrap(), 1.4826 * 2.0);
+}
@@ -3,6 +3,10 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::{
+    hash::{Hash, Hasher},
+    iter,
+    num::NonZeroUsize,
+    ops::Range,
     path::PathBuf,
     thread::{self, ScopedJoinHandle},
 };
@@ -21,19 +25,35 @@ use rayon::prelude::*;
 use scopeguard::defer_on_unwind;
 use vec1::Vec1;
 
-use super::{InputVisParams, ModellingParams, OutputVisParams};
+use super::{
+    resources::{get_read_memory, ResourceEstimate},
+    InputVisParams, ModelVisParams, ModellingParams, OutputVisParams,
+};
 use crate::{
-    averaging::Timeblock,
+    averaging::{Chanblock, Spw, Timeblock},
     beam::Beam,
+    cli::Warn,
     context::Polarisations,
-    di_calibrate::calibrate_timeblocks,
+    di_calibrate::{
+        calibrate_timeblocks,
+        checkpoint::{Checkpoint, CheckpointError, Fingerprint, ModelCheckpoint},
+        joint::{apply_phase_offsets, get_phase_offsets, JOINT_PHASE_OFFSET_ROUNDS},
+        redundant::{self, RedundantCalVis, RedundantGroups},
+        xy_phase::get_xy_phase,
+        IncompleteSolutions, RobustWeighting, SolveMode, Solver,
+    },
     io::{
         read::VisReadError,
         write::{write_vis, VisTimestep, VisWriteError},
     },
     misc::expensive_op,
-    model::{new_sky_modeller, ModelError},
-    solutions::CalSolutionType,
+    model::new_sky_modeller,
+    solutions::{
+        outliers::{find_outliers, flag_outliers, OutlierParams},
+        reference::{get_fallback_ref_tile, reference_phases, PhaseReferenceError},
+        smooth::{smooth, SmoothParams},
+        CalSolutionType, PhaseReference, ResidualStats,
+    },
     srclist::SourceList,
     CalibrationSolutions, PROGRESS_BARS,
 };
@@ -49,6 +69,10 @@ pub(crate) struct DiCalParams {
     /// The sky-model source list.
     pub(crate) source_list: SourceList,
 
+    /// Precomputed sky-model visibilities. If these are available, they are
+    /// used instead of generating model visibilities from the source list.
+    pub(crate) model_vis_params: Option<ModelVisParams>,
+
     /// Blocks of timesteps used for calibration. Each timeblock contains
     /// indices of the input data to average together during calibration. Each
     /// timeblock may have a different number of timesteps; the number of blocks
@@ -82,7 +106,8 @@ pub(crate) struct DiCalParams {
 
     /// Multiplicative factors to apply to unflagged baselines. These are mostly
     /// all 1.0, but flagged baselines (perhaps due to a UVW cutoff) have values
-    /// of 0.0.
+    /// of 0.0. Baselines may also have other values, e.g. from a taper beyond
+    /// a UVW cutoff or inverse-density weighting.
     pub(crate) baseline_weights: Vec1<f64>,
 
     /// The maximum number of times to iterate when performing calibration.
@@ -97,6 +122,66 @@ pub(crate) struct DiCalParams {
     /// the stop threshold. This is bigger than `stop_threshold`.
     pub(crate) min_threshold: f64,
 
+    /// How the calibration solutions are constrained (e.g. phase only).
+    pub(crate) solve_mode: SolveMode,
+
+    /// How visibilities are weighted while calibrating (e.g. to down-weight
+    /// outliers).
+    pub(crate) robust_weighting: RobustWeighting,
+
+    /// The iteration scheme used to find the calibration solutions.
+    pub(crate) solver: Solver,
+
+    /// If specified, redundant calibration is done with these groups of
+    /// redundant baselines instead of calibrating against a sky model.
+    pub(crate) redundant_groups: Option<RedundantGroups>,
+
+    /// Initial guesses of the calibration solutions, e.g. from a neighbouring
+    /// observation. If this isn't supplied, identity matrices are used. The
+    /// dimensions are (num_timeblocks, num_unflagged_tiles,
+    /// num_unflagged_chanblocks).
+    pub(crate) initial_di_jones: Option<Array3<Jones<f64>>>,
+
+    /// If specified, tiles and chanblocks with outlying solutions are flagged
+    /// after calibration.
+    pub(crate) outlier_params: Option<OutlierParams>,
+
+    /// If specified, smooth models are fitted to the solutions over frequency
+    /// after calibration.
+    pub(crate) smooth_params: Option<SmoothParams>,
+
+    /// If true, the phase between the X and Y polarisations that is common to
+    /// all tiles is determined after calibration (and smoothing).
+    pub(crate) xy_phase: bool,
+
+    /// If specified, the phases of the solutions are referenced to this tile
+    /// before they're returned.
+    pub(crate) phase_reference: Option<PhaseReference>,
+
+    /// Other observations to be calibrated together with this one. Their
+    /// visibilities are stacked after this observation's, and one common set
+    /// of solutions is made for all of them.
+    pub(crate) joint_obs: Vec<JointObsParams>,
+
+    /// If true, per-tile phase offsets are solved for each of the
+    /// `joint_obs`, relative to this observation.
+    pub(crate) joint_phase_offsets: bool,
+
+    /// If specified, chanblocks are calibrated this many at a time, and only
+    /// the visibilities of one chunk of chanblocks are held in memory. This
+    /// doesn't change the solutions, as chanblocks are calibrated
+    /// independently.
+    pub(crate) chanblocks_per_chunk: Option<NonZeroUsize>,
+
+    /// If specified, model visibilities (and the solutions of each chunk, if
+    /// calibrating in chunks) are checkpointed in this directory as they are
+    /// made, such that an interrupted job can be resumed.
+    pub(crate) checkpoint_dir: Option<PathBuf>,
+
+    /// If true, and `checkpoint_dir` contains a checkpoint of this
+    /// calibration, calibration resumes from the checkpoint.
+    pub(crate) resume: bool,
+
     /// The paths to the files where the calibration solutions are written. The
     /// same solutions are written to each file here, but the format may be
     /// different (indicated by the second part of the tuples).
@@ -110,19 +195,47 @@ pub(crate) struct DiCalParams {
     pub(crate) modelling_params: ModellingParams,
 }
 
+/// Parameters for an observation that is calibrated jointly with the
+/// observation of a [`DiCalParams`]. Everything not here (e.g. the UVW cutoffs)
+/// is shared with the [`DiCalParams`].
+pub(crate) struct JointObsParams {
+    /// The interface to the input data, metadata and flags. The tile flags and
+    /// chanblocks are the same as those of the [`DiCalParams`].
+    pub(crate) input_vis_params: InputVisParams,
+
+    /// Beam object.
+    pub(crate) beam: Box<dyn Beam>,
+
+    /// The sky-model source list.
+    pub(crate) source_list: SourceList,
+}
+
 impl DiCalParams {
     /// Use the [`DiCalParams`] to perform calibration and obtain solutions.
     pub(crate) fn run(&self) -> Result<CalibrationSolutions, DiCalibrateError> {
+        let checkpoint = self.open_checkpoint()?;
+        if let Some(chanblocks_per_chunk) = self.chanblocks_per_chunk {
+            return self.run_chunked(chanblocks_per_chunk, checkpoint.as_ref());
+        }
+
         let input_vis_params = &self.input_vis_params;
 
-        let CalVis {
-            vis_data,
-            vis_weights,
-            vis_model,
-            pols,
-        } = self.get_cal_vis()?;
+        let (
+            CalVis {
+                mut vis_data,
+                mut vis_weights,
+                mut vis_model,
+                pols,
+            },
+            obs_num_timesteps,
+        ) = self.get_joint_cal_vis(None, checkpoint.as_ref())?;
         assert_eq!(vis_weights.len_of(Axis(2)), self.baseline_weights.len());
 
+        if let Some(groups) = self.redundant_groups.as_ref() {
+            debug!("Preparing visibilities for redundant calibration");
+            redundant::prepare_cal_vis(groups, vis_data.view_mut(), vis_weights.view_mut());
+        }
+
         // The shape of the array containing output Jones matrices.
         let num_timeblocks = input_vis_params.timeblocks.len();
         let num_chanblocks = input_vis_params.spw.chanblocks.len();
@@ -141,33 +254,815 @@ impl DiCalParams {
         );
         }
 
+        let calibrate = |vis_data: ArrayView3<Jones<f32>>,
+                         vis_model: ArrayView3<Jones<f32>>,
+                         vis_weights: ArrayView3<f32>| {
+            let (sols, precisions, residual_stats) = self.calibrate_vis(
+                vis_data,
+                vis_model,
+                vis_weights,
+                &input_vis_params.spw.chanblocks,
+                self.initial_di_jones.clone(),
+                pols,
+            );
+
+            // "Complete" the solutions.
+            let mut sols = sols.into_cal_sols(self, Some(precisions));
+            sols.residual_stats = Some(residual_stats);
+            sols
+        };
+
+        if self.joint_phase_offsets && obs_num_timesteps.len() > 1 {
+            self.remove_joint_phase_offsets(
+                vis_data.view_mut(),
+                vis_model.view(),
+                &obs_num_timesteps,
+                pols,
+            );
+        }
+
+        let mut sols = calibrate(vis_data.view(), vis_model.view(), vis_weights.view());
+
+        self.handle_outliers(&mut sols, |outlier_tiles| {
+            self.flag_cal_vis_tiles(
+                outlier_tiles,
+                vis_data.view_mut(),
+                vis_model.view_mut(),
+                vis_weights.view_mut(),
+            );
+            Ok(calibrate(
+                vis_data.view(),
+                vis_model.view(),
+                vis_weights.view(),
+            ))
+        })?;
+
+        if let Some(smooth_params) = self.smooth_params.as_ref() {
+            info!("Smoothing solutions");
+            smooth(&mut sols, smooth_params);
+        }
+
+        if self.xy_phase {
+            if pols == Polarisations::XX_XY_YX_YY {
+                info!("Determining the XY-phase");
+                let total_num_tiles = input_vis_params.get_total_num_tiles();
+                let flagged_tiles = &input_vis_params.tile_baseline_flags.flagged_tiles;
+                let unflagged_tiles = (0..total_num_tiles)
+                    .filter(|i_tile| !flagged_tiles.contains(i_tile))
+                    .collect::<Vec<_>>();
+                sols.xy_phase = get_xy_phase(
+                    vis_data.view(),
+                    vis_model.view(),
+                    &sols,
+                    &self.cal_timeblocks,
+                    &input_vis_params.spw.chanblocks,
+                    &unflagged_tiles,
+                );
+                match sols.xy_phase.as_ref() {
+                    Some(xy_phase) => info!(
+                        "Fitted a cross-hand delay of {:.3} ns",
+                        xy_phase.delay * 1e9
+                    ),
+                    None => {
+                        "Couldn't determine the XY-phase; are there polarised sources in the sky model?"
+                            .warn()
+                    }
+                }
+            } else {
+                format!(
+                    "Can't determine the XY-phase without all polarisations (only have {pols})"
+                )
+                .warn();
+            }
+        }
+
+        self.reference_phases(&mut sols)?;
+
+        Ok(sols)
+    }
+
+    /// Reference the phases of the solutions to a tile, if requested. If the
+    /// requested tile has no solutions (e.g. it was flagged as an outlier),
+    /// the tile with the most solutions is used instead.
+    fn reference_phases(&self, sols: &mut CalibrationSolutions) -> Result<(), DiCalibrateError> {
+        if let Some(PhaseReference { tile, cross_hands }) = self.phase_reference {
+            let tile = if sols
+                .di_jones
+                .slice(s![.., tile, ..])
+                .iter()
+                .any(|j| !j.any_nan())
+            {
+                tile
+            } else {
+                match get_fallback_ref_tile(sols.di_jones.view()) {
+                    Some(fallback) => {
+                        format!("The reference tile {tile} has no solutions; referencing solution phases to tile {fallback} instead").warn();
+                        fallback
+                    }
+                    None => {
+                        "No tiles have solutions; not referencing solution phases".warn();
+                        return Ok(());
+                    }
+                }
+            };
+            info!("Referencing solution phases to tile {tile}");
+            reference_phases(sols, tile, cross_hands)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`DiCalParams::run`], but calibrate `chanblocks_per_chunk`
+    /// chanblocks (flagged and unflagged) at a time. Only the visibilities of
+    /// one chunk are read and modelled at once, so the memory needed scales
+    /// with the size of the chunks rather than with the whole band. If
+    /// outlier tiles are to be re-calibrated, the visibilities of each chunk
+    /// are read and modelled again.
+    fn run_chunked(
+        &self,
+        chanblocks_per_chunk: NonZeroUsize,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<CalibrationSolutions, DiCalibrateError> {
+        let spw = &self.input_vis_params.spw;
+        let total_num_chanblocks = spw.chanblocks.len() + spw.flagged_chanblock_indices.len();
+        let num_chans = self
+            .input_vis_params
+            .get_obs_context()
+            .fine_chan_freqs
+            .len();
+        let chunks = (0..total_num_chanblocks)
+            .step_by(chanblocks_per_chunk.get())
+            .map(|start| {
+                let chanblock_range =
+                    start..(start + chanblocks_per_chunk.get()).min(total_num_chanblocks);
+                let (chunk_spw, _) = spw.get_chunk(chanblock_range.clone(), num_chans);
+                (chanblock_range, chunk_spw)
+            })
+            // Chunks without unflagged chanblocks have nothing to calibrate.
+            .filter(|(_, chunk_spw)| !chunk_spw.chanblocks.is_empty())
+            .collect::<Vec<_>>();
+        info!(
+            "Calibrating {} chunks of up to {chanblocks_per_chunk} chanblocks",
+            chunks.len()
+        );
+
+        let mut sols = self.calibrate_chunks(&chunks, &[], checkpoint)?;
+        self.handle_outliers(&mut sols, |outlier_tiles| {
+            self.calibrate_chunks(&chunks, outlier_tiles, checkpoint)
+        })?;
+
+        if let Some(smooth_params) = self.smooth_params.as_ref() {
+            info!("Smoothing solutions");
+            smooth(&mut sols, smooth_params);
+        }
+
+        self.reference_phases(&mut sols)?;
+
+        Ok(sols)
+    }
+
+    /// Read, model and calibrate chunks of chanblocks one after the other,
+    /// and combine their solutions. `chunks` are the chanblock ranges and
+    /// [`Spw`] chunks of the chunks to calibrate (see [`Spw::get_chunk`]).
+    /// The visibilities of `flagged_tiles` are flagged before calibration. If
+    /// there's a checkpoint, the solutions of each chunk are checkpointed, and
+    /// checkpointed solutions are used instead of calibrating again (unless
+    /// tiles are being flagged).
+    fn calibrate_chunks(
+        &self,
+        chunks: &[(Range<usize>, Spw)],
+        flagged_tiles: &[usize],
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<CalibrationSolutions, DiCalibrateError> {
+        let mut chunk_sols = Vec::with_capacity(chunks.len());
+        let mut chunk_precisions = Vec::with_capacity(chunks.len());
+        let mut residual_stats: Option<ResidualStats> = None;
+        let mut i_unflagged_chanblock = 0;
+        for (i_chunk, (chanblock_range, chunk_spw)) in chunks.iter().enumerate() {
+            info!(
+                "Calibrating chunk {}/{} (chanblocks {} to {})",
+                i_chunk + 1,
+                chunks.len(),
+                chanblock_range.start,
+                chanblock_range.end - 1
+            );
+            // The initial guesses are for all unflagged chanblocks.
+            let num_chunk_chanblocks = chunk_spw.chanblocks.len();
+            let initial_di_jones = self.initial_di_jones.as_ref().map(|j| {
+                j.slice(s![
+                    ..,
+                    ..,
+                    i_unflagged_chanblock..i_unflagged_chanblock + num_chunk_chanblocks
+                ])
+                .to_owned()
+            });
+            i_unflagged_chanblock += num_chunk_chanblocks;
+
+            let sols_checkpoint = checkpoint.filter(|_| flagged_tiles.is_empty());
+            let checkpointed_sols = match sols_checkpoint {
+                Some(c) => c.read_solutions(chanblock_range, self, &chunk_spw.chanblocks)?,
+                None => None,
+            };
+            let (sols, precisions, chunk_residual_stats) = match checkpointed_sols {
+                Some(checkpointed_sols) => {
+                    info!("Using the checkpointed solutions of this chunk");
+                    checkpointed_sols
+                }
+                None => {
+                    let (
+                        CalVis {
+                            mut vis_data,
+                            mut vis_weights,
+                            mut vis_model,
+                            pols,
+                        },
+                        _,
+                    ) = self.get_joint_cal_vis(Some(chanblock_range.clone()), checkpoint)?;
+
+                    if let Some(groups) = self.redundant_groups.as_ref() {
+                        debug!("Preparing visibilities for redundant calibration");
+                        redundant::prepare_cal_vis(
+                            groups,
+                            vis_data.view_mut(),
+                            vis_weights.view_mut(),
+                        );
+                    }
+                    if !flagged_tiles.is_empty() {
+                        self.flag_cal_vis_tiles(
+                            flagged_tiles,
+                            vis_data.view_mut(),
+                            vis_model.view_mut(),
+                            vis_weights.view_mut(),
+                        );
+                    }
+
+                    let (sols, precisions, chunk_residual_stats) = self.calibrate_vis(
+                        vis_data.view(),
+                        vis_model.view(),
+                        vis_weights.view(),
+                        &chunk_spw.chanblocks,
+                        initial_di_jones,
+                        pols,
+                    );
+                    if let Some(c) = sols_checkpoint {
+                        c.write_solutions(
+                            chanblock_range,
+                            &sols,
+                            precisions.view(),
+                            &chunk_residual_stats,
+                        )?;
+                    }
+                    (sols, precisions, chunk_residual_stats)
+                }
+            };
+            match residual_stats.as_mut() {
+                Some(residual_stats) => residual_stats.combine(&chunk_residual_stats),
+                None => residual_stats = Some(chunk_residual_stats),
+            }
+            chunk_sols.push(sols);
+            chunk_precisions.push(precisions);
+        }
+
+        let sols = IncompleteSolutions::concatenate_chunks(
+            chunk_sols,
+            &self.cal_timeblocks,
+            &self.input_vis_params.spw.chanblocks,
+        );
+        let precisions = ndarray::concatenate(
+            Axis(1),
+            &chunk_precisions
+                .iter()
+                .map(|p| p.view())
+                .collect::<Vec<_>>(),
+        )
+        .expect("all chunks have the same timeblocks");
+        let mut sols = sols.into_cal_sols(self, Some(precisions));
+        sols.residual_stats = residual_stats;
+        Ok(sols)
+    }
+
+    /// Calibrate visibilities that have been prepared for calibration. The
+    /// solutions are returned along with the precisions that each timeblock
+    /// and chanblock converged with and statistics on the residuals.
+    fn calibrate_vis<'a>(
+        &'a self,
+        vis_data: ArrayView3<Jones<f32>>,
+        vis_model: ArrayView3<Jones<f32>>,
+        vis_weights: ArrayView3<f32>,
+        chanblocks: &'a [Chanblock],
+        initial_di_jones: Option<Array3<Jones<f64>>>,
+        pols: Polarisations,
+    ) -> (IncompleteSolutions<'a>, Array2<f64>, ResidualStats) {
+        let redundant_cal_weights = self
+            .redundant_groups
+            .as_ref()
+            .map(|_| redundant::get_cal_weights(vis_weights, &self.baseline_weights));
+        let redundant = self
+            .redundant_groups
+            .as_ref()
+            .zip(redundant_cal_weights.as_ref())
+            .map(|(groups, weights)| RedundantCalVis {
+                groups,
+                weights_tfb: weights.view(),
+            });
         let (sols, results) = calibrate_timeblocks(
-            vis_data.view(),
-            vis_model.view(),
+            vis_data,
+            vis_model,
             &self.cal_timeblocks,
-            &input_vis_params.spw.chanblocks,
+            chanblocks,
+            initial_di_jones,
             self.max_iterations,
             self.stop_threshold,
             self.min_threshold,
+            self.solve_mode,
+            self.robust_weighting,
+            self.solver,
+            redundant,
             pols,
             true,
         );
 
-        // "Complete" the solutions.
-        let sols = sols.into_cal_sols(self, Some(results.map(|r| r.max_precision)));
+        debug!("Calculating residual statistics");
+        let residual_stats = match redundant {
+            // The residuals need the visibilities of the redundant groups.
+            Some(redundant) => {
+                let mut vis_group_model = Array3::from_elem(vis_data.dim(), Jones::default());
+                redundant::set_model_vis(
+                    redundant,
+                    &sols,
+                    pols,
+                    vis_data,
+                    vis_group_model.view_mut(),
+                );
+                sols.get_residual_stats(vis_data, vis_group_model.view(), vis_weights, self)
+            }
+            None => sols.get_residual_stats(vis_data, vis_model, vis_weights, self),
+        };
+
+        (sols, results.map(|r| r.max_precision), residual_stats)
+    }
 
-        Ok(sols)
+    /// Flag tiles and chanblocks with outlying solutions, if this was
+    /// requested. If calibration is to be re-run without outlier tiles,
+    /// `recalibrate` is called with the outlier tiles to get new solutions.
+    fn handle_outliers(
+        &self,
+        sols: &mut CalibrationSolutions,
+        recalibrate: impl FnOnce(&[usize]) -> Result<CalibrationSolutions, DiCalibrateError>,
+    ) -> Result<(), DiCalibrateError> {
+        let outlier_params = match self.outlier_params.as_ref() {
+            Some(outlier_params) => outlier_params,
+            None => return Ok(()),
+        };
+
+        let outliers = find_outliers(sols, outlier_params.threshold);
+        if outliers.is_empty() {
+            info!("No outlier tiles or chanblocks found");
+            return Ok(());
+        }
+        info!(
+            "Found {} outlier tiles {:?} and {} outlier chanblocks {:?}",
+            outliers.tiles.len(),
+            outliers.tiles,
+            outliers.chanblocks.len(),
+            outliers.chanblocks
+        );
+
+        // Chanblocks are calibrated independently, so only outlier tiles
+        // affect the solutions of others.
+        if outlier_params.recalibrate && !outliers.tiles.is_empty() {
+            info!("Re-running calibration without outlier tiles");
+            *sols = recalibrate(&outliers.tiles)?;
+        }
+        flag_outliers(sols, &outliers);
+        Ok(())
+    }
+
+    /// Prepare the checkpoint of this calibration, if one was requested.
+    fn open_checkpoint(&self) -> Result<Option<Checkpoint>, DiCalibrateError> {
+        let dir = match self.checkpoint_dir.as_ref() {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+
+        // The model visibilities depend on the input data, the sky model, the
+        // beam, the array position, DUT1 and the chanblocks being modelled.
+        let mut fingerprint = Fingerprint::new();
+        fingerprint.write_f64s(self.input_vis_params.spw.chanblocks.iter().map(|c| c.freq));
+        self.chanblocks_per_chunk
+            .map(|n| n.get())
+            .hash(&mut fingerprint);
+        self.modelling_params
+            .apply_precession
+            .hash(&mut fingerprint);
+        let all_obs = iter::once((&self.input_vis_params, &*self.beam, &self.source_list)).chain(
+            self.joint_obs
+                .iter()
+                .map(|o| (&o.input_vis_params, &*o.beam, &o.source_list)),
+        );
+        for (input_vis_params, beam, source_list) in all_obs {
+            let obs_context = input_vis_params.get_obs_context();
+            obs_context.obsid.hash(&mut fingerprint);
+            fingerprint.write_f64s([
+                obs_context.array_position.longitude_rad,
+                obs_context.array_position.latitude_rad,
+                obs_context.array_position.height_metres,
+                input_vis_params.dut1.to_seconds(),
+            ]);
+            fingerprint.write_f64s(
+                input_vis_params
+                    .timeblocks
+                    .iter()
+                    .map(|t| t.median.to_gpst_seconds()),
+            );
+            input_vis_params
+                .tile_baseline_flags
+                .flagged_tiles
+                .iter()
+                .sorted()
+                .collect::<Vec<_>>()
+                .hash(&mut fingerprint);
+            beam.get_beam_type().to_string().hash(&mut fingerprint);
+            beam.get_ideal_dipole_delays().hash(&mut fingerprint);
+            if let Some(dipole_gains) = beam.get_dipole_gains() {
+                fingerprint.write_f64s(dipole_gains.iter().copied());
+            }
+            serde_json::to_vec(source_list)
+                .expect("source lists can be serialised")
+                .hash(&mut fingerprint);
+        }
+        if let Some(model_vis_params) = self.model_vis_params.as_ref() {
+            let model = model_vis_params.get_input_vis_params();
+            fingerprint.write_f64s(
+                model
+                    .timeblocks
+                    .iter()
+                    .flat_map(|t| t.timestamps.iter().map(|e| e.to_gpst_seconds())),
+            );
+            fingerprint.write_f64s(
+                model
+                    .get_obs_context()
+                    .fine_chan_freqs
+                    .iter()
+                    .map(|&f| f as f64),
+            );
+        }
+        let model_fingerprint = fingerprint.finish();
+
+        // The solutions also depend on the calibration settings.
+        let mut fingerprint = Fingerprint::new();
+        model_fingerprint.hash(&mut fingerprint);
+        fingerprint.write_f64s(
+            self.cal_timeblocks
+                .iter()
+                .flat_map(|t| t.timestamps.iter().map(|e| e.to_gpst_seconds())),
+        );
+        fingerprint.write_f64s(self.baseline_weights.iter().copied());
+        fingerprint.write_f64s([
+            self.uvw_min,
+            self.uvw_max,
+            self.stop_threshold,
+            self.min_threshold,
+        ]);
+        self.max_iterations.hash(&mut fingerprint);
+        for setting in [
+            self.solve_mode.to_string(),
+            self.robust_weighting.to_string(),
+            self.solver.to_string(),
+            format!("{:?}", self.redundant_groups),
+        ] {
+            setting.hash(&mut fingerprint);
+        }
+        if let Some(initial_di_jones) = self.initial_di_jones.as_ref() {
+            fingerprint.write_f64s(initial_di_jones.iter().flat_map(|j| j.to_float_array()));
+        }
+        self.joint_phase_offsets.hash(&mut fingerprint);
+        let calibration_fingerprint = fingerprint.finish();
+
+        Ok(Some(Checkpoint::new(
+            dir,
+            model_fingerprint,
+            calibration_fingerprint,
+            self.resume,
+        )?))
+    }
+
+    /// Remove the checkpoint of this calibration, if there is one. This should
+    /// be done once the solutions have been written.
+    pub(crate) fn remove_checkpoint(&self) -> Result<(), DiCalibrateError> {
+        if let Some(dir) = self.checkpoint_dir.as_ref() {
+            Checkpoint::remove(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Estimate the peak memory and sky-modelling work needed by
+    /// [`DiCalParams::run`].
+    pub(crate) fn get_resource_estimate(&self) -> ResourceEstimate {
+        let spw = &self.input_vis_params.spw;
+        let num_unflagged_tiles = self.input_vis_params.get_num_unflagged_tiles();
+        let num_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2;
+        let num_chans = self
+            .input_vis_params
+            .get_obs_context()
+            .fine_chan_freqs
+            .len();
+        // When calibrating in chunks, only one chunk's visibilities are held at
+        // once.
+        let (num_chanblocks, num_chans) = match self.chanblocks_per_chunk {
+            Some(n) => (
+                spw.chanblocks.len().min(n.get()),
+                (n.get() * spw.chans_per_chanblock.get()).min(num_chans),
+            ),
+            None => (spw.chanblocks.len(), num_chans),
+        };
+
+        let mut estimate = ResourceEstimate::default();
+        let mut vis_memory = 0;
+        let mut read_memory = 0;
+        for (input_vis_params, source_list) in
+            iter::once((&self.input_vis_params, &self.source_list)).chain(
+                self.joint_obs
+                    .iter()
+                    .map(|obs| (&obs.input_vis_params, &obs.source_list)),
+            )
+        {
+            let num_timesteps = input_vis_params
+                .timeblocks
+                .iter()
+                .map(|tb| tb.timestamps.len())
+                .sum::<usize>();
+            // The data and model visibilities, and their weights.
+            vis_memory += (num_timesteps * num_chanblocks * num_baselines) as u64
+                * (2 * std::mem::size_of::<Jones<f32>>() + std::mem::size_of::<f32>()) as u64;
+            read_memory =
+                read_memory.max(get_read_memory(input_vis_params, num_chans, num_baselines));
+            estimate = estimate.with_model_ops(
+                &source_list.get_counts(),
+                num_baselines,
+                spw.chanblocks.len(),
+                num_timesteps,
+            );
+        }
+        // The visibilities of joint observations are copied when they're
+        // stacked together.
+        if !self.joint_obs.is_empty() {
+            vis_memory *= 2;
+        }
+        estimate.peak_memory = vis_memory + read_memory;
+
+        // When calibrating in chunks, the sky is modelled again to re-calibrate
+        // without outlier tiles.
+        if self.chanblocks_per_chunk.is_some()
+            && self
+                .outlier_params
+                .as_ref()
+                .map(|p| p.recalibrate)
+                .unwrap_or(false)
+        {
+            estimate.num_model_ops *= 2;
+            estimate.num_shapelet_model_ops *= 2;
+        }
+        estimate
+    }
+
+    /// Alternate between solving for common solutions and per-tile phase
+    /// offsets of each joint observation, removing the offsets from the data as
+    /// they are found. The first observation is the reference and has no
+    /// offsets. `obs_num_timesteps` are the number of timesteps that each
+    /// observation has in the visibilities.
+    fn remove_joint_phase_offsets(
+        &self,
+        mut vis_data: ArrayViewMut3<Jones<f32>>,
+        vis_model: ArrayView3<Jones<f32>>,
+        obs_num_timesteps: &[usize],
+        pols: Polarisations,
+    ) {
+        let num_unflagged_tiles = self.input_vis_params.get_num_unflagged_tiles();
+        // The accumulated offsets of each observation, for reporting.
+        let mut total_offsets = Array2::from_elem(
+            (obs_num_timesteps.len(), num_unflagged_tiles),
+            Jones::identity(),
+        );
+
+        for i_round in 0..JOINT_PHASE_OFFSET_ROUNDS {
+            info!(
+                "Solving for joint phase offsets (round {}/{JOINT_PHASE_OFFSET_ROUNDS})",
+                i_round + 1
+            );
+            let (sols, _) = calibrate_timeblocks(
+                vis_data.view(),
+                vis_model,
+                &self.cal_timeblocks,
+                &self.input_vis_params.spw.chanblocks,
+                self.initial_di_jones.clone(),
+                self.max_iterations,
+                self.stop_threshold,
+                self.min_threshold,
+                self.solve_mode,
+                self.robust_weighting,
+                self.solver,
+                // Redundant calibration can't be used with joint phase
+                // offsets.
+                None,
+                pols,
+                false,
+            );
+
+            let mut start = obs_num_timesteps[0];
+            for (&num_timesteps, mut total_offsets) in obs_num_timesteps
+                .iter()
+                .zip(total_offsets.outer_iter_mut())
+                .skip(1)
+            {
+                let range = s![start..start + num_timesteps, .., ..];
+                start += num_timesteps;
+                let (offsets, _) = get_phase_offsets(
+                    vis_data.slice(range),
+                    vis_model.slice(range),
+                    sols.di_jones.slice(s![0, .., ..]),
+                    self.max_iterations,
+                    self.stop_threshold,
+                    self.min_threshold,
+                    pols,
+                );
+                apply_phase_offsets(vis_data.slice_mut(range), offsets.view());
+                total_offsets.zip_mut_with(&offsets, |t, o| *t = *o * *t);
+            }
+        }
+
+        for (i_obs, total_offsets) in total_offsets.outer_iter().enumerate().skip(1) {
+            // Report the RMS of the offset phases; the overall phase of the
+            // offsets is arbitrary, so remove the mean phase first.
+            let [rms_x, rms_y] = [0, 3].map(|i| {
+                let phases = total_offsets
+                    .iter()
+                    .filter(|o| !o.any_nan())
+                    .map(|o| o[i].arg())
+                    .collect::<Vec<_>>();
+                let mean = phases.iter().sum::<f64>() / phases.len() as f64;
+                (phases.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / phases.len() as f64)
+                    .sqrt()
+            });
+            info!(
+                "Joint observation {i_obs}: RMS phase offsets {:.2}° (X), {:.2}° (Y)",
+                rms_x.to_degrees(),
+                rms_y.to_degrees()
+            );
+            debug!(
+                "Joint observation {i_obs} phase offsets (X, Y) [degrees]: {:?}",
+                total_offsets
+                    .iter()
+                    .map(|o| (o[0].arg().to_degrees(), o[3].arg().to_degrees()))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    /// Flag tiles in visibilities prepared for calibration. The data and model
+    /// visibilities of any baseline containing one of the tiles are set to zero
+    /// (so they don't affect calibration) and their weights are made negative.
+    fn flag_cal_vis_tiles(
+        &self,
+        tiles: &[usize],
+        mut vis_data: ArrayViewMut3<Jones<f32>>,
+        mut vis_model: ArrayViewMut3<Jones<f32>>,
+        mut vis_weights: ArrayViewMut3<f32>,
+    ) {
+        let baseline_to_tile_map = &self
+            .input_vis_params
+            .tile_baseline_flags
+            .unflagged_cross_baseline_to_tile_map;
+        for (i_baseline, ((mut vis_data, mut vis_model), mut vis_weights)) in vis_data
+            .axis_iter_mut(Axis(2))
+            .zip(vis_model.axis_iter_mut(Axis(2)))
+            .zip(vis_weights.axis_iter_mut(Axis(2)))
+            .enumerate()
+        {
+            let (tile1, tile2) = baseline_to_tile_map[&i_baseline];
+            if tiles.contains(&tile1) || tiles.contains(&tile2) {
+                vis_data.fill(Jones::default());
+                vis_model.fill(Jones::default());
+                vis_weights.mapv_inplace(|w| -w.abs());
+            }
+        }
+    }
+
+    /// Get the calibration visibilities of this observation and those of any
+    /// joint observations, stacked along the time axis. The number of timesteps
+    /// of each observation is also returned. If `chanblock_range` is
+    /// specified, only the visibilities of these chanblocks are read.
+    fn get_joint_cal_vis(
+        &self,
+        chanblock_range: Option<Range<usize>>,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<(CalVis, Vec<usize>), DiCalibrateError> {
+        let cal_vis = self.get_obs_cal_vis(
+            &self.input_vis_params,
+            chanblock_range.clone(),
+            &*self.beam,
+            &self.source_list,
+            self.model_vis_params.as_ref(),
+            self.output_model_vis_params.as_ref(),
+            checkpoint.map(|c| (c, 0)),
+        )?;
+        if self.joint_obs.is_empty() {
+            let num_timesteps = cal_vis.vis_data.len_of(Axis(0));
+            return Ok((cal_vis, vec![num_timesteps]));
+        }
+
+        let mut all_cal_vis = vec![cal_vis];
+        for (i_obs, joint_obs) in self.joint_obs.iter().enumerate() {
+            info!(
+                "Reading joint observation {}/{}",
+                i_obs + 1,
+                self.joint_obs.len()
+            );
+            all_cal_vis.push(self.get_obs_cal_vis(
+                &joint_obs.input_vis_params,
+                chanblock_range.clone(),
+                &*joint_obs.beam,
+                &joint_obs.source_list,
+                None,
+                None,
+                checkpoint.map(|c| (c, i_obs + 1)),
+            )?);
+        }
+
+        debug!("Stacking the visibilities of joint observations");
+        let obs_num_timesteps = all_cal_vis
+            .iter()
+            .map(|c| c.vis_data.len_of(Axis(0)))
+            .collect();
+        let concat = |views: Vec<ArrayView3<Jones<f32>>>| {
+            ndarray::concatenate(Axis(0), &views).expect("all arrays have the same shape")
+        };
+        let vis_data = concat(all_cal_vis.iter().map(|c| c.vis_data.view()).collect());
+        let vis_model = concat(all_cal_vis.iter().map(|c| c.vis_model.view()).collect());
+        let vis_weights = ndarray::concatenate(
+            Axis(0),
+            &all_cal_vis
+                .iter()
+                .map(|c| c.vis_weights.view())
+                .collect::<Vec<_>>(),
+        )
+        .expect("all arrays have the same shape");
+        let pols = all_cal_vis[0].pols;
+        Ok((
+            CalVis {
+                vis_data,
+                vis_weights,
+                vis_model,
+                pols,
+            },
+            obs_num_timesteps,
+        ))
     }
 
     /// For calibration, read in unflagged visibilities and generate sky-model
     /// visibilities.
     pub(crate) fn get_cal_vis(&self) -> Result<CalVis, DiCalibrateError> {
-        let input_vis_params = &self.input_vis_params;
+        self.get_obs_cal_vis(
+            &self.input_vis_params,
+            None,
+            &*self.beam,
+            &self.source_list,
+            self.model_vis_params.as_ref(),
+            self.output_model_vis_params.as_ref(),
+            None,
+        )
+    }
 
+    /// Read in unflagged visibilities and generate sky-model visibilities for
+    /// an observation, which might not be this [`DiCalParams`]'s. If
+    /// `chanblock_range` is specified, only these chanblocks are read and
+    /// modelled. If `model_vis_params` is specified, the model visibilities
+    /// are read from it rather than generated from `source_list`. If
+    /// `checkpoint` is specified, generated model visibilities are
+    /// checkpointed (the `usize` is the index of the observation; 0 is this
+    /// [`DiCalParams`]'s observation, 1 onwards are the joint observations).
+    #[allow(clippy::too_many_arguments)]
+    fn get_obs_cal_vis(
+        &self,
+        input_vis_params: &InputVisParams,
+        chanblock_range: Option<Range<usize>>,
+        beam: &dyn Beam,
+        source_list: &SourceList,
+        model_vis_params: Option<&ModelVisParams>,
+        output_model_vis_params: Option<&OutputVisParams>,
+        checkpoint: Option<(&Checkpoint, usize)>,
+    ) -> Result<CalVis, DiCalibrateError> {
         // Get the time and frequency resolutions once; these functions issue
         // warnings if they have to guess, so doing this once means we aren't
         // issuing too many warnings.
         let obs_context = input_vis_params.get_obs_context();
+        let num_chans = obs_context.fine_chan_freqs.len();
+        let chunk = chanblock_range
+            .clone()
+            .map(|r| input_vis_params.spw.get_chunk(r, num_chans));
+        let (spw, chan_range) = match chunk.as_ref() {
+            Some((spw, chan_range)) => (spw, chan_range.clone()),
+            None => (&input_vis_params.spw, 0..num_chans),
+        };
         let num_unflagged_tiles = input_vis_params.get_num_unflagged_tiles();
         let num_unflagged_cross_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2;
 
@@ -177,7 +1072,7 @@ impl DiCalParams {
                 .iter()
                 .flat_map(|t| &t.timestamps)
                 .count(),
-            input_vis_params.spw.chanblocks.len(),
+            spw.chanblocks.len(),
             num_unflagged_cross_baselines,
         );
         let num_elems = vis_shape.0 * vis_shape.1 * vis_shape.2;
@@ -185,6 +1080,14 @@ impl DiCalParams {
         // calibration.
         let size = indicatif::HumanBytes((num_elems * std::mem::size_of::<Jones<f32>>()) as u64);
         debug!("Shape of data and model arrays: ({} timesteps, {} channels, {} baselines; {size} each)", vis_shape.0, vis_shape.1, vis_shape.2);
+        // There's no need to checkpoint model visibilities that are read
+        // from a file.
+        let model_checkpoint = checkpoint
+            .filter(|_| model_vis_params.is_none())
+            .map(|(c, i_obs)| {
+                c.open_model(i_obs, chanblock_range.as_ref(), vis_shape.1, vis_shape.2)
+            })
+            .transpose()?;
 
         macro_rules! fallible_allocator {
             ($default:expr) => {{
@@ -265,7 +1168,7 @@ impl DiCalParams {
         .with_message("Sky modelling");
         let model_progress = multi_progress.add(pb);
         // Only add a model writing progress bar if we need it.
-        let model_write_progress = self.output_model_vis_params.as_ref().map(|o| {
+        let model_write_progress = output_model_vis_params.map(|o| {
             let pb = ProgressBar::new(o.output_timeblocks.len() as _)
             .with_style(
                 ProgressStyle::default_bar()
@@ -301,8 +1204,10 @@ impl DiCalParams {
                         vis_data_slices,
                         vis_weight_slices
                     ) {
-                        let result = input_vis_params.read_timeblock(
+                        let result = input_vis_params.read_timeblock_chunk(
                             timeblock,
+                            spw,
+                            chan_range.clone(),
                             vis_data_fb,
                             vis_weights_fb,
                             None,
@@ -331,28 +1236,32 @@ impl DiCalParams {
                 .expect("OS can create threads");
 
             // Sky-model generation thread.
-            let model_handle: ScopedJoinHandle<Result<(), ModelError>> = thread::Builder::new()
-                .name("model".to_string())
-                .spawn_scoped(scope, || {
-                    defer_on_unwind! { error.store(true); }
-                    model_progress.tick();
-
-                    let result = model_thread(
-                        &*self.beam,
-                        &self.source_list,
-                        input_vis_params,
-                        self.modelling_params.apply_precession,
-                        vis_model_slices,
-                        tx_model,
-                        &error,
-                        model_progress,
-                    );
-                    if result.is_err() {
-                        error.store(true);
-                    }
-                    result
-                })
-                .expect("OS can create threads");
+            let model_handle: ScopedJoinHandle<Result<(), DiCalibrateError>> =
+                thread::Builder::new()
+                    .name("model".to_string())
+                    .spawn_scoped(scope, || {
+                        defer_on_unwind! { error.store(true); }
+                        model_progress.tick();
+
+                        let result = model_thread(
+                            beam,
+                            source_list,
+                            input_vis_params,
+                            spw,
+                            self.modelling_params.apply_precession,
+                            vis_model_slices,
+                            model_vis_params.map(|m| (m, chanblock_range.as_ref())),
+                            model_checkpoint,
+                            tx_model,
+                            &error,
+                            model_progress,
+                        );
+                        if result.is_err() {
+                            error.store(true);
+                        }
+                        result
+                    })
+                    .expect("OS can create threads");
 
             // Model writing thread. If the user hasn't specified to write the model
             // to a file, then this thread just consumes messages from the modeller.
@@ -369,7 +1278,7 @@ impl DiCalParams {
                         output_freq_average_factor,
                         output_timeblocks,
                         write_smallest_contiguous_band,
-                    }) = &self.output_model_vis_params
+                    }) = output_model_vis_params
                     {
                         if let Some(pb) = model_write_progress.as_ref() {
                             pb.tick();
@@ -484,12 +1393,15 @@ fn model_thread(
     beam: &dyn Beam,
     source_list: &SourceList,
     input_vis_params: &InputVisParams,
+    spw: &Spw,
     apply_precession: bool,
     vis_model_slices: AxisIterMut<'_, Jones<f32>, Ix2>,
+    model_vis: Option<(&ModelVisParams, Option<&Range<usize>>)>,
+    mut checkpoint: Option<ModelCheckpoint>,
     tx: Sender<VisTimestep>,
     error: &AtomicCell<bool>,
     progress_bar: ProgressBar,
-) -> Result<(), ModelError> {
+) -> Result<(), DiCalibrateError> {
     let obs_context = input_vis_params.get_obs_context();
     let unflagged_tile_xyzs = obs_context
         .tile_xyzs
@@ -503,39 +1415,68 @@ fn model_thread(
         })
         .map(|(_, xyz)| *xyz)
         .collect::<Vec<_>>();
-    let freqs = input_vis_params
-        .spw
-        .chanblocks
-        .iter()
-        .map(|c| c.freq)
-        .collect::<Vec<_>>();
-    let modeller = new_sky_modeller(
-        beam,
-        source_list,
-        obs_context.polarisations,
-        &unflagged_tile_xyzs,
-        &freqs,
-        &input_vis_params.tile_baseline_flags.flagged_tiles,
-        obs_context.phase_centre,
-        obs_context.array_position.longitude_rad,
-        obs_context.array_position.latitude_rad,
-        input_vis_params.dut1,
-        apply_precession,
-    )?;
-
-    let weight_factor = ((input_vis_params.spw.freq_res / FREQ_WEIGHT_FACTOR)
+    let freqs = spw.chanblocks.iter().map(|c| c.freq).collect::<Vec<_>>();
+    // A modeller is only needed if the model visibilities aren't being read
+    // from a file.
+    let modeller = match model_vis {
+        Some(_) => None,
+        None => Some(new_sky_modeller(
+            beam,
+            source_list,
+            obs_context.polarisations,
+            &unflagged_tile_xyzs,
+            &freqs,
+            &input_vis_params.tile_baseline_flags.flagged_tiles,
+            obs_context.phase_centre,
+            obs_context.array_position.longitude_rad,
+            obs_context.array_position.latitude_rad,
+            input_vis_params.dut1,
+            apply_precession,
+        )?),
+    };
+
+    let weight_factor = ((spw.freq_res / FREQ_WEIGHT_FACTOR)
         * (input_vis_params.time_res.to_seconds() / TIME_WEIGHT_FACTOR))
         as f32;
 
     // Iterate over all calibration timesteps and write to the model slices.
-    for (timestamp, mut vis_model_fb) in input_vis_params
+    for (i_timeblock, (timestamp, mut vis_model_fb)) in input_vis_params
         .timeblocks
         .iter()
         .map(|tb| tb.median)
         .zip(vis_model_slices)
+        .enumerate()
     {
-        debug!("Modelling timestamp {}", timestamp.to_gpst_seconds());
-        modeller.model_timestep_with(timestamp, vis_model_fb.view_mut())?;
+        // Use the checkpointed model if there is one; otherwise, generate
+        // the model and checkpoint it.
+        let checkpointed = match checkpoint.as_mut() {
+            Some(c) => c.read_timestep(vis_model_fb.view_mut())?,
+            None => false,
+        };
+        match (model_vis, modeller.as_ref()) {
+            (Some((model_vis_params, chanblock_range)), _) => {
+                debug!(
+                    "Reading model visibilities for timestamp {}",
+                    timestamp.to_gpst_seconds()
+                );
+                model_vis_params.read_timeblock(
+                    i_timeblock,
+                    chanblock_range,
+                    vis_model_fb.view_mut(),
+                    error,
+                )?;
+            }
+
+            (None, Some(modeller)) if !checkpointed => {
+                debug!("Modelling timestamp {}", timestamp.to_gpst_seconds());
+                modeller.model_timestep_with(timestamp, vis_model_fb.view_mut())?;
+                if let Some(c) = checkpoint.as_mut() {
+                    c.write_timestep(vis_model_fb.view())?;
+                }
+            }
+
+            _ => (),
+        }
 
         // Should we continue?
         if error.load() {
@@ -579,7 +1520,7 @@ pub(crate) struct CalVis {
 
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum DiCalibrateError {
-    #[error("Insufficient memory available to perform calibration; need {need_gib} of memory.\nYou could try using fewer timesteps and channels.")]
+    #[error("Insufficient memory available to perform calibration; need {need_gib} of memory.\nYou could try using fewer timesteps and channels, or calibrating fewer chanblocks at a time (--chanblocks-per-chunk).")]
     InsufficientMemory { need_gib: indicatif::HumanBytes },
 
     #[error(transparent)]
@@ -591,6 +1532,12 @@ pub(crate) enum DiCalibrateError {
     #[error(transparent)]
     Model(#[from] crate::model::ModelError),
 
+    #[error(transparent)]
+    Checkpoint(#[from] CheckpointError),
+
+    #[error(transparent)]
+    PhaseReference(#[from] PhaseReferenceError),
+
     #[error(transparent)]
     VisRead(#[from] crate::io::read::VisReadError),
 
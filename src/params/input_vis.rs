@@ -8,7 +8,7 @@
 //! whether calibration solutions have been supplied. Other info like
 //! chanblocks, tile and channel flags etc. also live here.
 
-use std::collections::HashSet;
+use std::{collections::HashSet, ops::Range};
 
 use crossbeam_utils::atomic::AtomicCell;
 use hifitime::{Duration, Epoch};
@@ -23,6 +23,7 @@ use crate::{
     context::ObsContext,
     io::read::{VisRead, VisReadError},
     math::TileBaselineFlags,
+    solutions::{interpolate_jones, ChanblockInterp, FreqInterpolation, TimeInterpolation},
     CalibrationSolutions,
 };
 
@@ -34,6 +35,13 @@ pub(crate) struct InputVisParams {
     /// when `InputVisParams::read_timeblock` is called.
     pub(crate) solutions: Option<CalibrationSolutions>,
 
+    /// How solutions with multiple timeblocks are applied to timestamps.
+    pub(crate) solutions_time_interpolation: TimeInterpolation,
+
+    /// How solutions are applied to frequencies that don't match the solution
+    /// chanblocks.
+    pub(crate) solutions_freq_interpolation: FreqInterpolation,
+
     /// The timeblocks to be used from the averaged data. If there is no
     /// averaging to be done, then these are the same as the timesteps to be
     /// read from the data.
@@ -86,16 +94,49 @@ impl InputVisParams {
     pub(crate) fn read_timeblock(
         &self,
         timeblock: &Timeblock,
+        cross_data_fb: ArrayViewMut2<Jones<f32>>,
+        cross_weights_fb: ArrayViewMut2<f32>,
+        autos_fb: Option<(ArrayViewMut2<Jones<f32>>, ArrayViewMut2<f32>)>,
+        error: &AtomicCell<bool>,
+    ) -> Result<(), VisReadError> {
+        self.read_timeblock_chunk(
+            timeblock,
+            &self.spw,
+            0..self.get_obs_context().fine_chan_freqs.len(),
+            cross_data_fb,
+            cross_weights_fb,
+            autos_fb,
+            error,
+        )
+    }
+
+    /// Like [`InputVisParams::read_timeblock`], but only read the channels in
+    /// `chan_range` (indices into all of the input data's channels) and
+    /// average them into the chanblocks of `spw`, which is a chunk of this
+    /// [`InputVisParams`]'s [`Spw`] (see [`Spw::get_chunk`]). No other
+    /// channels are read.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn read_timeblock_chunk(
+        &self,
+        timeblock: &Timeblock,
+        spw: &Spw,
+        chan_range: Range<usize>,
         mut cross_data_fb: ArrayViewMut2<Jones<f32>>,
         mut cross_weights_fb: ArrayViewMut2<f32>,
         mut autos_fb: Option<(ArrayViewMut2<Jones<f32>>, ArrayViewMut2<f32>)>,
         error: &AtomicCell<bool>,
     ) -> Result<(), VisReadError> {
         let obs_context = self.get_obs_context();
+        // Readers skip "flagged" channels, so flagging the channels outside of
+        // the chunk means that they aren't read.
+        let excluded_chans: HashSet<u16> = (0..obs_context.fine_chan_freqs.len())
+            .filter(|i_chan| !chan_range.contains(i_chan))
+            .map(|i_chan| i_chan as u16)
+            .collect();
         let num_unflagged_tiles = self.get_num_unflagged_tiles();
         let num_unflagged_cross_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2;
-        let avg_cross_vis_shape = (self.spw.chanblocks.len(), num_unflagged_cross_baselines);
-        let avg_auto_vis_shape = (self.spw.chanblocks.len(), num_unflagged_tiles);
+        let avg_cross_vis_shape = (spw.chanblocks.len(), num_unflagged_cross_baselines);
+        let avg_auto_vis_shape = (spw.chanblocks.len(), num_unflagged_tiles);
         assert_eq!(cross_data_fb.dim(), avg_cross_vis_shape);
         assert_eq!(cross_weights_fb.dim(), avg_cross_vis_shape);
         if let Some((auto_data_fb, auto_weights_fb)) = autos_fb.as_ref() {
@@ -103,12 +144,12 @@ impl InputVisParams {
             assert_eq!(auto_weights_fb.dim(), avg_auto_vis_shape);
         }
 
-        let averaging = timeblock.timestamps.len() > 1 || self.spw.chans_per_chanblock.get() > 1;
+        let averaging = timeblock.timestamps.len() > 1 || spw.chans_per_chanblock.get() > 1;
 
         if averaging {
             let cross_vis_shape = (
                 timeblock.timestamps.len(),
-                obs_context.fine_chan_freqs.len(),
+                chan_range.len(),
                 num_unflagged_cross_baselines,
             );
             let mut unaveraged_cross_data_tfb = Array3::zeros(cross_vis_shape);
@@ -120,7 +161,7 @@ impl InputVisParams {
                     (Some(_), true) => {
                         let auto_vis_shape = (
                             timeblock.timestamps.len(),
-                            obs_context.fine_chan_freqs.len(),
+                            chan_range.len(),
                             num_unflagged_tiles,
                         );
                         let unaveraged_auto_data_tfb = Array3::zeros(auto_vis_shape);
@@ -156,7 +197,7 @@ impl InputVisParams {
                         unaveraged_cross_data_fb,
                         unaveraged_cross_weights_fb,
                         Some((unaveraged_auto_data_fb, unaveraged_auto_weights_fb)),
-                        &HashSet::new(),
+                        &excluded_chans,
                     )?;
 
                     // Should we continue?
@@ -183,7 +224,7 @@ impl InputVisParams {
                         unaveraged_cross_data_fb,
                         unaveraged_cross_weights_fb,
                         None,
-                        &HashSet::new(),
+                        &excluded_chans,
                     )?;
 
                     // Should we continue?
@@ -194,7 +235,7 @@ impl InputVisParams {
             };
 
             // Apply flagged channels.
-            for i_chan in &self.spw.flagged_chan_indices {
+            for i_chan in &spw.flagged_chan_indices {
                 let i_chan = usize::from(*i_chan);
                 unaveraged_cross_weights_tfb
                     .slice_mut(s![.., i_chan, ..])
@@ -217,7 +258,10 @@ impl InputVisParams {
                     timeblock.index
                 );
 
-                let chan_freqs = obs_context.fine_chan_freqs.mapped_ref(|f| *f as f64);
+                let chan_freqs = obs_context.fine_chan_freqs[chan_range.clone()]
+                    .iter()
+                    .map(|f| *f as f64)
+                    .collect::<Vec<_>>();
                 if let Some((unaveraged_auto_data_tfb, unaveraged_auto_weights_tfb)) =
                     unaveraged_autos.as_mut()
                 {
@@ -240,6 +284,7 @@ impl InputVisParams {
                             cross_weights_fb,
                             Some((auto_data_fb, auto_weights_fb)),
                             &chan_freqs,
+                            spw,
                         );
                     }
                 } else {
@@ -255,6 +300,7 @@ impl InputVisParams {
                                 cross_weights_fb,
                                 None,
                                 &chan_freqs,
+                                spw,
                             );
                         }
                     }
@@ -269,7 +315,7 @@ impl InputVisParams {
                 cross_data_fb,
                 unaveraged_cross_weights_tfb.view(),
                 cross_weights_fb,
-                &self.spw.flagged_chanblock_indices,
+                &spw.flagged_chanblock_indices,
             );
             if let (
                 Some((mut auto_data_fb, mut auto_weights_fb)),
@@ -281,7 +327,7 @@ impl InputVisParams {
                     auto_data_fb.view_mut(),
                     unaveraged_auto_weights_tfb.view(),
                     auto_weights_fb.view_mut(),
-                    &self.spw.flagged_chanblock_indices,
+                    &spw.flagged_chanblock_indices,
                 );
             };
         } else {
@@ -296,7 +342,15 @@ impl InputVisParams {
                 autos_fb.as_mut().map(|(auto_data_fb, auto_weights_fb)| {
                     (auto_data_fb.view_mut(), auto_weights_fb.view_mut())
                 }),
-                &self.spw.flagged_chan_indices,
+                &excluded_chans
+                    .iter()
+                    .copied()
+                    .chain(
+                        spw.flagged_chan_indices
+                            .iter()
+                            .map(|&i_chan| i_chan + chan_range.start as u16),
+                    )
+                    .collect(),
             )?;
 
             // Should we continue?
@@ -312,12 +366,8 @@ impl InputVisParams {
                     cross_data_fb,
                     cross_weights_fb.view_mut(),
                     autos_fb,
-                    &self
-                        .spw
-                        .chanblocks
-                        .iter()
-                        .map(|c| c.freq)
-                        .collect::<Vec<_>>(),
+                    &spw.chanblocks.iter().map(|c| c.freq).collect::<Vec<_>>(),
+                    spw,
                 );
             }
         }
@@ -388,6 +438,7 @@ impl InputVisParams {
         mut cross_weights_fb: ArrayViewMut2<f32>,
         mut autos_fb: Option<(ArrayViewMut2<Jones<f32>>, ArrayViewMut2<f32>)>,
         chan_freqs: &[f64],
+        spw: &Spw,
     ) {
         assert_eq!(cross_data_fb.dim(), cross_weights_fb.dim());
         assert_eq!(cross_data_fb.len_of(Axis(0)), chan_freqs.len());
@@ -404,7 +455,7 @@ impl InputVisParams {
         // frequencies corresponding to the solutions are the same as what's in
         // the data, but there's no way of checking.
         if solution_freqs.is_none()
-            && cross_data_fb.len_of(Axis(0)) + self.spw.flagged_chanblock_indices.len()
+            && cross_data_fb.len_of(Axis(0)) + spw.flagged_chanblock_indices.len()
                 != solutions.di_jones.len_of(Axis(2))
         {
             panic!("Cannot apply calibration solutions to unequal sized data");
@@ -418,35 +469,28 @@ impl InputVisParams {
         .clamp(0.0, 0.99);
 
         // Find solutions corresponding to this timestamp.
-        let sols = solutions.get_timeblock(timestamp, timestamp_fraction);
+        let mut sols = solutions.get_interpolated_timeblock(
+            timestamp,
+            timestamp_fraction,
+            self.solutions_time_interpolation,
+        );
+        if let Some(xy_phase) = solutions.xy_phase.as_ref() {
+            let mut corrected = sols.into_owned();
+            xy_phase.apply(corrected.view_mut());
+            sols = corrected.into();
+        }
         // Now make a lookup vector for the channels. This is better than
         // searching for the right solution channel for each channel below (we
         // use more memory but avoid a quadratic-complexity algorithm).
-        let solution_freq_indices: Option<Vec<usize>> =
-            solution_freqs.as_ref().map(|solution_freqs| {
-                chan_freqs
-                    .iter()
-                    .map(|freq| {
-                        // Find the nearest solution freq to our data freq.
-                        let mut best = f64::INFINITY;
-                        let mut i_sol_freq = 0;
-                        for (i, &sol_freq) in solution_freqs.iter().enumerate() {
-                            let this_diff = (sol_freq - freq).abs();
-                            if this_diff < best {
-                                best = this_diff;
-                                i_sol_freq = i;
-                            } else {
-                                // Because the frequencies are always
-                                // ascendingly sorted, if the frequency
-                                // difference is getting bigger, we can break
-                                // early.
-                                break;
-                            }
-                        }
-                        i_sol_freq
-                    })
-                    .collect()
-            });
+        let chanblock_interps =
+            solutions.get_chanblock_interps(chan_freqs, self.solutions_freq_interpolation);
+        // Get the solution for a tile at a channel; `None` if the channel
+        // isn't covered by the solutions.
+        let get_sol = |i_tile: usize, interp: Option<ChanblockInterp>| {
+            interp.map(|i| {
+                interpolate_jones(sols[(i_tile, i.lower)], sols[(i_tile, i.upper)], i.weight)
+            })
+        };
 
         for (i_baseline, (mut cross_data_f, mut cross_weights_f)) in cross_data_fb
             .axis_iter_mut(Axis(1))
@@ -460,24 +504,25 @@ impl InputVisParams {
                     panic!("Couldn't find baseline index {i_baseline} in unflagged_cross_baseline_to_tile_map")
                 });
 
-            if let Some(solution_freq_indices) = solution_freq_indices.as_ref() {
+            if let Some(chanblock_interps) = chanblock_interps.as_ref() {
                 cross_data_f
                     .iter_mut()
                     .zip_eq(cross_weights_f.iter_mut())
-                    .zip_eq(solution_freq_indices.iter().copied())
-                    .for_each(|((vis_data, vis_weight), i_sol_freq)| {
+                    .zip_eq(chanblock_interps.iter().copied())
+                    .for_each(|((vis_data, vis_weight), interp)| {
                         // Get the solutions for both tiles and apply them.
-                        let sol1 = sols[(tile1, i_sol_freq)];
-                        let sol2 = sols[(tile2, i_sol_freq)];
+                        match (get_sol(tile1, interp), get_sol(tile2, interp)) {
+                            (Some(sol1), Some(sol2)) if !sol1.any_nan() && !sol2.any_nan() => {
+                                // Promote the data before demoting it again.
+                                let d: Jones<f64> = Jones::from(*vis_data);
+                                *vis_data = Jones::from((sol1 * d) * sol2.h());
+                            }
 
-                        // One of the tiles doesn't have a solution; flag.
-                        if sol1.any_nan() || sol2.any_nan() {
-                            *vis_weight = -vis_weight.abs();
-                            *vis_data = Jones::default();
-                        } else {
-                            // Promote the data before demoting it again.
-                            let d: Jones<f64> = Jones::from(*vis_data);
-                            *vis_data = Jones::from((sol1 * d) * sol2.h());
+                            // One of the tiles doesn't have a solution; flag.
+                            _ => {
+                                *vis_weight = -vis_weight.abs();
+                                *vis_data = Jones::default();
+                            }
                         }
                     });
             } else {
@@ -497,7 +542,7 @@ impl InputVisParams {
                         *vis_weight = -vis_weight.abs();
                         *vis_data = Jones::default();
                     } else {
-                        if self.spw.flagged_chan_indices.contains(&i_chan) {
+                        if spw.flagged_chan_indices.contains(&i_chan) {
                             // The channel is flagged, but we still have a solution for it.
                             *vis_weight = -vis_weight.abs();
                         }
@@ -526,23 +571,25 @@ impl InputVisParams {
                         )
                     });
 
-                if let Some(solution_freq_indices) = solution_freq_indices.as_ref() {
+                if let Some(chanblock_interps) = chanblock_interps.as_ref() {
                     auto_data_f
                         .iter_mut()
                         .zip_eq(auto_weights_f.iter_mut())
-                        .zip_eq(solution_freq_indices.iter().copied())
-                        .for_each(|((vis_data, vis_weight), i_sol_freq)| {
+                        .zip_eq(chanblock_interps.iter().copied())
+                        .for_each(|((vis_data, vis_weight), interp)| {
                             // Get the solutions for the tile and apply it twice.
-                            let sol = sols[(i_tile, i_sol_freq)];
-
-                            // No solution; flag.
-                            if sol.any_nan() {
-                                *vis_weight = -vis_weight.abs();
-                                *vis_data = Jones::default();
-                            } else {
-                                // Promote the data before demoting it again.
-                                let d: Jones<f64> = Jones::from(*vis_data);
-                                *vis_data = Jones::from((sol * d) * sol.h());
+                            match get_sol(i_tile, interp) {
+                                Some(sol) if !sol.any_nan() => {
+                                    // Promote the data before demoting it again.
+                                    let d: Jones<f64> = Jones::from(*vis_data);
+                                    *vis_data = Jones::from((sol * d) * sol.h());
+                                }
+
+                                // No solution; flag.
+                                _ => {
+                                    *vis_weight = -vis_weight.abs();
+                                    *vis_data = Jones::default();
+                                }
                             }
                         });
                 } else {
@@ -560,7 +607,7 @@ impl InputVisParams {
                             *vis_weight = -vis_weight.abs();
                             *vis_data = Jones::default();
                         } else {
-                            if self.spw.flagged_chan_indices.contains(&i_chan) {
+                            if spw.flagged_chan_indices.contains(&i_chan) {
                                 // The channel is flagged, but we still have a solution for it.
                                 *vis_weight = -vis_weight.abs();
                             }
@@ -12,6 +12,9 @@
 
 mod di_calibration;
 mod input_vis;
+mod model_vis;
+mod peel;
+mod resources;
 mod solutions_apply;
 mod vis_convert;
 mod vis_simulate;
@@ -19,8 +22,10 @@ mod vis_subtract;
 
 #[cfg(test)]
 pub(crate) use di_calibration::CalVis;
-pub(crate) use di_calibration::{DiCalParams, DiCalibrateError};
+pub(crate) use di_calibration::{DiCalParams, DiCalibrateError, JointObsParams};
 pub(crate) use input_vis::InputVisParams;
+pub(crate) use model_vis::{ModelVisError, ModelVisParams};
+pub(crate) use peel::{PeelError, PeelParams};
 pub(crate) use solutions_apply::SolutionsApplyParams;
 pub(crate) use vis_convert::{VisConvertError, VisConvertParams};
 pub(crate) use vis_simulate::{VisSimulateError, VisSimulateParams};
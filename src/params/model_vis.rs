@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parameters for sky-model visibilities that are read from a file (e.g. one
+//! written by `di-calibrate --model-filenames`, or by another simulator), rather
+//! than being generated from a source list.
+
+use std::{num::NonZeroUsize, ops::Range};
+
+use crossbeam_utils::atomic::AtomicCell;
+use hifitime::Duration;
+use log::debug;
+use marlu::Jones;
+use ndarray::prelude::*;
+use vec1::Vec1;
+
+use super::InputVisParams;
+use crate::{
+    averaging::{Spw, Timeblock},
+    io::read::VisReadError,
+    math::TileBaselineFlags,
+};
+
+/// How far apart the frequencies of model and data channels may be while
+/// still being considered the same \[Hz\].
+const FREQ_TOLERANCE: f64 = 1.0;
+
+/// How far apart the timestamps of model and data timesteps may be while
+/// still being considered the same \[seconds\].
+const TIME_TOLERANCE: f64 = 0.1;
+
+pub(crate) struct ModelVisParams {
+    /// The interface to the model visibilities. Its timeblocks correspond
+    /// one-to-one with the timeblocks of the data being modelled, and its
+    /// [`Spw`] averages the model channels into the data's chanblocks.
+    input_vis_params: InputVisParams,
+}
+
+impl ModelVisParams {
+    /// Match the model visibilities in `model` to the data described by
+    /// `input_vis_params`.
+    ///
+    /// The model must have the same tiles and phase centre as the data, and
+    /// tiles flagged in the model must also be flagged in the data. Its
+    /// channels must either be the same as the data's channels, or the same as
+    /// the data's chanblocks (e.g. if it was written by hyperdrive after
+    /// averaging). Each of the data's timeblocks must either have all of its
+    /// timestamps or its median timestamp in the model.
+    pub(crate) fn new(
+        mut model: InputVisParams,
+        input_vis_params: &InputVisParams,
+    ) -> Result<ModelVisParams, ModelVisError> {
+        let obs_context = input_vis_params.get_obs_context();
+        let model_obs_context = model.get_obs_context();
+
+        let total_num_tiles = obs_context.get_total_num_tiles();
+        if model_obs_context.get_total_num_tiles() != total_num_tiles {
+            return Err(ModelVisError::TilesMismatch {
+                model: model_obs_context.get_total_num_tiles(),
+                data: total_num_tiles,
+            });
+        }
+        if model_obs_context.tile_names != obs_context.tile_names {
+            return Err(ModelVisError::TileNamesMismatch);
+        }
+        // Tiles that are flagged in the model file may not have been modelled,
+        // so they must also be flagged in the data.
+        let unmodelled_tiles = model_obs_context
+            .flagged_tiles
+            .iter()
+            .filter(|i_tile| {
+                !input_vis_params
+                    .tile_baseline_flags
+                    .flagged_tiles
+                    .contains(*i_tile)
+            })
+            .map(|&i_tile| obs_context.tile_names[i_tile].clone())
+            .collect::<Vec<_>>();
+        if !unmodelled_tiles.is_empty() {
+            return Err(ModelVisError::UnmodelledTiles(unmodelled_tiles));
+        }
+
+        if model_obs_context
+            .phase_centre
+            .separation(obs_context.phase_centre)
+            > 1e-6
+        {
+            return Err(ModelVisError::PhaseCentreMismatch);
+        }
+
+        fn freqs_match(a: &[f64], b: &[f64]) -> bool {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| (a - b).abs() < FREQ_TOLERANCE)
+        }
+        let model_freqs = model_obs_context
+            .fine_chan_freqs
+            .iter()
+            .map(|&f| f as f64)
+            .collect::<Vec<_>>();
+        let data_freqs = obs_context
+            .fine_chan_freqs
+            .iter()
+            .map(|&f| f as f64)
+            .collect::<Vec<_>>();
+        let spw = &input_vis_params.spw;
+        let chanblock_freqs = spw.get_all_freqs();
+        let model_spw = if freqs_match(&model_freqs, &data_freqs) {
+            debug!("The model has the same channels as the data");
+            Spw {
+                chanblocks: spw.chanblocks.clone(),
+                flagged_chan_indices: spw.flagged_chan_indices.clone(),
+                flagged_chanblock_indices: spw.flagged_chanblock_indices.clone(),
+                chans_per_chanblock: spw.chans_per_chanblock,
+                freq_res: spw.freq_res,
+                first_freq: spw.first_freq,
+            }
+        } else if freqs_match(&model_freqs, &chanblock_freqs) {
+            debug!("The model channels are the data's chanblocks");
+            Spw {
+                chanblocks: spw.chanblocks.clone(),
+                flagged_chan_indices: spw.flagged_chanblock_indices.clone(),
+                flagged_chanblock_indices: spw.flagged_chanblock_indices.clone(),
+                chans_per_chanblock: NonZeroUsize::new(1).expect("is not 0"),
+                freq_res: spw.freq_res,
+                first_freq: spw.first_freq,
+            }
+        } else {
+            return Err(ModelVisError::ChannelsMismatch {
+                model: model_freqs.len(),
+                data: data_freqs.len(),
+                chanblocks: chanblock_freqs.len(),
+            });
+        };
+
+        // Find the model timesteps of each data timeblock.
+        let tolerance = Duration::from_seconds(TIME_TOLERANCE);
+        let find_timestep = |timestamp| {
+            model_obs_context
+                .timestamps
+                .iter()
+                .position(|&t| (t - timestamp).abs() < tolerance)
+        };
+        let mut model_timeblocks = Vec::with_capacity(input_vis_params.timeblocks.len());
+        let mut i_unflagged_timestep = 0;
+        for timeblock in &input_vis_params.timeblocks {
+            let timesteps = match timeblock
+                .timestamps
+                .iter()
+                .map(|&t| find_timestep(t))
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(timesteps) => timesteps,
+                None => match find_timestep(timeblock.median) {
+                    Some(timestep) => vec![timestep],
+                    None => {
+                        return Err(ModelVisError::MissingTimestamp(
+                            timeblock.median.to_gpst_seconds(),
+                        ))
+                    }
+                },
+            };
+            let timesteps = Vec1::try_from_vec(timesteps).expect("cannot be empty");
+            let range = i_unflagged_timestep..i_unflagged_timestep + timesteps.len();
+            i_unflagged_timestep = range.end;
+            model_timeblocks.push(Timeblock {
+                index: timeblock.index,
+                range,
+                timestamps: timesteps.mapped_ref(|&i| model_obs_context.timestamps[i]),
+                timesteps,
+                median: timeblock.median,
+            });
+        }
+
+        model.timeblocks = Vec1::try_from_vec(model_timeblocks).expect("cannot be empty");
+        model.spw = model_spw;
+        model.tile_baseline_flags = TileBaselineFlags::new(
+            total_num_tiles,
+            input_vis_params.tile_baseline_flags.flagged_tiles.clone(),
+        );
+        model.solutions = None;
+        model.using_autos = false;
+        // The weights of the model don't matter; flagged channels are still
+        // excluded when averaging.
+        model.ignore_weights = true;
+        model.dut1 = input_vis_params.dut1;
+
+        Ok(ModelVisParams {
+            input_vis_params: model,
+        })
+    }
+
+    /// Get the [`InputVisParams`] of the model visibilities.
+    pub(crate) fn get_input_vis_params(&self) -> &InputVisParams {
+        &self.input_vis_params
+    }
+
+    /// Read the model visibilities of the data timeblock with index
+    /// `i_timeblock` into `vis_fb`, averaged into the data's chanblocks. If
+    /// `chanblock_range` is specified, only these chanblocks are read (see
+    /// [`Spw::get_chunk`]).
+    pub(crate) fn read_timeblock(
+        &self,
+        i_timeblock: usize,
+        chanblock_range: Option<&Range<usize>>,
+        vis_fb: ArrayViewMut2<Jones<f32>>,
+        error: &AtomicCell<bool>,
+    ) -> Result<(), VisReadError> {
+        let num_chans = self
+            .input_vis_params
+            .get_obs_context()
+            .fine_chan_freqs
+            .len();
+        let chunk =
+            chanblock_range.map(|r| self.input_vis_params.spw.get_chunk(r.clone(), num_chans));
+        let (spw, chan_range) = match chunk.as_ref() {
+            Some((spw, chan_range)) => (spw, chan_range.clone()),
+            None => (&self.input_vis_params.spw, 0..num_chans),
+        };
+        let mut weights_fb = Array2::zeros(vis_fb.dim());
+        self.input_vis_params.read_timeblock_chunk(
+            &self.input_vis_params.timeblocks[i_timeblock],
+            spw,
+            chan_range,
+            vis_fb,
+            weights_fb.view_mut(),
+            None,
+            error,
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ModelVisError {
+    #[error("The model visibilities have {model} tiles, but the data has {data}")]
+    TilesMismatch { model: usize, data: usize },
+
+    #[error("The model visibilities have different tile names to the data")]
+    TileNamesMismatch,
+
+    #[error("The model visibilities flag tiles {0:?}, but the data doesn't; these tiles need to be flagged (e.g. with --tile-flags)")]
+    UnmodelledTiles(Vec<String>),
+
+    #[error("The model visibilities have a different phase centre to the data")]
+    PhaseCentreMismatch,
+
+    #[error("The model visibilities have {model} channels, which match neither the data's {data} channels nor its {chanblocks} chanblocks")]
+    ChannelsMismatch {
+        model: usize,
+        data: usize,
+        chanblocks: usize,
+    },
+
+    #[error("The model visibilities don't have the data's timestamp {0} (GPS seconds)")]
+    MissingTimestamp(f64),
+}
@@ -0,0 +1,523 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Given input data and a sky model, subtract the whole sky model from the
+//! input data, but "peel" the brightest sources; for each of these sources,
+//! solve for per-tile Jones matrices in the direction of the source and
+//! subtract the corrupted model of that source.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    num::NonZeroUsize,
+    thread::{self, ScopedJoinHandle},
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_utils::atomic::AtomicCell;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use itertools::Itertools;
+use log::{debug, info};
+use marlu::Jones;
+use ndarray::prelude::*;
+use scopeguard::defer_on_unwind;
+
+use super::{InputVisParams, ModellingParams, OutputVisParams};
+use crate::{
+    beam::Beam,
+    di_calibrate::{calibrate, RobustWeighting, SolveMode, Solver},
+    io::{
+        read::VisReadError,
+        write::{write_vis, VisTimestep},
+    },
+    model::{new_sky_modeller, ModelError},
+    srclist::SourceList,
+    PROGRESS_BARS,
+};
+
+pub(crate) struct PeelParams {
+    pub(crate) input_vis_params: InputVisParams,
+    pub(crate) output_vis_params: OutputVisParams,
+    pub(crate) beam: Box<dyn Beam>,
+
+    /// The sky-model source list. All of these sources are subtracted from the
+    /// input data. The list is sorted by reverse brightness, so the first
+    /// `num_sources_to_peel` sources are peeled.
+    pub(crate) source_list: SourceList,
+
+    /// The number of sources (the brightest in the source list) to peel.
+    pub(crate) num_sources_to_peel: usize,
+
+    /// The number of times to iterate over all of the peel sources. Sources
+    /// are re-added to the residuals before they are solved for again.
+    pub(crate) num_passes: NonZeroUsize,
+
+    /// The maximum number of times to iterate when solving for a peel source.
+    pub(crate) max_iterations: u32,
+
+    /// The threshold at which we stop iterating when solving for a peel source.
+    pub(crate) stop_threshold: f64,
+
+    /// The minimum threshold to satisfy convergence when solving for a peel
+    /// source.
+    pub(crate) min_threshold: f64,
+
+    pub(crate) modelling_params: ModellingParams,
+}
+
+impl PeelParams {
+    pub(crate) fn run(&self) -> Result<(), PeelError> {
+        let PeelParams {
+            input_vis_params,
+            output_vis_params,
+            ..
+        } = self;
+
+        let obs_context = input_vis_params.get_obs_context();
+        let num_unflagged_tiles = input_vis_params.get_num_unflagged_tiles();
+        let num_unflagged_cross_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2;
+        let vis_shape = (
+            input_vis_params.spw.chanblocks.len(),
+            num_unflagged_cross_baselines,
+        );
+
+        // Channel for modelling and peeling.
+        let (tx_peel, rx_peel) = bounded(5);
+        // Channel for writing peeled visibilities.
+        let (tx_write, rx_write) = bounded(5);
+
+        // Progress bars.
+        let multi_progress = MultiProgress::with_draw_target(if PROGRESS_BARS.load() {
+            ProgressDrawTarget::stdout()
+        } else {
+            ProgressDrawTarget::hidden()
+        });
+        let pb = ProgressBar::new(input_vis_params.timeblocks.len() as _)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{msg:17}: [{wide_bar:.blue}] {pos:2}/{len:2} timesteps ({elapsed_precise}<{eta_precise})").unwrap()
+                .progress_chars("=> "),
+        )
+        .with_position(0)
+        .with_message("Reading data");
+        let read_progress = multi_progress.add(pb);
+        let pb = ProgressBar::new(input_vis_params.timeblocks.len() as _)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{msg:17}: [{wide_bar:.blue}] {pos:2}/{len:2} timesteps ({elapsed_precise}<{eta_precise})").unwrap()
+                .progress_chars("=> "),
+        )
+        .with_position(0)
+        .with_message("Peeling");
+        let peel_progress = multi_progress.add(pb);
+        let pb = ProgressBar::new(output_vis_params.output_timeblocks.len() as _)
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("{msg:17}: [{wide_bar:.blue}] {pos:2}/{len:2} timeblocks ({elapsed_precise}<{eta_precise})").unwrap()
+                .progress_chars("=> "),
+        )
+        .with_position(0)
+        .with_message("Residual writing");
+        let write_progress = multi_progress.add(pb);
+
+        // Use a variable to track whether any threads have an issue.
+        let error = AtomicCell::new(false);
+
+        info!("Reading input data, peeling, and writing");
+        let scoped_threads_result: Result<String, PeelError> = thread::scope(|scope| {
+            // Input visibility-data reading thread.
+            let data_handle: ScopedJoinHandle<Result<(), VisReadError>> = thread::Builder::new()
+                .name("read".to_string())
+                .spawn_scoped(scope, || {
+                    // If a panic happens, update our atomic error.
+                    defer_on_unwind! { error.store(true); }
+                    read_progress.tick();
+
+                    for timeblock in &input_vis_params.timeblocks {
+                        let mut cross_data_fb = Array2::zeros(vis_shape);
+                        let mut cross_weights_fb = Array2::zeros(vis_shape);
+
+                        let result = input_vis_params.read_timeblock(
+                            timeblock,
+                            cross_data_fb.view_mut(),
+                            cross_weights_fb.view_mut(),
+                            None,
+                            &error,
+                        );
+
+                        // If the result of reading data was an error, allow the other
+                        // threads to see this so they can abandon their work early.
+                        if result.is_err() {
+                            error.store(true);
+                        }
+                        result?;
+
+                        // Should we continue?
+                        if error.load() {
+                            return Ok(());
+                        }
+
+                        match tx_peel.send(VisTimestep {
+                            cross_data_fb: cross_data_fb.into_shared(),
+                            cross_weights_fb: cross_weights_fb.into_shared(),
+                            autos: None,
+                            timestamp: timeblock.median,
+                        }) {
+                            Ok(()) => (),
+                            // If we can't send the message, it's because the channel
+                            // has been closed on the other side. That should only
+                            // happen because the writer has exited due to error; in
+                            // that case, just exit this thread.
+                            Err(_) => return Ok(()),
+                        }
+
+                        read_progress.inc(1);
+                    }
+
+                    debug!("Finished reading");
+                    read_progress.abandon_with_message("Finished reading visibilities");
+                    drop(tx_peel);
+                    Ok(())
+                })
+                .expect("OS can create threads");
+
+            // Sky-model generation and peeling thread.
+            let peel_handle: ScopedJoinHandle<Result<(), ModelError>> = thread::Builder::new()
+                .name("peel".to_string())
+                .spawn_scoped(scope, || {
+                    defer_on_unwind! { error.store(true); }
+                    peel_progress.tick();
+
+                    let result =
+                        peel_thread(self, vis_shape, rx_peel, tx_write, &error, peel_progress);
+                    if result.is_err() {
+                        error.store(true);
+                    }
+                    result
+                })
+                .expect("OS can create threads");
+
+            // Residual vis writing thread.
+            let write_handle = thread::Builder::new()
+                .name("write".to_string())
+                .spawn_scoped(scope, || {
+                    defer_on_unwind! { error.store(true); }
+                    write_progress.tick();
+
+                    let result = write_vis(
+                        &output_vis_params.output_files,
+                        obs_context.array_position,
+                        obs_context.phase_centre,
+                        obs_context.pointing_centre,
+                        &obs_context.tile_xyzs,
+                        &obs_context.tile_names,
+                        obs_context.obsid,
+                        &output_vis_params.output_timeblocks,
+                        input_vis_params.time_res,
+                        input_vis_params.dut1,
+                        &input_vis_params.spw,
+                        &input_vis_params
+                            .tile_baseline_flags
+                            .unflagged_cross_baseline_to_tile_map
+                            .values()
+                            .copied()
+                            .sorted()
+                            .collect::<Vec<_>>(),
+                        output_vis_params.output_time_average_factor,
+                        output_vis_params.output_freq_average_factor,
+                        input_vis_params.vis_reader.get_marlu_mwa_info().as_ref(),
+                        output_vis_params.write_smallest_contiguous_band,
+                        rx_write,
+                        &error,
+                        Some(write_progress),
+                    );
+                    if result.is_err() {
+                        error.store(true);
+                    }
+                    result
+                })
+                .expect("OS can create threads");
+
+            // Join all thread handles. This propagates any errors and lets us know
+            // if any threads panicked, if panics aren't aborting as per the
+            // Cargo.toml.
+            data_handle.join().unwrap()?;
+            peel_handle.join().unwrap()?;
+            let write_message = write_handle.join().unwrap()?;
+            Ok(write_message)
+        });
+
+        // Propagate errors and print out the write message.
+        info!("{}", scoped_threads_result?);
+
+        Ok(())
+    }
+}
+
+fn peel_thread(
+    params: &PeelParams,
+    vis_shape: (usize, usize),
+    rx: Receiver<VisTimestep>,
+    tx: Sender<VisTimestep>,
+    error: &AtomicCell<bool>,
+    progress_bar: ProgressBar,
+) -> Result<(), ModelError> {
+    let PeelParams {
+        input_vis_params,
+        output_vis_params: _,
+        beam,
+        source_list,
+        num_sources_to_peel,
+        num_passes,
+        max_iterations,
+        stop_threshold,
+        min_threshold,
+        modelling_params: ModellingParams { apply_precession },
+    } = params;
+
+    let obs_context = input_vis_params.get_obs_context();
+    let unflagged_tile_xyzs = obs_context
+        .tile_xyzs
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            !input_vis_params
+                .tile_baseline_flags
+                .flagged_tiles
+                .contains(i)
+        })
+        .map(|(_, xyz)| *xyz)
+        .collect::<Vec<_>>();
+    let num_unflagged_tiles = unflagged_tile_xyzs.len();
+    let freqs = input_vis_params
+        .spw
+        .chanblocks
+        .iter()
+        .map(|c| c.freq)
+        .collect::<Vec<_>>();
+
+    // One modeller for the whole sky, and one for each of the peel sources.
+    let sky_modeller = new_sky_modeller(
+        &**beam,
+        source_list,
+        obs_context.polarisations,
+        &unflagged_tile_xyzs,
+        &freqs,
+        &input_vis_params.tile_baseline_flags.flagged_tiles,
+        obs_context.phase_centre,
+        obs_context.array_position.longitude_rad,
+        obs_context.array_position.latitude_rad,
+        input_vis_params.dut1,
+        *apply_precession,
+    )?;
+    let peel_modellers = source_list
+        .iter()
+        .take(*num_sources_to_peel)
+        .map(|(name, source)| {
+            let peel_source_list = SourceList::from([(name.clone(), source.clone())]);
+            new_sky_modeller(
+                &**beam,
+                &peel_source_list,
+                obs_context.polarisations,
+                &unflagged_tile_xyzs,
+                &freqs,
+                &input_vis_params.tile_baseline_flags.flagged_tiles,
+                obs_context.phase_centre,
+                obs_context.array_position.longitude_rad,
+                obs_context.array_position.latitude_rad,
+                input_vis_params.dut1,
+                *apply_precession,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let num_peel_sources = peel_modellers.len();
+
+    // The per-source, per-tile Jones matrices. These are carried over between
+    // timesteps so that each solve has a good starting point.
+    let mut peel_jones: Array2<Jones<f64>> =
+        Array2::from_elem((num_peel_sources, num_unflagged_tiles), Jones::identity());
+    // The unaltered model visibilities of each peel source, as well as what has
+    // currently been subtracted from the residuals for each peel source.
+    let mut peel_models_sfb: Array3<Jones<f32>> =
+        Array3::zeros((num_peel_sources, vis_shape.0, vis_shape.1));
+    let mut peel_subtracted_sfb: Array3<Jones<f32>> = Array3::zeros(peel_models_sfb.dim());
+    // Recycle arrays for the model visibilities and weighted visibilities.
+    let mut vis_model_fb = Array2::zeros(vis_shape);
+    let mut weighted_data_tfb = Array3::zeros((1, vis_shape.0, vis_shape.1));
+    let mut weighted_model_tfb = Array3::zeros((1, vis_shape.0, vis_shape.1));
+    let mut num_failed_solves = 0;
+
+    // Iterate over the incoming data.
+    for VisTimestep {
+        mut cross_data_fb,
+        cross_weights_fb,
+        autos,
+        timestamp,
+    } in rx.iter()
+    {
+        debug!("Peeling timestamp {}", timestamp.to_gpst_seconds());
+
+        // Subtract the whole sky model.
+        sky_modeller.model_timestep_with(timestamp, vis_model_fb.view_mut())?;
+        cross_data_fb
+            .iter_mut()
+            .zip_eq(vis_model_fb.iter())
+            .for_each(|(vis_data, vis_model)| {
+                *vis_data =
+                    Jones::from(Jones::<f64>::from(*vis_data) - Jones::<f64>::from(*vis_model));
+            });
+        vis_model_fb.fill(Jones::default());
+
+        // Model each of the peel sources. Currently, the unaltered models are
+        // what have been subtracted.
+        for (modeller, mut peel_model_fb) in peel_modellers
+            .iter()
+            .zip_eq(peel_models_sfb.outer_iter_mut())
+        {
+            peel_model_fb.fill(Jones::default());
+            modeller.model_timestep_with(timestamp, peel_model_fb.view_mut())?;
+        }
+        peel_subtracted_sfb.assign(&peel_models_sfb);
+
+        for _ in 0..num_passes.get() {
+            for ((peel_model_fb, mut peel_subtracted_fb), mut jones) in peel_models_sfb
+                .outer_iter()
+                .zip_eq(peel_subtracted_sfb.outer_iter_mut())
+                .zip_eq(peel_jones.outer_iter_mut())
+            {
+                // Add this source back into the residuals, and use the result
+                // to solve for this source's Jones matrices. Visibilities are
+                // multiplied by their weights before solving, as is done for
+                // DI calibration.
+                ndarray::Zip::from(cross_data_fb.view_mut())
+                    .and(peel_subtracted_fb.view())
+                    .and(peel_model_fb)
+                    .and(cross_weights_fb.view())
+                    .and(weighted_data_tfb.index_axis_mut(Axis(0), 0))
+                    .and(weighted_model_tfb.index_axis_mut(Axis(0), 0))
+                    .for_each(
+                        |vis_data, subtracted, model, &weight, weighted_data, weighted_model| {
+                            *vis_data = Jones::from(
+                                Jones::<f64>::from(*vis_data) + Jones::<f64>::from(*subtracted),
+                            );
+                            if weight <= 0.0 {
+                                *weighted_data = Jones::default();
+                                *weighted_model = Jones::default();
+                            } else {
+                                *weighted_data = *vis_data * weight;
+                                *weighted_model = *model * weight;
+                            }
+                        },
+                    );
+
+                let result = calibrate(
+                    weighted_data_tfb.view(),
+                    weighted_model_tfb.view(),
+                    jones.view_mut(),
+                    *max_iterations,
+                    *stop_threshold,
+                    *min_threshold,
+                    SolveMode::Full,
+                    RobustWeighting::None,
+                    Solver::default(),
+                    obs_context.polarisations,
+                );
+
+                // If the solve failed, fall back on subtracting the unaltered
+                // model of this source.
+                if result.converged {
+                    corrupt_model(peel_model_fb, jones.view(), peel_subtracted_fb.view_mut());
+                } else {
+                    num_failed_solves += 1;
+                    jones.fill(Jones::identity());
+                    peel_subtracted_fb.assign(&peel_model_fb);
+                }
+
+                cross_data_fb
+                    .iter_mut()
+                    .zip_eq(peel_subtracted_fb.iter())
+                    .for_each(|(vis_data, subtracted)| {
+                        *vis_data = Jones::from(
+                            Jones::<f64>::from(*vis_data) - Jones::<f64>::from(*subtracted),
+                        );
+                    });
+            }
+        }
+
+        // Should we continue?
+        if error.load() {
+            return Ok(());
+        }
+
+        match tx.send(VisTimestep {
+            cross_data_fb,
+            cross_weights_fb,
+            autos,
+            timestamp,
+        }) {
+            Ok(()) => (),
+            Err(_) => return Ok(()),
+        }
+        progress_bar.inc(1);
+    }
+
+    debug!("Finished peeling");
+    if num_failed_solves > 0 {
+        info!("{num_failed_solves} peel solves did not converge; the unaltered models of these sources were subtracted instead");
+    }
+    progress_bar.abandon_with_message("Finished peeling");
+    Ok(())
+}
+
+/// Corrupt model visibilities with per-tile Jones matrices (i.e. J1 M J2^H).
+/// The baselines are expected to be ordered as they are for calibration (tile 1
+/// increments slowest).
+fn corrupt_model(
+    model_fb: ArrayView2<Jones<f32>>,
+    jones: ArrayView1<Jones<f64>>,
+    mut corrupted_fb: ArrayViewMut2<Jones<f32>>,
+) {
+    let num_tiles = jones.len();
+    model_fb
+        .outer_iter()
+        .zip_eq(corrupted_fb.outer_iter_mut())
+        .for_each(|(model_b, mut corrupted_b)| {
+            let mut i_tile1 = 0;
+            let mut i_tile2 = 0;
+            model_b
+                .iter()
+                .zip_eq(corrupted_b.iter_mut())
+                .for_each(|(model, corrupted)| {
+                    i_tile2 += 1;
+                    if i_tile2 == num_tiles {
+                        i_tile1 += 1;
+                        i_tile2 = i_tile1 + 1;
+                    }
+
+                    let j1 = jones[i_tile1];
+                    let j2 = jones[i_tile2];
+                    *corrupted = Jones::from(j1 * Jones::<f64>::from(*model) * j2.h());
+                });
+        });
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum PeelError {
+    #[error(transparent)]
+    VisRead(#[from] crate::io::read::VisReadError),
+
+    #[error(transparent)]
+    VisWrite(#[from] crate::io::write::VisWriteError),
+
+    #[error(transparent)]
+    Model(#[from] crate::model::ModelError),
+
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+
+    #[cfg(any(feature = "cuda", feature = "hip"))]
+    #[error(transparent)]
+    Gpu(#[from] crate::gpu::GpuError),
+}
@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Peeling tests.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+
+use super::corrupt_model;
+use crate::{
+    context::Polarisations,
+    di_calibrate::{calibrate, RobustWeighting, SolveMode, Solver},
+};
+
+/// Make some per-tile Jones matrices.
+fn get_jones(num_tiles: usize) -> Array1<Jones<f64>> {
+    Array1::from_shape_fn(num_tiles, |i_tile| {
+        let i = i_tile as f64;
+        Jones::from([
+            c64::new(1.0 + i * 0.1, i * 0.05),
+            c64::new(0.01, 0.0),
+            c64::new(0.0, -0.01),
+            c64::new(0.9 - i * 0.02, -i * 0.03),
+        ])
+    })
+}
+
+#[test]
+fn test_corrupt_model_with_identity_does_nothing() {
+    let num_tiles = 5;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let model_fb = Array2::from_shape_fn((3, num_baselines), |(i_chan, i_bl)| {
+        Jones::identity() * (1.0 + i_chan as f32 + i_bl as f32 * 0.1)
+    });
+    let jones = Array1::from_elem(num_tiles, Jones::identity());
+    let mut corrupted_fb = Array2::zeros(model_fb.dim());
+
+    corrupt_model(model_fb.view(), jones.view(), corrupted_fb.view_mut());
+    assert_abs_diff_eq!(corrupted_fb, model_fb);
+}
+
+#[test]
+fn test_corrupt_model_baseline_ordering() {
+    let num_tiles = 4;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let model_fb = Array2::from_elem((1, num_baselines), Jones::identity());
+    let jones = get_jones(num_tiles);
+    let mut corrupted_fb = Array2::zeros(model_fb.dim());
+
+    corrupt_model(model_fb.view(), jones.view(), corrupted_fb.view_mut());
+    let mut i_bl = 0;
+    for i_tile1 in 0..num_tiles {
+        for i_tile2 in i_tile1 + 1..num_tiles {
+            let expected = Jones::<f32>::from(jones[i_tile1] * jones[i_tile2].h());
+            assert_abs_diff_eq!(corrupted_fb[(0, i_bl)], expected, epsilon = 1e-6);
+            i_bl += 1;
+        }
+    }
+}
+
+/// If data are a corrupted model, solving for the Jones matrices and then
+/// corrupting the model again should reproduce the data (i.e. the peeled
+/// residuals are zero).
+#[test]
+fn test_peeled_residuals_are_zero() {
+    let num_tiles = 8;
+    let num_baselines = num_tiles * (num_tiles - 1) / 2;
+    let num_chans = 4;
+    let model_fb = Array2::from_shape_fn((num_chans, num_baselines), |(i_chan, i_bl)| {
+        Jones::from([
+            c64::new(1.0 + i_chan as f64 * 0.1, i_bl as f64 * 0.01),
+            c64::new(0.0, 0.0),
+            c64::new(0.0, 0.0),
+            c64::new(1.0 - i_chan as f64 * 0.1, -(i_bl as f64) * 0.01),
+        ])
+        .into()
+    });
+    let true_jones = get_jones(num_tiles);
+    let mut data_fb = Array2::zeros(model_fb.dim());
+    corrupt_model(model_fb.view(), true_jones.view(), data_fb.view_mut());
+
+    let mut jones = Array1::from_elem(num_tiles, Jones::identity());
+    let result = calibrate(
+        data_fb.view().insert_axis(Axis(0)),
+        model_fb.view().insert_axis(Axis(0)),
+        jones.view_mut(),
+        100,
+        1e-10,
+        1e-6,
+        SolveMode::Full,
+        RobustWeighting::None,
+        Solver::default(),
+        Polarisations::default(),
+    );
+    assert!(result.converged);
+    assert_eq!(result.num_failed, 0);
+
+    let mut corrupted_fb = Array2::zeros(model_fb.dim());
+    corrupt_model(model_fb.view(), jones.view(), corrupted_fb.view_mut());
+    assert_abs_diff_eq!(corrupted_fb, data_fb, epsilon = 1e-4);
+}
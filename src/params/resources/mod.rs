@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Rough estimates of the resources needed by `hyperdrive` jobs, so that users
+//! can check them with `--dry-run` before submitting a long job.
+//!
+//! Only the big visibility arrays are counted towards the memory estimate;
+//! beam caches, source lists and the like are usually much smaller. Sky
+//! modelling work is counted as the number of component-baseline-frequency
+//! (per timestep) operations. Shapelets are more expensive to model than
+//! points and Gaussians, so these are also counted separately.
+
+#[cfg(test)]
+mod tests;
+
+use indicatif::{HumanBytes, HumanCount};
+use log::{debug, info};
+use marlu::Jones;
+
+use super::{InputVisParams, OutputVisParams};
+use crate::{cli::Warn, io::read::VisInputType, srclist::ComponentCounts};
+
+/// The number of bytes used by a single visibility and its weight.
+pub(super) const VIS_AND_WEIGHT_BYTES: u64 =
+    (std::mem::size_of::<Jones<f32>>() + std::mem::size_of::<f32>()) as u64;
+
+/// An estimate of the resources needed by a job.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ResourceEstimate {
+    /// The peak memory needed \[bytes\].
+    pub(crate) peak_memory: u64,
+
+    /// The number of component-baseline-frequency operations done by the sky
+    /// modeller, summed over all timesteps.
+    pub(crate) num_model_ops: u64,
+
+    /// The number of those operations that are for shapelet components.
+    pub(crate) num_shapelet_model_ops: u64,
+}
+
+impl ResourceEstimate {
+    /// Report this estimate, warning if the estimated peak memory exceeds the
+    /// memory available to this process.
+    pub(crate) fn display(&self) {
+        info!(
+            "Estimated peak memory use: {}",
+            HumanBytes(self.peak_memory)
+        );
+        if self.num_model_ops > 0 {
+            info!(
+                "Estimated sky-modelling work: {} component-baseline-frequency operations ({} for shapelets)",
+                HumanCount(self.num_model_ops),
+                HumanCount(self.num_shapelet_model_ops)
+            );
+        }
+
+        match get_available_memory() {
+            Some(available) if self.peak_memory > available => format!(
+                "The estimated peak memory use ({}) exceeds the available memory ({})",
+                HumanBytes(self.peak_memory),
+                HumanBytes(available)
+            )
+            .warn(),
+            Some(available) => info!("Available memory: {}", HumanBytes(available)),
+            None => debug!("Couldn't determine the available memory"),
+        }
+    }
+
+    /// Set the sky-modelling work from the number of each component type, for
+    /// `num_baselines` baselines, `num_freqs` frequencies and `num_timesteps`
+    /// timesteps.
+    pub(super) fn with_model_ops(
+        mut self,
+        counts: &ComponentCounts,
+        num_baselines: usize,
+        num_freqs: usize,
+        num_timesteps: usize,
+    ) -> ResourceEstimate {
+        let per_component = (num_baselines * num_freqs * num_timesteps) as u64;
+        let num_components = counts.num_points + counts.num_gaussians + counts.num_shapelets;
+        self.num_model_ops += num_components as u64 * per_component;
+        self.num_shapelet_model_ops += counts.num_shapelets as u64 * per_component;
+        self
+    }
+}
+
+/// Get the number of bytes needed to read one timeblock of visibilities (with
+/// `num_chans` channels and `num_baselines` baselines) from the input data. If
+/// the input data are averaged, the un-averaged visibilities need their own
+/// arrays. Raw MWA data also need a buffer for the timestep being read.
+pub(super) fn get_read_memory(
+    input_vis_params: &InputVisParams,
+    num_chans: usize,
+    num_baselines: usize,
+) -> u64 {
+    let max_timeblock_len = input_vis_params
+        .timeblocks
+        .iter()
+        .map(|tb| tb.timestamps.len())
+        .max()
+        .unwrap_or(1);
+    let averaging_memory =
+        if max_timeblock_len > 1 || input_vis_params.spw.chans_per_chanblock.get() > 1 {
+            (max_timeblock_len * num_chans * num_baselines) as u64 * VIS_AND_WEIGHT_BYTES
+        } else {
+            0
+        };
+    averaging_memory + get_raw_read_memory(input_vis_params, num_chans)
+}
+
+/// Get the number of bytes needed by the raw MWA data reader to read a timestep
+/// with `num_chans` channels. The reader works with whole coarse channels and
+/// all baselines (including autos and flagged tiles), and it holds flags as
+/// well as weights. This is zero for other kinds of input data.
+fn get_raw_read_memory(input_vis_params: &InputVisParams, num_chans: usize) -> u64 {
+    if !matches!(
+        input_vis_params.vis_reader.get_input_data_type(),
+        VisInputType::Raw
+    ) {
+        return 0;
+    }
+
+    let obs_context = input_vis_params.get_obs_context();
+    let total_num_tiles = obs_context.get_total_num_tiles();
+    let num_all_baselines = (total_num_tiles * (total_num_tiles + 1)) / 2;
+    let total_num_chans = obs_context.fine_chan_freqs.len();
+    let num_raw_chans = match obs_context.num_fine_chans_per_coarse_chan {
+        Some(n) => {
+            let n = usize::from(n.get());
+            // A range of channels that isn't aligned with the coarse channels
+            // can straddle an extra coarse channel.
+            (((num_chans + n - 1) / n + 1) * n).min(total_num_chans)
+        }
+        None => total_num_chans,
+    };
+    (num_raw_chans * num_all_baselines) as u64
+        * (VIS_AND_WEIGHT_BYTES + std::mem::size_of::<bool>() as u64)
+}
+
+/// Get the number of bytes needed by the visibility writer, which holds all of
+/// the timesteps of an output timeblock (with `num_chanblocks` chanblocks and
+/// `num_baselines` baselines) before averaging and writing them.
+pub(super) fn get_write_memory(
+    output_vis_params: &OutputVisParams,
+    num_chanblocks: usize,
+    num_baselines: usize,
+) -> u64 {
+    (output_vis_params.output_time_average_factor.get() * num_chanblocks * num_baselines) as u64
+        * VIS_AND_WEIGHT_BYTES
+}
+
+/// Get the memory available to this process \[bytes\]. This is the smaller of
+/// the system's available memory and the memory allocated by SLURM to this job
+/// (if any). `None` is returned if neither can be determined.
+pub(crate) fn get_available_memory() -> Option<u64> {
+    let system = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|s| parse_meminfo_available(&s));
+    // SLURM specifies memory in megabytes.
+    let slurm = std::env::var("SLURM_MEM_PER_NODE")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .map(|mb| mb * 1024 * 1024);
+    match (system, slurm) {
+        (Some(system), Some(slurm)) => Some(system.min(slurm)),
+        (system, slurm) => system.or(slurm),
+    }
+}
+
+/// Get the available memory \[bytes\] from the contents of `/proc/meminfo`.
+fn parse_meminfo_available(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix("MemAvailable:")?;
+        let kib = value
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        Some(kib * 1024)
+    })
+}
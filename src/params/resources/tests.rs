@@ -0,0 +1,41 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::*;
+
+#[test]
+fn test_parse_meminfo_available() {
+    let meminfo = "MemTotal:       263840360 kB
+MemFree:        12345678 kB
+MemAvailable:   201234567 kB
+Buffers:            1234 kB
+";
+    assert_eq!(parse_meminfo_available(meminfo), Some(201234567 * 1024));
+
+    // Old kernels don't report the available memory.
+    let meminfo = "MemTotal:       263840360 kB
+MemFree:        12345678 kB
+";
+    assert_eq!(parse_meminfo_available(meminfo), None);
+    assert_eq!(parse_meminfo_available("MemAvailable: lots"), None);
+}
+
+#[test]
+fn test_model_ops() {
+    let counts = ComponentCounts {
+        num_points: 3,
+        num_gaussians: 2,
+        num_shapelets: 1,
+        num_power_laws: 6,
+        ..Default::default()
+    };
+    let estimate = ResourceEstimate::default().with_model_ops(&counts, 8128, 768, 56);
+    assert_eq!(estimate.num_model_ops, 6 * 8128 * 768 * 56);
+    assert_eq!(estimate.num_shapelet_model_ops, 8128 * 768 * 56);
+    assert_eq!(estimate.peak_memory, 0);
+
+    // Operations accumulate (e.g. over multiple observations).
+    let estimate = estimate.with_model_ops(&counts, 8128, 768, 56);
+    assert_eq!(estimate.num_model_ops, 2 * 6 * 8128 * 768 * 56);
+}
@@ -2,7 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use super::{InputVisParams, OutputVisParams, VisConvertError, VisConvertParams};
+use super::{
+    resources::ResourceEstimate, InputVisParams, OutputVisParams, VisConvertError, VisConvertParams,
+};
 
 pub(crate) struct SolutionsApplyParams {
     pub(crate) input_vis_params: InputVisParams,
@@ -23,4 +25,12 @@ impl SolutionsApplyParams {
 
         VisConvertParams::run_inner(input_vis_params, output_vis_params)
     }
+
+    /// Estimate the peak memory needed by [`SolutionsApplyParams::run`].
+    pub(crate) fn get_resource_estimate(&self) -> ResourceEstimate {
+        VisConvertParams::get_resource_estimate_inner(
+            &self.input_vis_params,
+            &self.output_vis_params,
+        )
+    }
 }
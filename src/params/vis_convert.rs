@@ -12,7 +12,10 @@ use log::{debug, info};
 use ndarray::prelude::*;
 use scopeguard::defer_on_unwind;
 
-use super::{InputVisParams, OutputVisParams};
+use super::{
+    resources::{get_read_memory, get_write_memory, ResourceEstimate, VIS_AND_WEIGHT_BYTES},
+    InputVisParams, OutputVisParams,
+};
 use crate::{
     io::{
         read::VisReadError,
@@ -21,6 +24,9 @@ use crate::{
     PROGRESS_BARS,
 };
 
+/// The capacity of the channel between the reading and writing threads.
+const READ_CHANNEL_CAPACITY: usize = 3;
+
 pub(crate) struct VisConvertParams {
     pub(crate) input_vis_params: InputVisParams,
     pub(crate) output_vis_params: OutputVisParams,
@@ -40,6 +46,36 @@ impl VisConvertParams {
     // `SolutionsApplyParams` is doing the exact same thing, but I can't work
     // out how to make a `&VisConvertParams` from `&InputVisParams` and
     // `&OutputVisParams` (if it's possible).
+    /// Estimate the peak memory needed to convert (and possibly calibrate)
+    /// visibilities.
+    pub(super) fn get_resource_estimate_inner(
+        input_vis_params: &InputVisParams,
+        output_vis_params: &OutputVisParams,
+    ) -> ResourceEstimate {
+        let spw = &input_vis_params.spw;
+        let num_unflagged_tiles = input_vis_params.get_num_unflagged_tiles();
+        // Autos are treated like extra baselines.
+        let num_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2
+            + if input_vis_params.using_autos {
+                num_unflagged_tiles
+            } else {
+                0
+            };
+        let num_chans = input_vis_params.get_obs_context().fine_chan_freqs.len();
+        let total_num_chanblocks = spw.chanblocks.len() + spw.flagged_chanblock_indices.len();
+
+        // Visibilities are held by the reading and writing threads, as well as
+        // in the channel between them.
+        let num_in_flight = 2 + READ_CHANNEL_CAPACITY;
+        ResourceEstimate {
+            peak_memory: (num_in_flight * spw.chanblocks.len() * num_baselines) as u64
+                * VIS_AND_WEIGHT_BYTES
+                + get_read_memory(input_vis_params, num_chans, num_baselines)
+                + get_write_memory(output_vis_params, total_num_chanblocks, num_baselines),
+            ..Default::default()
+        }
+    }
+
     pub(super) fn run_inner(
         input_vis_params: &InputVisParams,
         output_vis_params: &OutputVisParams,
@@ -47,7 +83,7 @@ impl VisConvertParams {
         let obs_context = input_vis_params.get_obs_context();
 
         // Channel for transferring visibilities from the reader to the writer.
-        let (tx_data, rx_data) = bounded(3);
+        let (tx_data, rx_data) = bounded(READ_CHANNEL_CAPACITY);
 
         // Progress bars.
         let multi_progress = MultiProgress::with_draw_target(if PROGRESS_BARS.load() {
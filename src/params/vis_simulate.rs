@@ -33,11 +33,17 @@ use crate::{
     io::write::{write_vis, VisTimestep, VisWriteError},
     math::TileBaselineFlags,
     model::{self, ModelError},
-    params::{ModellingParams, OutputVisParams},
+    params::{
+        resources::{get_write_memory, ResourceEstimate, VIS_AND_WEIGHT_BYTES},
+        ModellingParams, OutputVisParams,
+    },
     srclist::SourceList,
     PROGRESS_BARS,
 };
 
+/// The capacity of the channel between the modelling and writing threads.
+const CHANNEL_CAPACITY: usize = 5;
+
 /// Parameters needed to do sky-model visibility simulation.
 pub(crate) struct VisSimulateParams {
     /// Sky-model source list.
@@ -86,6 +92,31 @@ pub(crate) struct VisSimulateParams {
 }
 
 impl VisSimulateParams {
+    /// Estimate the peak memory and sky-modelling work needed by
+    /// [`VisSimulateParams::run`].
+    pub(crate) fn get_resource_estimate(&self) -> ResourceEstimate {
+        let num_baselines = self
+            .tile_baseline_flags
+            .unflagged_cross_baseline_to_tile_map
+            .len();
+        let num_chans = self.fine_chan_freqs.len();
+
+        // Visibilities are held by the modelling and writing threads, as well
+        // as in the channel between them.
+        let num_in_flight = 2 + CHANNEL_CAPACITY;
+        ResourceEstimate {
+            peak_memory: (num_in_flight * num_chans * num_baselines) as u64 * VIS_AND_WEIGHT_BYTES
+                + get_write_memory(&self.output_vis_params, num_chans, num_baselines),
+            ..Default::default()
+        }
+        .with_model_ops(
+            &self.source_list.get_counts(),
+            num_baselines,
+            num_chans,
+            self.timestamps.len(),
+        )
+    }
+
     pub(crate) fn run(&self) -> Result<(), VisSimulateError> {
         let VisSimulateParams {
             source_list,
@@ -113,7 +144,7 @@ impl VisSimulateParams {
         } = self;
 
         // Channel for writing simulated visibilities.
-        let (tx_model, rx_model) = bounded(5);
+        let (tx_model, rx_model) = bounded(CHANNEL_CAPACITY);
 
         // Progress bar.
         let multi_progress = MultiProgress::with_draw_target(if PROGRESS_BARS.load() {
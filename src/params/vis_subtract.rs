@@ -16,27 +16,65 @@ use marlu::Jones;
 use ndarray::prelude::*;
 use scopeguard::defer_on_unwind;
 
-use super::{InputVisParams, ModellingParams, OutputVisParams};
+use super::{
+    resources::{get_read_memory, get_write_memory, ResourceEstimate, VIS_AND_WEIGHT_BYTES},
+    InputVisParams, ModelVisParams, ModellingParams, OutputVisParams,
+};
 use crate::{
     beam::Beam,
     io::{
         read::VisReadError,
         write::{write_vis, VisTimestep},
     },
-    model::{new_sky_modeller, ModelError},
+    model::new_sky_modeller,
     srclist::SourceList,
     PROGRESS_BARS,
 };
 
+/// The capacity of each of the channels between the reading, modelling and
+/// writing threads.
+const CHANNEL_CAPACITY: usize = 5;
+
 pub(crate) struct VisSubtractParams {
     pub(crate) input_vis_params: InputVisParams,
     pub(crate) output_vis_params: OutputVisParams,
     pub(crate) beam: Box<dyn Beam>,
     pub(crate) source_list: SourceList,
+    /// Model visibilities read from a file. If this is specified, these are
+    /// subtracted instead of generating a model from `source_list`.
+    pub(crate) model_vis_params: Option<ModelVisParams>,
     pub(crate) modelling_params: ModellingParams,
 }
 
 impl VisSubtractParams {
+    /// Estimate the peak memory and sky-modelling work needed by
+    /// [`VisSubtractParams::run`].
+    pub(crate) fn get_resource_estimate(&self) -> ResourceEstimate {
+        let input_vis_params = &self.input_vis_params;
+        let spw = &input_vis_params.spw;
+        let num_unflagged_tiles = input_vis_params.get_num_unflagged_tiles();
+        let num_baselines = (num_unflagged_tiles * (num_unflagged_tiles - 1)) / 2;
+        let num_chans = input_vis_params.get_obs_context().fine_chan_freqs.len();
+        let total_num_chanblocks = spw.chanblocks.len() + spw.flagged_chanblock_indices.len();
+
+        // Visibilities are held by the reading, modelling and writing threads,
+        // as well as in the two channels between them.
+        let num_in_flight = 3 + 2 * CHANNEL_CAPACITY;
+        ResourceEstimate {
+            peak_memory: (num_in_flight * spw.chanblocks.len() * num_baselines) as u64
+                * VIS_AND_WEIGHT_BYTES
+                + get_read_memory(input_vis_params, num_chans, num_baselines)
+                + get_write_memory(&self.output_vis_params, total_num_chanblocks, num_baselines),
+            ..Default::default()
+        }
+        .with_model_ops(
+            &self.source_list.get_counts(),
+            num_baselines,
+            spw.chanblocks.len(),
+            input_vis_params.timeblocks.len(),
+        )
+    }
+
     pub(crate) fn run(&self) -> Result<(), VisSubtractError> {
         // Expose all the struct fields to ensure they're all used.
         let VisSubtractParams {
@@ -44,6 +82,7 @@ impl VisSubtractParams {
             output_vis_params,
             beam,
             source_list,
+            model_vis_params,
             modelling_params: ModellingParams { apply_precession },
         } = self;
 
@@ -56,9 +95,9 @@ impl VisSubtractParams {
         );
 
         // Channel for modelling and subtracting.
-        let (tx_model, rx_model) = bounded(5);
+        let (tx_model, rx_model) = bounded(CHANNEL_CAPACITY);
         // Channel for writing subtracted visibilities.
-        let (tx_write, rx_write) = bounded(5);
+        let (tx_write, rx_write) = bounded(CHANNEL_CAPACITY);
 
         // Progress bars.
         let multi_progress = MultiProgress::with_draw_target(if PROGRESS_BARS.load() {
@@ -159,29 +198,31 @@ impl VisSubtractParams {
                     .expect("OS can create threads");
 
             // Sky-model generation and subtraction thread.
-            let model_handle: ScopedJoinHandle<Result<(), ModelError>> = thread::Builder::new()
-                .name("model".to_string())
-                .spawn_scoped(scope, || {
-                    defer_on_unwind! { error.store(true); }
-                    model_progress.tick();
-
-                    let result = model_thread(
-                        &**beam,
-                        source_list,
-                        input_vis_params,
-                        *apply_precession,
-                        vis_shape,
-                        rx_model,
-                        tx_write,
-                        &error,
-                        model_progress,
-                    );
-                    if result.is_err() {
-                        error.store(true);
-                    }
-                    result
-                })
-                .expect("OS can create threads");
+            let model_handle: ScopedJoinHandle<Result<(), VisSubtractError>> =
+                thread::Builder::new()
+                    .name("model".to_string())
+                    .spawn_scoped(scope, || {
+                        defer_on_unwind! { error.store(true); }
+                        model_progress.tick();
+
+                        let result = model_thread(
+                            &**beam,
+                            source_list,
+                            input_vis_params,
+                            model_vis_params.as_ref(),
+                            *apply_precession,
+                            vis_shape,
+                            rx_model,
+                            tx_write,
+                            &error,
+                            model_progress,
+                        );
+                        if result.is_err() {
+                            error.store(true);
+                        }
+                        result
+                    })
+                    .expect("OS can create threads");
 
             // Subtracted vis writing thread.
             let write_handle = thread::Builder::new()
@@ -247,13 +288,14 @@ fn model_thread(
     beam: &dyn Beam,
     source_list: &SourceList,
     input_vis_params: &InputVisParams,
+    model_vis_params: Option<&ModelVisParams>,
     apply_precession: bool,
     vis_shape: (usize, usize),
     rx: Receiver<VisTimestep>,
     tx: Sender<VisTimestep>,
     error: &AtomicCell<bool>,
     progress_bar: ProgressBar,
-) -> Result<(), ModelError> {
+) -> Result<(), VisSubtractError> {
     let obs_context = input_vis_params.get_obs_context();
     let unflagged_tile_xyzs = obs_context
         .tile_xyzs
@@ -273,33 +315,60 @@ fn model_thread(
         .iter()
         .map(|c| c.freq)
         .collect::<Vec<_>>();
-    let modeller = new_sky_modeller(
-        beam,
-        source_list,
-        obs_context.polarisations,
-        &unflagged_tile_xyzs,
-        &freqs,
-        &input_vis_params.tile_baseline_flags.flagged_tiles,
-        obs_context.phase_centre,
-        obs_context.array_position.longitude_rad,
-        obs_context.array_position.latitude_rad,
-        input_vis_params.dut1,
-        apply_precession,
-    )?;
+    // A modeller is only needed if the model visibilities aren't being read
+    // from a file.
+    let modeller = match model_vis_params {
+        Some(_) => None,
+        None => Some(new_sky_modeller(
+            beam,
+            source_list,
+            obs_context.polarisations,
+            &unflagged_tile_xyzs,
+            &freqs,
+            &input_vis_params.tile_baseline_flags.flagged_tiles,
+            obs_context.phase_centre,
+            obs_context.array_position.longitude_rad,
+            obs_context.array_position.latitude_rad,
+            input_vis_params.dut1,
+            apply_precession,
+        )?),
+    };
 
     // Recycle an array for model visibilities.
     let mut vis_model_fb = Array2::zeros(vis_shape);
 
     // Iterate over the incoming data.
-    for VisTimestep {
-        mut cross_data_fb,
-        cross_weights_fb,
-        autos,
-        timestamp,
-    } in rx.iter()
+    for (
+        i_timeblock,
+        VisTimestep {
+            mut cross_data_fb,
+            cross_weights_fb,
+            autos,
+            timestamp,
+        },
+    ) in rx.iter().enumerate()
     {
-        debug!("Modelling timestamp {}", timestamp.to_gpst_seconds());
-        modeller.model_timestep_with(timestamp, vis_model_fb.view_mut())?;
+        match (model_vis_params, modeller.as_ref()) {
+            (Some(model_vis_params), _) => {
+                debug!(
+                    "Reading model visibilities for timestamp {}",
+                    timestamp.to_gpst_seconds()
+                );
+                model_vis_params.read_timeblock(
+                    i_timeblock,
+                    None,
+                    vis_model_fb.view_mut(),
+                    error,
+                )?;
+            }
+
+            (None, Some(modeller)) => {
+                debug!("Modelling timestamp {}", timestamp.to_gpst_seconds());
+                modeller.model_timestep_with(timestamp, vis_model_fb.view_mut())?;
+            }
+
+            (None, None) => unreachable!("a modeller is made without model visibilities"),
+        }
         cross_data_fb
             .iter_mut()
             .zip_eq(vis_model_fb.iter())
@@ -131,9 +131,12 @@ pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadErr
 }
 
 /// Write a "André-Offringa calibrate format" calibration solutions binary file.
+/// The format can't store XY-phase corrections, so any are included in the
+/// written Jones matrices.
 pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), SolutionsWriteError> {
     let num_polarisations = 4;
-    let (num_timeblocks, total_num_tiles, total_num_chanblocks) = sols.di_jones.dim();
+    let di_jones = sols.get_di_jones_with_xy_phase();
+    let (num_timeblocks, total_num_tiles, total_num_chanblocks) = di_jones.dim();
 
     let mut bin_file = BufWriter::new(File::create(file)?);
     // 8 floats, 8 bytes per float.
@@ -169,7 +172,7 @@ pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), Solu
     bin_file.write_f64::<LittleEndian>(start)?;
     bin_file.write_f64::<LittleEndian>(end)?;
 
-    for j in sols.di_jones.iter() {
+    for j in di_jones.iter() {
         LittleEndian::write_f64_into(
             &[
                 j[0].re, j[0].im, j[1].re, j[1].im, j[2].re, j[2].im, j[3].re, j[3].im,
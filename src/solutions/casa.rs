@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to write calibration solutions as CASA bandpass ("B Jones")
+//! calibration tables.
+//!
+//! See for more info:
+//! <https://mwatelescope.github.io/mwa_hyperdrive/defs/cal_sols_casa.html>
+
+use std::path::Path;
+
+use hifitime::Epoch;
+use log::debug;
+use marlu::{
+    c32,
+    rubbl_casatables::{GlueDataType, Table, TableCreateMode, TableDesc, TableDescCreateMode},
+};
+use ndarray::prelude::*;
+use vec1::Vec1;
+
+use super::{error::*, CalibrationSolutions};
+use crate::cli::Warn;
+
+/// The columns of the main table of a calibration table; the name, the type,
+/// and whether the column holds arrays.
+const MAIN_COLUMNS: [(&str, GlueDataType, bool); 13] = [
+    ("TIME", GlueDataType::TpDouble, false),
+    ("FIELD_ID", GlueDataType::TpInt, false),
+    ("SPECTRAL_WINDOW_ID", GlueDataType::TpInt, false),
+    ("ANTENNA1", GlueDataType::TpInt, false),
+    ("ANTENNA2", GlueDataType::TpInt, false),
+    ("INTERVAL", GlueDataType::TpDouble, false),
+    ("SCAN_NUMBER", GlueDataType::TpInt, false),
+    ("OBSERVATION_ID", GlueDataType::TpInt, false),
+    ("CPARAM", GlueDataType::TpComplex, true),
+    ("PARAMERR", GlueDataType::TpFloat, true),
+    ("FLAG", GlueDataType::TpBool, true),
+    ("SNR", GlueDataType::TpFloat, true),
+    ("WEIGHT", GlueDataType::TpFloat, true),
+];
+
+const ANTENNA_COLUMNS: [(&str, GlueDataType, bool); 8] = [
+    ("NAME", GlueDataType::TpString, false),
+    ("STATION", GlueDataType::TpString, false),
+    ("TYPE", GlueDataType::TpString, false),
+    ("MOUNT", GlueDataType::TpString, false),
+    ("POSITION", GlueDataType::TpDouble, true),
+    ("OFFSET", GlueDataType::TpDouble, true),
+    ("DISH_DIAMETER", GlueDataType::TpDouble, false),
+    ("FLAG_ROW", GlueDataType::TpBool, false),
+];
+
+const FIELD_COLUMNS: [(&str, GlueDataType, bool); 9] = [
+    ("NAME", GlueDataType::TpString, false),
+    ("CODE", GlueDataType::TpString, false),
+    ("TIME", GlueDataType::TpDouble, false),
+    ("NUM_POLY", GlueDataType::TpInt, false),
+    ("DELAY_DIR", GlueDataType::TpDouble, true),
+    ("PHASE_DIR", GlueDataType::TpDouble, true),
+    ("REFERENCE_DIR", GlueDataType::TpDouble, true),
+    ("SOURCE_ID", GlueDataType::TpInt, false),
+    ("FLAG_ROW", GlueDataType::TpBool, false),
+];
+
+const SPECTRAL_WINDOW_COLUMNS: [(&str, GlueDataType, bool); 14] = [
+    ("NUM_CHAN", GlueDataType::TpInt, false),
+    ("NAME", GlueDataType::TpString, false),
+    ("REF_FREQUENCY", GlueDataType::TpDouble, false),
+    ("CHAN_FREQ", GlueDataType::TpDouble, true),
+    ("CHAN_WIDTH", GlueDataType::TpDouble, true),
+    ("EFFECTIVE_BW", GlueDataType::TpDouble, true),
+    ("RESOLUTION", GlueDataType::TpDouble, true),
+    ("MEAS_FREQ_REF", GlueDataType::TpInt, false),
+    ("TOTAL_BANDWIDTH", GlueDataType::TpDouble, false),
+    ("NET_SIDEBAND", GlueDataType::TpInt, false),
+    ("IF_CONV_CHAIN", GlueDataType::TpInt, false),
+    ("FREQ_GROUP", GlueDataType::TpInt, false),
+    ("FREQ_GROUP_NAME", GlueDataType::TpString, false),
+    ("FLAG_ROW", GlueDataType::TpBool, false),
+];
+
+const OBSERVATION_COLUMNS: [(&str, GlueDataType, bool); 7] = [
+    ("TELESCOPE_NAME", GlueDataType::TpString, false),
+    ("TIME_RANGE", GlueDataType::TpDouble, true),
+    ("OBSERVER", GlueDataType::TpString, false),
+    ("PROJECT", GlueDataType::TpString, false),
+    ("RELEASE_DATE", GlueDataType::TpDouble, false),
+    ("SCHEDULE_TYPE", GlueDataType::TpString, false),
+    ("FLAG_ROW", GlueDataType::TpBool, false),
+];
+
+/// The "measures" frequency reference code for topocentric frequencies.
+const MEAS_FREQ_REF_TOPO: i32 = 5;
+
+/// Create a new casacore table at `path` with the specified columns and number
+/// of rows.
+fn new_table(
+    path: &Path,
+    name: &str,
+    columns: &[(&str, GlueDataType, bool)],
+    num_rows: usize,
+) -> Result<Table, SolutionsWriteError> {
+    let mut table_desc = TableDesc::new(name, TableDescCreateMode::TDM_SCRATCH)?;
+    for &(col_name, data_type, is_array) in columns {
+        if is_array {
+            table_desc.add_array_column(data_type, col_name, None, None, false, false)?;
+        } else {
+            table_desc.add_scalar_column(data_type, col_name, None, false, false)?;
+        }
+    }
+    Ok(Table::new(
+        path,
+        table_desc,
+        num_rows,
+        TableCreateMode::New,
+    )?)
+}
+
+/// Convert an [`Epoch`] to the casacore representation of time (UTC seconds
+/// since the MJD epoch).
+fn epoch_to_casacore(epoch: Epoch) -> f64 {
+    hifitime::J1900_OFFSET.mul_add(hifitime::SECONDS_PER_DAY, epoch.to_utc_seconds())
+}
+
+/// Write a CASA bandpass ("B Jones") calibration table. There is a row for
+/// each timeblock and tile, and a single spectral window containing all
+/// chanblocks.
+///
+/// CASA tables hold the gains that corrupt the data, whereas hyperdrive
+/// solutions correct the data, so the solutions are inverted before they're
+/// written. Only the XX and YY elements are written; bandpass tables can't hold
+/// the cross terms. Any XY-phase corrections are included in the written
+/// gains.
+///
+/// `freq_res` is the frequency resolution of the chanblocks \[Hz\]. If it
+/// isn't given, it's determined from the chanblock frequencies, which needs at
+/// least two chanblocks.
+pub(crate) fn write(
+    sols: &CalibrationSolutions,
+    file: &Path,
+    freq_res: Option<f64>,
+) -> Result<(), SolutionsWriteError> {
+    let di_jones = sols.get_di_jones_with_xy_phase();
+    let (num_timeblocks, total_num_tiles, total_num_chanblocks) = di_jones.dim();
+    let chanblock_freqs = sols
+        .chanblock_freqs
+        .as_ref()
+        .ok_or(SolutionsWriteError::CasaNoChanblockFreqs)?;
+    if chanblock_freqs.len() != total_num_chanblocks {
+        return Err(SolutionsWriteError::CasaNoChanblockFreqs);
+    }
+    // All chanblocks (flagged and unflagged) have a frequency, so the
+    // resolution is the gap between any two consecutive chanblocks.
+    let chan_width = match (freq_res, chanblock_freqs.as_slice()) {
+        (Some(freq_res), _) => freq_res,
+        (None, [f0, f1, ..]) => f1 - f0,
+        (None, _) => return Err(SolutionsWriteError::CasaNoFreqRes),
+    };
+    if !(chan_width.is_finite() && chan_width > 0.0) {
+        return Err(SolutionsWriteError::CasaNoFreqRes);
+    }
+
+    if di_jones
+        .iter()
+        .any(|j| (j[1].norm_sqr() + j[2].norm_sqr()) > 0.0)
+    {
+        "CASA bandpass tables can't hold the cross terms (XY and YX) of calibration solutions; these are not written".warn();
+    }
+
+    // casacore needs a timestamp for each timeblock. Use the average timestamps
+    // if available, otherwise the middle of the start and end timestamps.
+    let get_time = |i_timeblock: usize| {
+        let timestamp =
+            |timestamps: Option<&Vec1<Epoch>>| timestamps.and_then(|t| t.get(i_timeblock)).copied();
+        let start = timestamp(sols.start_timestamps.as_ref());
+        let end = timestamp(sols.end_timestamps.as_ref());
+        let time = match (timestamp(sols.average_timestamps.as_ref()), start, end) {
+            (Some(a), _, _) => epoch_to_casacore(a),
+            (None, Some(s), Some(e)) => (epoch_to_casacore(s) + epoch_to_casacore(e)) / 2.0,
+            _ => 0.0,
+        };
+        let interval = match (start, end) {
+            (Some(s), Some(e)) => (e - s).to_seconds(),
+            _ => 0.0,
+        };
+        (time, interval)
+    };
+
+    debug!("Writing the main table of {}", file.display());
+    let mut main_table = new_table(
+        file,
+        "CalTable",
+        &MAIN_COLUMNS,
+        num_timeblocks * total_num_tiles,
+    )?;
+    main_table.put_keyword("ParType", &"Complex".to_string())?;
+    main_table.put_keyword("MSName", &String::new())?;
+    main_table.put_keyword("VisCal", &"B Jones".to_string())?;
+    main_table.put_keyword("PolBasis", &"unknown".to_string())?;
+    main_table.put_column_keyword("TIME", "QuantumUnits", &vec!["s".to_string()])?;
+    main_table.put_column_keyword("INTERVAL", "QuantumUnits", &vec!["s".to_string()])?;
+
+    let chanblock_flags: Vec<bool> = (0..total_num_chanblocks)
+        .map(|i_chanblock| sols.flagged_chanblocks.contains(&(i_chanblock as u16)))
+        .collect();
+    let mut cparam = Array2::from_elem((total_num_chanblocks, 2), c32::new(1.0, 0.0));
+    let mut flags = Array2::from_elem((total_num_chanblocks, 2), false);
+    let zeros = Array2::<f32>::zeros((total_num_chanblocks, 2));
+    let ones = Array2::<f32>::ones((total_num_chanblocks, 2));
+    for (i_timeblock, di_jones) in di_jones.outer_iter().enumerate() {
+        let (time, interval) = get_time(i_timeblock);
+        for (i_tile, di_jones) in di_jones.outer_iter().enumerate() {
+            let row = (i_timeblock * total_num_tiles + i_tile) as u64;
+            let tile_flagged = sols.flagged_tiles.contains(&i_tile);
+            for ((j, &chanblock_flagged), (mut cparam, mut flags)) in di_jones
+                .iter()
+                .zip(chanblock_flags.iter())
+                .zip(cparam.outer_iter_mut().zip(flags.outer_iter_mut()))
+            {
+                let j = j.inv();
+                let flagged =
+                    tile_flagged || chanblock_flagged || !(j[0].is_finite() && j[3].is_finite());
+                if flagged {
+                    cparam.fill(c32::new(1.0, 0.0));
+                    flags.fill(true);
+                } else {
+                    cparam[0] = c32::new(j[0].re as f32, j[0].im as f32);
+                    cparam[1] = c32::new(j[3].re as f32, j[3].im as f32);
+                    flags.fill(false);
+                }
+            }
+
+            main_table.put_cell("TIME", row, &time)?;
+            main_table.put_cell("FIELD_ID", row, &0_i32)?;
+            main_table.put_cell("SPECTRAL_WINDOW_ID", row, &0_i32)?;
+            main_table.put_cell("ANTENNA1", row, &(i_tile as i32))?;
+            main_table.put_cell("ANTENNA2", row, &-1_i32)?;
+            main_table.put_cell("INTERVAL", row, &interval)?;
+            main_table.put_cell("SCAN_NUMBER", row, &0_i32)?;
+            main_table.put_cell("OBSERVATION_ID", row, &0_i32)?;
+            main_table.put_cell("CPARAM", row, &cparam)?;
+            main_table.put_cell("PARAMERR", row, &zeros)?;
+            main_table.put_cell("FLAG", row, &flags)?;
+            main_table.put_cell("SNR", row, &ones)?;
+            main_table.put_cell("WEIGHT", row, &ones)?;
+        }
+    }
+
+    // The subtables.
+    debug!("Writing the ANTENNA table of {}", file.display());
+    let mut antenna_table = new_table(
+        &file.join("ANTENNA"),
+        "ANTENNA",
+        &ANTENNA_COLUMNS,
+        total_num_tiles,
+    )?;
+    for i_tile in 0..total_num_tiles {
+        let name = sols
+            .tile_names
+            .as_ref()
+            .and_then(|names| names.get(i_tile))
+            .cloned()
+            .unwrap_or_else(|| format!("Tile{i_tile:03}"));
+        let row = i_tile as u64;
+        antenna_table.put_cell("NAME", row, &name)?;
+        antenna_table.put_cell("STATION", row, &name)?;
+        antenna_table.put_cell("TYPE", row, &"GROUND-BASED".to_string())?;
+        antenna_table.put_cell("MOUNT", row, &"ALT-AZ".to_string())?;
+        antenna_table.put_cell("POSITION", row, &vec![0.0_f64; 3])?;
+        antenna_table.put_cell("OFFSET", row, &vec![0.0_f64; 3])?;
+        antenna_table.put_cell("DISH_DIAMETER", row, &4.0_f64)?;
+        antenna_table.put_cell("FLAG_ROW", row, &sols.flagged_tiles.contains(&i_tile))?;
+    }
+
+    debug!("Writing the FIELD table of {}", file.display());
+    let mut field_table = new_table(&file.join("FIELD"), "FIELD", &FIELD_COLUMNS, 1)?;
+    let obsid = sols.obsid.map(|o| o.to_string()).unwrap_or_default();
+    field_table.put_cell("NAME", 0, &obsid)?;
+    field_table.put_cell("CODE", 0, &String::new())?;
+    field_table.put_cell("TIME", 0, &get_time(0).0)?;
+    field_table.put_cell("NUM_POLY", 0, &0_i32)?;
+    for col_name in ["DELAY_DIR", "PHASE_DIR", "REFERENCE_DIR"] {
+        field_table.put_cell(col_name, 0, &Array2::<f64>::zeros((1, 2)))?;
+    }
+    field_table.put_cell("SOURCE_ID", 0, &-1_i32)?;
+    field_table.put_cell("FLAG_ROW", 0, &false)?;
+
+    debug!("Writing the SPECTRAL_WINDOW table of {}", file.display());
+    let mut spw_table = new_table(
+        &file.join("SPECTRAL_WINDOW"),
+        "SPECTRAL_WINDOW",
+        &SPECTRAL_WINDOW_COLUMNS,
+        1,
+    )?;
+    let chan_freqs = chanblock_freqs.to_vec();
+    let chan_widths = vec![chan_width; chan_freqs.len()];
+    spw_table.put_cell("NUM_CHAN", 0, &(chan_freqs.len() as i32))?;
+    spw_table.put_cell("NAME", 0, &String::new())?;
+    spw_table.put_cell("REF_FREQUENCY", 0, &chan_freqs[0])?;
+    spw_table.put_cell("CHAN_FREQ", 0, &chan_freqs)?;
+    spw_table.put_cell("CHAN_WIDTH", 0, &chan_widths)?;
+    spw_table.put_cell("EFFECTIVE_BW", 0, &chan_widths)?;
+    spw_table.put_cell("RESOLUTION", 0, &chan_widths)?;
+    spw_table.put_cell("MEAS_FREQ_REF", 0, &MEAS_FREQ_REF_TOPO)?;
+    spw_table.put_cell(
+        "TOTAL_BANDWIDTH",
+        0,
+        &(chan_width * chan_freqs.len() as f64),
+    )?;
+    spw_table.put_cell("NET_SIDEBAND", 0, &1_i32)?;
+    spw_table.put_cell("IF_CONV_CHAIN", 0, &0_i32)?;
+    spw_table.put_cell("FREQ_GROUP", 0, &0_i32)?;
+    spw_table.put_cell("FREQ_GROUP_NAME", 0, &String::new())?;
+    spw_table.put_cell("FLAG_ROW", 0, &false)?;
+
+    debug!("Writing the OBSERVATION table of {}", file.display());
+    let mut obs_table = new_table(
+        &file.join("OBSERVATION"),
+        "OBSERVATION",
+        &OBSERVATION_COLUMNS,
+        1,
+    )?;
+    let (first_time, first_interval) = get_time(0);
+    let (last_time, last_interval) = get_time(num_timeblocks.saturating_sub(1));
+    obs_table.put_cell("TELESCOPE_NAME", 0, &"MWA".to_string())?;
+    obs_table.put_cell(
+        "TIME_RANGE",
+        0,
+        &vec![
+            first_time - first_interval / 2.0,
+            last_time + last_interval / 2.0,
+        ],
+    )?;
+    obs_table.put_cell("OBSERVER", 0, &String::new())?;
+    obs_table.put_cell("PROJECT", 0, &obsid)?;
+    obs_table.put_cell("RELEASE_DATE", 0, &0.0_f64)?;
+    obs_table.put_cell("SCHEDULE_TYPE", 0, &String::new())?;
+    obs_table.put_cell("FLAG_ROW", 0, &false)?;
+
+    // Link the subtables to the main table.
+    main_table.put_table_keyword("ANTENNA", antenna_table)?;
+    main_table.put_table_keyword("FIELD", field_table)?;
+    main_table.put_table_keyword("SPECTRAL_WINDOW", spw_table)?;
+    main_table.put_table_keyword("OBSERVATION", obs_table)?;
+
+    Ok(())
+}
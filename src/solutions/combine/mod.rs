@@ -0,0 +1,511 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to combine multiple sets of calibration solutions into one.
+//!
+//! Solutions can either be concatenated in frequency (e.g. solutions of the
+//! coarse-channel groups of a picket-fence observation), or averaged in time
+//! (e.g. solutions of the same field from different nights). All solutions
+//! must have the same tiles.
+//!
+//! When concatenating, the chanblocks of all solutions must be on the same
+//! frequency grid; gaps between the solutions are filled with flagged
+//! chanblocks, so the combined chanblocks are evenly spaced.
+//!
+//! When averaging, all timeblocks of all solutions are averaged into a single
+//! timeblock. Flagged solutions are ignored, and if all solutions have
+//! calibration precisions, each solution is weighted by the inverse of its
+//! precision, so poorly-converged chanblocks contribute little. Solutions
+//! should be referenced to the same tile (see [`super::reference`]) before
+//! they're averaged; a warning is printed if they aren't and no reference tile
+//! is given.
+//!
+//! Any XY-phase corrections are included in the combined Jones matrices, but
+//! solutions with XY-phase corrections can't be referenced to a tile.
+//! Metadata (e.g. the obsid) is kept only if it's the same in all solutions.
+
+#[cfg(test)]
+mod tests;
+
+use itertools::Itertools;
+use marlu::Jones;
+use ndarray::prelude::*;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
+use vec1::Vec1;
+
+use super::{
+    reference::{parse_ref_tile, reference_phases, PhaseReferenceError},
+    CalibrationSolutions, ResidualStat, ResidualStats,
+};
+use crate::{cli::Warn, math::average_epoch};
+
+lazy_static::lazy_static! {
+    pub(crate) static ref COMBINE_MODES_COMMA_SEPARATED: String = CombineMode::iter().join(", ");
+}
+
+/// How far apart chanblock frequencies may be while still being considered the
+/// same \[Hz\].
+const FREQ_TOLERANCE: f64 = 1.0;
+
+/// Calibration precisions better than this are treated as equal when
+/// weighting, so that a few exceptionally well-converged solutions don't
+/// dominate an average.
+const MIN_PRECISION: f64 = 1e-12;
+
+/// How multiple sets of calibration solutions are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub(crate) enum CombineMode {
+    /// Concatenate the solutions in frequency.
+    #[default]
+    #[strum(serialize = "freq")]
+    Frequency,
+
+    /// Average the solutions in time.
+    #[strum(serialize = "time")]
+    Time,
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SolutionsCombineError {
+    #[error("No solutions were supplied")]
+    NoInputs,
+
+    #[error("The tiles of solutions {0} don't match those of solutions 0")]
+    TilesMismatch(usize),
+
+    #[error("Solutions {index} have {got} timeblocks, but solutions 0 have {expected}; solutions concatenated in frequency must have the same timeblocks")]
+    TimeblockCountMismatch {
+        index: usize,
+        expected: usize,
+        got: usize,
+    },
+
+    #[error("Solutions {0} don't have chanblock frequencies; these are needed to concatenate solutions in frequency")]
+    NoFreqs(usize),
+
+    #[error("Solutions {0} have a different frequency resolution to the other solutions")]
+    FreqResMismatch(usize),
+
+    #[error("The chanblock frequencies of solutions {0} overlap with those of other solutions")]
+    OverlappingFreqs(usize),
+
+    #[error("The chanblock frequencies of solutions {0} aren't on the same frequency grid as those of the other solutions")]
+    FreqsNotOnGrid(usize),
+
+    #[error("The chanblocks of solutions {0} don't match those of solutions 0; solutions averaged in time must have the same chanblocks")]
+    ChanblocksMismatch(usize),
+
+    #[error(transparent)]
+    PhaseReference(#[from] PhaseReferenceError),
+}
+
+/// Combine the solutions `sols` according to `mode` (see the module
+/// documentation). If `ref_tile` is given (an index or name of a tile), the
+/// phases of each of the solutions are referenced to this tile before they're
+/// combined.
+pub(crate) fn combine_solutions(
+    mut sols: Vec<CalibrationSolutions>,
+    mode: CombineMode,
+    ref_tile: Option<&str>,
+) -> Result<CalibrationSolutions, SolutionsCombineError> {
+    let first = sols.first().ok_or(SolutionsCombineError::NoInputs)?;
+    let total_num_tiles = first.di_jones.len_of(Axis(1));
+    for (i, sol) in sols.iter().enumerate().skip(1) {
+        let names_match = match (first.tile_names.as_ref(), sol.tile_names.as_ref()) {
+            (Some(names1), Some(names2)) => names1 == names2,
+            _ => true,
+        };
+        if sol.di_jones.len_of(Axis(1)) != total_num_tiles || !names_match {
+            return Err(SolutionsCombineError::TilesMismatch(i));
+        }
+    }
+    if mode == CombineMode::Time
+        && ref_tile.is_none()
+        && !sols.iter().all(|sol| {
+            sol.phase_reference.is_some() && sol.phase_reference == first.phase_reference
+        })
+    {
+        "Averaging solutions that aren't referenced to the same tile; consider using --ref-tile"
+            .warn();
+    }
+
+    // Referencing the phases of solutions with an XY-phase correction would
+    // undo the correction.
+    if ref_tile.is_some() && sols.iter().any(|sol| sol.xy_phase.is_some()) {
+        return Err(PhaseReferenceError::XyPhase.into());
+    }
+
+    for sol in sols.iter_mut() {
+        if let Some(xy_phase) = sol.xy_phase.take() {
+            for di_jones in sol.di_jones.outer_iter_mut() {
+                xy_phase.apply(di_jones);
+            }
+        }
+        if let Some(ref_tile) = ref_tile {
+            let ref_tile = parse_ref_tile(
+                ref_tile,
+                sol.tile_names.as_ref().map(|n| n.as_slice()),
+                total_num_tiles,
+            )?;
+            reference_phases(sol, ref_tile, false)?;
+        }
+    }
+
+    let mut combined = match mode {
+        CombineMode::Frequency => concat_freq(&sols)?,
+        CombineMode::Time => average_time(&sols)?,
+    };
+
+    // Tiles are flagged only if they're flagged in all solutions; a tile
+    // flagged in only some of them still has solutions in the others.
+    combined.flagged_tiles = sols[0]
+        .flagged_tiles
+        .iter()
+        .copied()
+        .filter(|i_tile| sols.iter().all(|sol| sol.flagged_tiles.contains(i_tile)))
+        .collect();
+    combined.obsid = common(&sols, |sol| sol.obsid.as_ref());
+    combined.max_iterations = common(&sols, |sol| sol.max_iterations.as_ref());
+    combined.stop_threshold = common(&sols, |sol| sol.stop_threshold.as_ref());
+    combined.min_threshold = common(&sols, |sol| sol.min_threshold.as_ref());
+    combined.solve_mode = common(&sols, |sol| sol.solve_mode.as_ref());
+    combined.solver = common(&sols, |sol| sol.solver.as_ref());
+    combined.raw_data_corrections = common(&sols, |sol| sol.raw_data_corrections.as_ref());
+    // The tile names have already been checked to be the same.
+    combined.tile_names = sols.iter().find_map(|sol| sol.tile_names.clone());
+    combined.dipole_gains = common(&sols, |sol| sol.dipole_gains.as_ref());
+    combined.dipole_delays = common(&sols, |sol| sol.dipole_delays.as_ref());
+    combined.beam_file = common(&sols, |sol| sol.beam_file.as_ref());
+    combined.baseline_weights = common(&sols, |sol| sol.baseline_weights.as_ref());
+    combined.phase_reference = common(&sols, |sol| sol.phase_reference.as_ref());
+    combined.uvw_min = common(&sols, |sol| sol.uvw_min.as_ref());
+    combined.uvw_max = common(&sols, |sol| sol.uvw_max.as_ref());
+    combined.freq_centroid = common(&sols, |sol| sol.freq_centroid.as_ref());
+    combined.modeller = common(&sols, |sol| sol.modeller.as_ref());
+
+    Ok(combined)
+}
+
+/// Get the value of a field of the solutions if it's the same in all of them.
+fn common<T: Clone + PartialEq>(
+    sols: &[CalibrationSolutions],
+    field: impl Fn(&CalibrationSolutions) -> Option<&T>,
+) -> Option<T> {
+    let first = field(&sols[0])?;
+    sols[1..]
+        .iter()
+        .all(|sol| field(sol) == Some(first))
+        .then(|| first.clone())
+}
+
+/// Concatenate solutions in frequency. The timestamps of the solutions with
+/// the lowest frequencies are used.
+fn concat_freq(
+    sols: &[CalibrationSolutions],
+) -> Result<CalibrationSolutions, SolutionsCombineError> {
+    let (num_timeblocks, total_num_tiles, _) = sols[0].di_jones.dim();
+    let mut freqs = Vec::with_capacity(sols.len());
+    for (i, sol) in sols.iter().enumerate() {
+        let (sol_num_timeblocks, _, num_chanblocks) = sol.di_jones.dim();
+        if sol_num_timeblocks != num_timeblocks {
+            return Err(SolutionsCombineError::TimeblockCountMismatch {
+                index: i,
+                expected: num_timeblocks,
+                got: sol_num_timeblocks,
+            });
+        }
+        match sol.chanblock_freqs.as_ref() {
+            Some(f) if f.len() == num_chanblocks => freqs.push(f.as_slice()),
+            _ => return Err(SolutionsCombineError::NoFreqs(i)),
+        }
+    }
+
+    // All solutions with more than one chanblock must have the same frequency
+    // resolution.
+    let mut freq_res = None;
+    for (i, f) in freqs.iter().enumerate().filter(|(_, f)| f.len() > 1) {
+        let res = f[1] - f[0];
+        match freq_res {
+            None => freq_res = Some(res),
+            Some(freq_res) if (res - freq_res).abs() > FREQ_TOLERANCE => {
+                return Err(SolutionsCombineError::FreqResMismatch(i))
+            }
+            Some(_) => (),
+        }
+    }
+
+    // Order the solutions by frequency, and work out how many flagged
+    // chanblocks are needed before each of them to fill any gaps.
+    let mut order = (0..sols.len()).collect::<Vec<_>>();
+    order.sort_unstable_by(|&a, &b| freqs[a][0].total_cmp(&freqs[b][0]));
+    let mut num_padding = vec![0; sols.len()];
+    for pair in order.windows(2) {
+        let (prev, next) = (pair[0], pair[1]);
+        let gap = freqs[next][0] - freqs[prev][freqs[prev].len() - 1];
+        match freq_res {
+            Some(res) => {
+                let num_chanblocks = (gap / res).round();
+                if num_chanblocks < 1.0 {
+                    return Err(SolutionsCombineError::OverlappingFreqs(next));
+                }
+                if (gap - num_chanblocks * res).abs() > FREQ_TOLERANCE {
+                    return Err(SolutionsCombineError::FreqsNotOnGrid(next));
+                }
+                num_padding[next] = num_chanblocks as usize - 1;
+            }
+            // Every solution has a single chanblock, so there's no grid.
+            None => {
+                if gap < FREQ_TOLERANCE {
+                    return Err(SolutionsCombineError::OverlappingFreqs(next));
+                }
+            }
+        }
+    }
+
+    let total_num_chanblocks = order.iter().map(|&i| num_padding[i] + freqs[i].len()).sum();
+    let mut di_jones = Array3::from_elem(
+        (num_timeblocks, total_num_tiles, total_num_chanblocks),
+        Jones::nan(),
+    );
+    let mut chanblock_freqs = Vec::with_capacity(total_num_chanblocks);
+    let mut flagged_chanblocks = vec![];
+    let mut precisions = sols
+        .iter()
+        .all(|sol| {
+            matches!(sol.calibration_results.as_ref(), Some(p) if p.dim() == (num_timeblocks, sol.di_jones.len_of(Axis(2))))
+        })
+        .then(|| Array2::from_elem((num_timeblocks, total_num_chanblocks), f64::NAN));
+    let mut residual_stats = sols
+        .iter()
+        .map(|sol| sol.residual_stats.as_ref())
+        .collect::<Option<Vec<_>>>()
+        .filter(|stats| {
+            stats
+                .iter()
+                .zip(sols.iter())
+                .all(|(stat, sol)| stat.tiles.dim() == sol.di_jones.dim())
+                && stats.iter().map(|stat| stat.baselines.dim()).all_equal()
+        })
+        .map(|stats| {
+            (
+                stats,
+                Array3::from_elem(
+                    (num_timeblocks, total_num_tiles, total_num_chanblocks),
+                    ResidualStat::default(),
+                ),
+            )
+        });
+
+    let mut i_chanblock = 0;
+    for &i in &order {
+        let sol = &sols[i];
+        for _ in 0..num_padding[i] {
+            let prev_freq = chanblock_freqs.last().copied().unwrap_or_default();
+            chanblock_freqs.push(prev_freq + freq_res.unwrap_or_default());
+            flagged_chanblocks.push(i_chanblock as u16);
+            i_chanblock += 1;
+        }
+
+        let range = i_chanblock..i_chanblock + freqs[i].len();
+        di_jones
+            .slice_mut(s![.., .., range.clone()])
+            .assign(&sol.di_jones);
+        chanblock_freqs.extend_from_slice(freqs[i]);
+        flagged_chanblocks.extend(
+            sol.flagged_chanblocks
+                .iter()
+                .map(|&c| c + i_chanblock as u16),
+        );
+        if let (Some(precisions), Some(sol_precisions)) =
+            (precisions.as_mut(), sol.calibration_results.as_ref())
+        {
+            precisions
+                .slice_mut(s![.., range.clone()])
+                .assign(sol_precisions);
+        }
+        if let Some((stats, tiles)) = residual_stats.as_mut() {
+            tiles
+                .slice_mut(s![.., .., range.clone()])
+                .assign(&stats[i].tiles);
+        }
+        i_chanblock = range.end;
+    }
+
+    // Each baseline's statistics are over all chanblocks, so combine them.
+    let residual_stats = residual_stats.map(|(stats, tiles)| {
+        let mut baselines = stats[0].baselines.clone();
+        for stat in &stats[1..] {
+            baselines.zip_mut_with(&stat.baselines, |a, &b| *a = a.combine(b));
+        }
+        ResidualStats { tiles, baselines }
+    });
+
+    let lowest = &sols[order[0]];
+    Ok(CalibrationSolutions {
+        di_jones,
+        flagged_chanblocks,
+        chanblock_freqs: Vec1::try_from_vec(chanblock_freqs).ok(),
+        start_timestamps: lowest.start_timestamps.clone(),
+        end_timestamps: lowest.end_timestamps.clone(),
+        average_timestamps: lowest.average_timestamps.clone(),
+        calibration_results: precisions,
+        residual_stats,
+        ..Default::default()
+    })
+}
+
+/// Average all timeblocks of the solutions into a single timeblock.
+fn average_time(
+    sols: &[CalibrationSolutions],
+) -> Result<CalibrationSolutions, SolutionsCombineError> {
+    let (_, total_num_tiles, total_num_chanblocks) = sols[0].di_jones.dim();
+    for (i, sol) in sols.iter().enumerate().skip(1) {
+        let freqs_match = match (
+            sols[0].chanblock_freqs.as_ref(),
+            sol.chanblock_freqs.as_ref(),
+        ) {
+            (Some(freqs1), Some(freqs2)) => {
+                freqs1.len() == freqs2.len()
+                    && freqs1
+                        .iter()
+                        .zip(freqs2.iter())
+                        .all(|(f1, f2)| (f1 - f2).abs() < FREQ_TOLERANCE)
+            }
+            _ => true,
+        };
+        if sol.di_jones.len_of(Axis(2)) != total_num_chanblocks || !freqs_match {
+            return Err(SolutionsCombineError::ChanblocksMismatch(i));
+        }
+    }
+
+    // Precisions can only be used if every solution has them.
+    let use_precisions = sols.iter().all(|sol| {
+        matches!(sol.calibration_results.as_ref(), Some(p) if p.dim() == (sol.di_jones.len_of(Axis(0)), total_num_chanblocks))
+    });
+
+    let mut sum = Array2::from_elem((total_num_tiles, total_num_chanblocks), Jones::default());
+    let mut weights = Array2::<f64>::zeros((total_num_tiles, total_num_chanblocks));
+    for sol in sols {
+        for (i_timeblock, di_jones) in sol.di_jones.outer_iter().enumerate() {
+            for ((i_tile, i_chanblock), j) in di_jones.indexed_iter() {
+                if j.any_nan() || sol.flagged_chanblocks.contains(&(i_chanblock as u16)) {
+                    continue;
+                }
+                let weight = match sol.calibration_results.as_ref() {
+                    Some(precisions) if use_precisions => {
+                        let precision = precisions[(i_timeblock, i_chanblock)];
+                        if precision.is_finite() {
+                            1.0 / precision.max(MIN_PRECISION)
+                        } else {
+                            0.0
+                        }
+                    }
+                    _ => 1.0,
+                };
+                if weight > 0.0 {
+                    sum[(i_tile, i_chanblock)] += *j * weight;
+                    weights[(i_tile, i_chanblock)] += weight;
+                }
+            }
+        }
+    }
+
+    let mut di_jones = Array3::from_elem((1, total_num_tiles, total_num_chanblocks), Jones::nan());
+    di_jones
+        .slice_mut(s![0, .., ..])
+        .iter_mut()
+        .zip(sum.iter())
+        .zip(weights.iter())
+        .filter(|(_, weight)| **weight > 0.0)
+        .for_each(|((j, &sum), &weight)| *j = sum / weight);
+    // Chanblocks without any solutions are flagged.
+    let flagged_chanblocks = weights
+        .axis_iter(Axis(1))
+        .enumerate()
+        .filter(|(_, weights)| weights.iter().all(|&w| w == 0.0))
+        .map(|(i_chanblock, _)| i_chanblock as u16)
+        .collect();
+
+    // All of the visibilities used by the solutions are now used by a single
+    // timeblock.
+    let residual_stats = sols
+        .iter()
+        .map(|sol| sol.residual_stats.as_ref())
+        .collect::<Option<Vec<_>>>()
+        .filter(|stats| {
+            stats
+                .iter()
+                .zip(sols.iter())
+                .all(|(stat, sol)| stat.tiles.dim() == sol.di_jones.dim())
+                && stats
+                    .iter()
+                    .map(|stat| stat.baselines.len_of(Axis(1)))
+                    .all_equal()
+        })
+        .map(|stats| {
+            let mut tiles = Array3::from_elem(
+                (1, total_num_tiles, total_num_chanblocks),
+                ResidualStat::default(),
+            );
+            let mut baselines = Array2::from_elem(
+                (1, stats[0].baselines.len_of(Axis(1))),
+                ResidualStat::default(),
+            );
+            for stat in stats {
+                for stat_tiles in stat.tiles.outer_iter() {
+                    tiles
+                        .slice_mut(s![0, .., ..])
+                        .zip_mut_with(&stat_tiles, |a, &b| *a = a.combine(b));
+                }
+                for stat_baselines in stat.baselines.outer_iter() {
+                    baselines
+                        .slice_mut(s![0, ..])
+                        .zip_mut_with(&stat_baselines, |a, &b| *a = a.combine(b));
+                }
+            }
+            ResidualStats { tiles, baselines }
+        });
+
+    // The combined timeblock spans all of the timeblocks.
+    let start_timestamps = sols
+        .iter()
+        .map(|sol| sol.start_timestamps.as_ref())
+        .collect::<Option<Vec<_>>>()
+        .and_then(|t| {
+            t.into_iter()
+                .flatten()
+                .copied()
+                .reduce(|a, b| if b < a { b } else { a })
+        })
+        .map(Vec1::new);
+    let end_timestamps = sols
+        .iter()
+        .map(|sol| sol.end_timestamps.as_ref())
+        .collect::<Option<Vec<_>>>()
+        .and_then(|t| {
+            t.into_iter()
+                .flatten()
+                .copied()
+                .reduce(|a, b| if b > a { b } else { a })
+        })
+        .map(Vec1::new);
+    let average_timestamps = sols
+        .iter()
+        .map(|sol| sol.average_timestamps.as_ref())
+        .collect::<Option<Vec<_>>>()
+        .map(|t| Vec1::new(average_epoch(t.into_iter().flatten().copied())));
+
+    Ok(CalibrationSolutions {
+        di_jones,
+        flagged_chanblocks,
+        chanblock_freqs: sols[0].chanblock_freqs.clone(),
+        start_timestamps,
+        end_timestamps,
+        average_timestamps,
+        residual_stats,
+        ..Default::default()
+    })
+}
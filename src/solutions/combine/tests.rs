@@ -0,0 +1,273 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use hifitime::Epoch;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use vec1::{vec1, Vec1};
+
+use super::*;
+use crate::solutions::XyPhase;
+
+const NUM_TILES: usize = 3;
+const FREQ_RES: f64 = 80e3;
+
+/// Solutions with a different gain in each tile and chanblock, starting at
+/// chanblock `first_chanblock` of a frequency grid.
+fn get_sols(first_chanblock: usize, num_chanblocks: usize) -> CalibrationSolutions {
+    let di_jones = Array3::from_shape_fn(
+        (1, NUM_TILES, num_chanblocks),
+        |(_, i_tile, i_chanblock)| {
+            let i_chanblock = first_chanblock + i_chanblock;
+            Jones::from([
+                c64::from_polar(1.0 + 0.1 * i_tile as f64, 0.1 * i_chanblock as f64),
+                c64::default(),
+                c64::default(),
+                c64::from_polar(1.0 - 0.01 * i_chanblock as f64, 0.2 * i_tile as f64),
+            ])
+        },
+    );
+    CalibrationSolutions {
+        di_jones,
+        chanblock_freqs: Some(
+            Vec1::try_from_vec(
+                (0..num_chanblocks)
+                    .map(|i| 167e6 + (first_chanblock + i) as f64 * FREQ_RES)
+                    .collect(),
+            )
+            .unwrap(),
+        ),
+        tile_names: Some(vec1![
+            "Tile011".to_string(),
+            "Tile012".to_string(),
+            "Tile013".to_string()
+        ]),
+        obsid: Some(1090008640),
+        calibration_results: Some(Array2::from_elem((1, num_chanblocks), 1e-8)),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_concat_freq() {
+    // The solutions are out of order, and there's a gap of 2 chanblocks between
+    // the second and third sets.
+    let mut sols1 = get_sols(4, 2);
+    sols1.flagged_chanblocks = vec![1];
+    sols1.obsid = Some(1090008641);
+    let sols = vec![sols1, get_sols(0, 4), get_sols(8, 3)];
+
+    let combined = combine_solutions(sols, CombineMode::Frequency, None).unwrap();
+    assert_eq!(combined.di_jones.dim(), (1, NUM_TILES, 11));
+    let freqs = combined.chanblock_freqs.unwrap();
+    for (i, freq) in freqs.iter().enumerate() {
+        assert_abs_diff_eq!(*freq, 167e6 + i as f64 * FREQ_RES, epsilon = 1e-6);
+    }
+    assert_eq!(combined.flagged_chanblocks, [5, 6, 7]);
+    let expected = get_sols(0, 11);
+    for (i_chanblock, (di_jones, expected)) in combined
+        .di_jones
+        .axis_iter(Axis(2))
+        .zip(expected.di_jones.axis_iter(Axis(2)))
+        .enumerate()
+    {
+        if [6, 7].contains(&i_chanblock) {
+            assert!(di_jones.iter().all(|j| j.any_nan()));
+        } else {
+            assert_abs_diff_eq!(di_jones, expected);
+        }
+    }
+    let precisions = combined.calibration_results.unwrap();
+    assert!(precisions[(0, 6)].is_nan());
+    assert_abs_diff_eq!(precisions[(0, 10)], 1e-8);
+    // The obsids are different, but the tile names are the same.
+    assert!(combined.obsid.is_none());
+    assert_eq!(combined.tile_names.unwrap().len(), NUM_TILES);
+}
+
+#[test]
+fn test_concat_freq_errors() {
+    let result = combine_solutions(
+        vec![get_sols(0, 4), get_sols(3, 4)],
+        CombineMode::Frequency,
+        None,
+    );
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::OverlappingFreqs(1))
+    ));
+
+    let mut sols = get_sols(6, 4);
+    sols.chanblock_freqs = sols
+        .chanblock_freqs
+        .map(|f| f.mapped(|f| f + FREQ_RES / 3.0));
+    let result = combine_solutions(vec![get_sols(0, 4), sols], CombineMode::Frequency, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::FreqsNotOnGrid(1))
+    ));
+
+    let mut sols = get_sols(6, 4);
+    sols.chanblock_freqs = sols
+        .chanblock_freqs
+        .map(|f| f.mapped(|f| 167e6 + 6.0 * FREQ_RES + (f - 167e6 - 6.0 * FREQ_RES) * 2.0));
+    let result = combine_solutions(vec![get_sols(0, 4), sols], CombineMode::Frequency, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::FreqResMismatch(1))
+    ));
+
+    let mut sols = get_sols(6, 4);
+    sols.chanblock_freqs = None;
+    let result = combine_solutions(vec![get_sols(0, 4), sols], CombineMode::Frequency, None);
+    assert!(matches!(result, Err(SolutionsCombineError::NoFreqs(1))));
+
+    let mut sols = get_sols(6, 4);
+    sols.tile_names = Some(vec1![
+        "Tile011".to_string(),
+        "Tile013".to_string(),
+        "Tile012".to_string()
+    ]);
+    let result = combine_solutions(vec![get_sols(0, 4), sols], CombineMode::Frequency, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::TilesMismatch(1))
+    ));
+
+    let result = combine_solutions(vec![], CombineMode::Frequency, None);
+    assert!(matches!(result, Err(SolutionsCombineError::NoInputs)));
+}
+
+#[test]
+fn test_average_time() {
+    // The second solutions are 3 times bigger, but their precisions are 3
+    // times worse, so they have a third of the weight.
+    let mut sols1 = get_sols(0, 4);
+    sols1.start_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090008640.0)]);
+    sols1.end_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090008760.0)]);
+    sols1.average_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090008700.0)]);
+    sols1.di_jones[(0, 1, 2)] = Jones::nan();
+    sols1.flagged_chanblocks = vec![3];
+    let mut sols2 = get_sols(0, 4);
+    sols2.di_jones.mapv_inplace(|j| j * 3.0);
+    sols2.calibration_results = Some(Array2::from_elem((1, 4), 3e-8));
+    sols2.start_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090095040.0)]);
+    sols2.end_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090095160.0)]);
+    sols2.average_timestamps = Some(vec1![Epoch::from_gpst_seconds(1090095100.0)]);
+    sols2.flagged_chanblocks = vec![3];
+    let expected = get_sols(0, 4);
+
+    let combined = combine_solutions(vec![sols1, sols2], CombineMode::Time, None).unwrap();
+    assert_eq!(combined.di_jones.dim(), (1, NUM_TILES, 4));
+    for ((_, i_tile, i_chanblock), j) in combined.di_jones.indexed_iter() {
+        let expected = expected.di_jones[(0, i_tile, i_chanblock)];
+        if i_chanblock == 3 {
+            assert!(j.any_nan());
+        } else if (i_tile, i_chanblock) == (1, 2) {
+            // Only the second solutions are available.
+            assert_abs_diff_eq!(*j, expected * 3.0, epsilon = 1e-12);
+        } else {
+            assert_abs_diff_eq!(*j, expected * 1.5, epsilon = 1e-12);
+        }
+    }
+    assert_eq!(combined.flagged_chanblocks, [3]);
+    assert_eq!(
+        combined.start_timestamps.unwrap().as_slice(),
+        [Epoch::from_gpst_seconds(1090008640.0)]
+    );
+    assert_eq!(
+        combined.end_timestamps.unwrap().as_slice(),
+        [Epoch::from_gpst_seconds(1090095160.0)]
+    );
+    assert_abs_diff_eq!(
+        combined.average_timestamps.unwrap()[0].to_gpst_seconds(),
+        1090051900.0,
+        epsilon = 1e-3
+    );
+    assert_eq!(combined.obsid, Some(1090008640));
+    assert!(combined.calibration_results.is_none());
+
+    let result = combine_solutions(
+        vec![get_sols(0, 4), get_sols(1, 4)],
+        CombineMode::Time,
+        None,
+    );
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::ChanblocksMismatch(1))
+    ));
+}
+
+#[test]
+fn test_average_time_reference() {
+    // The second solutions have different (arbitrary) phases for each
+    // polarisation; once these are referenced away, they're the same as the
+    // first solutions.
+    let mut sols2 = get_sols(0, 4);
+    let diag = Jones::from([
+        c64::cis(0.5),
+        c64::default(),
+        c64::default(),
+        c64::cis(-1.0),
+    ]);
+    sols2.di_jones.mapv_inplace(|j| diag * j);
+
+    let mut expected = get_sols(0, 4);
+    reference_phases(&mut expected, 0, false).unwrap();
+
+    let combined = combine_solutions(
+        vec![get_sols(0, 4), sols2],
+        CombineMode::Time,
+        Some("Tile011"),
+    )
+    .unwrap();
+    assert_eq!(combined.phase_reference, expected.phase_reference);
+    assert_abs_diff_eq!(combined.di_jones, expected.di_jones, epsilon = 1e-12);
+}
+
+#[test]
+fn test_xy_phase() {
+    // The second solutions have an XY-phase correction; once this is applied,
+    // they're the same as the first solutions.
+    let xy_phased_sols = || {
+        let mut sols = get_sols(0, 4);
+        // Undo the XY-phase correction that will be applied.
+        let undo = Jones::from([
+            c64::new(1.0, 0.0),
+            c64::default(),
+            c64::default(),
+            c64::cis(0.3),
+        ]);
+        sols.di_jones.mapv_inplace(|j| undo * j);
+        sols.xy_phase = Some(XyPhase {
+            phases: Array1::from_elem(4, 0.3),
+            measured_phases: Array1::from_elem(4, 0.3),
+            delay: 0.0,
+        });
+        sols
+    };
+
+    let combined = combine_solutions(
+        vec![get_sols(0, 4), xy_phased_sols()],
+        CombineMode::Time,
+        None,
+    )
+    .unwrap();
+    assert!(combined.xy_phase.is_none());
+    assert_abs_diff_eq!(combined.di_jones, get_sols(0, 4).di_jones, epsilon = 1e-12);
+
+    // Referencing would remove the XY-phase correction.
+    let result = combine_solutions(
+        vec![get_sols(0, 4), xy_phased_sols()],
+        CombineMode::Time,
+        Some("Tile011"),
+    );
+    assert!(matches!(
+        result,
+        Err(SolutionsCombineError::PhaseReference(
+            PhaseReferenceError::XyPhase
+        ))
+    ));
+}
@@ -0,0 +1,382 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to compare two sets of calibration solutions.
+//!
+//! The solutions are aligned by tile name and chanblock frequency where
+//! possible (otherwise by index), and for each tile and chanblock the ratio of
+//! the XX and YY amplitudes (second over first) and the difference of their
+//! phases (second minus first) are found. Phase differences are only really
+//! meaningful if both sets of solutions are referenced to the same tile (see
+//! [`super::reference`]); this can be done before comparing.
+
+#[cfg(test)]
+mod tests;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{
+    reference::{parse_ref_tile, reference_phases, PhaseReferenceError},
+    CalibrationSolutions,
+};
+use crate::math::median;
+
+#[derive(Error, Debug)]
+pub(crate) enum SolutionsDiffError {
+    #[error("The solutions have {first} and {second} tiles and at least one doesn't have tile names, so the tiles can't be matched")]
+    TileCountMismatch { first: usize, second: usize },
+
+    #[error("The solutions have {first} and {second} chanblocks and at least one doesn't have chanblock frequencies, so the chanblocks can't be matched")]
+    ChanblockCountMismatch { first: usize, second: usize },
+
+    #[error("The solutions have {first} and {second} timeblocks; they can only be compared if they have the same number or one has a single timeblock")]
+    TimeblockCountMismatch { first: usize, second: usize },
+
+    #[error("None of the tiles in the first solutions are in the second solutions")]
+    NoCommonTiles,
+
+    #[error("None of the chanblocks in the first solutions are in the second solutions")]
+    NoCommonChanblocks,
+
+    #[error("The reference tile {0} isn't in the second solutions")]
+    RefTileNotMatched(String),
+
+    #[error(transparent)]
+    PhaseReference(#[from] PhaseReferenceError),
+}
+
+/// A comparison of two sets of calibration solutions. Ratios and differences
+/// are always of the second solutions relative to the first.
+#[derive(Debug, Serialize)]
+pub(crate) struct SolutionsDiff {
+    /// The names of the compared tiles. If either set of solutions doesn't
+    /// have tile names, these are the tile indices.
+    pub(crate) tile_names: Vec<String>,
+
+    /// The index of each compared tile in the first and second solutions.
+    pub(crate) tile_indices: Vec<[usize; 2]>,
+
+    /// The names (or indices) of tiles in the first solutions that aren't in
+    /// the second solutions.
+    pub(crate) unmatched_tiles: Vec<String>,
+
+    /// The frequencies of the compared chanblocks \[Hz\], if available. These
+    /// come from the first solutions where possible.
+    pub(crate) chanblock_freqs: Option<Vec<f64>>,
+
+    /// The index of each compared chanblock in the first and second solutions.
+    pub(crate) chanblock_indices: Vec<[usize; 2]>,
+
+    /// The name (or index) of the tile that both solutions were referenced to
+    /// before comparing, if any.
+    pub(crate) ref_tile: Option<String>,
+
+    /// The comparison of each timeblock.
+    pub(crate) timeblocks: Vec<TimeblockSolutionsDiff>,
+}
+
+/// A comparison of a single timeblock of two sets of calibration solutions.
+/// Values that couldn't be compared (e.g. because either solution is flagged)
+/// are NaN.
+#[derive(Debug, Serialize)]
+pub(crate) struct TimeblockSolutionsDiff {
+    /// The XX and YY amplitude ratios of each tile and chanblock. The first
+    /// dimension is tile, the second is chanblock.
+    pub(crate) amp_ratios: Vec<Vec<[f64; 2]>>,
+
+    /// The XX and YY phase differences of each tile and chanblock \[radians\].
+    /// The first dimension is tile, the second is chanblock.
+    pub(crate) phase_diffs: Vec<Vec<[f64; 2]>>,
+
+    /// Statistics of each tile over all chanblocks.
+    pub(crate) tiles: Vec<DiffStats>,
+
+    /// Statistics of each chanblock over all tiles.
+    pub(crate) chanblocks: Vec<DiffStats>,
+
+    /// Statistics over all tiles and chanblocks.
+    pub(crate) overall: DiffStats,
+}
+
+/// Statistics of the amplitude ratios and phase differences of some solutions.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct DiffStats {
+    /// The median XX and YY amplitude ratios.
+    pub(crate) median_amp_ratio: [f64; 2],
+
+    /// The RMS XX and YY phase differences \[radians\].
+    pub(crate) rms_phase_diff: [f64; 2],
+
+    /// The number of solutions that could be compared.
+    pub(crate) num_compared: usize,
+}
+
+impl DiffStats {
+    fn new<'a, I>(values: I) -> DiffStats
+    where
+        I: Iterator<Item = (&'a [f64; 2], &'a [f64; 2])>,
+    {
+        let mut amp_ratios = [vec![], vec![]];
+        let mut phase_diffs = [vec![], vec![]];
+        for (amp_ratio, phase_diff) in values {
+            for i_pol in 0..2 {
+                if amp_ratio[i_pol].is_finite() && phase_diff[i_pol].is_finite() {
+                    amp_ratios[i_pol].push(amp_ratio[i_pol]);
+                    phase_diffs[i_pol].push(phase_diff[i_pol]);
+                }
+            }
+        }
+        let num_compared = amp_ratios[0].len().max(amp_ratios[1].len());
+        let [mut xx_amp_ratios, mut yy_amp_ratios] = amp_ratios;
+        let rms = |phase_diffs: &[f64]| {
+            if phase_diffs.is_empty() {
+                f64::NAN
+            } else {
+                (phase_diffs.iter().map(|p| p * p).sum::<f64>() / phase_diffs.len() as f64).sqrt()
+            }
+        };
+        DiffStats {
+            median_amp_ratio: [
+                median(&mut xx_amp_ratios).unwrap_or(f64::NAN),
+                median(&mut yy_amp_ratios).unwrap_or(f64::NAN),
+            ],
+            rms_phase_diff: [rms(&phase_diffs[0]), rms(&phase_diffs[1])],
+            num_compared,
+        }
+    }
+}
+
+/// Compare the solutions `second` to the solutions `first`.
+///
+/// Tiles are matched by name if both solutions have tile names, otherwise by
+/// index (if the total number of tiles is the same). Chanblocks are matched by
+/// frequency if both solutions have chanblock frequencies, otherwise by index
+/// (if the total number of chanblocks is the same); matched chanblocks must be
+/// within half of the finer frequency resolution of the two solutions. If
+/// either solutions have a single timeblock, it is compared against every
+/// timeblock of the other solutions.
+///
+/// If `ref_tile` is given (an index or name of a tile in the first solutions),
+/// the phases of both solutions are referenced to this tile before comparing.
+pub(crate) fn diff_solutions(
+    first: &CalibrationSolutions,
+    second: &CalibrationSolutions,
+    ref_tile: Option<&str>,
+) -> Result<SolutionsDiff, SolutionsDiffError> {
+    let (num_timeblocks1, num_tiles1, num_chanblocks1) = first.di_jones.dim();
+    let (num_timeblocks2, num_tiles2, num_chanblocks2) = second.di_jones.dim();
+
+    // Match the tiles.
+    let (tile_names, tile_indices, unmatched_tiles) =
+        match (first.tile_names.as_ref(), second.tile_names.as_ref()) {
+            (Some(names1), Some(names2)) => {
+                let mut tile_names = vec![];
+                let mut tile_indices = vec![];
+                let mut unmatched_tiles = vec![];
+                for (i_tile1, name) in names1.iter().enumerate() {
+                    match names2.iter().position(|n| n == name) {
+                        Some(i_tile2) => {
+                            tile_names.push(name.clone());
+                            tile_indices.push([i_tile1, i_tile2]);
+                        }
+                        None => unmatched_tiles.push(name.clone()),
+                    }
+                }
+                (tile_names, tile_indices, unmatched_tiles)
+            }
+            _ if num_tiles1 == num_tiles2 => (
+                (0..num_tiles1).map(|i| i.to_string()).collect(),
+                (0..num_tiles1).map(|i| [i, i]).collect(),
+                vec![],
+            ),
+            _ => {
+                return Err(SolutionsDiffError::TileCountMismatch {
+                    first: num_tiles1,
+                    second: num_tiles2,
+                })
+            }
+        };
+    if tile_indices.is_empty() {
+        return Err(SolutionsDiffError::NoCommonTiles);
+    }
+
+    // Match the chanblocks.
+    let (chanblock_freqs, chanblock_indices) = match (
+        first.chanblock_freqs.as_ref(),
+        second.chanblock_freqs.as_ref(),
+    ) {
+        (Some(freqs1), Some(freqs2)) => {
+            let res = |freqs: &[f64]| {
+                if freqs.len() > 1 {
+                    (freqs[1] - freqs[0]).abs()
+                } else {
+                    f64::INFINITY
+                }
+            };
+            let half_res = res(freqs1.as_slice()).min(res(freqs2.as_slice())) / 2.0;
+            let mut chanblock_freqs = vec![];
+            let mut chanblock_indices = vec![];
+            for (i_chanblock1, &freq1) in freqs1.iter().enumerate() {
+                let i_chanblock2 = freqs2
+                    .iter()
+                    .map(|f| (f - freq1).abs())
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .filter(|(_, diff)| *diff < half_res)
+                    .map(|(i, _)| i);
+                if let Some(i_chanblock2) = i_chanblock2 {
+                    chanblock_freqs.push(freq1);
+                    chanblock_indices.push([i_chanblock1, i_chanblock2]);
+                }
+            }
+            (Some(chanblock_freqs), chanblock_indices)
+        }
+        _ if num_chanblocks1 == num_chanblocks2 => (
+            first
+                .chanblock_freqs
+                .as_ref()
+                .or(second.chanblock_freqs.as_ref())
+                .map(|f| f.to_vec()),
+            (0..num_chanblocks1).map(|i| [i, i]).collect(),
+        ),
+        _ => {
+            return Err(SolutionsDiffError::ChanblockCountMismatch {
+                first: num_chanblocks1,
+                second: num_chanblocks2,
+            })
+        }
+    };
+    if chanblock_indices.is_empty() {
+        return Err(SolutionsDiffError::NoCommonChanblocks);
+    }
+
+    let timeblock_indices: Vec<[usize; 2]> = if num_timeblocks1 == num_timeblocks2 {
+        (0..num_timeblocks1).map(|i| [i, i]).collect()
+    } else if num_timeblocks1 == 1 {
+        (0..num_timeblocks2).map(|i| [0, i]).collect()
+    } else if num_timeblocks2 == 1 {
+        (0..num_timeblocks1).map(|i| [i, 0]).collect()
+    } else {
+        return Err(SolutionsDiffError::TimeblockCountMismatch {
+            first: num_timeblocks1,
+            second: num_timeblocks2,
+        });
+    };
+
+    // Reference the phases, if requested. Only the Jones matrices are needed,
+    // but referencing also checks that the solutions can be referenced.
+    let (di_jones1, di_jones2, ref_tile) = match ref_tile {
+        Some(ref_tile) => {
+            let ref_tile1 = parse_ref_tile(
+                ref_tile,
+                first.tile_names.as_ref().map(|n| n.as_slice()),
+                num_tiles1,
+            )?;
+            let i_ref_tile = tile_indices
+                .iter()
+                .position(|[i_tile1, _]| *i_tile1 == ref_tile1)
+                .ok_or_else(|| SolutionsDiffError::RefTileNotMatched(ref_tile.to_string()))?;
+            let ref_tile2 = tile_indices[i_ref_tile][1];
+
+            let reference = |sols: &CalibrationSolutions, ref_tile: usize| {
+                let mut sols = CalibrationSolutions {
+                    di_jones: sols.di_jones.clone(),
+                    xy_phase: sols.xy_phase.clone(),
+                    ..Default::default()
+                };
+                reference_phases(&mut sols, ref_tile, false).map(|()| sols.di_jones)
+            };
+            (
+                reference(first, ref_tile1)?,
+                reference(second, ref_tile2)?,
+                Some(tile_names[i_ref_tile].clone()),
+            )
+        }
+        None => (first.di_jones.clone(), second.di_jones.clone(), None),
+    };
+
+    let timeblocks = timeblock_indices
+        .into_iter()
+        .map(|[i_timeblock1, i_timeblock2]| {
+            let di_jones1 = di_jones1.slice(s![i_timeblock1, .., ..]);
+            let di_jones2 = di_jones2.slice(s![i_timeblock2, .., ..]);
+            diff_timeblock(di_jones1, di_jones2, &tile_indices, &chanblock_indices)
+        })
+        .collect();
+
+    Ok(SolutionsDiff {
+        tile_names,
+        tile_indices,
+        unmatched_tiles,
+        chanblock_freqs,
+        chanblock_indices,
+        ref_tile,
+        timeblocks,
+    })
+}
+
+fn diff_timeblock(
+    di_jones1: ArrayView2<Jones<f64>>,
+    di_jones2: ArrayView2<Jones<f64>>,
+    tile_indices: &[[usize; 2]],
+    chanblock_indices: &[[usize; 2]],
+) -> TimeblockSolutionsDiff {
+    let mut amp_ratios = Vec::with_capacity(tile_indices.len());
+    let mut phase_diffs = Vec::with_capacity(tile_indices.len());
+    for &[i_tile1, i_tile2] in tile_indices {
+        let (tile_amp_ratios, tile_phase_diffs) = chanblock_indices
+            .iter()
+            .map(|&[i_chanblock1, i_chanblock2]| {
+                let j1 = di_jones1[(i_tile1, i_chanblock1)];
+                let j2 = di_jones2[(i_tile2, i_chanblock2)];
+                if j1.any_nan() || j2.any_nan() {
+                    return ([f64::NAN; 2], [f64::NAN; 2]);
+                }
+                // XX and YY are the first and last elements of the Jones
+                // matrices.
+                let ratio = |g1: c64, g2: c64| g2.norm() / g1.norm();
+                let diff = |g1: c64, g2: c64| (g2 * g1.conj()).arg();
+                (
+                    [ratio(j1[0], j2[0]), ratio(j1[3], j2[3])],
+                    [diff(j1[0], j2[0]), diff(j1[3], j2[3])],
+                )
+            })
+            .unzip();
+        amp_ratios.push(tile_amp_ratios);
+        phase_diffs.push(tile_phase_diffs);
+    }
+
+    let tiles = amp_ratios
+        .iter()
+        .zip(phase_diffs.iter())
+        .map(|(a, p)| DiffStats::new(a.iter().zip(p.iter())))
+        .collect();
+    let chanblocks = (0..chanblock_indices.len())
+        .map(|i_chanblock| {
+            DiffStats::new(
+                amp_ratios
+                    .iter()
+                    .zip(phase_diffs.iter())
+                    .map(|(a, p)| (&a[i_chanblock], &p[i_chanblock])),
+            )
+        })
+        .collect();
+    let overall = DiffStats::new(
+        amp_ratios
+            .iter()
+            .flatten()
+            .zip(phase_diffs.iter().flatten()),
+    );
+
+    TimeblockSolutionsDiff {
+        amp_ratios,
+        phase_diffs,
+        tiles,
+        chanblocks,
+        overall,
+    }
+}
@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use vec1::{vec1, Vec1};
+
+use super::*;
+
+const NUM_TILES: usize = 4;
+const NUM_CHANBLOCKS: usize = 6;
+
+fn get_freqs() -> Vec1<f64> {
+    Vec1::try_from_vec(
+        (0..NUM_CHANBLOCKS)
+            .map(|i| 167e6 + i as f64 * 80e3)
+            .collect(),
+    )
+    .unwrap()
+}
+
+fn get_tile_names() -> Vec1<String> {
+    Vec1::try_from_vec(
+        (0..NUM_TILES)
+            .map(|i| format!("Tile{:03}", 11 + i))
+            .collect(),
+    )
+    .unwrap()
+}
+
+/// Solutions with a different gain in each tile and chanblock.
+fn get_sols() -> CalibrationSolutions {
+    let di_jones = Array3::from_shape_fn(
+        (1, NUM_TILES, NUM_CHANBLOCKS),
+        |(_, i_tile, i_chanblock)| {
+            let gx = c64::from_polar(
+                1.0 + 0.1 * i_tile as f64,
+                0.2 * i_tile as f64 + 0.1 * i_chanblock as f64,
+            );
+            let gy = c64::from_polar(
+                1.2 - 0.05 * i_chanblock as f64,
+                -0.3 * i_tile as f64 + 0.05 * i_chanblock as f64,
+            );
+            Jones::from([gx, c64::default(), c64::default(), gy])
+        },
+    );
+    CalibrationSolutions {
+        di_jones,
+        tile_names: Some(get_tile_names()),
+        chanblock_freqs: Some(get_freqs()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_diff_identical_solutions() {
+    let sols = get_sols();
+    let diff = diff_solutions(&sols, &sols, None).unwrap();
+    assert_eq!(diff.tile_names, get_tile_names().to_vec());
+    assert!(diff.unmatched_tiles.is_empty());
+    assert_eq!(diff.chanblock_indices.len(), NUM_CHANBLOCKS);
+    assert_eq!(diff.timeblocks.len(), 1);
+    let overall = diff.timeblocks[0].overall;
+    assert_eq!(overall.num_compared, NUM_TILES * NUM_CHANBLOCKS);
+    assert_abs_diff_eq!(overall.median_amp_ratio[0], 1.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(overall.median_amp_ratio[1], 1.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(overall.rms_phase_diff[0], 0.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(overall.rms_phase_diff[1], 0.0, epsilon = 1e-12);
+}
+
+#[test]
+fn test_diff_aligns_tiles_and_chanblocks() {
+    let first = get_sols();
+
+    // The second solutions have their tiles in reverse order, the last tile
+    // missing, only every other chanblock and a flagged solution. Each tile's
+    // XX gain is 10% bigger and its YY phase is 0.1 radians bigger per tile.
+    let mut second_names = get_tile_names().to_vec();
+    second_names.pop();
+    second_names.reverse();
+    let second_freqs: Vec<f64> = get_freqs().iter().copied().step_by(2).collect();
+    let mut second_di_jones = Array3::from_elem(
+        (1, second_names.len(), second_freqs.len()),
+        Jones::identity(),
+    );
+    for (i_tile2, mut di_jones) in second_di_jones.axis_iter_mut(Axis(1)).enumerate() {
+        let i_tile1 = NUM_TILES - 2 - i_tile2;
+        for (i_chanblock2, j) in di_jones.iter_mut().enumerate() {
+            let j1 = first.di_jones[(0, i_tile1, i_chanblock2 * 2)];
+            *j = Jones::from([
+                j1[0] * 1.1,
+                j1[1],
+                j1[2],
+                j1[3] * c64::cis(0.1 * i_tile1 as f64),
+            ]);
+        }
+    }
+    second_di_jones[(0, 0, 1)] = Jones::nan();
+    let second = CalibrationSolutions {
+        di_jones: second_di_jones,
+        tile_names: Some(Vec1::try_from_vec(second_names).unwrap()),
+        chanblock_freqs: Some(Vec1::try_from_vec(second_freqs.clone()).unwrap()),
+        ..Default::default()
+    };
+
+    let diff = diff_solutions(&first, &second, None).unwrap();
+    assert_eq!(diff.tile_names, ["Tile011", "Tile012", "Tile013"]);
+    assert_eq!(diff.tile_indices, [[0, 2], [1, 1], [2, 0]]);
+    assert_eq!(diff.unmatched_tiles, ["Tile014"]);
+    assert_eq!(
+        diff.chanblock_freqs.as_deref(),
+        Some(second_freqs.as_slice())
+    );
+    assert_eq!(diff.chanblock_indices, [[0, 0], [2, 1], [4, 2]]);
+
+    let timeblock = &diff.timeblocks[0];
+    for (i_tile, (amp_ratios, phase_diffs)) in timeblock
+        .amp_ratios
+        .iter()
+        .zip(timeblock.phase_diffs.iter())
+        .enumerate()
+    {
+        for (i_chanblock, (amp_ratio, phase_diff)) in
+            amp_ratios.iter().zip(phase_diffs.iter()).enumerate()
+        {
+            // The flagged solution is tile 2 in the first solutions.
+            if i_tile == 2 && i_chanblock == 1 {
+                assert!(amp_ratio.iter().all(|r| r.is_nan()));
+                assert!(phase_diff.iter().all(|p| p.is_nan()));
+                continue;
+            }
+            assert_abs_diff_eq!(amp_ratio[0], 1.1, epsilon = 1e-12);
+            assert_abs_diff_eq!(amp_ratio[1], 1.0, epsilon = 1e-12);
+            assert_abs_diff_eq!(phase_diff[0], 0.0, epsilon = 1e-12);
+            assert_abs_diff_eq!(phase_diff[1], 0.1 * i_tile as f64, epsilon = 1e-12);
+        }
+    }
+    assert_eq!(timeblock.tiles[2].num_compared, 2);
+    assert_abs_diff_eq!(timeblock.tiles[1].rms_phase_diff[1], 0.1, epsilon = 1e-12);
+    assert_eq!(timeblock.chanblocks[1].num_compared, 2);
+    assert_eq!(timeblock.overall.num_compared, 8);
+}
+
+#[test]
+fn test_diff_reference_tile() {
+    let first = get_sols();
+
+    // Rotate the phases of every tile by the same (chanblock-dependent)
+    // amount; this is invisible to DI calibration.
+    let mut second = get_sols();
+    for mut di_jones in second.di_jones.axis_iter_mut(Axis(2)) {
+        let diag = Jones::from([
+            c64::cis(0.7),
+            c64::default(),
+            c64::default(),
+            c64::cis(-0.4),
+        ]);
+        di_jones.iter_mut().for_each(|j| *j = diag * *j);
+    }
+
+    let diff = diff_solutions(&first, &second, None).unwrap();
+    assert_abs_diff_eq!(
+        diff.timeblocks[0].overall.rms_phase_diff[0],
+        0.7,
+        epsilon = 1e-12
+    );
+
+    let diff = diff_solutions(&first, &second, Some("tile012")).unwrap();
+    assert_eq!(diff.ref_tile.as_deref(), Some("Tile012"));
+    let overall = diff.timeblocks[0].overall;
+    assert_abs_diff_eq!(overall.rms_phase_diff[0], 0.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(overall.rms_phase_diff[1], 0.0, epsilon = 1e-12);
+    assert_abs_diff_eq!(overall.median_amp_ratio[0], 1.0, epsilon = 1e-12);
+
+    let result = diff_solutions(&first, &second, Some("Tile099"));
+    assert!(matches!(
+        result,
+        Err(SolutionsDiffError::PhaseReference(
+            PhaseReferenceError::BadTileName(_)
+        ))
+    ));
+}
+
+#[test]
+fn test_diff_without_names_or_freqs() {
+    let mut first = get_sols();
+    first.tile_names = None;
+    first.chanblock_freqs = None;
+    // The second solutions have a single timeblock, which is compared against
+    // both of the first solutions' timeblocks.
+    let second = get_sols();
+    first.di_jones =
+        ndarray::concatenate(Axis(0), &[first.di_jones.view(), first.di_jones.view()]).unwrap();
+
+    let diff = diff_solutions(&first, &second, None).unwrap();
+    assert_eq!(diff.tile_names, ["0", "1", "2", "3"]);
+    assert_eq!(
+        diff.chanblock_freqs.as_deref(),
+        Some(get_freqs().as_slice())
+    );
+    assert_eq!(diff.timeblocks.len(), 2);
+
+    let mut second = get_sols();
+    second.tile_names = Some(vec1!["Tile011".to_string()]);
+    second.di_jones = second.di_jones.slice(s![.., ..1, ..]).to_owned();
+    let result = diff_solutions(&first, &second, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsDiffError::TileCountMismatch {
+            first: 4,
+            second: 1
+        })
+    ));
+
+    let mut second = get_sols();
+    second.di_jones = Array3::from_elem((3, NUM_TILES, NUM_CHANBLOCKS), Jones::identity());
+    let result = diff_solutions(&first, &second, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsDiffError::TimeblockCountMismatch {
+            first: 2,
+            second: 3
+        })
+    ));
+}
@@ -6,7 +6,7 @@
 
 use thiserror::Error;
 
-use super::rts::RtsReadSolsError;
+use super::rts::{RtsReadSolsError, RtsWriteSolsError};
 
 #[derive(Error, Debug)]
 pub(crate) enum SolutionsReadError {
@@ -36,6 +36,12 @@ pub(crate) enum SolutionsReadError {
         actual: usize,
     },
 
+    #[error("Unrecognised calibration solve mode '{0}'. Supported modes: {}", *crate::di_calibrate::SOLVE_MODES_COMMA_SEPARATED)]
+    UnknownSolveMode(String),
+
+    #[error("Unrecognised calibration solver '{0}'. Supported solvers: {}", *crate::di_calibrate::SOLVERS_COMMA_SEPARATED)]
+    UnknownSolver(String),
+
     #[error(transparent)]
     ParsePfbFlavour(#[from] crate::io::read::pfb_gains::PfbParseError),
 
@@ -60,6 +66,24 @@ pub(crate) enum SolutionsWriteError {
     #[error("Tried to write calibration solutions file with an unsupported extension '{ext}'!")]
     UnsupportedExt { ext: String },
 
+    #[error("When writing RTS calibration solutions, a metafits file is required")]
+    RtsMetafitsRequired,
+
+    #[error(transparent)]
+    Rts(#[from] RtsWriteSolsError),
+
+    #[error("Writing a CASA calibration table requires the frequencies of all chanblocks")]
+    CasaNoChanblockFreqs,
+
+    #[error("Writing a CASA calibration table requires the frequency resolution of the chanblocks, but it couldn't be determined")]
+    CasaNoFreqRes,
+
+    #[error("casacore table error: {0}")]
+    CasaTable(#[from] marlu::rubbl_casatables::TableError),
+
+    #[error("Error from casacore: {0}")]
+    Casacore(#[from] marlu::rubbl_casatables::CasacoreError),
+
     #[error(transparent)]
     Fitsio(#[from] fitsio::errors::Error),
 
@@ -10,6 +10,7 @@
 use std::{
     ffi::CString,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use fitsio::{
@@ -24,14 +25,17 @@ use ndarray::prelude::*;
 use rayon::prelude::*;
 use vec1::Vec1;
 
-use super::{error::*, CalibrationSolutions};
-use crate::io::read::{
-    fits::{
-        fits_get_image, fits_get_optional_key, fits_get_optional_key_long_string,
-        fits_get_required_key, fits_open, fits_open_hdu,
+use super::{error::*, CalibrationSolutions, PhaseReference, ResidualStat, ResidualStats, XyPhase};
+use crate::{
+    di_calibrate::{SolveMode, Solver},
+    io::read::{
+        fits::{
+            fits_get_image, fits_get_optional_key, fits_get_optional_key_long_string,
+            fits_get_required_key, fits_open, fits_open_hdu,
+        },
+        pfb_gains::PfbFlavour,
+        RawDataCorrections,
     },
-    pfb_gains::PfbFlavour,
-    RawDataCorrections,
 };
 
 pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadError> {
@@ -42,6 +46,20 @@ pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadErr
     let max_iterations: Option<u32> = fits_get_optional_key(&mut fptr, &hdu, "MAXITER")?;
     let stop_threshold: Option<f64> = fits_get_optional_key(&mut fptr, &hdu, "S_THRESH")?;
     let min_threshold: Option<f64> = fits_get_optional_key(&mut fptr, &hdu, "M_THRESH")?;
+    let solve_mode: Option<String> = fits_get_optional_key(&mut fptr, &hdu, "SOLVMODE")?;
+    let solve_mode = solve_mode
+        .map(|s| SolveMode::from_str(&s).map_err(|_| SolutionsReadError::UnknownSolveMode(s)))
+        .transpose()?;
+    let solver: Option<String> = fits_get_optional_key(&mut fptr, &hdu, "SOLVER")?;
+    let solver = solver
+        .map(|s| Solver::from_str(&s).map_err(|_| SolutionsReadError::UnknownSolver(s)))
+        .transpose()?;
+    let ref_tile: Option<usize> = fits_get_optional_key(&mut fptr, &hdu, "REFTILE")?;
+    let ref_cross_hands: Option<String> = fits_get_optional_key(&mut fptr, &hdu, "REFXHAND")?;
+    let phase_reference = ref_tile.map(|tile| PhaseReference {
+        tile,
+        cross_hands: matches!(ref_cross_hands.as_deref(), Some("Y")),
+    });
     let uvw_min: Option<f64> = fits_get_optional_key(&mut fptr, &hdu, "UVW_MIN")?;
     let uvw_max: Option<f64> = fits_get_optional_key(&mut fptr, &hdu, "UVW_MAX")?;
     let freq_centroid: Option<f64> = {
@@ -518,6 +536,117 @@ pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadErr
         }
     };
 
+    // If available, open the "TILE_RESIDUALS" and "BASELINE_RESIDUALS" HDUs
+    // and get the residual statistics out. Both need to be present.
+    let residual_stats = {
+        let mut read_stats = |hdu_name: &str,
+                              thing: &'static str,
+                              shape: &[usize]|
+         -> Result<Option<Vec<ResidualStat>>, SolutionsReadError> {
+            match fptr.hdu(hdu_name) {
+                Ok(hdu) => {
+                    // Complain if the shape isn't right. Note that FITS axes
+                    // are in the reverse order.
+                    for (i_axis, &expected) in shape.iter().rev().enumerate() {
+                        let actual: usize = fits_get_required_key(
+                            &mut fptr,
+                            &hdu,
+                            &format!("NAXIS{}", i_axis + 1),
+                        )?;
+                        if actual != expected {
+                            return Err(SolutionsReadError::BadShape {
+                                thing,
+                                expected,
+                                actual,
+                            });
+                        }
+                    }
+
+                    let stats_vec: Vec<f64> = fits_get_image(&mut fptr, &hdu)?;
+                    Ok(Some(
+                        stats_vec
+                            .chunks_exact(3)
+                            .map(|s| ResidualStat {
+                                rms: s[0],
+                                chi_squared: s[1],
+                                num_vis: s[2] as usize,
+                            })
+                            .collect(),
+                    ))
+                }
+                Err(e) => match e {
+                    // Status code 301 means "unavailable".
+                    fitsio::errors::Error::Fits(fitsio::errors::FitsError {
+                        status: 301, ..
+                    }) => Ok(None),
+                    _ => Err(SolutionsReadError::Fitsio(e)),
+                },
+            }
+        };
+
+        let tile_shape = (num_timeblocks, total_num_tiles, total_num_chanblocks);
+        let baseline_shape = (
+            num_timeblocks,
+            (total_num_tiles * (total_num_tiles - 1)) / 2,
+        );
+        let tiles = read_stats(
+            "TILE_RESIDUALS",
+            "the shape of TILE_RESIDUALS",
+            &[tile_shape.0, tile_shape.1, tile_shape.2, 3],
+        )?;
+        let baselines = read_stats(
+            "BASELINE_RESIDUALS",
+            "the shape of BASELINE_RESIDUALS",
+            &[baseline_shape.0, baseline_shape.1, 3],
+        )?;
+        match (tiles, baselines) {
+            (Some(tiles), Some(baselines)) => Some(ResidualStats {
+                tiles: Array3::from_shape_vec(tile_shape, tiles).unwrap(),
+                baselines: Array2::from_shape_vec(baseline_shape, baselines).unwrap(),
+            }),
+            _ => None,
+        }
+    };
+
+    // If available, open the "XY_PHASE" HDU and get the XY-phase correction
+    // out.
+    let xy_phase = {
+        match fptr.hdu("XY_PHASE") {
+            Ok(hdu) => {
+                let n_rows: usize = fits_get_required_key(&mut fptr, &hdu, "NAXIS2")?;
+                let n_chanblocks: usize = fits_get_required_key(&mut fptr, &hdu, "NAXIS1")?;
+                if n_rows != 2 {
+                    return Err(SolutionsReadError::BadShape {
+                        thing: "the number of rows in XY_PHASE",
+                        expected: 2,
+                        actual: n_rows,
+                    });
+                }
+                if n_chanblocks != total_num_chanblocks {
+                    return Err(SolutionsReadError::BadShape {
+                        thing: "the number of chanblocks in XY_PHASE",
+                        expected: total_num_chanblocks,
+                        actual: n_chanblocks,
+                    });
+                }
+                let delay: f64 = fits_get_required_key(&mut fptr, &hdu, "XYDELAY")?;
+
+                let phases_vec: Vec<f64> = fits_get_image(&mut fptr, &hdu)?;
+                let phases = Array2::from_shape_vec((2, n_chanblocks), phases_vec).unwrap();
+                Some(XyPhase {
+                    phases: phases.row(0).to_owned(),
+                    measured_phases: phases.row(1).to_owned(),
+                    delay,
+                })
+            }
+            Err(e) => match e {
+                // Status code 301 means "unavailable".
+                fitsio::errors::Error::Fits(fitsio::errors::FitsError { status: 301, .. }) => None,
+                _ => return Err(SolutionsReadError::Fitsio(e)),
+            },
+        }
+    };
+
     Ok(CalibrationSolutions {
         di_jones,
         flagged_tiles,
@@ -530,6 +659,8 @@ pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadErr
         max_iterations,
         stop_threshold,
         min_threshold,
+        solve_mode,
+        solver,
         raw_data_corrections,
         tile_names,
         dipole_gains,
@@ -537,6 +668,9 @@ pub(crate) fn read(file: &Path) -> Result<CalibrationSolutions, SolutionsReadErr
         beam_file: beam_file.map(PathBuf::from),
         calibration_results,
         baseline_weights,
+        residual_stats,
+        xy_phase,
+        phase_reference,
         uvw_min,
         uvw_max,
         freq_centroid,
@@ -564,6 +698,8 @@ pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), Solu
         max_iterations,
         stop_threshold,
         min_threshold,
+        solve_mode,
+        solver,
         raw_data_corrections,
         tile_names,
         dipole_gains,
@@ -571,6 +707,9 @@ pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), Solu
         beam_file,
         calibration_results,
         baseline_weights,
+        residual_stats,
+        xy_phase,
+        phase_reference,
         uvw_min,
         uvw_max,
         freq_centroid,
@@ -661,6 +800,16 @@ pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), Solu
     if let Some(min_threshold) = min_threshold {
         hdu.write_key(&mut fptr, "M_THRESH", *min_threshold)?;
     }
+    if let Some(solve_mode) = solve_mode {
+        hdu.write_key(&mut fptr, "SOLVMODE", solve_mode.to_string())?;
+    }
+    if let Some(solver) = solver {
+        hdu.write_key(&mut fptr, "SOLVER", solver.to_string())?;
+    }
+    if let Some(PhaseReference { tile, cross_hands }) = phase_reference {
+        hdu.write_key(&mut fptr, "REFTILE", *tile as u32)?;
+        hdu.write_key(&mut fptr, "REFXHAND", if *cross_hands { "Y" } else { "N" })?;
+    }
     // UVW cutoffs can be infinite, and cfitsio doesn't know how to convert
     // these to strings...
     if let Some(uvw_min) = uvw_min {
@@ -993,5 +1142,54 @@ pub(crate) fn write(sols: &CalibrationSolutions, file: &Path) -> Result<(), Solu
         hdu.write_image(&mut fptr, baseline_weights)?;
     }
 
+    // Write residual statistics ("TILE_RESIDUALS" and "BASELINE_RESIDUALS"
+    // HDUs). Each statistic is written as three floats; the RMS, chi-squared
+    // and number of visibilities.
+    if let Some(ResidualStats { tiles, baselines }) = residual_stats {
+        let stats_to_floats = |stats: &mut dyn Iterator<Item = &ResidualStat>| -> Vec<f64> {
+            stats
+                .flat_map(|s| [s.rms, s.chi_squared, s.num_vis as f64])
+                .collect()
+        };
+
+        let (num_timeblocks, total_num_tiles, total_num_chanblocks) = tiles.dim();
+        let image_description = ImageDescription {
+            data_type: ImageType::Double,
+            dimensions: &[num_timeblocks, total_num_tiles, total_num_chanblocks, 3],
+        };
+        let hdu = fptr.create_image("TILE_RESIDUALS", &image_description)?;
+        hdu.write_image(&mut fptr, &stats_to_floats(&mut tiles.iter()))?;
+
+        let (num_timeblocks, total_num_baselines) = baselines.dim();
+        let image_description = ImageDescription {
+            data_type: ImageType::Double,
+            dimensions: &[num_timeblocks, total_num_baselines, 3],
+        };
+        let hdu = fptr.create_image("BASELINE_RESIDUALS", &image_description)?;
+        hdu.write_image(&mut fptr, &stats_to_floats(&mut baselines.iter()))?;
+    }
+
+    // Write the XY-phase correction ("XY_PHASE" HDU). The first row is the
+    // fitted XY-phases, the second the measured XY-phases.
+    if let Some(XyPhase {
+        phases,
+        measured_phases,
+        delay,
+    }) = xy_phase
+    {
+        let image_description = ImageDescription {
+            data_type: ImageType::Double,
+            dimensions: &[2, phases.len()],
+        };
+        let hdu = fptr.create_image("XY_PHASE", &image_description)?;
+        let fits_image_data: Vec<f64> = phases
+            .iter()
+            .chain(measured_phases.iter())
+            .copied()
+            .collect();
+        hdu.write_image(&mut fptr, &fits_image_data)?;
+        hdu.write_key(&mut fptr, "XYDELAY", *delay)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,438 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to fit ionospheric models to calibration solutions.
+//!
+//! Free electrons in the ionosphere delay a signal by an amount proportional to
+//! the total electron content (TEC) along its path, which shows up as a phase
+//! proportional to wavelength (i.e. inversely proportional to frequency). The
+//! TEC above each tile is a little different, so the phases of each tile's
+//! solutions (relative to a reference tile) contain a "differential TEC"
+//! (dTEC) term. For each timeblock and tile, a phase offset for each of the X
+//! and Y polarisations and a dTEC common to both are fitted across chanblocks.
+//! A plane (i.e. a phase gradient across the array) is then fitted to the dTECs
+//! of all tiles.
+//!
+//! The phases are those of the solutions as they're stored, i.e. the
+//! data-correcting solutions, which are the inverse of the gains. The phase of
+//! a tile is modelled as a phase offset minus [`TEC_PHASE_CONSTANT`] × dTEC /
+//! frequency, so the sign of a fitted dTEC is opposite to that of a dTEC fitted
+//! to the gains.
+
+#[cfg(test)]
+mod tests;
+
+use std::f64::consts::{FRAC_PI_2, TAU};
+
+use marlu::{c64, constants::VEL_C, Jones, ENH};
+use ndarray::prelude::*;
+use rayon::prelude::*;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::CalibrationSolutions;
+use crate::math::least_squares;
+
+/// The phase \[radians\] caused by one TEC unit (10^16 electrons per square
+/// metre) at a frequency of 1 Hz. The phase at another frequency is this
+/// divided by the frequency \[Hz\].
+pub(crate) const TEC_PHASE_CONSTANT: f64 = TAU * 40.308193e16 / VEL_C;
+
+/// The default largest dTEC (in either direction) searched for \[TECU\].
+pub(crate) const DEFAULT_MAX_DTEC: f64 = 1.0;
+
+/// The largest number of dTECs (in either direction) searched for in a tile.
+const MAX_NUM_DTEC_STEPS: usize = 1000;
+
+/// The minimum number of good chanblocks needed to fit a tile.
+const MIN_NUM_CHANBLOCKS: usize = 3;
+
+/// The minimum number of fitted tiles needed to fit a plane.
+const MIN_NUM_PLANE_TILES: usize = 4;
+
+/// The number of linear refinements made to a tile's fit after the initial
+/// search.
+const NUM_REFINEMENTS: usize = 2;
+
+#[derive(Error, Debug)]
+pub(crate) enum IonosphereFitError {
+    #[error("The calibration solutions don't have chanblock frequencies; these are needed to fit the ionosphere")]
+    NoFreqs,
+
+    #[error("The reference tile {0} doesn't exist or has no solutions")]
+    BadRefTile(usize),
+
+    #[error("None of the tiles have solutions")]
+    NoGoodTiles,
+
+    #[error("There are {positions} tile positions, but the solutions have {tiles} tiles")]
+    TilePositionsMismatch { positions: usize, tiles: usize },
+
+    #[error("Searching for dTECs up to {max_dtec} TECU needs {num_steps} steps in each direction, but the limit is {MAX_NUM_DTEC_STEPS}; use a smaller maximum dTEC")]
+    TooManyDtecSteps { max_dtec: f64, num_steps: usize },
+}
+
+/// Ionospheric fits to calibration solutions.
+#[derive(Debug, Serialize)]
+pub(crate) struct IonosphereFit {
+    /// The index of the tile that the phases and dTECs are relative to.
+    pub(crate) ref_tile: usize,
+
+    /// The names of the tiles, if available.
+    pub(crate) tile_names: Option<Vec<String>>,
+
+    /// The frequencies of the chanblocks \[Hz\].
+    pub(crate) chanblock_freqs: Vec<f64>,
+
+    /// The fits of each timeblock.
+    pub(crate) timeblocks: Vec<TimeblockIonosphereFit>,
+}
+
+/// Ionospheric fits to a single timeblock of calibration solutions. Values
+/// that couldn't be fitted (e.g. because a tile is flagged) are NaN.
+#[derive(Debug, Serialize)]
+pub(crate) struct TimeblockIonosphereFit {
+    /// The dTEC of each tile, relative to the reference tile \[TECU\].
+    pub(crate) dtecs: Vec<f64>,
+
+    /// The X and Y phase offsets of each tile \[radians\].
+    pub(crate) phase_offsets: Vec<[f64; 2]>,
+
+    /// The X and Y phases of each tile and chanblock that aren't described by
+    /// the phase offsets and dTECs \[radians\]. The first dimension is tile,
+    /// the second is chanblock.
+    pub(crate) residual_phases: Vec<Vec<[f64; 2]>>,
+
+    /// The plane fitted to the dTECs. This is only available if tile positions
+    /// were supplied and enough tiles were fitted.
+    pub(crate) plane: Option<TecPlane>,
+}
+
+/// A plane fitted to the dTECs of tiles, i.e. a dTEC gradient across the
+/// array.
+#[derive(Debug, Serialize)]
+pub(crate) struct TecPlane {
+    /// The dTEC at the array position \[TECU\].
+    pub(crate) offset: f64,
+
+    /// The dTEC gradient towards the east \[TECU/km\].
+    pub(crate) east_gradient: f64,
+
+    /// The dTEC gradient towards the north \[TECU/km\].
+    pub(crate) north_gradient: f64,
+
+    /// The dTEC of each tile that isn't described by the plane \[TECU\].
+    pub(crate) residual_dtecs: Vec<f64>,
+}
+
+/// Fit dTECs and phase offsets to each tile of calibration solutions and, if
+/// `tile_positions` are given, a plane to the dTECs of each timeblock.
+/// `tile_positions` must have one position per tile in the solutions. If
+/// `ref_tile` isn't given, the last tile with solutions is used. dTECs are
+/// searched for between `-max_dtec` and `max_dtec`.
+pub(crate) fn fit_ionosphere(
+    sols: &CalibrationSolutions,
+    tile_positions: Option<&[ENH]>,
+    ref_tile: Option<usize>,
+    max_dtec: f64,
+) -> Result<IonosphereFit, IonosphereFitError> {
+    let (_, total_num_tiles, num_chanblocks) = sols.di_jones.dim();
+    let freqs = match sols.chanblock_freqs.as_ref() {
+        Some(f) if f.len() == num_chanblocks => f.to_vec(),
+        _ => return Err(IonosphereFitError::NoFreqs),
+    };
+    if let Some(positions) = tile_positions {
+        if positions.len() != total_num_tiles {
+            return Err(IonosphereFitError::TilePositionsMismatch {
+                positions: positions.len(),
+                tiles: total_num_tiles,
+            });
+        }
+    }
+
+    // Tiles with any solutions in the first timeblock can be the reference.
+    let has_sols = |i_tile: usize| {
+        sols.di_jones
+            .slice(s![0, i_tile, ..])
+            .iter()
+            .any(|j| !j.any_nan())
+    };
+    let ref_tile = match ref_tile {
+        Some(r) if r < total_num_tiles && has_sols(r) => r,
+        Some(r) => return Err(IonosphereFitError::BadRefTile(r)),
+        None => (0..total_num_tiles)
+            .rev()
+            .find(|&i_tile| has_sols(i_tile))
+            .ok_or(IonosphereFitError::NoGoodTiles)?,
+    };
+
+    let mut usable = sols.get_usable_chanblocks();
+    for (mut usable, &freq) in usable.axis_iter_mut(Axis(1)).zip(freqs.iter()) {
+        if !freq.is_finite() || freq <= 0.0 {
+            usable.fill(false);
+        }
+    }
+
+    // The search for dTECs is finest for tiles using all of the frequencies;
+    // make sure that it isn't too big.
+    let inv_freqs = freqs
+        .iter()
+        .filter(|&&f| f.is_finite() && f > 0.0)
+        .map(|f| 1.0 / f);
+    let phase_span = TEC_PHASE_CONSTANT
+        * (inv_freqs.clone().fold(f64::NEG_INFINITY, f64::max)
+            - inv_freqs.fold(f64::INFINITY, f64::min));
+    if phase_span > 0.0 {
+        let (_, num_steps) = get_dtec_steps(max_dtec, phase_span);
+        if num_steps > MAX_NUM_DTEC_STEPS {
+            return Err(IonosphereFitError::TooManyDtecSteps {
+                max_dtec,
+                num_steps,
+            });
+        }
+    }
+
+    let timeblocks = sols
+        .di_jones
+        .outer_iter()
+        .zip(usable.outer_iter())
+        .map(|(di_jones_tc, usable)| {
+            let ref_jones = di_jones_tc.row(ref_tile);
+            let tile_fits: Vec<Option<TileFit>> = di_jones_tc
+                .outer_iter()
+                .into_par_iter()
+                .map(|di_jones| TileFit::fit(di_jones, ref_jones, usable, &freqs, max_dtec))
+                .collect();
+
+            let dtecs: Vec<f64> = tile_fits
+                .iter()
+                .map(|f| f.as_ref().map(|f| f.dtec).unwrap_or(f64::NAN))
+                .collect();
+            let phase_offsets = tile_fits
+                .iter()
+                .map(|f| f.as_ref().map(|f| f.phase_offsets).unwrap_or([f64::NAN; 2]))
+                .collect();
+            let residual_phases = tile_fits
+                .iter()
+                .zip(di_jones_tc.outer_iter())
+                .map(|(f, di_jones)| match f {
+                    Some(f) => f.residual_phases(di_jones, ref_jones, &freqs),
+                    None => vec![[f64::NAN; 2]; num_chanblocks],
+                })
+                .collect();
+            let plane = tile_positions.and_then(|p| TecPlane::fit(&dtecs, p));
+
+            TimeblockIonosphereFit {
+                dtecs,
+                phase_offsets,
+                residual_phases,
+                plane,
+            }
+        })
+        .collect();
+
+    Ok(IonosphereFit {
+        ref_tile,
+        tile_names: sols.tile_names.as_ref().map(|n| n.to_vec()),
+        chanblock_freqs: freqs,
+        timeblocks,
+    })
+}
+
+/// Get the step size \[TECU\] and the number of steps in each direction needed
+/// to search for dTECs up to `max_dtec`, given the largest phase change that a
+/// dTEC of 1 TECU makes across the band \[radians\]. The steps change the phase
+/// across the band by a quarter of a turn, which avoids having to unwrap
+/// phases.
+fn get_dtec_steps(max_dtec: f64, phase_span: f64) -> (f64, usize) {
+    let step = (FRAC_PI_2 / phase_span).min(max_dtec);
+    (step, (max_dtec / step).ceil() as usize)
+}
+
+/// The phases of the X and Y gains of a tile relative to a reference tile, as
+/// unit-amplitude complex numbers. `None` is returned if either tile's gains
+/// aren't usable.
+fn get_relative_phasors(jones: Jones<f64>, ref_jones: Jones<f64>) -> Option<[c64; 2]> {
+    let mut phasors = [c64::default(); 2];
+    for (phasor, i_pol) in phasors.iter_mut().zip([0, 3]) {
+        let z = jones[i_pol] * ref_jones[i_pol].conj();
+        if !z.is_finite() || z.norm_sqr() == 0.0 {
+            return None;
+        }
+        *phasor = z / z.norm();
+    }
+    Some(phasors)
+}
+
+/// The ionospheric fit of a single tile.
+#[derive(Debug)]
+struct TileFit {
+    /// \[TECU\]
+    dtec: f64,
+
+    /// The X and Y phase offsets \[radians\].
+    phase_offsets: [f64; 2],
+}
+
+impl TileFit {
+    /// Fit a dTEC and phase offsets to the phases of a tile's solutions
+    /// relative to the reference tile's. `None` is returned if there are too
+    /// few usable chanblocks.
+    fn fit(
+        di_jones: ArrayView1<Jones<f64>>,
+        ref_jones: ArrayView1<Jones<f64>>,
+        usable: ArrayView1<bool>,
+        freqs: &[f64],
+        max_dtec: f64,
+    ) -> Option<TileFit> {
+        // (inverse frequency, relative phasors) of each good chanblock.
+        let good: Vec<(f64, [c64; 2])> = di_jones
+            .iter()
+            .zip(ref_jones.iter())
+            .zip(usable.iter())
+            .zip(freqs)
+            .filter(|&(((_, _), &u), _)| u)
+            .filter_map(|(((&j, &r), _), &f)| get_relative_phasors(j, r).map(|z| (1.0 / f, z)))
+            .collect();
+        if good.len() < MIN_NUM_CHANBLOCKS {
+            return None;
+        }
+
+        let (min_inv_freq, max_inv_freq) = good
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(f, _)| {
+                (min.min(f), max.max(f))
+            });
+        let phase_span = TEC_PHASE_CONSTANT * (max_inv_freq - min_inv_freq);
+        if phase_span <= 0.0 {
+            return None;
+        }
+
+        // The phase offsets that best fit a dTEC, and how well they fit.
+        let get_offsets = |dtec: f64| -> ([c64; 2], f64) {
+            let mut sums = [c64::default(); 2];
+            for (inv_freq, z) in &good {
+                let rotation = c64::cis(TEC_PHASE_CONSTANT * dtec * inv_freq);
+                sums[0] += z[0] * rotation;
+                sums[1] += z[1] * rotation;
+            }
+            (sums, sums[0].norm() + sums[1].norm())
+        };
+
+        // Search for the dTEC that best fits.
+        let (step, num_steps) = get_dtec_steps(max_dtec, phase_span);
+        let num_steps = num_steps as i64;
+        let mut dtec = 0.0;
+        let mut best_coherence = f64::NEG_INFINITY;
+        for i_step in -num_steps..=num_steps {
+            let trial = i_step as f64 * step;
+            let (_, coherence) = get_offsets(trial);
+            if coherence > best_coherence {
+                (dtec, best_coherence) = (trial, coherence);
+            }
+        }
+        let (sums, _) = get_offsets(dtec);
+        let mut phase_offsets = sums.map(|s| s.arg());
+
+        // Refine the fit with linear fits to the residual phases. Both
+        // polarisations share the dTEC.
+        let num_rows = 2 * good.len();
+        let design = Array2::from_shape_fn((num_rows, 3), |(i_row, i_col)| {
+            let (inv_freq, _) = good[i_row / 2];
+            match i_col {
+                // The phase offset of this row's polarisation.
+                0 | 1 if i_col == i_row % 2 => 1.0,
+                0 | 1 => 0.0,
+                _ => -TEC_PHASE_CONSTANT * inv_freq,
+            }
+        });
+        let weights = Array1::ones(num_rows);
+        for _ in 0..NUM_REFINEMENTS {
+            let residuals = Array1::from_shape_fn(num_rows, |i_row| {
+                let (inv_freq, z) = good[i_row / 2];
+                let i_pol = i_row % 2;
+                (z[i_pol] * c64::cis(TEC_PHASE_CONSTANT * dtec * inv_freq - phase_offsets[i_pol]))
+                    .arg()
+            });
+            match least_squares(design.view(), residuals.view(), weights.view()) {
+                Some(c) => {
+                    phase_offsets[0] += c[0];
+                    phase_offsets[1] += c[1];
+                    dtec += c[2];
+                }
+                None => break,
+            }
+        }
+
+        Some(TileFit {
+            dtec,
+            phase_offsets: phase_offsets.map(|p| c64::cis(p).arg()),
+        })
+    }
+
+    /// Get the phases of a tile's solutions (relative to the reference tile)
+    /// that aren't described by this fit. Chanblocks without solutions are NaN.
+    fn residual_phases(
+        &self,
+        di_jones: ArrayView1<Jones<f64>>,
+        ref_jones: ArrayView1<Jones<f64>>,
+        freqs: &[f64],
+    ) -> Vec<[f64; 2]> {
+        di_jones
+            .iter()
+            .zip(ref_jones.iter())
+            .zip(freqs)
+            .map(|((&j, &r), &freq)| match get_relative_phasors(j, r) {
+                Some(z) if freq.is_finite() && freq > 0.0 => {
+                    let tec_phase = TEC_PHASE_CONSTANT * self.dtec / freq;
+                    [0, 1].map(|i_pol| {
+                        (z[i_pol] * c64::cis(tec_phase - self.phase_offsets[i_pol])).arg()
+                    })
+                }
+                _ => [f64::NAN; 2],
+            })
+            .collect()
+    }
+}
+
+impl TecPlane {
+    /// Fit a plane to the dTECs of tiles. Tiles with NaN dTECs are ignored.
+    /// `None` is returned if there are too few dTECs to fit.
+    fn fit(dtecs: &[f64], tile_positions: &[ENH]) -> Option<TecPlane> {
+        let good: Vec<(f64, &ENH)> = dtecs
+            .iter()
+            .zip(tile_positions)
+            .filter(|(d, _)| d.is_finite())
+            .map(|(&d, p)| (d, p))
+            .collect();
+        if good.len() < MIN_NUM_PLANE_TILES {
+            return None;
+        }
+
+        // Positions are in km so that the gradients are in TECU/km.
+        let design = Array2::from_shape_fn((good.len(), 3), |(i_row, i_col)| {
+            let (_, p) = good[i_row];
+            match i_col {
+                0 => 1.0,
+                1 => p.e / 1000.0,
+                _ => p.n / 1000.0,
+            }
+        });
+        let b = Array1::from_iter(good.iter().map(|(d, _)| *d));
+        let weights = Array1::ones(good.len());
+        let c = least_squares(design.view(), b.view(), weights.view())?;
+
+        let residual_dtecs = dtecs
+            .iter()
+            .zip(tile_positions)
+            .map(|(d, p)| d - (c[0] + c[1] * p.e / 1000.0 + c[2] * p.n / 1000.0))
+            .collect();
+        Some(TecPlane {
+            offset: c[0],
+            east_gradient: c[1],
+            north_gradient: c[2],
+            residual_dtecs,
+        })
+    }
+}
@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones, ENH};
+use ndarray::prelude::*;
+use vec1::Vec1;
+
+use super::*;
+
+const NUM_TILES: usize = 8;
+const NUM_CHANBLOCKS: usize = 48;
+
+fn get_freqs() -> Vec<f64> {
+    (0..NUM_CHANBLOCKS)
+        .map(|i| 167e6 + i as f64 * 640e3)
+        .collect()
+}
+
+fn get_positions() -> Vec<ENH> {
+    (0..NUM_TILES)
+        .map(|i| {
+            let x = i as f64;
+            ENH {
+                e: 300.0 * (1.1 * x).cos(),
+                n: 250.0 * (0.7 * x).sin(),
+                h: 0.0,
+            }
+        })
+        .collect()
+}
+
+/// The "true" dTEC of each tile, which is a plane across the array.
+fn get_dtec(i_tile: usize) -> f64 {
+    let p = get_positions()[i_tile];
+    0.02 + 0.05 * p.e / 1000.0 - 0.08 * p.n / 1000.0
+}
+
+fn get_sols() -> CalibrationSolutions {
+    let freqs = get_freqs();
+    let di_jones = Array3::from_shape_fn((1, NUM_TILES, NUM_CHANBLOCKS), |(_, i_tile, i_chan)| {
+        let x = i_tile as f64;
+        let tec_phase = -TEC_PHASE_CONSTANT * get_dtec(i_tile) / freqs[i_chan];
+        Jones::from([
+            c64::from_polar(1.0 + 0.01 * x, 0.4 * x.sin() + tec_phase),
+            c64::default(),
+            c64::default(),
+            c64::from_polar(1.0 - 0.01 * x, 0.6 * x.cos() + tec_phase),
+        ])
+    });
+    CalibrationSolutions {
+        di_jones,
+        chanblock_freqs: Some(Vec1::try_from_vec(freqs).unwrap()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_fit_ionosphere_recovers_dtecs() {
+    let mut sols = get_sols();
+    // Flag a tile and a chanblock.
+    sols.di_jones
+        .slice_mut(s![.., 3, ..])
+        .fill(Jones::from([c64::new(f64::NAN, f64::NAN); 4]));
+    sols.flagged_tiles.push(3);
+    sols.di_jones
+        .slice_mut(s![.., .., 10])
+        .fill(Jones::from([c64::new(f64::NAN, f64::NAN); 4]));
+    sols.flagged_chanblocks.push(10);
+
+    let positions = get_positions();
+    let fit = fit_ionosphere(&sols, Some(&positions), None, DEFAULT_MAX_DTEC).unwrap();
+    // The last tile is the reference.
+    let ref_tile = NUM_TILES - 1;
+    assert_eq!(fit.ref_tile, ref_tile);
+    assert_eq!(fit.timeblocks.len(), 1);
+    let fit = &fit.timeblocks[0];
+
+    for (i_tile, (&dtec, offsets)) in fit.dtecs.iter().zip(fit.phase_offsets.iter()).enumerate() {
+        if i_tile == 3 {
+            assert!(dtec.is_nan());
+            assert!(offsets.iter().all(|o| o.is_nan()));
+            continue;
+        }
+        assert_abs_diff_eq!(dtec, get_dtec(i_tile) - get_dtec(ref_tile), epsilon = 1e-9);
+        let x = i_tile as f64;
+        let r = ref_tile as f64;
+        let expected = [0.4 * (x.sin() - r.sin()), 0.6 * (x.cos() - r.cos())];
+        for (o, e) in offsets.iter().zip(expected) {
+            // Compare phasors to avoid phase wrapping.
+            assert_abs_diff_eq!((c64::cis(*o) - c64::cis(e)).norm(), 0.0, epsilon = 1e-6);
+        }
+    }
+
+    // Everything is described by the model.
+    for (i_tile, residuals) in fit.residual_phases.iter().enumerate() {
+        assert_eq!(residuals.len(), NUM_CHANBLOCKS);
+        for (i_chan, r) in residuals.iter().enumerate() {
+            if i_tile == 3 || i_chan == 10 {
+                assert!(r.iter().all(|r| r.is_nan()));
+            } else {
+                assert_abs_diff_eq!(r[0], 0.0, epsilon = 1e-6);
+                assert_abs_diff_eq!(r[1], 0.0, epsilon = 1e-6);
+            }
+        }
+    }
+
+    // The plane describes the dTECs. The reference tile's dTEC is subtracted
+    // from all tiles, so only the offset of the plane changes.
+    let plane = fit.plane.as_ref().unwrap();
+    assert_abs_diff_eq!(plane.east_gradient, 0.05, epsilon = 1e-6);
+    assert_abs_diff_eq!(plane.north_gradient, -0.08, epsilon = 1e-6);
+    assert_abs_diff_eq!(plane.offset, 0.02 - get_dtec(ref_tile), epsilon = 1e-6);
+    for (i_tile, r) in plane.residual_dtecs.iter().enumerate() {
+        if i_tile == 3 {
+            assert!(r.is_nan());
+        } else {
+            assert_abs_diff_eq!(*r, 0.0, epsilon = 1e-6);
+        }
+    }
+}
+
+#[test]
+fn test_fit_ionosphere_ref_tile() {
+    let sols = get_sols();
+    let fit = fit_ionosphere(&sols, None, Some(2), DEFAULT_MAX_DTEC).unwrap();
+    assert_eq!(fit.ref_tile, 2);
+    let fit = &fit.timeblocks[0];
+    assert!(fit.plane.is_none());
+    assert_abs_diff_eq!(fit.dtecs[2], 0.0, epsilon = 1e-9);
+    assert_abs_diff_eq!(fit.dtecs[5], get_dtec(5) - get_dtec(2), epsilon = 1e-9);
+}
+
+#[test]
+fn test_fit_ionosphere_errors() {
+    let mut sols = get_sols();
+    let result = fit_ionosphere(&sols, None, Some(NUM_TILES), DEFAULT_MAX_DTEC);
+    assert!(matches!(result, Err(IonosphereFitError::BadRefTile(_))));
+
+    let positions = get_positions();
+    let result = fit_ionosphere(&sols, Some(&positions[1..]), None, DEFAULT_MAX_DTEC);
+    assert!(matches!(
+        result,
+        Err(IonosphereFitError::TilePositionsMismatch { .. })
+    ));
+
+    // The search for dTECs would be too big.
+    let result = fit_ionosphere(&sols, None, None, 1e6);
+    assert!(matches!(
+        result,
+        Err(IonosphereFitError::TooManyDtecSteps { .. })
+    ));
+
+    sols.chanblock_freqs = None;
+    let result = fit_ionosphere(&sols, None, None, DEFAULT_MAX_DTEC);
+    assert!(matches!(result, Err(IonosphereFitError::NoFreqs)));
+}
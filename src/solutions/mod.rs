@@ -8,9 +8,16 @@
 //! <https://mwatelescope.github.io/mwa_hyperdrive/defs/cal_sols.html>
 
 pub(crate) mod ao;
+pub(crate) mod casa;
+pub(crate) mod combine;
+pub(crate) mod diff;
 mod error;
 pub(crate) mod hyperdrive;
-mod rts;
+pub(crate) mod ionosphere;
+pub(crate) mod outliers;
+pub(crate) mod reference;
+pub(crate) mod rts;
+pub(crate) mod smooth;
 #[cfg(test)]
 mod tests;
 
@@ -24,16 +31,24 @@ use std::{
 use hifitime::Epoch;
 use itertools::Itertools;
 use log::debug;
-use marlu::Jones;
+use marlu::{c64, Jones};
 use ndarray::prelude::*;
 use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
 use vec1::Vec1;
 
-use crate::{io::read::RawDataCorrections, HyperdriveError};
+use crate::{
+    di_calibrate::{SolveMode, Solver},
+    io::read::RawDataCorrections,
+    HyperdriveError,
+};
 
 lazy_static::lazy_static! {
     pub(crate) static ref CAL_SOLUTION_EXTENSIONS: String = CalSolutionType::iter().join(", ");
+
+    pub(crate) static ref TIME_INTERPOLATIONS_COMMA_SEPARATED: String = TimeInterpolation::iter().join(", ");
+
+    pub(crate) static ref FREQ_INTERPOLATIONS_COMMA_SEPARATED: String = FreqInterpolation::iter().join(", ");
 }
 
 #[derive(Debug, Display, EnumIter, EnumString)]
@@ -45,6 +60,190 @@ pub(crate) enum CalSolutionType {
     /// The "André Offringa" format used by mwa-reduce.
     #[strum(serialize = "bin")]
     Bin,
+
+    /// RTS DI_JonesMatrices and BandpassCalibration files in a directory.
+    #[strum(serialize = "rts")]
+    Rts,
+
+    /// A CASA bandpass calibration table (write only).
+    #[strum(serialize = "bcal")]
+    Casa,
+}
+
+impl CalSolutionType {
+    /// Get the type of calibration solutions to be written to `file` from its
+    /// extension. Paths with an "rts" extension and existing directories (with
+    /// any other extension) are for RTS solutions.
+    pub(crate) fn from_path(file: &Path) -> Option<CalSolutionType> {
+        file.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|ext| CalSolutionType::from_str(ext).ok())
+            .or_else(|| file.is_dir().then_some(CalSolutionType::Rts))
+    }
+}
+
+/// How calibration solutions with multiple timeblocks are used for a timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub(crate) enum TimeInterpolation {
+    /// Use the timeblock best corresponding to the timestamp.
+    #[default]
+    #[strum(serialize = "nearest")]
+    Nearest,
+
+    /// Linearly interpolate between the two timeblocks (using their average
+    /// timestamps) surrounding the timestamp.
+    #[strum(serialize = "linear")]
+    Linear,
+}
+
+/// How calibration solutions are used for a frequency that doesn't exactly
+/// match a solution chanblock's frequency (e.g. when applying solutions made at
+/// 80 kHz to 40 kHz data).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumIter, EnumString)]
+pub(crate) enum FreqInterpolation {
+    /// Use the chanblock with the nearest frequency.
+    #[default]
+    #[strum(serialize = "nearest")]
+    Nearest,
+
+    /// Linearly interpolate between the two chanblocks surrounding the
+    /// frequency.
+    #[strum(serialize = "linear")]
+    Linear,
+}
+
+/// For a single frequency, the solution chanblocks to be used. The solutions
+/// of the `lower` and `upper` chanblocks are combined with
+/// [`interpolate_jones`] using `weight`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ChanblockInterp {
+    pub(crate) lower: usize,
+    pub(crate) upper: usize,
+    pub(crate) weight: f64,
+}
+
+/// Statistics on the residuals between calibrated data and the model (i.e.
+/// `D - J1 M J2^H`) for some collection of visibilities. The size of each
+/// residual is its Frobenius norm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResidualStat {
+    /// The root-mean-square of the residuals \[Jy\]. This is NaN if there are
+    /// no unflagged visibilities.
+    pub rms: f64,
+
+    /// The sum of the squared residuals, each weighted by its visibility
+    /// weight. This is NaN if there are no unflagged visibilities.
+    pub chi_squared: f64,
+
+    /// The number of unflagged visibilities.
+    pub num_vis: usize,
+}
+
+impl Default for ResidualStat {
+    fn default() -> Self {
+        ResidualStat {
+            rms: f64::NAN,
+            chi_squared: f64::NAN,
+            num_vis: 0,
+        }
+    }
+}
+
+/// A correction for the phase between the X and Y polarisations ("XY-phase")
+/// that is common to all tiles, which can't be determined by DI calibration
+/// against an unpolarised sky model. When solutions are applied, the Y
+/// polarisation of each tile's solutions has the XY-phase removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XyPhase {
+    /// The XY-phase of each chanblock that is corrected for \[radians\]. These
+    /// are the fitted phases, i.e. a phase offset and a cross-hand delay.
+    /// Chanblocks without an XY-phase are NaN.
+    pub phases: Array1<f64>,
+
+    /// The XY-phase measured from the data of each chanblock \[radians\].
+    /// Chanblocks that couldn't be measured are NaN.
+    pub measured_phases: Array1<f64>,
+
+    /// The cross-hand delay \[seconds\].
+    pub delay: f64,
+}
+
+impl XyPhase {
+    /// Include the XY-phase corrections in solutions. The first dimension of
+    /// `di_jones` is tile, the second is chanblock.
+    pub(crate) fn apply(&self, mut di_jones: ArrayViewMut2<Jones<f64>>) {
+        for mut di_jones in di_jones.outer_iter_mut() {
+            for (j, &phase) in di_jones.iter_mut().zip(self.phases.iter()) {
+                if phase.is_finite() {
+                    let correction = Jones::from([
+                        c64::new(1.0, 0.0),
+                        c64::default(),
+                        c64::default(),
+                        c64::cis(-phase),
+                    ]);
+                    *j = correction * *j;
+                }
+            }
+        }
+    }
+}
+
+/// The tile that the phases of calibration solutions are referenced to (see
+/// [`reference::reference_phases`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhaseReference {
+    /// The index of the reference tile.
+    pub tile: usize,
+
+    /// Are the phases of the reference tile's cross-hand (XY and YX) terms
+    /// also zero?
+    pub cross_hands: bool,
+}
+
+/// Statistics on the residuals left in the data after calibration. Flagged
+/// tiles, chanblocks and baselines have default [`ResidualStat`]s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResidualStats {
+    /// Statistics for each tile. This has dimensions of (num_timeblocks,
+    /// total_num_tiles, total_num_chanblocks). A tile's statistics use the
+    /// visibilities of all baselines including that tile.
+    pub tiles: Array3<ResidualStat>,
+
+    /// Statistics for each baseline over all chanblocks. This has dimensions of
+    /// (num_timeblocks, total_num_baselines).
+    pub baselines: Array2<ResidualStat>,
+}
+
+impl ResidualStat {
+    /// Combine these statistics with those of other visibilities, as if the
+    /// statistics were determined from all of the visibilities together.
+    pub(crate) fn combine(self, other: ResidualStat) -> ResidualStat {
+        match (self.num_vis, other.num_vis) {
+            (_, 0) => self,
+            (0, _) => other,
+            (n1, n2) => {
+                let num_vis = n1 + n2;
+                let sum_sq = self.rms.powi(2) * n1 as f64 + other.rms.powi(2) * n2 as f64;
+                ResidualStat {
+                    rms: (sum_sq / num_vis as f64).sqrt(),
+                    chi_squared: self.chi_squared + other.chi_squared,
+                    num_vis,
+                }
+            }
+        }
+    }
+}
+
+impl ResidualStats {
+    /// Combine these statistics with those of other visibilities (e.g. those
+    /// of other chanblocks), as if the statistics were determined from all of
+    /// the visibilities together. Both must have the same dimensions.
+    pub(crate) fn combine(&mut self, other: &ResidualStats) {
+        self.tiles
+            .zip_mut_with(&other.tiles, |a, &b| *a = a.combine(b));
+        self.baselines
+            .zip_mut_with(&other.baselines, |a, &b| *a = a.combine(b));
+    }
 }
 
 #[derive(Default)]
@@ -57,9 +256,10 @@ pub struct CalibrationSolutions {
     /// calibration.
     pub di_jones: Array3<Jones<f64>>,
 
-    /// The indices of flagged tiles before calibration. Note that there may
-    /// appear to be more flagged tiles in the solutions; this might happen if
-    /// an unflagged has no power. The indices are zero indexed.
+    /// The indices of flagged tiles before calibration (and any flagged as
+    /// outliers after calibration). Note that there may appear to be more
+    /// flagged tiles in the solutions; this might happen if an unflagged has no
+    /// power. The indices are zero indexed.
     pub flagged_tiles: Vec<usize>,
 
     /// Which chanblocks are flagged? Zero indexed.
@@ -96,6 +296,13 @@ pub struct CalibrationSolutions {
     /// The minimum threshold used during calibration.
     pub min_threshold: Option<f64>,
 
+    /// How the solutions were constrained during calibration (e.g. phase
+    /// only).
+    pub solve_mode: Option<SolveMode>,
+
+    /// The iteration scheme used during calibration.
+    pub solver: Option<Solver>,
+
     /// The raw data corrections applied to the visibilities before calibration.
     pub raw_data_corrections: Option<RawDataCorrections>,
 
@@ -123,6 +330,15 @@ pub struct CalibrationSolutions {
     /// NaN values).
     pub baseline_weights: Option<Vec1<f64>>,
 
+    /// Statistics on the residuals left in the data after calibration.
+    pub residual_stats: Option<ResidualStats>,
+
+    /// The XY-phase correction found after calibration.
+    pub xy_phase: Option<XyPhase>,
+
+    /// The tile that the phases of these solutions are referenced to, if any.
+    pub phase_reference: Option<PhaseReference>,
+
     /// The minimum UVW cutoff used in calibration \[metres\].
     pub uvw_min: Option<f64>,
 
@@ -179,25 +395,187 @@ impl CalibrationSolutions {
     /// It is generally preferable to use [hyperdrive::write] for
     /// hyperdrive-style files, because that allows more metadata to be written.
     pub fn write_solutions_from_ext<P: AsRef<Path>>(&self, file: P) -> Result<(), HyperdriveError> {
-        Self::write_solutions_from_ext_inner(self, file.as_ref()).map_err(HyperdriveError::from)
+        Self::write_solutions_from_ext_inner(self, file.as_ref(), None)
+            .map_err(HyperdriveError::from)
+    }
+
+    /// Like [`CalibrationSolutions::write_solutions_from_ext`], but a metafits
+    /// file can be supplied, which is needed to write RTS solutions. RTS
+    /// calibration solution files are written into the target directory if it
+    /// has an "rts" extension or is an existing directory.
+    pub fn write_solutions_from_ext_with_metafits<P: AsRef<Path>, P2: AsRef<Path>>(
+        &self,
+        file: P,
+        metafits: Option<P2>,
+    ) -> Result<(), HyperdriveError> {
+        Self::write_solutions_from_ext_inner(
+            self,
+            file.as_ref(),
+            metafits.as_ref().map(|f| f.as_ref()),
+        )
+        .map_err(HyperdriveError::from)
     }
 
     pub(crate) fn write_solutions_from_ext_inner(
         sols: &CalibrationSolutions,
         file: &Path,
+        metafits: Option<&Path>,
     ) -> Result<(), SolutionsWriteError> {
-        let ext = file.extension().and_then(|e| e.to_str());
-        match ext.and_then(|s| CalSolutionType::from_str(s).ok()) {
+        match CalSolutionType::from_path(file) {
             Some(CalSolutionType::Fits) => hyperdrive::write(sols, file),
             Some(CalSolutionType::Bin) => ao::write(sols, file),
+            Some(CalSolutionType::Casa) => casa::write(sols, file, None),
+            Some(CalSolutionType::Rts) => {
+                let metafits = metafits.ok_or(SolutionsWriteError::RtsMetafitsRequired)?;
+                let context = mwalib::MetafitsContext::new(metafits, None)
+                    .map_err(rts::RtsWriteSolsError::from)?;
+                rts::write(sols, file, &context).map_err(SolutionsWriteError::from)
+            }
             None => Err(SolutionsWriteError::UnsupportedExt {
-                ext: ext.unwrap_or("<no extension>").to_string(),
+                ext: file
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or("<no extension>")
+                    .to_string(),
             }),
         }?;
 
         Ok(())
     }
 
+    /// Get the Jones matrices of the solutions with any XY-phase corrections
+    /// included. This is for formats that can't store XY-phase corrections
+    /// separately from the solutions.
+    pub(crate) fn get_di_jones_with_xy_phase(&self) -> CowArray<'_, Jones<f64>, Ix3> {
+        match self.xy_phase.as_ref() {
+            None => self.di_jones.view().into(),
+            Some(xy_phase) => {
+                let mut di_jones = self.di_jones.clone();
+                for di_jones in di_jones.outer_iter_mut() {
+                    xy_phase.apply(di_jones);
+                }
+                di_jones.into()
+            }
+        }
+    }
+
+    /// Given a timestamp, get solutions for it according to the
+    /// [`TimeInterpolation`]. Linear interpolation requires an average
+    /// timestamp for each timeblock; if these aren't available, or the
+    /// timestamp is outside of the average timestamps, this behaves like
+    /// [`TimeInterpolation::Nearest`] (see `get_timeblock`).
+    ///
+    /// When interpolating, if one of the two solutions is flagged (NaN), the
+    /// other is used.
+    pub(crate) fn get_interpolated_timeblock(
+        &self,
+        timestamp: Epoch,
+        timestamp_fraction: f64,
+        time_interpolation: TimeInterpolation,
+    ) -> CowArray<'_, Jones<f64>, Ix2> {
+        let num_timeblocks = self.di_jones.len_of(Axis(0));
+        let average_timestamps = match (time_interpolation, self.average_timestamps.as_ref()) {
+            (TimeInterpolation::Linear, Some(a))
+                if num_timeblocks > 1 && a.len() == num_timeblocks =>
+            {
+                a
+            }
+            _ => return self.get_timeblock(timestamp, timestamp_fraction).into(),
+        };
+
+        let i_timeblock = match average_timestamps
+            .windows(2)
+            .position(|w| timestamp >= w[0] && timestamp < w[1])
+        {
+            Some(i) => i,
+            None => return self.get_timeblock(timestamp, timestamp_fraction).into(),
+        };
+        let (start, end) = (
+            average_timestamps[i_timeblock],
+            average_timestamps[i_timeblock + 1],
+        );
+        let weight = (timestamp - start).to_seconds() / (end - start).to_seconds();
+        debug!(
+            "Interpolating solutions timeblocks {i_timeblock} and {} (weight {weight}) for timestamp {}",
+            i_timeblock + 1,
+            timestamp.to_gpst_seconds()
+        );
+
+        let mut sols = self.di_jones.slice(s![i_timeblock, .., ..]).to_owned();
+        sols.zip_mut_with(
+            &self.di_jones.slice(s![i_timeblock + 1, .., ..]),
+            |j1, &j2| *j1 = interpolate_jones(*j1, j2, weight),
+        );
+        sols.into()
+    }
+
+    /// For each of the given frequencies \[Hz\], get the solution chanblocks
+    /// that should be used according to the [`FreqInterpolation`]. `None` is
+    /// returned if the solutions have no chanblock frequencies. If a frequency
+    /// isn't covered by the solutions (i.e. it is more than half a chanblock
+    /// away from the nearest solution chanblock), it has no solution
+    /// chanblocks.
+    ///
+    /// The chanblock frequencies of the solutions are assumed to be ascendingly
+    /// sorted. If there's only one solution chanblock, it is used for all
+    /// frequencies.
+    pub(crate) fn get_chanblock_interps(
+        &self,
+        freqs: &[f64],
+        freq_interpolation: FreqInterpolation,
+    ) -> Option<Vec<Option<ChanblockInterp>>> {
+        let sol_freqs = self.chanblock_freqs.as_ref()?;
+        // The smallest separation between solution frequencies is the
+        // resolution. Allow a small amount of wiggle room for float errors.
+        let half_res = sol_freqs
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .fold(f64::INFINITY, f64::min)
+            * 0.5
+            * (1.0 + 1e-6);
+
+        let interps = freqs
+            .iter()
+            .map(|&freq| {
+                // The index of the first solution frequency above this freq.
+                let i_upper = sol_freqs.partition_point(|&f| f <= freq);
+                let nearest = match i_upper {
+                    0 => 0,
+                    i if i == sol_freqs.len() => i - 1,
+                    i if sol_freqs[i] - freq < freq - sol_freqs[i - 1] => i,
+                    i => i - 1,
+                };
+                if (sol_freqs[nearest] - freq).abs() > half_res {
+                    return None;
+                }
+
+                let interp = match freq_interpolation {
+                    // Only interpolate between contiguous chanblocks.
+                    FreqInterpolation::Linear
+                        if i_upper > 0
+                            && i_upper < sol_freqs.len()
+                            && sol_freqs[i_upper] - sol_freqs[i_upper - 1] <= 2.0 * half_res =>
+                    {
+                        let (lower, upper) = (sol_freqs[i_upper - 1], sol_freqs[i_upper]);
+                        ChanblockInterp {
+                            lower: i_upper - 1,
+                            upper: i_upper,
+                            weight: (freq - lower) / (upper - lower),
+                        }
+                    }
+
+                    _ => ChanblockInterp {
+                        lower: nearest,
+                        upper: nearest,
+                        weight: 0.0,
+                    },
+                };
+                Some(interp)
+            })
+            .collect();
+        Some(interps)
+    }
+
     /// Given a timestamp, get the timeblock of solutions that best correspond
     /// to it. If necessary, the "timestamp fraction" is used; this is a 0-to-1
     /// number that (hopefully) represents how far this timestamp is into the
@@ -285,4 +663,42 @@ impl CalibrationSolutions {
         );
         self.di_jones.slice(s![0, .., ..])
     }
+
+    /// Get which chanblocks of each timeblock are good enough to be used for
+    /// fitting, i.e. chanblocks that aren't flagged and (if calibration results
+    /// are available) converged. The returned array has dimensions
+    /// (num_timeblocks, total_num_chanblocks).
+    pub(crate) fn get_usable_chanblocks(&self) -> Array2<bool> {
+        let (num_timeblocks, _, num_chanblocks) = self.di_jones.dim();
+        let mut usable = Array2::from_elem((num_timeblocks, num_chanblocks), true);
+        for &i_chanblock in &self.flagged_chanblocks {
+            if (i_chanblock as usize) < num_chanblocks {
+                usable.column_mut(i_chanblock as usize).fill(false);
+            }
+        }
+        if let Some(results) = self.calibration_results.as_ref() {
+            if results.dim() == usable.dim() {
+                let min_threshold = self.min_threshold.unwrap_or(f64::INFINITY);
+                usable.zip_mut_with(results, |u, &precision| {
+                    if precision.is_nan() || precision > min_threshold {
+                        *u = false;
+                    }
+                });
+            } else {
+                debug!("Calibration results have an unexpected shape; not using them");
+            }
+        }
+        usable
+    }
+}
+
+/// Linearly interpolate between two solutions; a `weight` of 0 gives `j1` and a
+/// `weight` of 1 gives `j2`. If one of the solutions is flagged (NaN), the
+/// other is used.
+pub(crate) fn interpolate_jones(j1: Jones<f64>, j2: Jones<f64>, weight: f64) -> Jones<f64> {
+    match (j1.any_nan(), j2.any_nan()) {
+        (false, false) => j1 * (1.0 - weight) + j2 * weight,
+        (true, false) => j2,
+        (_, true) => j1,
+    }
 }
@@ -0,0 +1,352 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to find tiles and chanblocks with outlying calibration solutions.
+//!
+//! For each timeblock and diagonal Jones matrix element (XX and YY), the gains
+//! are first referenced to the array; amplitudes are divided by the median
+//! amplitude over all tiles of each chanblock (removing the bandpass), and
+//! phases are relative to a reference tile (removing the arbitrary phase of
+//! each chanblock). A linear phase model (i.e. a delay) is fitted to each
+//! tile's phases and removed, as is the remaining average phase of each
+//! chanblock over all tiles. This leaves log amplitudes and phases that should
+//! be close to zero for "good" solutions. Statistics are then formed for each
+//! tile and chanblock:
+//!
+//! - Tile amplitudes: the median (over chanblocks) of the absolute log
+//!   amplitudes, i.e. how different the tile's gains are to those of the
+//!   array;
+//! - Chanblock amplitudes: the median (over tiles) of the absolute log
+//!   amplitudes, relative to each tile's median log amplitude;
+//! - Tile and chanblock phases: the median (over chanblocks or tiles) of the
+//!   absolute phases.
+//!
+//! A tile or chanblock is an outlier if any of its statistics exceeds the
+//! median statistic by more than a threshold number of robust standard
+//! deviations (derived from the median absolute deviation). As the gains are
+//! referenced to the array, problems common to all tiles of a chanblock aren't
+//! detected.
+
+#[cfg(test)]
+mod tests;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+
+use super::CalibrationSolutions;
+use crate::math::{median, robust_sigma};
+
+/// The default number of robust standard deviations that a statistic needs to
+/// be above the median statistic for a tile or chanblock to be an outlier.
+pub(crate) const DEFAULT_OUTLIER_THRESHOLD: f64 = 5.0;
+
+/// The smallest robust standard deviation of statistics used when finding
+/// outliers. Without this, statistics that are all (nearly) identical, e.g.
+/// from simulated data, would lead to spurious outliers.
+const MIN_SIGMA: f64 = 1e-3;
+
+/// Parameters controlling the flagging of outlying calibration solutions.
+#[derive(Debug, Clone)]
+pub(crate) struct OutlierParams {
+    /// The number of robust standard deviations that a statistic needs to be
+    /// above the median statistic for a tile or chanblock to be an outlier.
+    pub(crate) threshold: f64,
+
+    /// Should calibration be re-run without any outlier tiles?
+    pub(crate) recalibrate: bool,
+}
+
+impl Default for OutlierParams {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_OUTLIER_THRESHOLD,
+            recalibrate: false,
+        }
+    }
+}
+
+/// Tiles and chanblocks with outlying calibration solutions. The indices are
+/// zero indexed, and include flagged tiles and chanblocks (like
+/// [`CalibrationSolutions::flagged_tiles`] and
+/// [`CalibrationSolutions::flagged_chanblocks`]).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Outliers {
+    pub(crate) tiles: Vec<usize>,
+    pub(crate) chanblocks: Vec<u16>,
+}
+
+impl Outliers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.chanblocks.is_empty()
+    }
+}
+
+/// Find the tiles and chanblocks of calibration solutions that are outliers.
+/// Tiles and chanblocks that are already flagged are ignored.
+pub(crate) fn find_outliers(sols: &CalibrationSolutions, threshold: f64) -> Outliers {
+    let (_, total_num_tiles, total_num_chanblocks) = sols.di_jones.dim();
+    let unflagged_tiles: Vec<usize> = (0..total_num_tiles)
+        .filter(|i_tile| !sols.flagged_tiles.contains(i_tile))
+        .collect();
+    let unflagged_chanblocks: Vec<usize> = (0..total_num_chanblocks)
+        .filter(|&i_chanblock| !sols.flagged_chanblocks.contains(&(i_chanblock as u16)))
+        .collect();
+
+    let mut outlier_tiles = vec![false; total_num_tiles];
+    let mut outlier_chanblocks = vec![false; total_num_chanblocks];
+    for di_jones in sols.di_jones.outer_iter() {
+        for i_pol in [0, 3] {
+            // Gains without a usable value (e.g. NaN, or zero because the data
+            // only had one polarisation) are ignored.
+            let gains = Array2::from_shape_fn(
+                (unflagged_tiles.len(), unflagged_chanblocks.len()),
+                |(i, j)| {
+                    let g = di_jones[(unflagged_tiles[i], unflagged_chanblocks[j])][i_pol];
+                    if g.is_finite() && g.norm_sqr() > 0.0 {
+                        Some(g)
+                    } else {
+                        None
+                    }
+                },
+            );
+            let residuals = match get_residuals(gains.view(), &unflagged_chanblocks) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            let (tile_amp_stats, tile_phase_stats) = get_tile_stats(residuals.view());
+            for (&i_tile, is_outlier) in unflagged_tiles.iter().zip(
+                get_outliers(&tile_amp_stats, threshold)
+                    .into_iter()
+                    .zip(get_outliers(&tile_phase_stats, threshold))
+                    .map(|(a, p)| a || p),
+            ) {
+                outlier_tiles[i_tile] |= is_outlier;
+            }
+
+            let (chanblock_amp_stats, chanblock_phase_stats) =
+                get_chanblock_stats(residuals.view());
+            for (&i_chanblock, is_outlier) in unflagged_chanblocks.iter().zip(
+                get_outliers(&chanblock_amp_stats, threshold)
+                    .into_iter()
+                    .zip(get_outliers(&chanblock_phase_stats, threshold))
+                    .map(|(a, p)| a || p),
+            ) {
+                outlier_chanblocks[i_chanblock] |= is_outlier;
+            }
+        }
+    }
+
+    Outliers {
+        tiles: outlier_tiles
+            .into_iter()
+            .enumerate()
+            .filter(|(_, o)| *o)
+            .map(|(i, _)| i)
+            .collect(),
+        chanblocks: outlier_chanblocks
+            .into_iter()
+            .enumerate()
+            .filter(|(_, o)| *o)
+            .map(|(i, _)| i as u16)
+            .collect(),
+    }
+}
+
+/// Flag outlier tiles and chanblocks in calibration solutions. Their solutions
+/// (and calibration results and baseline weights, if available) are set to
+/// NaN.
+pub(crate) fn flag_outliers(sols: &mut CalibrationSolutions, outliers: &Outliers) {
+    let (_, total_num_tiles, _) = sols.di_jones.dim();
+
+    for &i_tile in &outliers.tiles {
+        sols.di_jones
+            .slice_mut(s![.., i_tile, ..])
+            .fill(Jones::nan());
+    }
+    for &i_chanblock in &outliers.chanblocks {
+        sols.di_jones
+            .slice_mut(s![.., .., usize::from(i_chanblock)])
+            .fill(Jones::nan());
+        if let Some(results) = sols.calibration_results.as_mut() {
+            if usize::from(i_chanblock) < results.len_of(Axis(1)) {
+                results.column_mut(usize::from(i_chanblock)).fill(f64::NAN);
+            }
+        }
+    }
+    if let Some(baseline_weights) = sols.baseline_weights.as_mut() {
+        let mut i_baseline = 0;
+        for i_tile1 in 0..total_num_tiles {
+            for i_tile2 in i_tile1 + 1..total_num_tiles {
+                if outliers.tiles.contains(&i_tile1) || outliers.tiles.contains(&i_tile2) {
+                    if let Some(w) = baseline_weights.get_mut(i_baseline) {
+                        *w = f64::NAN;
+                    }
+                }
+                i_baseline += 1;
+            }
+        }
+    }
+
+    sols.flagged_tiles.extend_from_slice(&outliers.tiles);
+    sols.flagged_tiles.sort_unstable();
+    sols.flagged_tiles.dedup();
+    sols.flagged_chanblocks
+        .extend_from_slice(&outliers.chanblocks);
+    sols.flagged_chanblocks.sort_unstable();
+    sols.flagged_chanblocks.dedup();
+}
+
+/// Get the log amplitudes and phases of gains (with dimensions of tile and
+/// chanblock) after referencing them to the array and removing the phase model
+/// of each tile (see the module documentation). `unflagged_chanblocks` are the
+/// chanblock indices of the gains. `None` is returned if there aren't any
+/// usable gains.
+fn get_residuals(
+    gains: ArrayView2<Option<c64>>,
+    unflagged_chanblocks: &[usize],
+) -> Option<Array2<Option<(f64, f64)>>> {
+    let median_amps: Vec<Option<f64>> = gains
+        .axis_iter(Axis(1))
+        .map(|gains| {
+            let mut amps: Vec<f64> = gains.iter().flatten().map(|g| g.norm()).collect();
+            median(&mut amps)
+        })
+        .collect();
+    let log_amps = Array2::from_shape_fn(gains.dim(), |(i_tile, i_chanblock)| {
+        match (gains[(i_tile, i_chanblock)], median_amps[i_chanblock]) {
+            (Some(g), Some(m)) => Some((g.norm() / m).ln()),
+            _ => None,
+        }
+    });
+
+    // The reference tile is the one with amplitudes closest to the medians, so
+    // it's unlikely to be an outlier itself.
+    let i_ref_tile = log_amps
+        .outer_iter()
+        .enumerate()
+        .filter_map(|(i_tile, log_amps)| {
+            let mut devs: Vec<f64> = log_amps.iter().flatten().map(|a| a.abs()).collect();
+            median(&mut devs).map(|m| (i_tile, m))
+        })
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map(|(i_tile, _)| i_tile)?;
+    let mut phasors = Array2::from_shape_fn(gains.dim(), |(i_tile, i_chanblock)| {
+        match (
+            gains[(i_tile, i_chanblock)],
+            gains[(i_ref_tile, i_chanblock)],
+        ) {
+            (Some(g), Some(r)) => Some(g / g.norm() * (r / r.norm()).conj()),
+            _ => None,
+        }
+    });
+
+    // Remove the phase model of each tile. The delay is estimated from the
+    // phase differences of adjacent chanblocks, which avoids having to unwrap
+    // phases.
+    for mut phasors in phasors.outer_iter_mut() {
+        let slope: c64 = phasors
+            .windows(2)
+            .into_iter()
+            .zip(unflagged_chanblocks.windows(2))
+            .filter(|(_, c)| c[1] == c[0] + 1)
+            .filter_map(|(p, _)| Some(p[1]? * p[0]?.conj()))
+            .sum();
+        let slope = slope.arg();
+        let offset: c64 = phasors
+            .iter()
+            .zip(unflagged_chanblocks)
+            .filter_map(|(p, &c)| Some(p.as_ref()? * c64::cis(-slope * c as f64)))
+            .sum();
+        let offset = offset.arg();
+        for (p, &c) in phasors.iter_mut().zip(unflagged_chanblocks) {
+            if let Some(p) = p.as_mut() {
+                *p *= c64::cis(-(slope * c as f64 + offset));
+            }
+        }
+    }
+    // Remove the average phase of each chanblock.
+    for mut phasors in phasors.axis_iter_mut(Axis(1)) {
+        let average: c64 = phasors.iter().flatten().sum();
+        let average = average.arg();
+        for p in phasors.iter_mut().flatten() {
+            *p *= c64::cis(-average);
+        }
+    }
+
+    Some(Array2::from_shape_fn(
+        gains.dim(),
+        |(i_tile, i_chanblock)| match (
+            log_amps[(i_tile, i_chanblock)],
+            phasors[(i_tile, i_chanblock)],
+        ) {
+            (Some(a), Some(p)) => Some((a, p.arg())),
+            _ => None,
+        },
+    ))
+}
+
+/// Get the amplitude and phase statistics of each tile from residuals. Tiles
+/// without statistics get NaN.
+fn get_tile_stats(residuals: ArrayView2<Option<(f64, f64)>>) -> (Vec<f64>, Vec<f64>) {
+    residuals
+        .outer_iter()
+        .map(|residuals| {
+            let (mut amps, mut phases): (Vec<f64>, Vec<f64>) = residuals
+                .iter()
+                .flatten()
+                .map(|(a, p)| (a.abs(), p.abs()))
+                .unzip();
+            (
+                median(&mut amps).unwrap_or(f64::NAN),
+                median(&mut phases).unwrap_or(f64::NAN),
+            )
+        })
+        .unzip()
+}
+
+/// Get the amplitude and phase statistics of each chanblock from residuals.
+/// Chanblocks without statistics get NaN.
+fn get_chanblock_stats(residuals: ArrayView2<Option<(f64, f64)>>) -> (Vec<f64>, Vec<f64>) {
+    // The median log amplitude of each tile.
+    let tile_levels: Vec<f64> = residuals
+        .outer_iter()
+        .map(|residuals| {
+            let mut amps: Vec<f64> = residuals.iter().flatten().map(|(a, _)| *a).collect();
+            median(&mut amps).unwrap_or(0.0)
+        })
+        .collect();
+
+    residuals
+        .axis_iter(Axis(1))
+        .map(|residuals| {
+            let (mut amps, mut phases): (Vec<f64>, Vec<f64>) = residuals
+                .iter()
+                .zip(tile_levels.iter())
+                .filter_map(|(r, level)| r.map(|(a, p)| ((a - level).abs(), p.abs())))
+                .unzip();
+            (
+                median(&mut amps).unwrap_or(f64::NAN),
+                median(&mut phases).unwrap_or(f64::NAN),
+            )
+        })
+        .unzip()
+}
+
+/// Which of the statistics are outliers? NaN statistics are never outliers.
+fn get_outliers(stats: &[f64], threshold: f64) -> Vec<bool> {
+    let mut finite: Vec<f64> = stats.iter().copied().filter(|s| s.is_finite()).collect();
+    let median_stat = match median(&mut finite) {
+        Some(m) => m,
+        None => return vec![false; stats.len()],
+    };
+    let sigma = robust_sigma(&mut finite, median_stat)
+        .unwrap_or(0.0)
+        .max(MIN_SIGMA);
+
+    stats
+        .iter()
+        .map(|&s| s - median_stat > threshold * sigma)
+        .collect()
+}
@@ -0,0 +1,138 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::f64::consts::TAU;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use vec1::Vec1;
+
+use super::*;
+
+const NUM_TILES: usize = 24;
+const NUM_CHANBLOCKS: usize = 64;
+
+/// A deterministic "random" number between -0.5 and 0.5.
+fn noise(a: usize, b: usize, c: usize) -> f64 {
+    let x = (a as f64 * 12.9898 + b as f64 * 78.233 + c as f64 * 37.719).sin() * 43758.5453;
+    x - x.floor() - 0.5
+}
+
+/// Solutions with a bandpass, a different delay for each tile, an arbitrary
+/// phase for each chanblock and a little noise.
+fn get_sols() -> CalibrationSolutions {
+    let di_jones = Array3::from_shape_fn(
+        (1, NUM_TILES, NUM_CHANBLOCKS),
+        |(_, i_tile, i_chanblock)| {
+            let x = i_chanblock as f64 / NUM_CHANBLOCKS as f64;
+            let bandpass = 1.0 + 0.3 * (TAU * x).sin();
+            let mut j = [c64::default(); 4];
+            for i_pol in [0, 3] {
+                let amp = bandpass
+                    * (1.0 + 0.05 * (i_tile as f64 / NUM_TILES as f64))
+                    * (1.0 + 0.02 * noise(i_tile, i_chanblock, i_pol));
+                let phase = 0.02 * i_tile as f64 * i_chanblock as f64
+                    + TAU * noise(0, i_chanblock, 100 + i_pol)
+                    + 0.02 * noise(i_tile, i_chanblock, 200 + i_pol);
+                j[i_pol] = c64::from_polar(amp, phase);
+            }
+            Jones::from(j)
+        },
+    );
+    CalibrationSolutions {
+        di_jones,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_no_outliers() {
+    let sols = get_sols();
+    let outliers = find_outliers(&sols, DEFAULT_OUTLIER_THRESHOLD);
+    assert!(outliers.is_empty(), "{outliers:?}");
+}
+
+#[test]
+fn test_find_outliers() {
+    let mut sols = get_sols();
+    // Tile 5 is dead; its solutions are noise.
+    for i_chanblock in 0..NUM_CHANBLOCKS {
+        sols.di_jones[(0, 5, i_chanblock)] = Jones::from([
+            c64::from_polar(
+                10.0_f64.powf(2.0 * noise(5, i_chanblock, 300)),
+                TAU * noise(5, i_chanblock, 301),
+            ),
+            c64::default(),
+            c64::default(),
+            c64::from_polar(
+                10.0_f64.powf(2.0 * noise(5, i_chanblock, 302)),
+                TAU * noise(5, i_chanblock, 303),
+            ),
+        ]);
+    }
+    // Chanblock 40 is affected by RFI; its solutions are noisy.
+    for i_tile in 0..NUM_TILES {
+        sols.di_jones[(0, i_tile, 40)] *=
+            c64::from_polar(1.0 + noise(i_tile, 40, 400), 2.0 * noise(i_tile, 40, 401));
+    }
+    // Tile 2 and chanblock 10 are already flagged; they shouldn't be reported
+    // as outliers, even though their solutions are bad.
+    sols.flagged_tiles.push(2);
+    sols.di_jones.slice_mut(s![.., 2, ..]).fill(Jones::nan());
+    sols.flagged_chanblocks.push(10);
+    sols.di_jones.slice_mut(s![.., .., 10]).fill(Jones::nan());
+
+    let outliers = find_outliers(&sols, DEFAULT_OUTLIER_THRESHOLD);
+    assert_eq!(
+        outliers,
+        Outliers {
+            tiles: vec![5],
+            chanblocks: vec![40],
+        }
+    );
+}
+
+#[test]
+fn test_flag_outliers() {
+    let mut sols = get_sols();
+    sols.flagged_tiles.push(2);
+    sols.flagged_chanblocks.push(10);
+    sols.calibration_results = Some(Array2::from_elem((1, NUM_CHANBLOCKS), 1e-10));
+    sols.baseline_weights = Vec1::try_from_vec(vec![1.0; (NUM_TILES * (NUM_TILES - 1)) / 2]).ok();
+    let outliers = Outliers {
+        tiles: vec![1, 5],
+        chanblocks: vec![3],
+    };
+
+    flag_outliers(&mut sols, &outliers);
+
+    assert_eq!(sols.flagged_tiles, vec![1, 2, 5]);
+    assert_eq!(sols.flagged_chanblocks, vec![3, 10]);
+    for i_tile in 0..NUM_TILES {
+        for i_chanblock in 0..NUM_CHANBLOCKS {
+            let j = sols.di_jones[(0, i_tile, i_chanblock)];
+            if [1, 5].contains(&i_tile) || i_chanblock == 3 {
+                assert!(j.any_nan());
+            } else {
+                assert!(!j.any_nan());
+            }
+        }
+    }
+    let results = sols.calibration_results.unwrap();
+    assert!(results[(0, 3)].is_nan());
+    assert_eq!(results.iter().filter(|r| r.is_nan()).count(), 1);
+
+    // Baseline 0 is between tiles 0 and 1, baseline 4 is between tiles 0 and
+    // 5. Baseline 1 is between tiles 0 and 2, which was already flagged, but
+    // the baseline weights weren't; leave them alone.
+    let baseline_weights = sols.baseline_weights.unwrap();
+    assert!(baseline_weights[0].is_nan());
+    assert!(!baseline_weights[1].is_nan());
+    assert!(baseline_weights[4].is_nan());
+    assert_eq!(
+        baseline_weights.iter().filter(|w| w.is_nan()).count(),
+        // All baselines involving tile 1 or tile 5.
+        2 * (NUM_TILES - 1) - 1
+    );
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to reference the phases of calibration solutions to a tile.
+//!
+//! DI calibration can't determine the absolute phase of each polarisation;
+//! only phases relative to other tiles are meaningful. Referencing the
+//! solutions makes the XX and YY phases of a chosen tile zero for every
+//! timeblock and chanblock, so solutions from different observations can be
+//! compared directly. The solutions (which correct the data, i.e. they are the
+//! inverses of the instrumental gains) of every tile are multiplied on the
+//! left by the same diagonal matrix of phases, so the calibrated XX and YY
+//! visibilities don't change. The calibrated cross-hand visibilities are
+//! rotated by the phase between the reference tile's X and Y polarisations,
+//! which DI calibration against an unpolarised sky model can't determine
+//! anyway; solutions with an XY-phase correction can't be referenced, as this
+//! would invalidate the correction.
+//!
+//! Optionally, the phases of the cross-hand (XY and YX) terms of the reference
+//! tile can also be made zero. There's no matrix that does this for all tiles
+//! at once, so each tile's cross-hand terms are rotated independently; this
+//! changes the calibrated cross-hand visibilities further.
+
+#[cfg(test)]
+mod tests;
+
+use std::cmp::Reverse;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use thiserror::Error;
+
+use super::{CalibrationSolutions, PhaseReference};
+
+#[derive(Error, Debug)]
+pub(crate) enum PhaseReferenceError {
+    #[error("Got a reference tile index {got}, but the biggest tile index is {max}")]
+    BadTileIndex { got: usize, max: usize },
+
+    #[error("Couldn't find a tile named '{0}' to use as the reference")]
+    BadTileName(String),
+
+    #[error("The reference tile {0} has no solutions")]
+    NoSolutions(usize),
+
+    #[error("Solutions with an XY-phase correction can't be referenced to a tile")]
+    XyPhase,
+}
+
+/// Get the index of the reference tile specified by `tile`, which is either an
+/// index or (case-insensitively) the name of a tile in `tile_names`.
+pub(crate) fn parse_ref_tile(
+    tile: &str,
+    tile_names: Option<&[String]>,
+    total_num_tiles: usize,
+) -> Result<usize, PhaseReferenceError> {
+    match tile.trim().parse() {
+        Ok(i) if i < total_num_tiles => Ok(i),
+        Ok(i) => Err(PhaseReferenceError::BadTileIndex {
+            got: i,
+            max: total_num_tiles - 1,
+        }),
+        Err(_) => tile_names
+            .and_then(|names| {
+                names
+                    .iter()
+                    .position(|name| name.to_lowercase() == tile.trim().to_lowercase())
+            })
+            .ok_or_else(|| PhaseReferenceError::BadTileName(tile.to_string())),
+    }
+}
+
+/// Get the index of the tile with the most solutions in `di_jones` (preferring
+/// lower indices), to be used as the reference tile when the requested one has
+/// no solutions. `None` is returned if no tiles have solutions.
+pub(crate) fn get_fallback_ref_tile(di_jones: ArrayView3<Jones<f64>>) -> Option<usize> {
+    di_jones
+        .axis_iter(Axis(1))
+        .map(|di_jones| di_jones.iter().filter(|j| !j.any_nan()).count())
+        .enumerate()
+        .filter(|(_, num_sols)| *num_sols > 0)
+        .max_by_key(|&(i_tile, num_sols)| (num_sols, Reverse(i_tile)))
+        .map(|(i_tile, _)| i_tile)
+}
+
+/// Reference the phases of the solutions to the tile `ref_tile` (see the
+/// module documentation). Chanblocks where the reference tile has no solution
+/// are left alone. The reference is recorded in the solutions.
+pub(crate) fn reference_phases(
+    sols: &mut CalibrationSolutions,
+    ref_tile: usize,
+    cross_hands: bool,
+) -> Result<(), PhaseReferenceError> {
+    if sols.xy_phase.is_some() {
+        return Err(PhaseReferenceError::XyPhase);
+    }
+    let total_num_tiles = sols.di_jones.len_of(Axis(1));
+    if ref_tile >= total_num_tiles {
+        return Err(PhaseReferenceError::BadTileIndex {
+            got: ref_tile,
+            max: total_num_tiles - 1,
+        });
+    }
+    if sols
+        .di_jones
+        .slice(s![.., ref_tile, ..])
+        .iter()
+        .all(|j| j.any_nan())
+    {
+        return Err(PhaseReferenceError::NoSolutions(ref_tile));
+    }
+
+    for mut di_jones_t in sols.di_jones.outer_iter_mut() {
+        for mut di_jones_tc in di_jones_t.axis_iter_mut(Axis(1)) {
+            let ref_jones = di_jones_tc[ref_tile];
+            if ref_jones.any_nan() {
+                continue;
+            }
+
+            let diag = Jones::from([
+                c64::cis(-ref_jones[0].arg()),
+                c64::default(),
+                c64::default(),
+                c64::cis(-ref_jones[3].arg()),
+            ]);
+            let ref_jones = diag * ref_jones;
+            let xy = c64::cis(-ref_jones[1].arg());
+            let yx = c64::cis(-ref_jones[2].arg());
+            di_jones_tc.iter_mut().for_each(|j| {
+                *j = diag * *j;
+                if cross_hands {
+                    *j = Jones::from([j[0], j[1] * xy, j[2] * yx, j[3]]);
+                }
+            });
+        }
+    }
+
+    sols.phase_reference = Some(PhaseReference {
+        tile: ref_tile,
+        cross_hands,
+    });
+    Ok(())
+}
@@ -0,0 +1,199 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+
+use super::*;
+use crate::solutions::XyPhase;
+
+const NUM_TILES: usize = 4;
+const NUM_CHANBLOCKS: usize = 3;
+
+/// Solutions with a different (non-zero) phase in every element.
+fn get_sols() -> CalibrationSolutions {
+    let di_jones = Array3::from_shape_fn(
+        (2, NUM_TILES, NUM_CHANBLOCKS),
+        |(i_timeblock, i_tile, i_chanblock)| {
+            let mut j = [c64::default(); 4];
+            for (i_pol, j) in j.iter_mut().enumerate() {
+                let amp = if i_pol == 0 || i_pol == 3 { 1.0 } else { 0.1 };
+                let phase = 0.1 * (i_timeblock + 1) as f64
+                    + 0.2 * i_tile as f64
+                    + 0.3 * i_chanblock as f64
+                    + 0.4 * i_pol as f64;
+                *j = c64::from_polar(amp * (1.0 + 0.1 * i_tile as f64), phase);
+            }
+            Jones::from(j)
+        },
+    );
+    CalibrationSolutions {
+        di_jones,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_reference_phases() {
+    let mut sols = get_sols();
+    let unreferenced = sols.di_jones.clone();
+    reference_phases(&mut sols, 2, false).unwrap();
+    assert_eq!(
+        sols.phase_reference,
+        Some(PhaseReference {
+            tile: 2,
+            cross_hands: false
+        })
+    );
+
+    for i_timeblock in 0..2 {
+        for i_chanblock in 0..NUM_CHANBLOCKS {
+            let sols_tc = sols.di_jones.slice(s![i_timeblock, .., i_chanblock]);
+            let unreferenced_tc = unreferenced.slice(s![i_timeblock, .., i_chanblock]);
+
+            // The reference tile's XX and YY phases are zero, but its
+            // cross-hand phases aren't.
+            let ref_jones = sols_tc[2];
+            assert_abs_diff_eq!(ref_jones[0].arg(), 0.0, epsilon = 1e-12);
+            assert_abs_diff_eq!(ref_jones[3].arg(), 0.0, epsilon = 1e-12);
+            assert!(ref_jones[1].arg().abs() > 1e-3);
+
+            // Amplitudes are unchanged. The calibrated XX and YY
+            // visibilities (J_p D J_q^H, for data visibilities D) are also
+            // unchanged, and the calibrated cross-hand visibilities only
+            // change in phase.
+            for i_tile in 0..NUM_TILES {
+                for i_pol in 0..4 {
+                    assert_abs_diff_eq!(
+                        sols_tc[i_tile][i_pol].norm(),
+                        unreferenced_tc[i_tile][i_pol].norm(),
+                        epsilon = 1e-12
+                    );
+                }
+                for j_tile in 0..NUM_TILES {
+                    // Arbitrary (non-diagonal) data visibilities.
+                    let data = Jones::from([
+                        c64::new(1.0, 0.5),
+                        c64::new(0.3, -0.2),
+                        c64::new(-0.1, 0.4),
+                        c64::new(0.8, -0.6),
+                    ]) * (1 + i_tile + j_tile) as f64;
+                    let calibrated = sols_tc[i_tile] * data * sols_tc[j_tile].h();
+                    let unreferenced_calibrated =
+                        unreferenced_tc[i_tile] * data * unreferenced_tc[j_tile].h();
+                    for i_pol in [0, 3] {
+                        assert_abs_diff_eq!(
+                            (calibrated[i_pol] - unreferenced_calibrated[i_pol]).norm(),
+                            0.0,
+                            epsilon = 1e-12
+                        );
+                    }
+                    for i_pol in [1, 2] {
+                        assert_abs_diff_eq!(
+                            calibrated[i_pol].norm(),
+                            unreferenced_calibrated[i_pol].norm(),
+                            epsilon = 1e-12
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reference_phases_cross_hands() {
+    let mut sols = get_sols();
+    // A flagged chanblock of the reference tile is left alone.
+    sols.di_jones[(0, 1, 2)] = Jones::identity() * f64::NAN;
+    let unreferenced = sols.di_jones.clone();
+    reference_phases(&mut sols, 1, true).unwrap();
+    assert_eq!(
+        sols.phase_reference,
+        Some(PhaseReference {
+            tile: 1,
+            cross_hands: true
+        })
+    );
+
+    for i_timeblock in 0..2 {
+        for i_chanblock in 0..NUM_CHANBLOCKS {
+            let ref_jones = sols.di_jones[(i_timeblock, 1, i_chanblock)];
+            if i_timeblock == 0 && i_chanblock == 2 {
+                assert!(ref_jones.any_nan());
+                assert_abs_diff_eq!(
+                    sols.di_jones[(0, 0, 2)],
+                    unreferenced[(0, 0, 2)],
+                    epsilon = 1e-12
+                );
+                continue;
+            }
+            for i_pol in 0..4 {
+                assert_abs_diff_eq!(ref_jones[i_pol].arg(), 0.0, epsilon = 1e-12);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reference_phases_bad_tile() {
+    let mut sols = get_sols();
+    let result = reference_phases(&mut sols, NUM_TILES, false);
+    assert!(matches!(
+        result,
+        Err(PhaseReferenceError::BadTileIndex { got: 4, max: 3 })
+    ));
+
+    sols.di_jones
+        .slice_mut(s![.., 3, ..])
+        .fill(Jones::identity() * f64::NAN);
+    let result = reference_phases(&mut sols, 3, false);
+    assert!(matches!(result, Err(PhaseReferenceError::NoSolutions(3))));
+    assert!(sols.phase_reference.is_none());
+
+    sols.xy_phase = Some(XyPhase {
+        phases: Array1::zeros(NUM_CHANBLOCKS),
+        measured_phases: Array1::zeros(NUM_CHANBLOCKS),
+        delay: 0.0,
+    });
+    let result = reference_phases(&mut sols, 0, false);
+    assert!(matches!(result, Err(PhaseReferenceError::XyPhase)));
+}
+
+#[test]
+fn test_get_fallback_ref_tile() {
+    let mut sols = get_sols();
+    // All tiles have all of their solutions, so the first tile is used.
+    assert_eq!(get_fallback_ref_tile(sols.di_jones.view()), Some(0));
+
+    sols.di_jones
+        .slice_mut(s![.., 0, ..])
+        .fill(Jones::identity() * f64::NAN);
+    sols.di_jones[(1, 1, 0)] = Jones::identity() * f64::NAN;
+    assert_eq!(get_fallback_ref_tile(sols.di_jones.view()), Some(2));
+
+    sols.di_jones.fill(Jones::identity() * f64::NAN);
+    assert_eq!(get_fallback_ref_tile(sols.di_jones.view()), None);
+}
+
+#[test]
+fn test_parse_ref_tile() {
+    let names = ["Tile011", "Tile012", "Tile013"].map(|s| s.to_string());
+    assert_eq!(parse_ref_tile("1", Some(&names[..]), 3).unwrap(), 1);
+    assert_eq!(parse_ref_tile("tile013", Some(&names[..]), 3).unwrap(), 2);
+    assert_eq!(parse_ref_tile(" 0 ", None, 3).unwrap(), 0);
+    assert!(matches!(
+        parse_ref_tile("3", Some(&names[..]), 3),
+        Err(PhaseReferenceError::BadTileIndex { got: 3, max: 2 })
+    ));
+    assert!(matches!(
+        parse_ref_tile("Tile014", Some(&names[..]), 3),
+        Err(PhaseReferenceError::BadTileName(_))
+    ));
+    assert!(matches!(
+        parse_ref_tile("Tile011", None, 3),
+        Err(PhaseReferenceError::BadTileName(_))
+    ));
+}
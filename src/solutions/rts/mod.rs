@@ -23,6 +23,8 @@ use read_files::*;
 
 use std::{
     collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
 };
 
@@ -259,13 +261,13 @@ fn read_no_files(
                 acc.min(bp.fine_channel_resolution.unwrap_or(f64::INFINITY))
             });
 
-        // Only handling legacy correlator settings for now.
-        if (smallest_fine_chan_res - 40e3).abs() < f64::EPSILON {
-            32
-        } else if (smallest_fine_chan_res - 20e3).abs() < f64::EPSILON {
-            64
-        } else if (smallest_fine_chan_res - 10e3).abs() < f64::EPSILON {
-            128
+        // The fine channels must evenly divide the coarse channel.
+        let num_fine_chans = f64::from(context.coarse_chan_width_hz) / smallest_fine_chan_res;
+        if num_fine_chans.is_finite()
+            && num_fine_chans >= 1.0
+            && (num_fine_chans - num_fine_chans.round()).abs() < 1e-6
+        {
+            num_fine_chans.round() as usize
         } else {
             return Err(RtsReadSolsError::UnhandledFreqRes(smallest_fine_chan_res));
         }
@@ -273,11 +275,27 @@ fn read_no_files(
     debug!("Number of fine channels per coarse channel: {num_fine_chans_per_coarse_chan}");
     let total_num_fine_freq_chans = num_fine_chans_per_coarse_chan * total_num_coarse_chans;
     debug!("Total number of fine freq. channels: {total_num_fine_freq_chans}");
+    // The index of each receiver channel among all of the metafits coarse
+    // channels. Files may be missing for some coarse channels, so this isn't
+    // necessarily the index of the file.
+    let receiver_channels: Vec<u8> = context
+        .metafits_coarse_chans
+        .iter()
+        .map(|cc| cc.rec_chan_number.try_into().unwrap())
+        .sorted()
+        .collect();
+    let get_coarse_chan_index = |rec_chan: u8| {
+        receiver_channels
+            .iter()
+            .position(|&r| r == rec_chan)
+            .expect("receiver channel came from the metafits")
+    };
+
     // Get the flagged fine channels. Start by checking available channels.
     let unflagged_fine_chans = receiver_channel_to_data
-        .values()
-        .flat_map(|(i_gpubox, _, bp)| {
-            let i_cc = usize::from(*i_gpubox - 1);
+        .iter()
+        .flat_map(|(&rec_chan, (_, _, bp))| {
+            let i_cc = get_coarse_chan_index(rec_chan);
             let offset = i_cc * num_fine_chans_per_coarse_chan;
             bp.unflagged_fine_channel_indices
                 .iter()
@@ -310,7 +328,8 @@ fn read_no_files(
     // Iterating over the BTreeMap gives the solutions in the correct order,
     // becauase the map's keys are ascendingly sorted and correspond to
     // ascending sky frequency (which is how we want the data).
-    for (i_cc, (_, (_, di_jm, mut bp_cal))) in receiver_channel_to_data.into_iter().enumerate() {
+    for (rec_chan, (_, di_jm, mut bp_cal)) in receiver_channel_to_data {
+        let i_cc = get_coarse_chan_index(rec_chan);
         debug!("Reading coarse channel {i_cc}");
 
         // Apply di_jm to the bp_cal data. Modify the bp_cal data in place, then
@@ -429,192 +448,216 @@ fn read_no_files(
     })
 }
 
-// Writing RTS solutions is perhaps more art than science. Hopefully no one ever
-// needs this, but some commented code is better than nothing.
-
-// pub(super) fn write<P: AsRef<Path>, P2: AsRef<Path>>(
-//     sols: &CalibrationSolutions,
-//     dir: P,
-//     metafits: P2,
-// ) -> Result<(), RtsWriteSolsError> {
-//     fn inner(
-//         sols: &CalibrationSolutions,
-//         dir: &Path,
-//         metafits: &Path,
-//     ) -> Result<(), RtsWriteSolsError> {
-//         if dir.exists() {
-//             // If it exists, check that `dir` really is a directory.
-//             if !dir.is_dir() {
-//                 return Err(RtsWriteSolsError::NotADir(dir.to_path_buf()));
-//             }
-//         } else {
-//             // Make the specified directory and all its parents if `dir` doesn't
-//             // exist.
-//             std::fs::create_dir_all(dir).map_err(|e| RtsWriteSolsError::CouldntMakeDir {
-//                 dir: dir.to_path_buf(),
-//                 err: e,
-//             })?;
-//         }
-
-//         let context = MetafitsContext::new(metafits, None)?;
-//         let num_fine_chans_per_coarse_chan = context.num_corr_fine_chans_per_coarse;
-//         let freq_res = context.corr_fine_chan_width_hz as f64;
-
-//         if sols.di_jones.len_of(Axis(0)) > 1 {
-//             warn!("Multiple timeblocks of solutions aren't supported by the RTS; using only the first one");
-//         }
-
-//         // Make a map from the receiver channel (a proxy for the sky frequency) to
-//         // the gpubox number (which is the same as the node??? number in the files).
-//         let mut receiver_to_gpubox: BTreeMap<usize, usize> = BTreeMap::new();
-//         for cc in &context.metafits_coarse_chans {
-//             receiver_to_gpubox.insert(cc.rec_chan_number, cc.gpubox_number);
-//         }
-//         debug!("Receiver channel map to RTS DI calibration file node num:");
-//         debug!("{:?}", receiver_to_gpubox);
-//         // And a map from the RF input number divided by 2 to the tile (a.k.a.
-//         // ANTENNA) number.
-//         let mut input_to_tile: BTreeMap<usize, usize> = BTreeMap::new();
-//         for rf in &context.rf_inputs {
-//             input_to_tile.insert(rf.input as usize / 2, rf.ant as usize);
-//         }
-//         debug!("RF input / 2 map to tile index:");
-//         debug!("{:?}", input_to_tile);
-
-//         for (i_cc, (_, i_gpubox)) in receiver_to_gpubox.into_iter().enumerate() {
-//             // Create the RTS files.
-//             let di_jm_fp = format!("{}/DI_JonesMatrices_node{:03}.dat", dir.display(), i_gpubox);
-//             debug!("Writing to {di_jm_fp}");
-//             let mut di_jm_file = BufWriter::new(File::create(di_jm_fp)?);
-//             let bp_cal_fp = format!(
-//                 "{}/BandpassCalibration_node{:03}.dat",
-//                 dir.display(),
-//                 i_gpubox
-//             );
-//             debug!("Writing to {bp_cal_fp}");
-//             let mut bp_cal_file = BufWriter::new(File::create(bp_cal_fp)?);
-
-//             // Isolate the applicable data.
-//             let chan_offset = i_cc * num_fine_chans_per_coarse_chan;
-//             let chan_range = chan_offset..((i_cc + 1) * num_fine_chans_per_coarse_chan);
-//             let data = sols.di_jones.slice(s![0, .., chan_range]);
-
-//             // Write the useless alignment flux density...
-//             writeln!(&mut di_jm_file, "{:.6}", 1.0)?;
-//             // ... and make the post-aligment matrix identity.
-//             write!(&mut di_jm_file, "{:+.6}, ", 1.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 0.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 0.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 0.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 0.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 0.0)?;
-//             write!(&mut di_jm_file, "{:+.6}, ", 1.0)?;
-//             writeln!(&mut di_jm_file, "{:+.6}", 0.0)?;
-
-//             // Write the unflagged fine channel frequencies in MHz.
-//             let mut unflagged_chan_line = String::new();
-//             for i_chan in 0..num_fine_chans_per_coarse_chan {
-//                 if !sols
-//                     .flagged_chanblocks
-//                     .contains(&((i_chan + chan_offset).try_into().unwrap()))
-//                 {
-//                     unflagged_chan_line.push_str(&format!(
-//                         "{:.6}, ",
-//                         (i_chan as f64 * freq_res).round() / 1e6
-//                     ));
-//                 }
-//             }
-//             writeln!(
-//                 &mut bp_cal_file,
-//                 "{}",
-//                 unflagged_chan_line.trim_end_matches(", ")
-//             )?;
-
-//             // And now write tile Jones matrices.
-//             for (&i_input, &i_tile) in &input_to_tile {
-//                 let flagged = sols.flagged_tiles.contains(&i_tile);
-
-//                 // Find the "average" Jones matrix for the DI JM file.
-//                 let data = data.slice(s![i_tile, ..]);
-//                 let average = if flagged {
-//                     Jones::default()
-//                 } else {
-//                     let (sum, length) = data.iter().enumerate().fold(
-//                         (Jones::default(), 0),
-//                         |(acc_sum, acc_length), (i_chan_in_cc, j)| {
-//                             if !sols
-//                                 .flagged_chanblocks
-//                                 .contains(&((i_chan_in_cc + chan_offset) as _))
-//                             {
-//                                 (acc_sum + *j, acc_length + 1)
-//                             } else {
-//                                 (acc_sum, acc_length)
-//                             }
-//                         },
-//                     );
-//                     if length > 0 {
-//                         sum / length as f64
-//                     } else {
-//                         sum
-//                     }
-//                 };
-//                 writeln!(
-//                     &mut di_jm_file,
-//                     "{:+.6}, {:+.6}, {:+.6}, {:+.6}, {:+.6}, {:+.6}, {:+.6}, {:+.6}",
-//                     // Don't forget to reorder into RTS PX, PY, QX, QY.
-//                     average[3].re,
-//                     average[3].im,
-//                     average[2].re,
-//                     average[2].im,
-//                     average[1].re,
-//                     average[1].im,
-//                     average[0].re,
-//                     average[0].im
-//                 )?;
-
-//                 // BP cal files don't include flagged tiles.
-//                 if flagged {
-//                     continue;
-//                 }
-
-//                 // With the average, find the bandpass Jones matrices.
-//                 let avg_inv = average.inv();
-//                 let bp_data = data.mapv(|j| avg_inv * j);
-//                 // Write "fit" data the same as "lsq". No one cares...
-//                 let mut bp_cal_line = String::new();
-//                 for i in 0..8 {
-//                     let i_jones_elem = match i / 2 {
-//                         0 => 3,
-//                         1 => 2,
-//                         2 => 1,
-//                         3 => 0,
-//                         _ => unreachable!(),
-//                     };
-
-//                     bp_cal_line.push_str(&format!("{}, ", i_input + 1));
-//                     for (_, j) in bp_data.iter().enumerate().filter(|(i_chan, _)| {
-//                         !sols
-//                             .flagged_chanblocks
-//                             .contains(&((i_chan + chan_offset) as _))
-//                     }) {
-//                         bp_cal_line.push_str(&format!(
-//                             "{:+.6},{:+.6}, ",
-//                             j[i_jones_elem].norm(),
-//                             j[i_jones_elem].arg()
-//                         ));
-//                     }
-//                     writeln!(&mut bp_cal_file, "{}", bp_cal_line.trim_end_matches(", "))?;
-//                     bp_cal_line.clear();
-//                 }
-//             }
-//             di_jm_file.flush()?;
-//             bp_cal_file.flush()?;
-//         }
-
-//         Ok(())
-//     }
-//     inner(sols, dir.as_ref(), metafits.as_ref())
-// }
+// Writing RTS solutions is perhaps more art than science. The RTS stores a
+// single Jones matrix per tile per coarse channel in the DI_JonesMatrices file
+// and a bandpass relative to it in the BandpassCalibration file; here, the
+// former is the average of the tile's unflagged solutions in the coarse
+// channel, and the latter is the average's inverse multiplied by each
+// solution. The post-alignment matrix is identity. Reading the files back (see
+// `read`) reproduces the solutions.
+
+/// Write the first timeblock of the solutions into RTS DI_JonesMatrices and
+/// BandpassCalibration files in `dir`, one pair per coarse channel. The
+/// solutions must have a chanblock for every fine channel of every coarse
+/// channel in the metafits (at any frequency resolution). Coarse channels
+/// without any unflagged chanblocks have no files. Any XY-phase corrections
+/// are included in the written Jones matrices.
+pub(crate) fn write<P: AsRef<Path>>(
+    sols: &CalibrationSolutions,
+    dir: P,
+    context: &MetafitsContext,
+) -> Result<(), RtsWriteSolsError> {
+    fn inner(
+        sols: &CalibrationSolutions,
+        dir: &Path,
+        context: &MetafitsContext,
+    ) -> Result<(), RtsWriteSolsError> {
+        let di_jones = sols.get_di_jones_with_xy_phase();
+        let (num_timeblocks, total_num_tiles, total_num_chanblocks) = di_jones.dim();
+        if total_num_tiles != context.num_ants {
+            return Err(RtsWriteSolsError::TileCountMismatch {
+                sols: total_num_tiles,
+                metafits: context.num_ants,
+            });
+        }
+        let total_num_coarse_chans = context.num_metafits_coarse_chans;
+        if total_num_chanblocks % total_num_coarse_chans != 0 {
+            return Err(RtsWriteSolsError::ChanblockCountMismatch {
+                num_chanblocks: total_num_chanblocks,
+                num_coarse_chans: total_num_coarse_chans,
+            });
+        }
+        let num_fine_chans_per_coarse_chan = total_num_chanblocks / total_num_coarse_chans;
+        let freq_res =
+            f64::from(context.coarse_chan_width_hz) / num_fine_chans_per_coarse_chan as f64;
+        debug!("Writing {num_fine_chans_per_coarse_chan} fine channels per coarse channel ({freq_res} Hz resolution)");
+
+        if num_timeblocks > 1 {
+            "The RTS doesn't support multiple timeblocks of solutions; using only the first one"
+                .warn();
+        }
+
+        if dir.exists() {
+            // If it exists, check that `dir` really is a directory.
+            if !dir.is_dir() {
+                return Err(RtsWriteSolsError::NotADir(dir.to_path_buf()));
+            }
+        } else {
+            // Make the specified directory and all its parents if `dir` doesn't
+            // exist.
+            std::fs::create_dir_all(dir).map_err(|e| RtsWriteSolsError::CouldntMakeDir {
+                dir: dir.to_path_buf(),
+                err: e,
+            })?;
+        }
+
+        // Make a map from the receiver channel (a proxy for the sky frequency)
+        // to the gpubox number (which is the same as the node??? number in the
+        // files).
+        let mut receiver_to_gpubox: BTreeMap<usize, usize> = BTreeMap::new();
+        for cc in &context.metafits_coarse_chans {
+            receiver_to_gpubox.insert(cc.rec_chan_number, cc.gpubox_number);
+        }
+        debug!("Receiver channel map to RTS DI calibration file node num:");
+        debug!("{:?}", receiver_to_gpubox);
+        // And a map from the RF input number divided by 2 to the tile (a.k.a.
+        // ANTENNA) number.
+        let mut input_to_tile: BTreeMap<usize, usize> = BTreeMap::new();
+        for rf in &context.rf_inputs {
+            input_to_tile.insert(rf.input as usize / 2, rf.ant as usize);
+        }
+        debug!("RF input / 2 map to tile index:");
+        debug!("{:?}", input_to_tile);
+
+        let flagged_chanblocks: HashSet<usize> = sols
+            .flagged_chanblocks
+            .iter()
+            .map(|&i| usize::from(i))
+            .collect();
+
+        for (i_cc, (_, i_gpubox)) in receiver_to_gpubox.into_iter().enumerate() {
+            // Isolate the applicable data.
+            let chan_offset = i_cc * num_fine_chans_per_coarse_chan;
+            let chan_range = chan_offset..chan_offset + num_fine_chans_per_coarse_chan;
+            let unflagged_chans: Vec<usize> = (0..num_fine_chans_per_coarse_chan)
+                .filter(|i_chan| !flagged_chanblocks.contains(&(i_chan + chan_offset)))
+                .collect();
+            // A BandpassCalibration file can't be empty; the reader treats
+            // coarse channels without files as flagged.
+            if unflagged_chans.is_empty() {
+                debug!("Not writing files for node{i_gpubox:03}; all of its channels are flagged");
+                continue;
+            }
+            let data = di_jones.slice(s![0, .., chan_range]);
+
+            // Create the RTS files.
+            let di_jm_fp = dir.join(format!("DI_JonesMatrices_node{i_gpubox:03}.dat"));
+            debug!("Writing to {}", di_jm_fp.display());
+            let mut di_jm_file = BufWriter::new(File::create(di_jm_fp)?);
+            let bp_cal_fp = dir.join(format!("BandpassCalibration_node{i_gpubox:03}.dat"));
+            debug!("Writing to {}", bp_cal_fp.display());
+            let mut bp_cal_file = BufWriter::new(File::create(bp_cal_fp)?);
+
+            // Write the useless alignment flux density...
+            writeln!(&mut di_jm_file, "{:.6}", 1.0)?;
+            // ... and make the post-aligment matrix identity.
+            write_rts_jones(&mut di_jm_file, Jones::identity())?;
+
+            // Write the unflagged fine channel frequencies in MHz.
+            writeln!(
+                &mut bp_cal_file,
+                "{}",
+                unflagged_chans
+                    .iter()
+                    .map(|&i_chan| format!("{:.6}", (i_chan as f64 * freq_res).round() / 1e6))
+                    .join(", ")
+            )?;
+
+            // And now write tile Jones matrices.
+            for (&i_input, &i_tile) in &input_to_tile {
+                let data = data.slice(s![i_tile, ..]);
+
+                // BP cal files don't include flagged tiles, but DI JM files do.
+                if sols.flagged_tiles.contains(&i_tile) {
+                    write_rts_jones(&mut di_jm_file, Jones::identity())?;
+                    continue;
+                }
+
+                // Find the "average" Jones matrix for the DI JM file. If there
+                // isn't a sensible one (e.g. the tile has no solutions in this
+                // coarse channel), use identity.
+                let (sum, length) = unflagged_chans
+                    .iter()
+                    .map(|&i_chan| data[i_chan])
+                    .filter(|j| !j.any_nan())
+                    .fold((Jones::default(), 0), |(acc_sum, acc_length), j| {
+                        (acc_sum + j, acc_length + 1)
+                    });
+                let avg_inv = if length > 0 {
+                    (sum / length as f64).inv()
+                } else {
+                    Jones::identity()
+                };
+                let avg_inv = if (0..4).all(|i| avg_inv[i].is_finite()) {
+                    avg_inv
+                } else {
+                    Jones::identity()
+                };
+
+                // The reader takes the inverse of the conjugate of the DI JM
+                // matrix, and we need to reorder into RTS PX, PY, QX, QY.
+                write_rts_jones(
+                    &mut di_jm_file,
+                    Jones::from([
+                        avg_inv[3].conj(),
+                        avg_inv[2].conj(),
+                        avg_inv[1].conj(),
+                        avg_inv[0].conj(),
+                    ]),
+                )?;
+
+                // With the average, find the bandpass Jones matrices. Write
+                // "fit" data the same as "lsq".
+                let bp_data: Vec<Jones<f64>> = unflagged_chans
+                    .iter()
+                    .map(|&i_chan| avg_inv * data[i_chan])
+                    .collect();
+                for i_jones_elem in [3, 2, 1, 0] {
+                    let bp_cal_line = format!(
+                        "{}, {}",
+                        i_input + 1,
+                        bp_data
+                            .iter()
+                            .map(|j| format!(
+                                "{:e},{:e}",
+                                j[i_jones_elem].norm(),
+                                j[i_jones_elem].arg()
+                            ))
+                            .join(", ")
+                    );
+                    // lsq
+                    writeln!(&mut bp_cal_file, "{bp_cal_line}")?;
+                    // fit
+                    writeln!(&mut bp_cal_file, "{bp_cal_line}")?;
+                }
+            }
+            di_jm_file.flush()?;
+            bp_cal_file.flush()?;
+        }
+
+        Ok(())
+    }
+    inner(sols, dir.as_ref(), context)
+}
+
+/// Write a Jones matrix as a line of a DI_JonesMatrices file.
+fn write_rts_jones<W: Write>(file: &mut W, j: Jones<f64>) -> Result<(), std::io::Error> {
+    writeln!(
+        file,
+        "{:+e}, {:+e}, {:+e}, {:+e}, {:+e}, {:+e}, {:+e}, {:+e}",
+        j[0].re, j[0].im, j[1].re, j[1].im, j[2].re, j[2].im, j[3].re, j[3].im
+    )
+}
 
 #[derive(Error, Debug)]
 pub(crate) enum RtsReadSolsError {
@@ -676,17 +719,26 @@ pub(crate) enum RtsReadSolsError {
     Mwalib(#[from] mwalib::MwalibError),
 }
 
-// #[derive(Error, Debug)]
-// pub(crate) enum RtsWriteSolsError {
-//     #[error("Attempted to write RTS solutions into '{0}', but this isn't a directory")]
-//     NotADir(PathBuf),
+#[derive(Error, Debug)]
+pub(crate) enum RtsWriteSolsError {
+    #[error("Attempted to write RTS solutions into '{0}', but this isn't a directory")]
+    NotADir(PathBuf),
+
+    #[error("Couldn't create directory '{dir}' (or its parents): {err}")]
+    CouldntMakeDir { dir: PathBuf, err: std::io::Error },
 
-//     #[error("Couldn't create directory '{dir}' (or its parents): {err}")]
-//     CouldntMakeDir { dir: PathBuf, err: std::io::Error },
+    #[error("The solutions have {sols} tiles, but the metafits has {metafits}")]
+    TileCountMismatch { sols: usize, metafits: usize },
 
-//     #[error("Error when reading metafits: {0}")]
-//     Mwalib(#[from] mwalib::MwalibError),
+    #[error("The solutions have {num_chanblocks} chanblocks, which can't be evenly split over the {num_coarse_chans} coarse channels in the metafits")]
+    ChanblockCountMismatch {
+        num_chanblocks: usize,
+        num_coarse_chans: usize,
+    },
+
+    #[error("Error when reading metafits: {0}")]
+    Mwalib(#[from] mwalib::MwalibError),
 
-//     #[error(transparent)]
-//     IO(#[from] std::io::Error),
-// }
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
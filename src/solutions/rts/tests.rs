@@ -4,7 +4,9 @@
 
 use std::fs::File;
 
+use approx::assert_abs_diff_eq;
 use flate2::read::GzDecoder;
+use marlu::c64;
 use tar::Archive;
 use tempfile::TempDir;
 
@@ -68,3 +70,94 @@ fn test_rts_solutions_correctly_read() {
         ])
     );
 }
+
+#[test]
+fn test_rts_solutions_write_read_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let metafits = "test_files/1088284872/1088284872.metafits";
+    let context = MetafitsContext::new(metafits, None).unwrap();
+
+    // 40 kHz chanblocks.
+    let num_chanblocks = 768;
+    let flagged_tiles = vec![3, 112, 113, 114, 115, 116, 117, 118, 119, 123];
+    // The standard flagged channels of each coarse channel, and all of the
+    // channels of the sixth coarse channel.
+    let flagged_chanblocks: Vec<u16> = (0..num_chanblocks as u16)
+        .filter(|i_chan| [0, 1, 16, 30, 31].contains(&(i_chan % 32)) || i_chan / 32 == 5)
+        .collect();
+    let mut di_jones = Array3::from_shape_fn((1, 128, num_chanblocks), |(_, i_tile, i_chan)| {
+        let x = i_tile as f64 / 128.0;
+        let f = i_chan as f64 / num_chanblocks as f64;
+        Jones::from([
+            c64::from_polar(1.0 + x + 0.2 * f, 0.5 - x + 3.0 * f),
+            c64::from_polar(0.05 + 0.01 * f, 2.0 * x - f),
+            c64::from_polar(0.03 + 0.02 * x, -x + 0.3 * f),
+            c64::from_polar(0.9 + x * f, -0.2 + x - 2.0 * f),
+        ])
+    });
+    for &i_tile in &flagged_tiles {
+        di_jones.slice_mut(s![.., i_tile, ..]).fill(Jones::nan());
+    }
+    for &i_chan in &flagged_chanblocks {
+        di_jones
+            .slice_mut(s![.., .., usize::from(i_chan)])
+            .fill(Jones::nan());
+    }
+    // An unflagged tile without solutions in a coarse channel.
+    di_jones.slice_mut(s![.., 7, 64..96]).fill(Jones::nan());
+    let sols = CalibrationSolutions {
+        di_jones,
+        flagged_tiles: flagged_tiles.clone(),
+        flagged_chanblocks: flagged_chanblocks.clone(),
+        ..Default::default()
+    };
+
+    write(&sols, temp_dir.path(), &context).unwrap();
+    // No files are written for the completely-flagged coarse channel.
+    let num_files = std::fs::read_dir(temp_dir.path()).unwrap().count();
+    assert_eq!(num_files, 2 * 23);
+
+    let sols2 = super::read(temp_dir.path(), metafits).unwrap();
+    assert_eq!(sols2.flagged_tiles, flagged_tiles);
+    assert_eq!(sols2.flagged_chanblocks, flagged_chanblocks);
+    assert_eq!(sols2.di_jones.dim(), sols.di_jones.dim());
+    for (j, j2) in sols.di_jones.iter().zip(sols2.di_jones.iter()) {
+        if j.any_nan() {
+            assert!(j2.any_nan());
+        } else {
+            assert_abs_diff_eq!(*j, *j2, epsilon = 1e-10);
+        }
+    }
+}
+
+#[test]
+fn test_rts_solutions_write_bad_shapes() {
+    let temp_dir = TempDir::new().unwrap();
+    let context = MetafitsContext::new("test_files/1088284872/1088284872.metafits", None).unwrap();
+
+    let sols = CalibrationSolutions {
+        di_jones: Array3::from_elem((1, 127, 768), Jones::identity()),
+        ..Default::default()
+    };
+    let result = write(&sols, temp_dir.path(), &context);
+    assert!(matches!(
+        result,
+        Err(RtsWriteSolsError::TileCountMismatch {
+            sols: 127,
+            metafits: 128
+        })
+    ));
+
+    let sols = CalibrationSolutions {
+        di_jones: Array3::from_elem((1, 128, 100), Jones::identity()),
+        ..Default::default()
+    };
+    let result = write(&sols, temp_dir.path(), &context);
+    assert!(matches!(
+        result,
+        Err(RtsWriteSolsError::ChanblockCountMismatch {
+            num_chanblocks: 100,
+            num_coarse_chans: 24
+        })
+    ));
+}
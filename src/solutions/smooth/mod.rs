@@ -0,0 +1,379 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Code to smooth calibration solutions over frequency.
+//!
+//! For each timeblock, tile and Jones matrix element ("polarisation"), a model
+//! is fitted across chanblocks. The amplitudes are modelled with a low-order
+//! polynomial, the phases with a linear delay and, optionally, a cable
+//! reflection is modelled with a complex sinusoid. Flagged and non-converged
+//! chanblocks are not used in the fit, but are filled with the model.
+
+#[cfg(test)]
+mod tests;
+
+use std::f64::consts::TAU;
+
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use rayon::prelude::*;
+
+use super::CalibrationSolutions;
+use crate::math::least_squares;
+
+/// The default order of the polynomial used to model solution amplitudes.
+pub(crate) const DEFAULT_AMP_ORDER: usize = 3;
+
+/// The number of times to alternate between fitting a cable reflection and the
+/// smooth model.
+const NUM_REFLECTION_ITERATIONS: usize = 3;
+
+/// Parameters controlling the smoothing of calibration solutions.
+#[derive(Debug, Clone)]
+pub(crate) struct SmoothParams {
+    /// The order of the polynomial used to model amplitudes.
+    pub(crate) amp_order: usize,
+
+    /// Should a cable reflection be fitted (in addition to the amplitude and
+    /// phase models)?
+    pub(crate) reflection: bool,
+
+    /// Only replace flagged and non-converged chanblocks with the model, rather
+    /// than all chanblocks.
+    pub(crate) fill_only: bool,
+}
+
+impl Default for SmoothParams {
+    fn default() -> Self {
+        Self {
+            amp_order: DEFAULT_AMP_ORDER,
+            reflection: false,
+            fill_only: false,
+        }
+    }
+}
+
+/// Smooth calibration solutions in place. Tiles without any solutions (i.e. all
+/// NaN) are left alone, as are any timeblock-tile pairs that don't have enough
+/// good chanblocks to be fitted.
+pub(crate) fn smooth(sols: &mut CalibrationSolutions, params: &SmoothParams) {
+    let num_chanblocks = sols.di_jones.len_of(Axis(2));
+
+    // If chanblock frequencies aren't available, the chanblock indices are
+    // used; the units don't matter for the fits.
+    let freqs: Vec<f64> = match sols.chanblock_freqs.as_ref() {
+        Some(f) if f.len() == num_chanblocks && f.iter().all(|f| f.is_finite()) => f.to_vec(),
+        _ => (0..num_chanblocks).map(|i| i as f64).collect(),
+    };
+
+    // Chanblocks that are flagged or failed to converge are excluded from the
+    // fits.
+    let usable = sols.get_usable_chanblocks();
+
+    sols.di_jones
+        .outer_iter_mut()
+        .zip(usable.outer_iter())
+        .for_each(|(mut di_jones_tc, usable)| {
+            di_jones_tc
+                .outer_iter_mut()
+                .into_par_iter()
+                .for_each(|mut di_jones| {
+                    smooth_tile(di_jones.view_mut(), usable, &freqs, params);
+                });
+        });
+
+    // Chanblocks that have been filled are no longer flagged.
+    let di_jones = &sols.di_jones;
+    sols.flagged_chanblocks.retain(|&i_chanblock| {
+        di_jones
+            .slice(s![.., .., i_chanblock as usize])
+            .iter()
+            .all(|j| j.any_nan())
+    });
+}
+
+/// Smooth the solutions of a single tile (for a single timeblock).
+fn smooth_tile(
+    mut di_jones: ArrayViewMut1<Jones<f64>>,
+    usable: ArrayView1<bool>,
+    freqs: &[f64],
+    params: &SmoothParams,
+) {
+    // Which chanblocks have good solutions?
+    let good: Vec<usize> = di_jones
+        .iter()
+        .zip(usable.iter())
+        .enumerate()
+        .filter(|(_, (j, &u))| u && !j.any_nan())
+        .map(|(i, _)| i)
+        .collect();
+    if good.is_empty() {
+        return;
+    }
+    let good_freqs: Vec<f64> = good.iter().map(|&i| freqs[i]).collect();
+
+    let mut models = Vec::with_capacity(4);
+    for i_pol in 0..4 {
+        let gains: Vec<c64> = good.iter().map(|&i| di_jones[i][i_pol]).collect();
+        match ElementModel::fit(&good_freqs, &gains, params) {
+            Some(m) => models.push(m),
+            // If we can't fit all polarisations, leave this tile alone.
+            None => return,
+        }
+    }
+
+    for ((j, &usable), &freq) in di_jones.iter_mut().zip(usable.iter()).zip(freqs) {
+        if params.fill_only && usable && !j.any_nan() {
+            continue;
+        }
+        *j = Jones::from([
+            models[0].eval(freq),
+            models[1].eval(freq),
+            models[2].eval(freq),
+            models[3].eval(freq),
+        ]);
+    }
+}
+
+/// A model of a single Jones matrix element over frequency.
+#[derive(Debug)]
+struct ElementModel {
+    /// The polynomial coefficients of the amplitude, in order of increasing
+    /// power. The polynomial is evaluated on frequencies scaled to the range
+    /// -1 to 1.
+    amp_coeffs: Vec<f64>,
+
+    /// The central frequency of the fit.
+    freq_centre: f64,
+
+    /// Half of the frequency range of the fit.
+    freq_scale: f64,
+
+    /// The linear delay of the phases (the inverse of the frequency units).
+    delay: f64,
+
+    /// The phase at `freq_centre` \[radians\].
+    phase_offset: f64,
+
+    /// The complex amplitude and delay of a cable reflection.
+    reflection: Option<(c64, f64)>,
+}
+
+impl ElementModel {
+    /// Fit a model to gains at the supplied frequencies. `None` is returned if
+    /// there are too few gains to fit.
+    fn fit(freqs: &[f64], gains: &[c64], params: &SmoothParams) -> Option<ElementModel> {
+        let mut model = Self::fit_smooth(freqs, gains, params.amp_order)?;
+        if !params.reflection || model.amp_coeffs.iter().all(|&c| c == 0.0) {
+            return Some(model);
+        }
+
+        // A reflection biases the smooth model, which in turn biases the
+        // reflection fit. Alternate between the two fits to reduce this.
+        for _ in 0..NUM_REFLECTION_ITERATIONS {
+            let reflection = match model.fit_reflection(freqs, gains) {
+                Some(r) => r,
+                None => break,
+            };
+            let corrected: Vec<c64> = gains
+                .iter()
+                .zip(freqs)
+                .map(|(g, f)| g / reflection_factor(reflection, f - model.freq_centre))
+                .collect();
+            model = Self::fit_smooth(freqs, &corrected, params.amp_order)?;
+            model.reflection = Some(reflection);
+        }
+
+        Some(model)
+    }
+
+    /// Fit the amplitude polynomial and linear phase delay to gains.
+    fn fit_smooth(freqs: &[f64], gains: &[c64], amp_order: usize) -> Option<ElementModel> {
+        let num_gains = gains.len();
+        let (min_freq, max_freq) = freqs
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &f| {
+                (min.min(f), max.max(f))
+            });
+        let freq_centre = (min_freq + max_freq) / 2.0;
+        let freq_scale = if max_freq > min_freq {
+            (max_freq - min_freq) / 2.0
+        } else {
+            1.0
+        };
+
+        // Elements that are all zero (e.g. leakage terms of diagonal
+        // solutions) stay zero.
+        if gains.iter().all(|g| g.norm_sqr() == 0.0) {
+            return Some(ElementModel {
+                amp_coeffs: vec![0.0],
+                freq_centre,
+                freq_scale,
+                delay: 0.0,
+                phase_offset: 0.0,
+                reflection: None,
+            });
+        }
+        if num_gains < amp_order + 2 {
+            return None;
+        }
+
+        // Amplitudes.
+        let ones = Array1::ones(num_gains);
+        let design = Array2::from_shape_fn((num_gains, amp_order + 1), |(i, p)| {
+            ((freqs[i] - freq_centre) / freq_scale).powi(p as i32)
+        });
+        let amps = Array1::from_iter(gains.iter().map(|g| g.norm()));
+        let amp_coeffs = least_squares(design.view(), amps.view(), ones.view())?.to_vec();
+
+        // Get an initial estimate of the delay from the phase differences of
+        // adjacent chanblocks. This avoids having to unwrap phases.
+        let min_df = freqs
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .fold(f64::INFINITY, f64::min);
+        let mut delay = 0.0;
+        if min_df.is_finite() && min_df > 0.0 {
+            let z: c64 = freqs
+                .windows(2)
+                .zip(gains.windows(2))
+                .filter(|(f, _)| (f[1] - f[0]) < min_df * 1.01)
+                .map(|(_, g)| g[1] * g[0].conj())
+                .sum();
+            delay = z.arg() / (TAU * min_df);
+        }
+        let phase_offset = gains
+            .iter()
+            .zip(freqs)
+            .map(|(g, f)| g * c64::cis(-TAU * (f - freq_centre) * delay))
+            .sum::<c64>()
+            .arg();
+
+        // Refine the delay and phase with a linear fit to the residual phases.
+        let design = Array2::from_shape_fn((num_gains, 2), |(i, p)| {
+            ((freqs[i] - freq_centre) / freq_scale).powi(p as i32)
+        });
+        let residual_phases =
+            Array1::from_iter(gains.iter().zip(freqs).map(|(g, f)| {
+                (g * c64::cis(-(TAU * (f - freq_centre) * delay + phase_offset))).arg()
+            }));
+        let weights = Array1::from_iter(gains.iter().map(|g| g.norm()));
+        let (delay, phase_offset) =
+            match least_squares(design.view(), residual_phases.view(), weights.view()) {
+                Some(c) => (delay + c[1] / (TAU * freq_scale), phase_offset + c[0]),
+                None => (delay, phase_offset),
+            };
+
+        Some(ElementModel {
+            amp_coeffs,
+            freq_centre,
+            freq_scale,
+            delay,
+            phase_offset,
+            reflection: None,
+        })
+    }
+
+    /// Search for the strongest sinusoid in the fractional residuals of the
+    /// gains (ignoring any existing reflection). Delays shorter than the
+    /// inverse of the bandwidth are already handled by the amplitude and phase
+    /// models, and delays longer than the inverse of twice the chanblock width
+    /// can't be detected.
+    fn fit_reflection(&self, freqs: &[f64], gains: &[c64]) -> Option<(c64, f64)> {
+        let min_df = freqs
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .fold(f64::INFINITY, f64::min);
+        if !min_df.is_finite() || min_df <= 0.0 {
+            return None;
+        }
+
+        let smooth = ElementModel {
+            reflection: None,
+            amp_coeffs: self.amp_coeffs.clone(),
+            ..*self
+        };
+        let residuals: Vec<(f64, c64)> = freqs
+            .iter()
+            .zip(gains)
+            .filter_map(|(&f, &g)| {
+                let m = smooth.eval(f);
+                if m.norm_sqr() > 0.0 {
+                    Some((f - self.freq_centre, g / m - 1.0))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if residuals.is_empty() {
+            return None;
+        }
+        let fit_delay = |delay: f64| -> c64 {
+            residuals
+                .iter()
+                .map(|(df, r)| r * c64::cis(-TAU * df * delay))
+                .sum::<c64>()
+                / residuals.len() as f64
+        };
+
+        let bandwidth = 2.0 * self.freq_scale;
+        let min_delay = 1.0 / bandwidth;
+        let max_delay = 1.0 / (2.0 * min_df);
+        let mut delay_step = 1.0 / (4.0 * bandwidth);
+        let num_steps = ((max_delay - min_delay) / delay_step).floor() as i64;
+        if num_steps < 0 {
+            return None;
+        }
+
+        // Coarse search over all delays.
+        let mut best: Option<(c64, f64)> = None;
+        for i in 0..=num_steps {
+            for sign in [-1.0, 1.0] {
+                let delay = sign * (min_delay + i as f64 * delay_step);
+                let amp = fit_delay(delay);
+                if best.map(|(a, _)| amp.norm() > a.norm()).unwrap_or(true) {
+                    best = Some((amp, delay));
+                }
+            }
+        }
+
+        // Fine searches around the best delay.
+        let (mut best_amp, mut best_delay) = best?;
+        for _ in 0..3 {
+            let centre = best_delay;
+            for i in -10..=10 {
+                let delay = centre + i as f64 * delay_step / 10.0;
+                let amp = fit_delay(delay);
+                if amp.norm() > best_amp.norm() {
+                    (best_amp, best_delay) = (amp, delay);
+                }
+            }
+            delay_step /= 10.0;
+        }
+
+        Some((best_amp, best_delay))
+    }
+
+    /// Evaluate the model at a frequency.
+    fn eval(&self, freq: f64) -> c64 {
+        let x = (freq - self.freq_centre) / self.freq_scale;
+        let amp = self
+            .amp_coeffs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &c| acc * x + c);
+        let df = freq - self.freq_centre;
+        let mut gain = c64::from_polar(amp, TAU * df * self.delay + self.phase_offset);
+        if let Some(reflection) = self.reflection {
+            gain *= reflection_factor(reflection, df);
+        }
+        gain
+    }
+}
+
+/// The multiplicative effect of a cable reflection (complex amplitude and
+/// delay) on a gain, `df` away from the central frequency.
+fn reflection_factor((amp, delay): (c64, f64), df: f64) -> c64 {
+    1.0 + amp * c64::cis(TAU * df * delay)
+}
@@ -0,0 +1,152 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use approx::assert_abs_diff_eq;
+use marlu::{c64, Jones};
+use ndarray::prelude::*;
+use vec1::Vec1;
+
+use super::*;
+
+const NUM_CHANBLOCKS: usize = 48;
+
+fn get_freqs() -> Vec<f64> {
+    (0..NUM_CHANBLOCKS)
+        .map(|i| 167e6 + i as f64 * 640e3)
+        .collect()
+}
+
+/// A gain with a quadratic amplitude and a linear phase.
+fn get_gain(freq: f64, i_tile: usize) -> c64 {
+    let x = (freq - 182.04e6) / 15.04e6;
+    let amp = 1.0 + 0.1 * x - 0.05 * x * x + 0.01 * i_tile as f64;
+    let delay = 20e-9 * (i_tile as f64 + 1.0);
+    c64::from_polar(amp, TAU * (freq - 182.04e6) * delay + 0.3)
+}
+
+fn get_sols(freqs: &[f64]) -> CalibrationSolutions {
+    let di_jones = Array3::from_shape_fn((1, 3, NUM_CHANBLOCKS), |(_, i_tile, i_chanblock)| {
+        let g = get_gain(freqs[i_chanblock], i_tile);
+        Jones::from([g, c64::default(), c64::default(), g * 1.1])
+    });
+    CalibrationSolutions {
+        di_jones,
+        chanblock_freqs: Some(Vec1::try_from_vec(freqs.to_vec()).unwrap()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_smooth_recovers_model() {
+    let freqs = get_freqs();
+    let mut sols = get_sols(&freqs);
+    let expected = sols.di_jones.clone();
+
+    // Flag a chanblock and make another one very noisy, but say it didn't
+    // converge. Make the last tile flagged.
+    sols.di_jones
+        .slice_mut(s![.., .., 16])
+        .fill(Jones::from([c64::new(f64::NAN, f64::NAN); 4]));
+    sols.flagged_chanblocks.push(16);
+    sols.di_jones
+        .slice_mut(s![.., .., 30])
+        .fill(Jones::identity() * 100.0);
+    let mut results = Array2::from_elem((1, NUM_CHANBLOCKS), 1e-10);
+    results[(0, 16)] = f64::NAN;
+    results[(0, 30)] = 1e-2;
+    sols.calibration_results = Some(results);
+    sols.min_threshold = Some(1e-4);
+    sols.di_jones
+        .slice_mut(s![.., 2, ..])
+        .fill(Jones::from([c64::new(f64::NAN, f64::NAN); 4]));
+
+    smooth(&mut sols, &SmoothParams::default());
+
+    for i_tile in 0..2 {
+        assert_abs_diff_eq!(
+            sols.di_jones.slice(s![0, i_tile, ..]),
+            expected.slice(s![0, i_tile, ..]),
+            epsilon = 1e-10
+        );
+    }
+    assert!(sols
+        .di_jones
+        .slice(s![0, 2, ..])
+        .iter()
+        .all(|j| j.any_nan()));
+    assert!(sols.flagged_chanblocks.is_empty());
+}
+
+#[test]
+fn test_smooth_fill_only() {
+    let freqs = get_freqs();
+    let mut sols = get_sols(&freqs);
+    let expected = sols.di_jones.clone();
+
+    // Add a little noise to one chanblock; it should be left alone.
+    sols.di_jones[(0, 0, 5)] *= 1.01;
+    sols.di_jones
+        .slice_mut(s![.., .., 16])
+        .fill(Jones::from([c64::new(f64::NAN, f64::NAN); 4]));
+    sols.flagged_chanblocks.push(16);
+    let noisy = sols.di_jones[(0, 0, 5)];
+
+    smooth(
+        &mut sols,
+        &SmoothParams {
+            fill_only: true,
+            ..Default::default()
+        },
+    );
+
+    assert_abs_diff_eq!(sols.di_jones[(0, 0, 5)], noisy);
+    assert_abs_diff_eq!(
+        sols.di_jones.slice(s![0, .., 16]),
+        expected.slice(s![0, .., 16]),
+        epsilon = 1e-3
+    );
+    assert!(sols.flagged_chanblocks.is_empty());
+}
+
+#[test]
+fn test_smooth_reflection() {
+    let freqs = get_freqs();
+    let mut sols = get_sols(&freqs);
+    // Add a cable reflection to every gain.
+    let reflection_delay = 600e-9;
+    let reflection_amp = c64::from_polar(0.02, 1.0);
+    for mut di_jones in sols.di_jones.lanes_mut(Axis(2)) {
+        for (j, &freq) in di_jones.iter_mut().zip(freqs.iter()) {
+            let df = freq - 182.04e6;
+            *j *= 1.0 + reflection_amp * c64::cis(TAU * df * reflection_delay);
+        }
+    }
+    let expected = sols.di_jones.clone();
+
+    // Without modelling the reflection, the smoothed solutions don't match.
+    let mut smoothed = CalibrationSolutions {
+        di_jones: sols.di_jones.clone(),
+        chanblock_freqs: sols.chanblock_freqs.clone(),
+        ..Default::default()
+    };
+    smooth(&mut smoothed, &SmoothParams::default());
+    let max_diff = (&smoothed.di_jones - &expected)
+        .iter()
+        .map(|j| j.norm_sqr().iter().sum::<f64>().sqrt())
+        .fold(0.0, f64::max);
+    assert!(max_diff > 1e-2, "{max_diff}");
+
+    smooth(
+        &mut sols,
+        &SmoothParams {
+            reflection: true,
+            ..Default::default()
+        },
+    );
+    let max_diff = (&sols.di_jones - &expected)
+        .iter()
+        .map(|j| j.norm_sqr().iter().sum::<f64>().sqrt())
+        .fold(0.0, f64::max);
+    assert!(max_diff < 1e-6, "{max_diff}");
+}
@@ -4,12 +4,20 @@
 
 use approx::assert_abs_diff_eq;
 use hifitime::Epoch;
-use marlu::{c64, Jones};
+use marlu::{
+    c32, c64,
+    rubbl_casatables::{Table, TableOpenMode},
+    Jones,
+};
 use ndarray::prelude::*;
+use serial_test::serial; // casacore isn't thread safe.
 use vec1::{vec1, Vec1};
 
 use super::*;
-use crate::io::read::{pfb_gains::PfbFlavour, RawDataCorrections};
+use crate::{
+    di_calibrate::{SolveMode, Solver},
+    io::read::{pfb_gains::PfbFlavour, RawDataCorrections},
+};
 
 fn make_solutions() -> CalibrationSolutions {
     let num_timeblocks = 2;
@@ -41,6 +49,17 @@ fn make_solutions() -> CalibrationSolutions {
     di_jones
         .slice_mut(s![.., .., flagged_chanblocks.clone()])
         .fill(Jones::nan());
+    let xy_phase = XyPhase {
+        phases: Array1::from_shape_fn(num_chanblocks, |i| 0.1 * i as f64),
+        measured_phases: Array1::from_shape_fn(num_chanblocks, |i| {
+            if flagged_chanblocks.contains(&i) {
+                f64::NAN
+            } else {
+                0.1 * i as f64 + 0.01
+            }
+        }),
+        delay: 1.5e-9,
+    };
 
     CalibrationSolutions {
         di_jones,
@@ -68,6 +87,8 @@ fn make_solutions() -> CalibrationSolutions {
         max_iterations: Some(30),
         stop_threshold: Some(1e-10),
         min_threshold: Some(1e-5),
+        solve_mode: Some(SolveMode::PhaseOnly),
+        solver: Some(Solver::Anderson),
         raw_data_corrections: Some(RawDataCorrections {
             pfb_flavour: PfbFlavour::Cotter2014,
             digital_gains: true,
@@ -81,6 +102,33 @@ fn make_solutions() -> CalibrationSolutions {
         beam_file: None,
         calibration_results: Some(Array2::from_elem((num_timeblocks, num_chanblocks), 1e-6)),
         baseline_weights: Vec1::try_from_vec((0..num_baselines).map(|i| i as _).collect()).ok(),
+        residual_stats: Some(ResidualStats {
+            tiles: Array3::from_shape_fn(
+                (num_timeblocks, num_tiles, num_chanblocks),
+                |(i_timeblock, i_tile, i_chanblock)| ResidualStat {
+                    rms: i_tile as f64,
+                    chi_squared: i_chanblock as f64,
+                    num_vis: i_timeblock + 1,
+                },
+            ),
+            // Make one of the baselines flagged.
+            baselines: Array2::from_shape_fn((num_timeblocks, num_baselines), |(_, i_bl)| {
+                if i_bl == 0 {
+                    ResidualStat::default()
+                } else {
+                    ResidualStat {
+                        rms: 0.5,
+                        chi_squared: i_bl as f64,
+                        num_vis: 7,
+                    }
+                }
+            }),
+        }),
+        xy_phase: Some(xy_phase),
+        phase_reference: Some(PhaseReference {
+            tile: 1,
+            cross_hands: true,
+        }),
         uvw_min: Some(82.0),
         uvw_max: Some(f64::INFINITY),
         freq_centroid: Some(182e6),
@@ -130,6 +178,9 @@ fn test_write_and_read_hyperdrive_solutions() {
     assert!(sols_from_disk.min_threshold.is_some());
     let disk_min_threshold = sols_from_disk.min_threshold.unwrap();
     assert_abs_diff_eq!(disk_min_threshold, sols.min_threshold.unwrap());
+    assert_eq!(sols_from_disk.solve_mode, Some(SolveMode::PhaseOnly));
+    assert_eq!(sols_from_disk.solver, Some(Solver::Anderson));
+    assert_eq!(sols_from_disk.phase_reference, sols.phase_reference);
 
     assert_eq!(sols_from_disk.flagged_tiles[..], [3, 4]);
     assert_eq!(sols_from_disk.flagged_chanblocks[..], [5, 6, 7]);
@@ -159,6 +210,41 @@ fn test_write_and_read_hyperdrive_solutions() {
     assert!(sols_from_disk.baseline_weights.is_some());
     let disk_baseline_weights = sols_from_disk.baseline_weights.unwrap();
     assert_abs_diff_eq!(disk_baseline_weights[..], sols.baseline_weights.unwrap());
+
+    assert!(sols_from_disk.residual_stats.is_some());
+    let disk_residual_stats = sols_from_disk.residual_stats.unwrap();
+    let residual_stats = sols.residual_stats.unwrap();
+    assert_eq!(disk_residual_stats.tiles, residual_stats.tiles);
+    assert_eq!(
+        disk_residual_stats.baselines.dim(),
+        residual_stats.baselines.dim()
+    );
+    // NaNs aren't equal to each other, so check the flagged baseline
+    // separately.
+    assert!(disk_residual_stats.baselines[(0, 0)].rms.is_nan());
+    assert!(disk_residual_stats.baselines[(0, 0)].chi_squared.is_nan());
+    assert_eq!(disk_residual_stats.baselines[(0, 0)].num_vis, 0);
+    assert_eq!(
+        disk_residual_stats.baselines.slice(s![.., 1..]),
+        residual_stats.baselines.slice(s![.., 1..])
+    );
+
+    assert!(sols_from_disk.xy_phase.is_some());
+    let disk_xy_phase = sols_from_disk.xy_phase.unwrap();
+    let xy_phase = sols.xy_phase.unwrap();
+    assert_abs_diff_eq!(disk_xy_phase.phases, xy_phase.phases);
+    assert_abs_diff_eq!(disk_xy_phase.delay, xy_phase.delay);
+    for (disk, expected) in disk_xy_phase
+        .measured_phases
+        .iter()
+        .zip(xy_phase.measured_phases.iter())
+    {
+        if expected.is_nan() {
+            assert!(disk.is_nan());
+        } else {
+            assert_abs_diff_eq!(disk, expected);
+        }
+    }
 }
 
 #[test]
@@ -175,6 +261,8 @@ fn test_write_and_read_hyperdrive_solutions_no_metadata() {
         max_iterations: _,
         stop_threshold: _,
         min_threshold: _,
+        solve_mode: _,
+        solver: _,
         raw_data_corrections: _,
         tile_names: _,
         dipole_gains: _,
@@ -182,6 +270,9 @@ fn test_write_and_read_hyperdrive_solutions_no_metadata() {
         beam_file: _,
         calibration_results: _,
         baseline_weights: _,
+        residual_stats: _,
+        xy_phase: _,
+        phase_reference: _,
         uvw_min: _,
         uvw_max: _,
         freq_centroid: _,
@@ -225,6 +316,8 @@ fn test_write_and_read_hyperdrive_solutions_no_metadata() {
     assert_eq!(sols_from_disk.max_iterations, sols.max_iterations);
     assert_eq!(sols_from_disk.stop_threshold, sols.stop_threshold);
     assert_eq!(sols_from_disk.min_threshold, sols_from_disk.min_threshold);
+    assert_eq!(sols_from_disk.solve_mode, sols.solve_mode);
+    assert_eq!(sols_from_disk.solver, sols.solver);
 
     assert_eq!(sols_from_disk.flagged_tiles, sols.flagged_tiles);
     assert_eq!(sols_from_disk.flagged_chanblocks, sols.flagged_chanblocks);
@@ -280,13 +373,17 @@ fn test_write_and_read_ao_solutions() {
     assert!(result.is_ok());
     let sols_from_disk = result.unwrap();
 
-    assert_eq!(sols.di_jones.dim(), sols_from_disk.di_jones.dim());
+    // The format can't hold XY-phase corrections, so they're included in the
+    // Jones matrices.
+    assert!(sols_from_disk.xy_phase.is_none());
+    let expected = sols.get_di_jones_with_xy_phase();
+    assert_eq!(expected.dim(), sols_from_disk.di_jones.dim());
     // Can't use assert_abs_diff_eq on the whole array, because it rejects NaN
     // equality.
-    sols.di_jones
-        .into_iter()
-        .zip(sols_from_disk.di_jones.into_iter())
-        .for_each(|(expected, result)| {
+    expected
+        .iter()
+        .zip(sols_from_disk.di_jones.iter())
+        .for_each(|(&expected, &result)| {
             if expected.any_nan() {
                 assert!(result.any_nan());
             } else {
@@ -311,3 +408,268 @@ fn test_write_and_read_ao_solutions() {
     assert_eq!(disk_average_timestamps.len(), 1);
     assert_abs_diff_eq!(disk_average_timestamps[0].to_gpst_seconds(), 1090008650.0);
 }
+
+#[test]
+fn test_cal_solution_type_from_path() {
+    assert!(matches!(
+        CalSolutionType::from_path(Path::new("sols.fits")),
+        Some(CalSolutionType::Fits)
+    ));
+    assert!(matches!(
+        CalSolutionType::from_path(Path::new("sols.bin")),
+        Some(CalSolutionType::Bin)
+    ));
+    assert!(matches!(
+        CalSolutionType::from_path(Path::new("sols.rts")),
+        Some(CalSolutionType::Rts)
+    ));
+    // A path without an extension is only for RTS solutions if it's an
+    // existing directory.
+    assert!(CalSolutionType::from_path(Path::new("does_not_exist")).is_none());
+    assert!(CalSolutionType::from_path(Path::new("sols.txt")).is_none());
+    let tmp_dir = tempfile::tempdir().expect("Couldn't make tmp dir");
+    assert!(matches!(
+        CalSolutionType::from_path(tmp_dir.path()),
+        Some(CalSolutionType::Rts)
+    ));
+}
+
+#[test]
+fn test_time_interpolation() {
+    let mut di_jones = Array3::from_elem((2, 2, 1), Jones::identity());
+    di_jones
+        .slice_mut(s![1, .., ..])
+        .fill(Jones::identity() * 3.0);
+    // The second tile is flagged in the first timeblock.
+    di_jones[(0, 1, 0)] = Jones::from([c64::new(f64::NAN, f64::NAN); 4]);
+    let sols = CalibrationSolutions {
+        di_jones,
+        start_timestamps: Some(vec1![
+            Epoch::from_gpst_seconds(1090008640.0),
+            Epoch::from_gpst_seconds(1090008660.0)
+        ]),
+        end_timestamps: Some(vec1![
+            Epoch::from_gpst_seconds(1090008660.0),
+            Epoch::from_gpst_seconds(1090008680.0)
+        ]),
+        average_timestamps: Some(vec1![
+            Epoch::from_gpst_seconds(1090008650.0),
+            Epoch::from_gpst_seconds(1090008670.0)
+        ]),
+        ..Default::default()
+    };
+
+    // Halfway between the average timestamps.
+    let timestamp = Epoch::from_gpst_seconds(1090008660.0);
+    let result = sols.get_interpolated_timeblock(timestamp, 0.5, TimeInterpolation::Linear);
+    assert_abs_diff_eq!(result[(0, 0)], Jones::identity() * 2.0);
+    assert_abs_diff_eq!(result[(1, 0)], Jones::identity() * 3.0);
+
+    // A quarter of the way.
+    let timestamp = Epoch::from_gpst_seconds(1090008655.0);
+    let result = sols.get_interpolated_timeblock(timestamp, 0.375, TimeInterpolation::Linear);
+    assert_abs_diff_eq!(result[(0, 0)], Jones::identity() * 1.5);
+
+    // Nearest uses the bounding timeblock.
+    let result = sols.get_interpolated_timeblock(timestamp, 0.375, TimeInterpolation::Nearest);
+    assert_abs_diff_eq!(result[(0, 0)], Jones::identity());
+    assert!(result[(1, 0)].any_nan());
+
+    // Outside of the average timestamps, there's nothing to interpolate.
+    let timestamp = Epoch::from_gpst_seconds(1090008675.0);
+    let result = sols.get_interpolated_timeblock(timestamp, 0.875, TimeInterpolation::Linear);
+    assert_abs_diff_eq!(result[(0, 0)], Jones::identity() * 3.0);
+}
+
+#[test]
+fn test_chanblock_interps() {
+    // Solutions at 80 kHz, data at 40 kHz.
+    let mut sols = CalibrationSolutions {
+        di_jones: Array3::from_elem((1, 1, 3), Jones::identity()),
+        chanblock_freqs: Some(vec1![100.0e6, 100.08e6, 100.16e6]),
+        ..Default::default()
+    };
+    let freqs = [99.9e6, 99.98e6, 100.02e6, 100.06e6, 100.19e6, 100.3e6];
+
+    let result = sols
+        .get_chanblock_interps(&freqs, FreqInterpolation::Nearest)
+        .unwrap();
+    let indices = result
+        .iter()
+        .map(|i| i.map(|i| (i.lower, i.upper)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        indices,
+        [
+            None,
+            Some((0, 0)),
+            Some((0, 0)),
+            Some((1, 1)),
+            Some((2, 2)),
+            None
+        ]
+    );
+    assert!(result.iter().flatten().all(|i| i.weight == 0.0));
+
+    let result = sols
+        .get_chanblock_interps(&freqs, FreqInterpolation::Linear)
+        .unwrap();
+    assert!(result[0].is_none());
+    assert!(result[5].is_none());
+    // Outside of the solution frequencies, there's nothing to interpolate.
+    let i = result[1].unwrap();
+    assert_eq!((i.lower, i.upper), (0, 0));
+    let i = result[2].unwrap();
+    assert_eq!((i.lower, i.upper), (0, 1));
+    assert_abs_diff_eq!(i.weight, 0.25, epsilon = 1e-6);
+    let i = result[3].unwrap();
+    assert_eq!((i.lower, i.upper), (0, 1));
+    assert_abs_diff_eq!(i.weight, 0.75, epsilon = 1e-6);
+    let i = result[4].unwrap();
+    assert_eq!((i.lower, i.upper), (2, 2));
+
+    // Without frequencies, there's no way to map the solutions.
+    sols.chanblock_freqs = None;
+    assert!(sols
+        .get_chanblock_interps(&freqs, FreqInterpolation::Nearest)
+        .is_none());
+}
+
+#[test]
+fn test_combine_residual_stats() {
+    // The residuals of two sets of visibilities: [3, 4] and [1, 1, 1].
+    let a = ResidualStat {
+        rms: (25.0_f64 / 2.0).sqrt(),
+        chi_squared: 25.0,
+        num_vis: 2,
+    };
+    let b = ResidualStat {
+        rms: 1.0,
+        chi_squared: 1.5,
+        num_vis: 3,
+    };
+    let c = a.combine(b);
+    assert_abs_diff_eq!(c.rms, (28.0_f64 / 5.0).sqrt(), epsilon = 1e-12);
+    assert_abs_diff_eq!(c.chi_squared, 26.5);
+    assert_eq!(c.num_vis, 5);
+
+    // Statistics without visibilities don't change anything.
+    assert_eq!(a.combine(ResidualStat::default()), a);
+    assert_eq!(ResidualStat::default().combine(b), b);
+    assert!(ResidualStat::default()
+        .combine(ResidualStat::default())
+        .rms
+        .is_nan());
+
+    let mut stats = ResidualStats {
+        tiles: Array3::from_elem((1, 2, 2), ResidualStat::default()),
+        baselines: Array2::from_elem((1, 1), a),
+    };
+    stats.tiles[(0, 0, 0)] = a;
+    let other = ResidualStats {
+        tiles: Array3::from_shape_fn((1, 2, 2), |(_, _, i_chanblock)| {
+            if i_chanblock == 1 {
+                b
+            } else {
+                ResidualStat::default()
+            }
+        }),
+        baselines: Array2::from_elem((1, 1), b),
+    };
+    stats.combine(&other);
+    assert_eq!(stats.tiles[(0, 0, 0)], a);
+    assert!(stats.tiles[(0, 1, 0)].rms.is_nan());
+    assert_eq!(stats.tiles[(0, 0, 1)], b);
+    assert_eq!(stats.tiles[(0, 1, 1)], b);
+    assert_eq!(stats.baselines[(0, 0)], c);
+}
+
+#[test]
+#[serial]
+fn test_write_casa_solutions() {
+    let sols = make_solutions();
+    let tmp_dir = tempfile::tempdir().expect("Couldn't make tmp dir");
+    let table_path = tmp_dir.path().join("sols.bcal");
+    casa::write(&sols, &table_path, None).unwrap();
+
+    let mut main_table = Table::open(&table_path, TableOpenMode::Read).unwrap();
+    let vis_cal: String = main_table
+        .get_keyword_record()
+        .unwrap()
+        .get_field("VisCal")
+        .unwrap();
+    assert_eq!(vis_cal, "B Jones");
+    assert_eq!(main_table.n_rows(), 2 * 128);
+
+    let antenna1: Vec<i32> = main_table.get_col_as_vec("ANTENNA1").unwrap();
+    assert_eq!(antenna1[0], 0);
+    assert_eq!(antenna1[129], 1);
+    let times: Vec<f64> = main_table.get_col_as_vec("TIME").unwrap();
+    assert_abs_diff_eq!(
+        times[0],
+        hifitime::J1900_OFFSET.mul_add(
+            hifitime::SECONDS_PER_DAY,
+            Epoch::from_gpst_seconds(1090008645.0).to_utc_seconds()
+        )
+    );
+    assert_abs_diff_eq!(times[128] - times[0], 10.0, epsilon = 1e-6);
+
+    // Second timeblock, tile 1.
+    let cparam: Array2<c32> = main_table.get_cell("CPARAM", 129).unwrap();
+    let flags: Array2<bool> = main_table.get_cell("FLAG", 129).unwrap();
+    assert_eq!(cparam.dim(), (768, 2));
+    // CASA tables hold the inverse of hyperdrive solutions, including any
+    // XY-phase corrections.
+    let j = sols.get_di_jones_with_xy_phase()[(1, 1, 2)].inv();
+    assert_eq!(cparam[(2, 0)], c32::new(j[0].re as f32, j[0].im as f32));
+    assert_eq!(cparam[(2, 1)], c32::new(j[3].re as f32, j[3].im as f32));
+    assert!(!flags[(0, 0)]);
+    // Flagged chanblocks.
+    assert!(flags[(5, 0)] && flags[(7, 1)]);
+    assert_eq!(cparam[(5, 0)], c32::new(1.0, 0.0));
+    // Flagged tiles.
+    let flags: Array2<bool> = main_table.get_cell("FLAG", 3).unwrap();
+    assert!(flags.iter().all(|&f| f));
+
+    let mut spw_table =
+        Table::open(table_path.join("SPECTRAL_WINDOW"), TableOpenMode::Read).unwrap();
+    let chan_freqs: Vec<f64> = spw_table.get_cell_as_vec("CHAN_FREQ", 0).unwrap();
+    assert_eq!(
+        chan_freqs.as_slice(),
+        sols.chanblock_freqs.as_ref().unwrap().as_slice()
+    );
+    let chan_widths: Vec<f64> = spw_table.get_cell_as_vec("CHAN_WIDTH", 0).unwrap();
+    let freqs = sols.chanblock_freqs.as_ref().unwrap();
+    assert_abs_diff_eq!(chan_widths[0], freqs[1] - freqs[0]);
+    let mut antenna_table = Table::open(table_path.join("ANTENNA"), TableOpenMode::Read).unwrap();
+    let names: Vec<String> = antenna_table.get_col_as_vec("NAME").unwrap();
+    assert_eq!(names[5], "tile005");
+
+    // Frequencies are required.
+    let sols = CalibrationSolutions {
+        chanblock_freqs: None,
+        ..sols
+    };
+    let result = casa::write(&sols, &table_path, None);
+    assert!(matches!(
+        result,
+        Err(SolutionsWriteError::CasaNoChanblockFreqs)
+    ));
+
+    // So is the frequency resolution, which can't be determined from a single
+    // chanblock.
+    let sols = CalibrationSolutions {
+        di_jones: sols.di_jones.slice(s![.., .., ..1]).to_owned(),
+        flagged_chanblocks: vec![],
+        chanblock_freqs: Some(vec1![150e6]),
+        ..sols
+    };
+    let table_path = tmp_dir.path().join("sols2.bcal");
+    let result = casa::write(&sols, &table_path, None);
+    assert!(matches!(result, Err(SolutionsWriteError::CasaNoFreqRes)));
+    casa::write(&sols, &table_path, Some(40e3)).unwrap();
+    let mut spw_table =
+        Table::open(table_path.join("SPECTRAL_WINDOW"), TableOpenMode::Read).unwrap();
+    let chan_widths: Vec<f64> = spw_table.get_cell_as_vec("CHAN_WIDTH", 0).unwrap();
+    assert_abs_diff_eq!(chan_widths[0], 40e3);
+}
@@ -10,6 +10,7 @@
 mod di_calibrate;
 mod no_stderr;
 mod solutions_apply;
+mod vis_subtract;
 
 use std::{
     path::{Path, PathBuf},
@@ -0,0 +1,65 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tests against the command-line interface for vis-subtract.
+
+use std::path::Path;
+
+use marlu::Jones;
+use ndarray::prelude::*;
+use tempfile::TempDir;
+use vec1::Vec1;
+
+use mwa_hyperdrive::CalibrationSolutions;
+
+use crate::{get_cmd_output, get_reduced_1090008640, hyperdrive, Files};
+
+#[test]
+fn test_vis_subtract_freq_interpolation() {
+    let tmp_dir = TempDir::new().expect("couldn't make tmp dir");
+    let Files { data, srclist } = get_reduced_1090008640(false);
+
+    // Solutions every MHz over the whole MWA band, so that they cover the
+    // data at a coarser resolution.
+    let chanblock_freqs: Vec<f64> = (70..=300).map(|f| f as f64 * 1e6).collect();
+    let sols = CalibrationSolutions {
+        di_jones: Array3::from_elem((1, 128, chanblock_freqs.len()), Jones::identity()),
+        chanblock_freqs: Some(Vec1::try_from_vec(chanblock_freqs).unwrap()),
+        ..Default::default()
+    };
+    let sols_file = tmp_dir.path().join("sols.fits");
+    sols.write_solutions_from_ext::<&Path>(&sols_file).unwrap();
+    let output = tmp_dir.path().join("subtracted.uvfits");
+
+    for (method, should_work) in [("nearest", true), ("linear", true), ("cubic", false)] {
+        #[rustfmt::skip]
+        let cmd = hyperdrive()
+            .args([
+                "vis-subtract",
+                "--data", &data[0], &data[1], &format!("{}", sols_file.display()),
+                "--freq-interpolation", method,
+                "--source-list", &srclist,
+                "--num-sources", "1",
+                "--invert",
+                "--no-beam",
+                "--outputs", &format!("{}", output.display()),
+                "--no-progress-bars",
+            ])
+            .ok();
+        if should_work {
+            assert!(
+                cmd.is_ok(),
+                "vis-subtract failed with --freq-interpolation {method}: {}",
+                cmd.err().unwrap()
+            );
+        } else {
+            assert!(cmd.is_err());
+            let (_, stderr) = get_cmd_output(cmd);
+            assert!(
+                stderr.contains("Unrecognised frequency interpolation method 'cubic'"),
+                "{stderr}"
+            );
+        }
+    }
+}